@@ -10,6 +10,9 @@ enum export_format_type {
     EXPORT_PLAIN_TEXT,
     EXPORT_JSON,
     EXPORT_RAW_EVENT,
+    EXPORT_CSV,
+    EXPORT_LOGFMT,
+    EXPORT_NDJSON,
 };
 struct eunomia_bpf;
 struct eunomia_polling_handle;
@@ -67,6 +70,14 @@ int parse_args_to_json_config(const char* json_config,
                               int argc,
                               char* out_buffer,
                               size_t out_buffer_size);
+/// @brief get the delivery statistics of export maps, as a json array
+/// @details each element contains `map_name`, `events`, `bytes`,
+/// `decode_failures`, `ringbuf_errors` and `lost_samples` (keyed by cpu).
+/// The output will be truncated if the buffer is too small.
+/// Returns -1 if the buffer is null or its size is zero
+int get_eunomia_map_stats(struct eunomia_bpf* prog,
+                          char* out_buffer,
+                          size_t out_buffer_size);
 /// @brief create a polling handle from a ready-to-poll eunomia
 eunomia_polling_handle* handle_create(struct eunomia_bpf* prog);
 /// @brief pause or resume the poller
@@ -4,11 +4,12 @@
 //! All rights reserved.
 //!
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use bpf_loader_lib::serde::Deserialize;
 use std::{
     any::type_name,
     ffi::{c_char, CStr},
+    slice,
 };
 pub(crate) unsafe fn convert_args(args: &[*const c_char]) -> Result<Vec<&str>> {
     let mut ret = vec![];
@@ -44,3 +45,22 @@ pub(crate) fn load_null_ptr_to_option_string(s: *const c_char) -> Result<Option<
         )
     })
 }
+
+/// Copy the bytes into the buffer provided by the caller, followed by a trailing zero. They will be truncated if the buffer is too small
+pub(crate) fn copy_to_out_buffer(
+    data: &[u8],
+    out_buffer: *mut c_char,
+    out_buffer_size: usize,
+) -> Result<()> {
+    if out_buffer.is_null() {
+        bail!("The output buffer should not be null");
+    }
+    if out_buffer_size == 0 {
+        bail!("The size of the output buffer should not be zero");
+    }
+    let out_slice = unsafe { slice::from_raw_parts_mut(out_buffer as *mut u8, out_buffer_size) };
+    let len = data.len().min(out_buffer_size - 1);
+    out_slice[..len].copy_from_slice(&data[..len]);
+    out_slice[len] = 0;
+    Ok(())
+}
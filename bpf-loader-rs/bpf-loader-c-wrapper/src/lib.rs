@@ -21,7 +21,7 @@ use bpf_loader_lib::{
     meta::{arg_parser::UnpresentVariableAction, ComposedObject, EunomiaObjectMeta},
     skeleton::builder::BpfSkeletonBuilder,
 };
-use helper::{convert_args, load_null_ptr_to_option_string, load_object};
+use helper::{convert_args, copy_to_out_buffer, load_null_ptr_to_option_string, load_object};
 use wrapper::{HandleWrapper, SkeletonWrapper};
 
 mod helper;
@@ -191,6 +191,9 @@ pub extern "C" fn wait_and_poll_events_to_handler(
         0 => ExportFormatType::PlainText,
         1 => ExportFormatType::Json,
         2 => ExportFormatType::RawEvent,
+        3 => ExportFormatType::Csv,
+        4 => ExportFormatType::Logfmt,
+        5 => ExportFormatType::Ndjson,
         s => my_bail_custom!(format!("Invalid export format type: {}", s), -1),
     };
     let prog = match unsafe { &*prog } {
@@ -299,14 +302,34 @@ pub extern "C" fn parse_args_to_json_config(
         Ok(v) => v,
         Err(e) => my_bail_custom!(format!("Failed to serialize new skel to json: {}", e), -1),
     };
-    let out_json_str = out_json_str.as_bytes();
-    let out_slice = unsafe { slice::from_raw_parts_mut(out_buffer, out_buffer_size) };
-    let mut i = 0;
-    while i < out_json_str.len() && i + 1 < out_slice.len() {
-        out_slice[i] = out_json_str[i] as c_char;
-        i += 1;
+    if let Err(e) = copy_to_out_buffer(out_json_str.as_bytes(), out_buffer, out_buffer_size) {
+        my_bail_custom!(e, -1);
+    }
+    0
+}
+
+#[no_mangle]
+/// @brief get the delivery statistics of export maps, as a json array
+/// @details each element contains `map_name`, `events`, `bytes`,
+/// `decode_failures`, `ringbuf_errors` and `lost_samples` (keyed by cpu).
+/// The output will be truncated if the buffer is too small.
+/// Returns -1 if the buffer is null or its size is zero
+pub extern "C" fn get_eunomia_map_stats(
+    prog: *mut SkeletonWrapper,
+    out_buffer: *mut c_char,
+    out_buffer_size: usize,
+) -> c_int {
+    let prog = match unsafe { &*prog } {
+        SkeletonWrapper::Loaded(prog) => prog,
+        _ => my_bail_custom!("Expected a loaded skeleton", -1),
+    };
+    let out_json_str = match serde_json::to_string(&prog.stats()) {
+        Ok(v) => v,
+        Err(e) => my_bail_custom!(format!("Failed to serialize stats to json: {}", e), -1),
+    };
+    if let Err(e) = copy_to_out_buffer(out_json_str.as_bytes(), out_buffer, out_buffer_size) {
+        my_bail_custom!(e, -1);
     }
-    out_slice[i] = 0;
     0
 }
 
@@ -344,14 +367,7 @@ pub extern "C" fn handle_destroy(handle: *mut HandleWrapper) {
 /// @brief Get the error message
 pub extern "C" fn get_error_message(str_out: *mut c_char, buf_size: usize) {
     ERROR_MESSAGE.with(|v| {
-        let borrow_ref = v.borrow();
-        let bytes = borrow_ref.as_bytes();
-        let out_bytes = unsafe { std::slice::from_raw_parts_mut(str_out as *mut u8, buf_size) };
-        let mut i = 0;
-        while i + 1 < out_bytes.len() && i < bytes.len() {
-            out_bytes[i] = bytes[i];
-            i += 1;
-        }
-        out_bytes[i] = 0;
+        // Nothing could be reported if the buffer is invalid
+        copy_to_out_buffer(v.borrow().as_bytes(), str_out, buf_size).ok();
     });
 }
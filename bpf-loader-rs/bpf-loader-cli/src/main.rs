@@ -7,9 +7,15 @@
 use std::thread;
 
 use bpf_loader_lib::{
-    clap::{Arg, ArgAction, Command},
-    export_event::ExportFormatType,
-    meta::{arg_parser::UnpresentVariableAction, ComposedObject, EunomiaObjectMeta},
+    clap::{Arg, ArgAction, ArgGroup, Command},
+    export_event::{
+        filter::EventFilter,
+        metrics::{MetricsRegistry, MetricsServer},
+        ExportFormatType,
+    },
+    meta::{
+        arg_parser::UnpresentVariableAction, AggregationMeta, ComposedObject, EunomiaObjectMeta,
+    },
     skeleton::builder::BpfSkeletonBuilder,
 };
 
@@ -43,6 +49,74 @@ fn main() -> Result<()> {
                 .action(ArgAction::Append)
                 .help("Args to the bpf program"),
         )
+        .arg(
+            Arg::new("filter")
+                .long("filter")
+                .short('f')
+                .help("Only print events matching the expression, such as `comm == \"nginx\" && latency_ns > 1ms`")
+                .value_parser(|s: &str| {
+                    EventFilter::parse(s)
+                        .map(|_| s.to_string())
+                        .map_err(|e| format!("{:#}", e))
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .help("Output format of the exported events")
+                .value_parser(["plain", "json", "csv", "logfmt", "ndjson"])
+                .default_value("plain"),
+        )
+        .arg(
+            Arg::new("group-by")
+                .long("group-by")
+                .help("Aggregate events grouped by these fields, such as `comm,filename`")
+                .value_delimiter(',')
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("aggregate")
+                .long("aggregate")
+                .help("Aggregate functions over each group: `count`, `sum(field)`, `min(field)`, `max(field)` or `hist(field)`")
+                .value_delimiter(',')
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("window")
+                .long("window")
+                .help("Length of the aggregation window, in ms")
+                .value_parser(bpf_loader_lib::clap::value_parser!(usize))
+                .requires("aggregation")
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::new("top")
+                .long("top")
+                .help("Only print the first such groups of each window, ordered by the first aggregate")
+                .value_parser(bpf_loader_lib::clap::value_parser!(usize))
+                .requires("aggregation")
+                .default_value("0"),
+        )
+        .group(
+            ArgGroup::new("aggregation")
+                .args(["group-by", "aggregate"])
+                .multiple(true),
+        )
+        .arg(
+            Arg::new("stats-interval")
+                .long("stats-interval")
+                .help("Log delivery statistics of export maps every such ms")
+                .value_parser(bpf_loader_lib::clap::value_parser!(usize))
+                .required(false),
+        )
+        .arg(
+            Arg::new("metrics")
+                .long("metrics")
+                .help("Serve sample maps and event counters as Prometheus metrics at http://<ADDRESS>/metrics, such as `127.0.0.1:9100`. Sample maps will not be printed")
+                .value_name("ADDRESS")
+                .required(false),
+        )
         .arg(
             Arg::new("no-log")
                 .long("no-log")
@@ -56,6 +130,10 @@ fn main() -> Result<()> {
             .start()?;
     }
     let json_skel = matches.get_one::<String>("json_skeleton").unwrap();
+    let export_format = matches
+        .get_one::<String>("format")
+        .unwrap()
+        .parse::<ExportFormatType>()?;
     let elf_file = matches.get_one::<String>("elf_file");
     let mut bpf_args = matches
         .get_many::<String>("bpf_args")
@@ -90,6 +168,28 @@ fn main() -> Result<()> {
         (data.bpf_object, data.meta)
     };
 
+    if let Some(filter) = matches.get_one::<String>("filter") {
+        meta.event_filter = Some(filter.clone());
+    }
+    let group_by = matches.get_many::<String>("group-by");
+    let aggregates = matches.get_many::<String>("aggregate");
+    if group_by.is_some() || aggregates.is_some() {
+        let aggregation = AggregationMeta {
+            group_by: group_by.map(|v| v.cloned().collect()).unwrap_or_default(),
+            aggregates: aggregates
+                .map(|v| v.cloned().collect())
+                .unwrap_or_else(|| vec!["count".into()]),
+            window_ms: *matches.get_one::<usize>("window").unwrap(),
+            top: *matches.get_one::<usize>("top").unwrap(),
+        };
+        // Only export maps will make use of it
+        for map in meta.bpf_skel.maps.iter_mut() {
+            map.aggregation = Some(aggregation.clone());
+        }
+    }
+    if let Some(interval) = matches.get_one::<usize>("stats-interval") {
+        meta.stats_interval_ms = *interval;
+    }
     let bpf_parser = meta.build_argument_parser()?;
     let bpf_matches = bpf_parser.get_matches_from(bpf_args);
     meta.parse_arguments_and_fill_skeleton_variables(
@@ -130,7 +230,24 @@ fn main() -> Result<()> {
             }
         }
     });
-    skel.wait_and_poll_to_handler(ExportFormatType::PlainText, None, None)
-        .with_context(|| anyhow!("Failed to poll"))?;
+    if let Some(address) = matches.get_one::<String>("metrics") {
+        let registry = MetricsRegistry::new().with_stats(skel.create_stats_handle());
+        let server = MetricsServer::serve(address.as_str(), registry.clone())?;
+        info!("Serving metrics at http://{}/metrics", server.local_addr());
+        skel.wait_and_poll_to_handler_with_metrics(export_format, None, None, &registry)
+            .with_context(|| anyhow!("Failed to poll"))?;
+    } else {
+        skel.wait_and_poll_to_handler(export_format, None, None)
+            .with_context(|| anyhow!("Failed to poll"))?;
+    }
+    for stat in skel.stats() {
+        info!(
+            "Map `{}`: {} events, {} decode failures, {} lost samples",
+            stat.map_name,
+            stat.events,
+            stat.decode_failures,
+            stat.total_lost_samples()
+        );
+    }
     Ok(())
 }
@@ -0,0 +1,537 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+//! # Windowed aggregation
+//!
+//! Instead of exporting each event, events are grouped by some fields and folded into aggregates. When a window ends, the groups are exported as one table (in plain text), or one json object, then the aggregator starts over.
+
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{Local, TimeZone};
+use serde_json::{json, Value};
+
+use crate::{helper::log2_hist::print_log2_hist, meta::AggregationMeta};
+
+use super::data_dumper::template::{lookup, parse_path};
+
+/// Slots of a log2 histogram, which are enough for any u64
+const HIST_SLOTS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AggregateKind {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Log2Hist,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AggregateFunction {
+    /// Such as `sum(size)`, which is used as the column name
+    name: String,
+    kind: AggregateKind,
+    /// Path of the aggregated field. Empty for `count`
+    path: Vec<String>,
+}
+
+impl AggregateFunction {
+    fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        if spec == "count" || spec == "count()" {
+            return Ok(Self {
+                name: "count".into(),
+                kind: AggregateKind::Count,
+                path: vec![],
+            });
+        }
+        let (func, field) = spec
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid aggregate `{}`, expected something like `sum(field)`",
+                    spec
+                )
+            })?;
+        let kind = match func.trim() {
+            "sum" => AggregateKind::Sum,
+            "min" => AggregateKind::Min,
+            "max" => AggregateKind::Max,
+            "hist" => AggregateKind::Log2Hist,
+            s => bail!(
+                "Unknown aggregate function `{}`. Valid ones are `count`, `sum`, `min`, `max` and `hist`",
+                s
+            ),
+        };
+        let field = field.trim();
+        Ok(Self {
+            name: format!("{}({})", func.trim(), field),
+            kind,
+            path: parse_path(field)?,
+        })
+    }
+}
+
+/// An integer, or a float if any of the aggregated values is a float
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+impl Number {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => n
+                .as_i64()
+                .map(|v| Self::Int(v as i128))
+                .or_else(|| n.as_u64().map(|v| Self::Int(v as i128)))
+                .or_else(|| n.as_f64().map(Self::Float)),
+            Value::Bool(b) => Some(Self::Int(*b as i128)),
+            // 128-bit integers are dumped as strings
+            Value::String(s) => s.parse::<i128>().ok().map(Self::Int),
+            _ => None,
+        }
+    }
+    fn as_f64(self) -> f64 {
+        match self {
+            Self::Int(v) => v as f64,
+            Self::Float(v) => v,
+        }
+    }
+    fn add(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => Self::Int(a.saturating_add(b)),
+            (a, b) => Self::Float(a.as_f64() + b.as_f64()),
+        }
+    }
+    fn compare(self, other: Self) -> Ordering {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => a.cmp(&b),
+            (a, b) => a.as_f64().total_cmp(&b.as_f64()),
+        }
+    }
+    fn to_json(self) -> Value {
+        match self {
+            Self::Int(v) => i64::try_from(v)
+                .map(|v| json!(v))
+                .or_else(|_| u64::try_from(v).map(|v| json!(v)))
+                .unwrap_or_else(|_| json!(v.to_string())),
+            Self::Float(v) => json!(v),
+        }
+    }
+}
+
+impl std::fmt::Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(v) => write!(f, "{v}"),
+            Self::Float(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Accumulator {
+    Count(u64),
+    Sum(Option<Number>),
+    Min(Option<Number>),
+    Max(Option<Number>),
+    Log2Hist(Vec<u32>),
+}
+
+impl Accumulator {
+    fn new(kind: &AggregateKind) -> Self {
+        match kind {
+            AggregateKind::Count => Self::Count(0),
+            AggregateKind::Sum => Self::Sum(None),
+            AggregateKind::Min => Self::Min(None),
+            AggregateKind::Max => Self::Max(None),
+            AggregateKind::Log2Hist => Self::Log2Hist(vec![0; HIST_SLOTS]),
+        }
+    }
+    /// Fold a value into it. `value` is None for `count`, or if the field is not a number
+    fn update(&mut self, value: Option<Number>) {
+        match (self, value) {
+            (Self::Count(count), _) => *count += 1,
+            (Self::Sum(sum), Some(v)) => *sum = Some(sum.map_or(v, |s| s.add(v))),
+            (Self::Min(min), Some(v)) => {
+                if !matches!(*min, Some(m) if v.compare(m) != Ordering::Less) {
+                    *min = Some(v);
+                }
+            }
+            (Self::Max(max), Some(v)) => {
+                if !matches!(*max, Some(m) if v.compare(m) != Ordering::Greater) {
+                    *max = Some(v);
+                }
+            }
+            (Self::Log2Hist(slots), Some(v)) => {
+                let v = match v {
+                    Number::Int(v) => v.clamp(0, u64::MAX as i128) as u64,
+                    Number::Float(v) => v.max(0.0) as u64,
+                };
+                // Same as bcc: 0 and 1 are in slot 0, 2..=3 in slot 1, 4..=7 in slot 2..
+                let slot = if v < 2 {
+                    0
+                } else {
+                    63 - v.leading_zeros() as usize
+                };
+                slots[slot] = slots[slot].saturating_add(1);
+            }
+            (_, None) => {}
+        }
+    }
+    /// Value used to order the groups
+    fn sort_key(&self) -> Number {
+        match self {
+            Self::Count(v) => Number::Int(*v as i128),
+            Self::Sum(v) | Self::Min(v) | Self::Max(v) => v.unwrap_or(Number::Int(0)),
+            Self::Log2Hist(slots) => Number::Int(slots.iter().map(|v| *v as i128).sum()),
+        }
+    }
+    /// Trailing empty slots of a histogram are removed
+    fn to_json(&self) -> Value {
+        match self {
+            Self::Count(v) => json!(v),
+            Self::Sum(v) | Self::Min(v) | Self::Max(v) => v.map_or(Value::Null, Number::to_json),
+            Self::Log2Hist(slots) => {
+                let len = slots.iter().rposition(|v| *v != 0).map_or(0, |v| v + 1);
+                json!(slots[..len])
+            }
+        }
+    }
+    fn to_plain_text(&self) -> String {
+        match self {
+            Self::Count(v) => v.to_string(),
+            Self::Sum(v) | Self::Min(v) | Self::Max(v) => {
+                v.map_or_else(|| "-".to_string(), |v| v.to_string())
+            }
+            Self::Log2Hist(_) => String::new(),
+        }
+    }
+}
+
+struct Group {
+    /// Values of the group-by fields. Null if the field is absent
+    values: Vec<Value>,
+    accumulators: Vec<Accumulator>,
+}
+
+struct Window {
+    start_ns: u64,
+    /// Keyed by the serialized group-by values, so that the groups are ordered stably
+    groups: BTreeMap<String, Group>,
+}
+
+/// Groups that were aggregated in a window
+pub(crate) struct AggregatedWindow {
+    start_ns: u64,
+    end_ns: u64,
+    groups: Vec<Group>,
+}
+
+/// Aggregates decoded events in tumbling windows
+pub(crate) struct Aggregator {
+    group_by: Vec<(String, Vec<String>)>,
+    functions: Vec<AggregateFunction>,
+    window: Duration,
+    top: usize,
+    state: Mutex<Window>,
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+impl Aggregator {
+    /// Parse the group-by fields and the aggregate functions
+    pub(crate) fn new(meta: &AggregationMeta) -> Result<Self> {
+        if meta.window_ms == 0 {
+            bail!("Length of the aggregation window should not be zero");
+        }
+        if meta.aggregates.is_empty() {
+            bail!("At least one aggregate function should be provided");
+        }
+        let mut group_by = vec![];
+        for name in meta.group_by.iter() {
+            let name = name.trim();
+            group_by.push((name.to_string(), parse_path(name)?));
+        }
+        let functions = meta
+            .aggregates
+            .iter()
+            .map(|v| AggregateFunction::parse(v))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            group_by,
+            functions,
+            window: Duration::from_millis(meta.window_ms as u64),
+            top: meta.top,
+            state: Mutex::new(Window {
+                start_ns: now_ns(),
+                groups: BTreeMap::new(),
+            }),
+        })
+    }
+    /// Length of each window
+    pub(crate) fn window(&self) -> Duration {
+        self.window
+    }
+    /// Check that the group-by fields and the aggregated fields refer to one of the provided top-level fields
+    pub(crate) fn check_fields(&self, fields: &[&str]) -> Result<()> {
+        let paths = self
+            .group_by
+            .iter()
+            .map(|(name, path)| (name.as_str(), path))
+            .chain(
+                self.functions
+                    .iter()
+                    .filter(|f| !f.path.is_empty())
+                    .map(|f| (f.name.as_str(), &f.path)),
+            );
+        for (name, path) in paths {
+            if !fields.contains(&path[0].as_str()) {
+                bail!(
+                    "Field `{}` in the aggregation is not exported. Available fields: {}",
+                    name,
+                    fields.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+    /// Fold a decoded event into the current window
+    pub(crate) fn add(&self, event: &Value) {
+        let values = self
+            .group_by
+            .iter()
+            .map(|(_, path)| lookup(event, path).cloned().unwrap_or(Value::Null))
+            .collect::<Vec<_>>();
+        // SAFETY: Serializing a json value won't fail
+        let key = serde_json::to_string(&values).unwrap();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let group = state.groups.entry(key).or_insert_with(|| Group {
+            values,
+            accumulators: self
+                .functions
+                .iter()
+                .map(|f| Accumulator::new(&f.kind))
+                .collect(),
+        });
+        for (func, acc) in self.functions.iter().zip(group.accumulators.iter_mut()) {
+            let value = if func.path.is_empty() {
+                None
+            } else {
+                lookup(event, &func.path).and_then(Number::from_value)
+            };
+            acc.update(value);
+        }
+    }
+    /// End the current window and start a new one. The groups are ordered by the first aggregate, descending
+    pub(crate) fn take_window(&self) -> AggregatedWindow {
+        let end_ns = now_ns();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let start_ns = std::mem::replace(&mut state.start_ns, end_ns);
+        let mut groups = std::mem::take(&mut state.groups)
+            .into_values()
+            .collect::<Vec<_>>();
+        drop(state);
+        // The sort is stable, so groups with the same value keep ordered by their keys
+        groups.sort_by(|a, b| {
+            b.accumulators[0]
+                .sort_key()
+                .compare(a.accumulators[0].sort_key())
+        });
+        if self.top != 0 {
+            groups.truncate(self.top);
+        }
+        AggregatedWindow {
+            start_ns,
+            end_ns,
+            groups,
+        }
+    }
+    /// `{"window_start_ns": .., "window_end_ns": .., "groups": [{"comm": .., "count": ..}]}`
+    ///
+    /// Histograms are arrays of the counts of each log2 slot
+    pub(crate) fn window_to_json(&self, window: &AggregatedWindow) -> Value {
+        let groups = window
+            .groups
+            .iter()
+            .map(|group| {
+                let mut obj = serde_json::Map::new();
+                for ((name, _), value) in self.group_by.iter().zip(group.values.iter()) {
+                    obj.insert(name.clone(), value.clone());
+                }
+                for (func, acc) in self.functions.iter().zip(group.accumulators.iter()) {
+                    obj.insert(func.name.clone(), acc.to_json());
+                }
+                Value::Object(obj)
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "window_start_ns": window.start_ns,
+            "window_end_ns": window.end_ns,
+            "groups": groups,
+        })
+    }
+    /// Render the window as a table below a line of the end time. Histograms are printed after the row of their group
+    pub(crate) fn window_to_plain_text(
+        &self,
+        window: &AggregatedWindow,
+        time_format: &str,
+    ) -> String {
+        let mut out = String::new();
+        let end_time = Local
+            .timestamp_nanos(window.end_ns as i64)
+            .format(time_format);
+        // SAFETY: It won't fail
+        writeln!(out, "[{end_time}]").unwrap();
+        let columns = self
+            .functions
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.kind != AggregateKind::Log2Hist)
+            .collect::<Vec<_>>();
+        let header = self
+            .group_by
+            .iter()
+            .map(|(name, _)| name.to_ascii_uppercase())
+            .chain(columns.iter().map(|(_, f)| f.name.to_ascii_uppercase()))
+            .collect::<Vec<_>>();
+        let rows = window
+            .groups
+            .iter()
+            .map(|group| {
+                group
+                    .values
+                    .iter()
+                    .map(|v| match v {
+                        Value::String(s) => s.clone(),
+                        v => v.to_string(),
+                    })
+                    .chain(
+                        columns
+                            .iter()
+                            .map(|(idx, _)| group.accumulators[*idx].to_plain_text()),
+                    )
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let widths = header
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(std::iter::once(name.chars().count()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+        let write_row = |out: &mut String, cells: &[String]| {
+            let mut line = String::new();
+            for (cell, width) in cells.iter().zip(widths.iter()) {
+                write!(line, "{cell:<width$} ").unwrap();
+            }
+            out.push_str(line.trim_end());
+            out.push('\n');
+        };
+        write_row(&mut out, &header);
+        for (group, row) in window.groups.iter().zip(rows.iter()) {
+            write_row(&mut out, row);
+            for (func, acc) in self.functions.iter().zip(group.accumulators.iter()) {
+                if let Accumulator::Log2Hist(slots) = acc {
+                    print_log2_hist(slots, func.path.join("."), &mut out);
+                }
+            }
+        }
+        // The caller prints a line for each string
+        out.truncate(out.trim_end().len());
+        out
+    }
+}
+
+impl AggregatedWindow {
+    /// Whether no event arrived in the window
+    pub(crate) fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Aggregator;
+    use crate::meta::AggregationMeta;
+
+    #[test]
+    fn test_aggregate_window() {
+        let aggregator = Aggregator::new(&AggregationMeta {
+            group_by: vec!["comm".into()],
+            aggregates: vec![
+                "count".into(),
+                "sum(size)".into(),
+                "max(size)".into(),
+                "hist(size)".into(),
+            ],
+            window_ms: 1000,
+            top: 2,
+        })
+        .unwrap();
+        assert!(aggregator.check_fields(&["comm", "size"]).is_ok());
+        assert!(aggregator.check_fields(&["comm"]).is_err());
+        for (comm, size) in [
+            ("bash", 1),
+            ("cat", 4),
+            ("bash", 8),
+            ("ls", 1),
+            ("cat", 5),
+            ("cat", 2),
+        ] {
+            aggregator.add(&json!({ "comm": comm, "size": size }));
+        }
+        let window = aggregator.take_window();
+        let result = aggregator.window_to_json(&window);
+        assert_eq!(
+            result["groups"],
+            json!([
+                {"comm": "cat", "count": 3, "sum(size)": 11, "max(size)": 5, "hist(size)": [0, 1, 2]},
+                {"comm": "bash", "count": 2, "sum(size)": 9, "max(size)": 8, "hist(size)": [1, 0, 0, 1]},
+            ])
+        );
+        let text = aggregator.window_to_plain_text(&window, "%H:%M:%S");
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[1], "COMM COUNT SUM(SIZE) MAX(SIZE)");
+        assert_eq!(lines[2], "cat  3     11        5");
+        assert!(lines[3].contains("size"));
+        // A new window starts empty
+        assert!(aggregator.take_window().is_empty());
+
+        assert!(Aggregator::new(&AggregationMeta {
+            aggregates: vec!["avg(size)".into()],
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Aggregator::new(&AggregationMeta {
+            window_ms: 0,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
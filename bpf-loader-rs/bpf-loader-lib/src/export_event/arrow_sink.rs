@@ -0,0 +1,380 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+//! # Arrow schemas and the parquet sink
+//!
+//! The schema is built from the same BTF types that the json dumper uses, and each arrow type matches what the json dumper produces:
+//! - Integers are mapped to integers of the same width and signedness. 128-bit integers are dumped as strings, so they are `Utf8`
+//! - Bools, floats and pointers are mapped to `Boolean`, `Float32`/`Float64` and `UInt32`/`UInt64`
+//! - `char[N]` is a `Utf8` string. Other arrays are lists of their element type
+//! - Enums are `Utf8`, in the form of `VARIANT(value)`
+//! - Structs and unions are `Struct`s of their members
+//!
+//! `ParquetSink` is an `EventHandler` which accepts events exported in `ExportFormatType::Json`, groups them into record batches, and writes them to parquet files. A file is closed and a new one is started once it grows larger than `max_file_size`, or was opened longer than `max_file_age` ago.
+
+use std::{
+    any::Any,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use arrow_json::{reader::Decoder, ReaderBuilder};
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef};
+use btf::types::{Btf, BtfConst, BtfIntEncoding, BtfRestrict, BtfType, BtfTypedef, BtfVolatile};
+use log::{error, warn};
+use parquet::{arrow::ArrowWriter, file::properties::WriterProperties};
+use serde_json::Value;
+
+use crate::meta::ExportedTypesStructMeta;
+
+use super::{
+    checker::check_export_types_btf, type_descriptor::TypeDescriptor, CheckedExportedMember,
+    EventHandler, EventMeta, ReceivedEventData, JSON_EVENT_META_KEY,
+};
+
+/// Map a BTF type to the arrow type of its json representation
+pub fn arrow_type_of_btf(btf: &Btf, type_id: u32) -> Result<DataType> {
+    let ty = btf
+        .types()
+        .get(type_id as usize)
+        .ok_or_else(|| anyhow!("Invalid type id: {}", type_id))?;
+    let result = match ty {
+        BtfType::Int(btf_int) => match (btf_int.bits, btf_int.encoding) {
+            (_, BtfIntEncoding::Bool) => DataType::Boolean,
+            (8, BtfIntEncoding::Signed) => DataType::Int8,
+            (8, _) => DataType::UInt8,
+            (16, BtfIntEncoding::Signed) => DataType::Int16,
+            (16, _) => DataType::UInt16,
+            (32, BtfIntEncoding::Signed) => DataType::Int32,
+            (32, _) => DataType::UInt32,
+            (64, BtfIntEncoding::Signed) => DataType::Int64,
+            (64, _) => DataType::UInt64,
+            (128, _) => DataType::Utf8,
+            (a, _) => bail!("Unsupported integer length: {} in bits", a),
+        },
+        BtfType::Ptr(_) => match btf.get_size_of(type_id) {
+            4 => DataType::UInt32,
+            _ => DataType::UInt64,
+        },
+        BtfType::Array(arr) => {
+            let elem_ty = btf
+                .types()
+                .get(arr.val_type_id as usize)
+                .ok_or_else(|| anyhow!("Invalid element type of array {}", type_id))?;
+            if elem_ty.name() == "char" {
+                DataType::Utf8
+            } else {
+                DataType::new_list(arrow_type_of_btf(btf, arr.val_type_id)?, false)
+            }
+        }
+        BtfType::Struct(comp) | BtfType::Union(comp) => {
+            let mut fields = vec![];
+            for member in comp.members.iter() {
+                fields.push(Field::new(
+                    member.name,
+                    arrow_type_of_btf(btf, member.type_id)?,
+                    false,
+                ));
+            }
+            DataType::Struct(Fields::from(fields))
+        }
+        BtfType::Enum(_) => DataType::Utf8,
+        BtfType::Float(ft) => match ft.sz {
+            4 => DataType::Float32,
+            8 => DataType::Float64,
+            s => bail!("Unsupported float size: {}", s),
+        },
+        BtfType::Typedef(BtfTypedef { type_id, .. })
+        | BtfType::Volatile(BtfVolatile { type_id })
+        | BtfType::Const(BtfConst { type_id })
+        | BtfType::Restrict(BtfRestrict { type_id }) => arrow_type_of_btf(btf, *type_id)?,
+        other => bail!("Type `{}` can't be mapped to an arrow type", other),
+    };
+    Ok(result)
+}
+
+fn fields_of_members(btf: &Btf, members: &[CheckedExportedMember]) -> Result<Fields> {
+    let mut fields = vec![];
+    for member in members.iter() {
+        fields.push(Field::new(
+            member.field_name.as_str(),
+            arrow_type_of_btf(btf, member.type_id).with_context(|| {
+                anyhow!("Failed to get arrow type of field `{}`", member.field_name)
+            })?,
+            false,
+        ));
+    }
+    Ok(Fields::from(fields))
+}
+
+/// Build the schema of events dumped from the checked members, one column for each member
+pub fn arrow_schema_of_members(btf: &Btf, members: &[CheckedExportedMember]) -> Result<Schema> {
+    Ok(Schema::new(fields_of_members(btf, members)?))
+}
+
+/// Build the schema of a ringbuf or perf event array map, exporting the given struct
+pub fn arrow_schema_for_single_value(
+    btf: &Btf,
+    export_type: &ExportedTypesStructMeta,
+) -> Result<Schema> {
+    arrow_schema_of_members(btf, &check_export_types_btf(export_type, btf)?)
+}
+
+/// Build the schema of a sampled map, which has a `key` and a `value` column
+pub fn arrow_schema_for_key_value(
+    btf: &Btf,
+    key_type: TypeDescriptor,
+    value_type: TypeDescriptor,
+) -> Result<Schema> {
+    let key_fields = fields_of_members(btf, &key_type.build_checked_exported_members(btf)?)?;
+    let value_fields = fields_of_members(btf, &value_type.build_checked_exported_members(btf)?)?;
+    Ok(Schema::new(vec![
+        Field::new("key", DataType::Struct(key_fields), false),
+        Field::new("value", DataType::Struct(value_fields), false),
+    ]))
+}
+
+/// The column holding the event meta, in the same layout as `EventMeta::to_json`
+fn event_meta_field() -> Field {
+    Field::new(
+        JSON_EVENT_META_KEY,
+        DataType::Struct(Fields::from(vec![
+            Field::new("map", DataType::Utf8, false),
+            Field::new("cpu", DataType::Int32, true),
+            Field::new("timestamp_ns", DataType::UInt64, false),
+            Field::new("seq", DataType::UInt64, false),
+        ])),
+        false,
+    )
+}
+
+struct ParquetSinkState {
+    decoder: Decoder,
+    /// Rows in the decoder which are not written yet
+    pending_rows: usize,
+    writer: Option<ArrowWriter<File>>,
+    opened_at: Instant,
+    next_file_index: usize,
+    written_files: Vec<PathBuf>,
+}
+
+/// A sink which writes json events to parquet files
+pub struct ParquetSink {
+    schema: SchemaRef,
+    directory: PathBuf,
+    file_prefix: String,
+    batch_rows: usize,
+    max_file_size: usize,
+    max_file_age: Option<Duration>,
+    with_event_meta: bool,
+    properties: WriterProperties,
+    state: Mutex<ParquetSinkState>,
+}
+
+impl ParquetSink {
+    /// Create a builder. Files will be written to `directory`, with columns described by `schema`
+    pub fn builder(schema: Schema, directory: impl AsRef<Path>) -> ParquetSinkBuilder {
+        ParquetSinkBuilder {
+            schema,
+            directory: directory.as_ref().to_path_buf(),
+            file_prefix: "events".into(),
+            batch_rows: 8192,
+            max_file_size: 128 * 1024 * 1024,
+            max_file_age: None,
+            with_event_meta: false,
+            properties: WriterProperties::default(),
+        }
+    }
+    /// The schema of the written files, including the meta column if enabled
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+    /// Write the buffered events and close the current file. Returns all files written so far
+    ///
+    /// Events received after this will be written to a new file
+    pub fn finish(&self) -> Result<Vec<PathBuf>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.flush_batch(&mut state)?;
+        self.close_file(&mut state)?;
+        Ok(state.written_files.clone())
+    }
+    fn handle_json(&self, text: &str, meta: &EventMeta) -> Result<()> {
+        let mut event: Value =
+            serde_json::from_str(text).with_context(|| anyhow!("Received invalid json"))?;
+        if self.with_event_meta {
+            if let Value::Object(obj) = &mut event {
+                obj.insert(JSON_EVENT_META_KEY.into(), meta.to_json());
+            }
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .decoder
+            .serialize(&[event])
+            .with_context(|| anyhow!("Event doesn't match the schema"))?;
+        state.pending_rows += 1;
+        let expired = matches!(self.max_file_age, Some(age) if state.writer.is_some() && state.opened_at.elapsed() >= age);
+        if state.pending_rows >= self.batch_rows || expired {
+            self.flush_batch(&mut state)?;
+        }
+        let oversized = matches!(&state.writer, Some(w) if w.bytes_written() + w.in_progress_size() >= self.max_file_size);
+        if oversized || expired {
+            self.close_file(&mut state)?;
+        }
+        Ok(())
+    }
+    fn flush_batch(&self, state: &mut ParquetSinkState) -> Result<()> {
+        let Some(batch) = state.decoder.flush()? else {
+            return Ok(());
+        };
+        state.pending_rows = 0;
+        if state.writer.is_none() {
+            let path = self.directory.join(format!(
+                "{}-{:05}.parquet",
+                self.file_prefix, state.next_file_index
+            ));
+            let file = File::create(&path)
+                .with_context(|| anyhow!("Failed to create `{}`", path.display()))?;
+            state.writer = Some(ArrowWriter::try_new(
+                file,
+                self.schema.clone(),
+                Some(self.properties.clone()),
+            )?);
+            state.opened_at = Instant::now();
+            state.next_file_index += 1;
+            state.written_files.push(path);
+        }
+        // SAFETY: It was created above
+        state.writer.as_mut().unwrap().write(&batch)?;
+        Ok(())
+    }
+    fn close_file(&self, state: &mut ParquetSinkState) -> Result<()> {
+        if let Some(writer) = state.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl EventHandler for ParquetSink {
+    fn handle_event(&self, context: Option<Arc<dyn Any>>, data: ReceivedEventData) {
+        self.handle_event_with_meta(context, data, &EventMeta::default());
+    }
+    fn handle_event_with_meta(
+        &self,
+        _context: Option<Arc<dyn Any>>,
+        data: ReceivedEventData,
+        meta: &EventMeta,
+    ) {
+        match data {
+            ReceivedEventData::JsonText(text) => {
+                if let Err(e) = self.handle_json(text, meta) {
+                    error!("Failed to write event to parquet: {:?}", e);
+                }
+            }
+            _ => warn!("ParquetSink only accepts events exported in json"),
+        }
+    }
+}
+
+impl Drop for ParquetSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("Failed to finish the parquet file: {:?}", e);
+        }
+    }
+}
+
+/// Builder of `ParquetSink`
+pub struct ParquetSinkBuilder {
+    schema: Schema,
+    directory: PathBuf,
+    file_prefix: String,
+    batch_rows: usize,
+    max_file_size: usize,
+    max_file_age: Option<Duration>,
+    with_event_meta: bool,
+    properties: WriterProperties,
+}
+
+impl ParquetSinkBuilder {
+    /// Files will be named `<prefix>-<index>.parquet`. Default to `events`
+    pub fn file_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            file_prefix: prefix.into(),
+            ..self
+        }
+    }
+    /// Count of events in a record batch. Default to 8192
+    pub fn batch_rows(self, rows: usize) -> Self {
+        Self {
+            batch_rows: rows.max(1),
+            ..self
+        }
+    }
+    /// Start a new file once the current one is larger than `bytes`. Default to 128MiB
+    pub fn max_file_size(self, bytes: usize) -> Self {
+        Self {
+            max_file_size: bytes,
+            ..self
+        }
+    }
+    /// Start a new file once the current one was opened longer than `age` ago
+    ///
+    /// It's checked when an event arrives, so an idle file stays open until `finish` is called or the sink is dropped
+    pub fn max_file_age(self, age: Duration) -> Self {
+        Self {
+            max_file_age: Some(age),
+            ..self
+        }
+    }
+    /// Add a `_meta` column, which holds the map name, cpu, timestamp and sequence of each event
+    pub fn with_event_meta(self, enable: bool) -> Self {
+        Self {
+            with_event_meta: enable,
+            ..self
+        }
+    }
+    /// Properties of the parquet writer, such as compression
+    pub fn writer_properties(self, properties: WriterProperties) -> Self {
+        Self { properties, ..self }
+    }
+    /// Build the sink. The directory will be created if it doesn't exist
+    pub fn build(self) -> Result<ParquetSink> {
+        let mut fields = self.schema.fields().to_vec();
+        if self.with_event_meta {
+            fields.retain(|f| f.name() != JSON_EVENT_META_KEY);
+            fields.push(Arc::new(event_meta_field()));
+        }
+        let schema = Arc::new(Schema::new(fields));
+        std::fs::create_dir_all(&self.directory).with_context(|| {
+            anyhow!("Failed to create directory `{}`", self.directory.display())
+        })?;
+        let decoder = ReaderBuilder::new(schema.clone())
+            .with_batch_size(self.batch_rows)
+            .build_decoder()?;
+        Ok(ParquetSink {
+            schema,
+            directory: self.directory,
+            file_prefix: self.file_prefix,
+            batch_rows: self.batch_rows,
+            max_file_size: self.max_file_size,
+            max_file_age: self.max_file_age,
+            with_event_meta: self.with_event_meta,
+            properties: self.properties,
+            state: Mutex::new(ParquetSinkState {
+                decoder,
+                pending_rows: 0,
+                writer: None,
+                opened_at: Instant::now(),
+                next_file_index: 0,
+                written_files: vec![],
+            }),
+        })
+    }
+}
@@ -71,6 +71,7 @@ pub(crate) fn check_export_types_btf(
                 bit_offset: bit_off,
                 size: size as usize,
                 output_header_offset: 0,
+                output_width: 0,
             });
         }
         Ok(result)
@@ -153,6 +154,7 @@ fn check_and_push_export_type_btf(
         bit_offset: bit_off,
         size: size as usize,
         output_header_offset: 0,
+        output_width: 0,
     });
     Ok(())
 }
@@ -0,0 +1,405 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+//! # Enter/exit correlation
+//!
+//! Programs tracing both the entry and the return of something (such as a syscall) usually export two kinds of events, which are told apart by a discriminator field. `CorrelationStage` is a pipeline stage which pairs them up in userspace, so that the program doesn't need an extra hash map to compute durations.
+//!
+//! - An enter event is held back, keyed by the values of the key fields (such as `pid` and `tid`). If another enter event with the same key arrives before the exit event, it replaces the pending one, which is counted in `CorrelationStats::reentered`
+//! - When the exit event with the same key arrives, a merged event is emitted: fields of the enter event, overridden by fields of the exit event, along with the duration between them
+//! - Times are taken from a field of the events, which should be a kernel timestamp such as the one from `bpf_ktime_get_ns()`. The time events were received in userspace says nothing about when they happened
+//! - Enter events which wait longer than the timeout are dropped. If the table is full, the oldest one is dropped
+//! - Exit events without an enter event, and enter or exit events without the time field, are dropped. Events that are neither enter nor exit are passed through
+//!
+//! It could also be set in the `correlate` entry of a map in the skeleton meta, which applies it to the exporter of the map
+//!
+//! ```ignore
+//! let stage = CorrelationStage::builder("exit_event", json!(false), json!(true))
+//!     .key("pid")
+//!     .time_field("ts")
+//!     .timeout(Duration::from_secs(10))
+//!     .build()?;
+//! let pipeline = HandlerPipeline::builder().stage(stage).sink(handler).build();
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+use super::{
+    data_dumper::template::{lookup, parse_path},
+    pipeline::{PipelineStage, StageOutcome},
+    EventMeta,
+};
+
+/// Counters of the events that couldn't be paired
+#[derive(Default, Debug)]
+pub struct CorrelationStats {
+    timed_out: AtomicU64,
+    evicted: AtomicU64,
+    unmatched_exits: AtomicU64,
+    reentered: AtomicU64,
+    missing_time: AtomicU64,
+}
+
+impl CorrelationStats {
+    /// Enter events dropped because no exit event arrived in time
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+    /// Enter events dropped because the table was full
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+    /// Exit events dropped because no enter event was waiting for them
+    pub fn unmatched_exits(&self) -> u64 {
+        self.unmatched_exits.load(Ordering::Relaxed)
+    }
+    /// Enter events replaced by a later enter event with the same key, before their exit events arrived
+    pub fn reentered(&self) -> u64 {
+        self.reentered.load(Ordering::Relaxed)
+    }
+    /// Enter or exit events dropped because the time field is missing or not an unsigned integer
+    pub fn missing_time(&self) -> u64 {
+        self.missing_time.load(Ordering::Relaxed)
+    }
+}
+
+struct PendingEvent {
+    event: Value,
+    timestamp_ns: u64,
+}
+
+#[derive(Default)]
+struct PendingTable {
+    events: HashMap<String, PendingEvent>,
+    /// Keys in the order they were inserted, along with the insertion time. A key may appear again if it was entered again, so stale entries are skipped by comparing the time
+    order: VecDeque<(String, u64)>,
+}
+
+impl PendingTable {
+    /// Remove the oldest entry of the table. Returns false if the table is empty
+    fn pop_oldest(&mut self, expired_before: Option<u64>) -> bool {
+        while let Some((key, timestamp_ns)) = self.order.front() {
+            let current =
+                matches!(self.events.get(key), Some(v) if v.timestamp_ns == *timestamp_ns);
+            if !current {
+                self.order.pop_front();
+                continue;
+            }
+            if matches!(expired_before, Some(t) if *timestamp_ns >= t) {
+                return false;
+            }
+            // SAFETY: It was checked above
+            let (key, _) = self.order.pop_front().unwrap();
+            self.events.remove(&key);
+            return true;
+        }
+        false
+    }
+}
+
+/// A pipeline stage which pairs enter and exit events up
+pub struct CorrelationStage {
+    keys: Vec<Vec<String>>,
+    discriminator: Vec<String>,
+    enter_value: Value,
+    exit_value: Value,
+    time_field: Vec<String>,
+    duration_field: String,
+    timeout_ns: u64,
+    max_entries: usize,
+    table: Mutex<PendingTable>,
+    stats: Arc<CorrelationStats>,
+}
+
+impl CorrelationStage {
+    /// Create a builder. An event is an enter event if the `discriminator` field equals to `enter_value`, or an exit event if it equals to `exit_value`
+    pub fn builder(
+        discriminator: impl Into<String>,
+        enter_value: Value,
+        exit_value: Value,
+    ) -> CorrelationStageBuilder {
+        CorrelationStageBuilder {
+            keys: vec![],
+            discriminator: discriminator.into(),
+            enter_value,
+            exit_value,
+            time_field: None,
+            duration_field: "duration_ns".into(),
+            timeout: Duration::from_secs(10),
+            max_entries: 10240,
+        }
+    }
+    /// Counters of the events that couldn't be paired. They could be read after the stage was moved into a pipeline
+    pub fn stats(&self) -> Arc<CorrelationStats> {
+        self.stats.clone()
+    }
+    /// Count of enter events waiting for their exit events
+    pub fn pending(&self) -> usize {
+        self.table
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .events
+            .len()
+    }
+    /// Name of the field holding the duration in the merged events
+    pub(crate) fn duration_field(&self) -> &str {
+        &self.duration_field
+    }
+    /// Check that the key, discriminator and time fields refer to the available top-level fields
+    pub(crate) fn check_fields(&self, fields: &[&str]) -> Result<()> {
+        let paths = self
+            .keys
+            .iter()
+            .chain([&self.discriminator, &self.time_field]);
+        for path in paths {
+            if !fields.contains(&path[0].as_str()) {
+                bail!(
+                    "Field `{}` in the correlation is not exported. Available fields: {}",
+                    path.join("."),
+                    fields.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PipelineStage for CorrelationStage {
+    fn process(&self, event: Value, _meta: &EventMeta) -> StageOutcome {
+        let is_enter = match lookup(&event, &self.discriminator) {
+            Some(v) if *v == self.enter_value => true,
+            Some(v) if *v == self.exit_value => false,
+            _ => return StageOutcome::Continue(event),
+        };
+        let key_values = self
+            .keys
+            .iter()
+            .map(|path| lookup(&event, path).cloned().unwrap_or(Value::Null))
+            .collect::<Vec<_>>();
+        // SAFETY: Serializing a json value won't fail
+        let key = serde_json::to_string(&key_values).unwrap();
+        let Some(timestamp_ns) = lookup(&event, &self.time_field).and_then(Value::as_u64) else {
+            self.stats.missing_time.fetch_add(1, Ordering::Relaxed);
+            return StageOutcome::Drop;
+        };
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        let expired_before = timestamp_ns.saturating_sub(self.timeout_ns);
+        while table.pop_oldest(Some(expired_before)) {
+            self.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+        }
+        if is_enter {
+            if table.events.contains_key(&key) {
+                self.stats.reentered.fetch_add(1, Ordering::Relaxed);
+            } else if table.events.len() >= self.max_entries {
+                table.pop_oldest(None);
+                self.stats.evicted.fetch_add(1, Ordering::Relaxed);
+            }
+            table.order.push_back((key.clone(), timestamp_ns));
+            table.events.insert(
+                key,
+                PendingEvent {
+                    event,
+                    timestamp_ns,
+                },
+            );
+            return StageOutcome::Drop;
+        }
+        let Some(enter) = table.events.remove(&key) else {
+            self.stats.unmatched_exits.fetch_add(1, Ordering::Relaxed);
+            return StageOutcome::Drop;
+        };
+        drop(table);
+        let mut merged = match enter.event {
+            Value::Object(obj) => obj,
+            _ => serde_json::Map::new(),
+        };
+        if let Value::Object(obj) = event {
+            merged.extend(obj);
+        }
+        merged.insert(
+            self.duration_field.clone(),
+            json!(timestamp_ns.saturating_sub(enter.timestamp_ns)),
+        );
+        StageOutcome::Continue(Value::Object(merged))
+    }
+}
+
+/// The builder of `CorrelationStage`
+pub struct CorrelationStageBuilder {
+    keys: Vec<String>,
+    discriminator: String,
+    enter_value: Value,
+    exit_value: Value,
+    time_field: Option<String>,
+    duration_field: String,
+    timeout: Duration,
+    max_entries: usize,
+}
+
+impl CorrelationStageBuilder {
+    /// Pair events with the same value of this field, such as `pid`. Could be called multiple times
+    pub fn key(mut self, field: impl Into<String>) -> Self {
+        self.keys.push(field.into());
+        self
+    }
+    /// Take the time of events from this field, in nanoseconds, such as a timestamp from `bpf_ktime_get_ns()`. Required
+    pub fn time_field(self, field: impl Into<String>) -> Self {
+        Self {
+            time_field: Some(field.into()),
+            ..self
+        }
+    }
+    /// Name of the field holding the duration in the merged event. Defaults to `duration_ns`
+    pub fn duration_field(self, field: impl Into<String>) -> Self {
+        Self {
+            duration_field: field.into(),
+            ..self
+        }
+    }
+    /// Drop enter events which waited longer than this. Defaults to 10s
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+    /// Max count of enter events waiting. Defaults to 10240
+    pub fn max_entries(self, count: usize) -> Self {
+        Self {
+            max_entries: count,
+            ..self
+        }
+    }
+    /// Build the stage
+    pub fn build(self) -> Result<CorrelationStage> {
+        if self.keys.is_empty() {
+            bail!("At least one key field should be provided");
+        }
+        if self.enter_value == self.exit_value {
+            bail!("Enter and exit events should have different discriminators");
+        }
+        if self.max_entries == 0 {
+            bail!("Size of the table should not be zero");
+        }
+        let Some(time_field) = self.time_field.as_deref() else {
+            bail!("A time field holding the kernel timestamp of events should be provided");
+        };
+        Ok(CorrelationStage {
+            keys: self
+                .keys
+                .iter()
+                .map(|v| parse_path(v))
+                .collect::<Result<_>>()?,
+            discriminator: parse_path(&self.discriminator)?,
+            enter_value: self.enter_value,
+            exit_value: self.exit_value,
+            time_field: parse_path(time_field)?,
+            duration_field: self.duration_field,
+            timeout_ns: self.timeout.as_nanos() as u64,
+            max_entries: self.max_entries,
+            table: Mutex::new(PendingTable::default()),
+            stats: Arc::new(CorrelationStats::default()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use super::CorrelationStage;
+    use crate::export_event::{
+        pipeline::{PipelineStage, StageOutcome},
+        EventMeta,
+    };
+
+    fn process(stage: &CorrelationStage, mut event: Value, ts: u64) -> Option<Value> {
+        event["ts"] = json!(ts);
+        match stage.process(event, &EventMeta::default()) {
+            StageOutcome::Continue(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_correlate_enter_exit() {
+        let stage = CorrelationStage::builder("exit_event", json!(false), json!(true))
+            .key("pid")
+            .key("tid")
+            .time_field("ts")
+            .timeout(Duration::from_nanos(1000))
+            .max_entries(2)
+            .build()
+            .unwrap();
+        let stats = stage.stats();
+        let enter = |pid: u32, tid: u32| json!({"pid": pid, "tid": tid, "exit_event": false, "filename": "/a"});
+        let exit =
+            |pid: u32, tid: u32| json!({"pid": pid, "tid": tid, "exit_event": true, "ret": 3});
+
+        assert!(process(&stage, enter(1, 1), 100).is_none());
+        assert!(process(&stage, enter(1, 2), 150).is_none());
+        assert_eq!(
+            process(&stage, exit(1, 1), 400),
+            Some(
+                json!({"pid": 1, "tid": 1, "exit_event": true, "filename": "/a", "ret": 3, "ts": 400, "duration_ns": 300})
+            )
+        );
+        assert_eq!(stats.unmatched_exits(), 0);
+        assert!(process(&stage, exit(1, 1), 450).is_none());
+        assert_eq!(stats.unmatched_exits(), 1);
+
+        // The table is full, so the oldest one (1, 2) is evicted
+        process(&stage, enter(2, 2), 500);
+        process(&stage, enter(3, 3), 600);
+        assert_eq!(stats.evicted(), 1);
+        assert!(process(&stage, exit(1, 2), 700).is_none());
+
+        // (2, 2) and (3, 3) wait too long
+        assert!(process(&stage, json!({"pid": 4, "other": 1}), 2000).is_some());
+        assert!(process(&stage, enter(4, 4), 2000).is_none());
+        assert_eq!(stats.timed_out(), 2);
+        assert_eq!(stage.pending(), 1);
+
+        // A second enter replaces the pending one
+        assert!(process(&stage, enter(4, 4), 2100).is_none());
+        assert_eq!(stats.reentered(), 1);
+        assert_eq!(
+            process(&stage, exit(4, 4), 2300).unwrap()["duration_ns"],
+            200
+        );
+        assert!(matches!(
+            stage.process(
+                json!({"pid": 5, "tid": 5, "exit_event": false}),
+                &EventMeta::default()
+            ),
+            StageOutcome::Drop
+        ));
+        assert_eq!(stats.missing_time(), 1);
+
+        assert!(CorrelationStage::builder("exit_event", json!(0), json!(0))
+            .key("pid")
+            .time_field("ts")
+            .build()
+            .is_err());
+        assert!(CorrelationStage::builder("exit_event", json!(0), json!(1))
+            .time_field("ts")
+            .build()
+            .is_err());
+        assert!(CorrelationStage::builder("exit_event", json!(0), json!(1))
+            .key("pid")
+            .build()
+            .is_err());
+    }
+}
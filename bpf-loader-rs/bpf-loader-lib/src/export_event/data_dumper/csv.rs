@@ -0,0 +1,74 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use serde_json::Value;
+
+use crate::export_event::CheckedExportedMember;
+
+/// Append a field to the row. Fields containing delimiters, quotes or line breaks are quoted, as RFC 4180 describes
+fn push_csv_field(field: &str, out: &mut String) {
+    if field.contains([',', '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+/// Build the header from names of the members
+pub(crate) fn get_csv_header<'a>(
+    members: impl Iterator<Item = &'a CheckedExportedMember>,
+) -> String {
+    let mut out = String::default();
+    for (i, member) in members.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        push_csv_field(&member.field_name, &mut out);
+    }
+    out
+}
+
+/// Build a row from the decoded values of each column. Missing values are left empty
+///
+/// Strings are written as they are, and other values (including structs and arrays) are written in json
+pub(crate) fn dump_to_csv_row<'a>(values: impl Iterator<Item = Option<&'a Value>>) -> String {
+    let mut out = String::default();
+    for (i, value) in values.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        match value {
+            None | Some(Value::Null) => {}
+            Some(Value::String(s)) => push_csv_field(s, &mut out),
+            Some(v) => push_csv_field(&v.to_string(), &mut out),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::dump_to_csv_row;
+
+    #[test]
+    fn test_csv_row() {
+        let values = [
+            json!(1),
+            json!("bash"),
+            json!("a,\"b\""),
+            json!([1, 2]),
+            json!(-1.5),
+        ];
+        assert_eq!(
+            dump_to_csv_row(values.iter().map(Some).chain([None])),
+            r#"1,bash,"a,""b""","[1,2]",-1.5,"#
+        );
+    }
+}
@@ -4,8 +4,6 @@
 //! All rights reserved.
 //!
 
-use std::ffi::CStr;
-
 use anyhow::{anyhow, bail, Result};
 use btf::types::{
     Btf, BtfArray, BtfComposite, BtfConst, BtfEnum, BtfFloat, BtfInt, BtfIntEncoding, BtfRestrict,
@@ -14,7 +12,7 @@ use btf::types::{
 use log::debug;
 use serde_json::{json, Value};
 
-use crate::export_event::CheckedExportedMember;
+use crate::export_event::{CheckedExportedMember, EventMeta};
 
 /// The caller is responsible to ensure data is large enough
 pub(crate) fn dump_to_json(btf: &Btf, type_id: u32, data: &[u8]) -> Result<Value> {
@@ -154,8 +152,8 @@ pub(crate) fn dump_array(btf: &Btf, arr: &BtfArray, type_id: u32, range: &[u8])
         while last_idx < range.len() && range[last_idx] != 0 {
             last_idx += 1;
         }
-        let out_str = CStr::from_bytes_with_nul(&range[..=last_idx])?.to_str()?;
-        Ok(json!(out_str))
+        // Strings from the kernel are not guaranteed to be valid UTF-8, so decode them lossily
+        Ok(json!(String::from_utf8_lossy(&range[..last_idx])))
     } else {
         // For non-strings, just create a json array and recursively to fill it
         let mut result: Vec<Value> = vec![];
@@ -258,6 +256,16 @@ pub(crate) fn dump_float(ft: &BtfFloat, range: &[u8]) -> Result<Value> {
     }
 }
 
+/// Wrap an event with where it comes from, so that events of different maps can be told apart in a single stream
+pub(crate) fn wrap_in_envelope(meta: &EventMeta, type_name: Option<&str>, data: Value) -> Value {
+    json!({
+        "timestamp_ns": meta.timestamp_ns,
+        "map": meta.map_name,
+        "type": type_name,
+        "data": data,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use crate::tests::{get_assets_dir, ExampleTestStruct};
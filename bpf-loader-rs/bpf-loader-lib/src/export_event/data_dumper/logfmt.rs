@@ -0,0 +1,73 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use serde_json::Value;
+
+/// Append a value, quoting it if it's empty or contains spaces, quotes or `=`
+fn push_logfmt_value(value: &str, out: &mut String) {
+    if value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '=')
+    {
+        // The escaping of json strings is compatible with logfmt parsers
+        out.push_str(&Value::String(value.to_string()).to_string());
+    } else {
+        out.push_str(value);
+    }
+}
+
+/// Append `key=value` pairs of a field. Structs are flattened into `key.member=value`, and arrays are written in json
+fn push_logfmt_field(key: &str, value: &Value, out: &mut String) {
+    match value {
+        Value::Object(members) => {
+            for (name, value) in members.iter() {
+                // Skip the type info attached by `dump_composed_type`
+                if name.starts_with("__EUNOMIA") {
+                    continue;
+                }
+                push_logfmt_field(&format!("{key}.{name}"), value, out);
+            }
+        }
+        value => {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            out.push_str(key);
+            out.push('=');
+            match value {
+                Value::String(s) => push_logfmt_value(s, out),
+                v => push_logfmt_value(&v.to_string(), out),
+            }
+        }
+    }
+}
+
+/// Dump the fields in logfmt, such as `pid=1 comm=bash filename="/tmp/a b"`
+pub(crate) fn dump_to_logfmt<'a>(fields: impl Iterator<Item = (&'a str, &'a Value)>) -> String {
+    let mut out = String::default();
+    for (key, value) in fields {
+        push_logfmt_field(key, value, &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::dump_to_logfmt;
+
+    #[test]
+    fn test_logfmt() {
+        let key = json!({"__EUNOMIA_TYPE": "struct", "pid": 1});
+        let value = json!({"comm": "a \"b\"", "empty": "", "arr": [1, 2]});
+        assert_eq!(
+            dump_to_logfmt([("key", &key), ("value", &value)].into_iter()),
+            r#"key.pid=1 value.arr=[1,2] value.comm="a \"b\"" value.empty="""#
+        );
+    }
+}
@@ -4,5 +4,9 @@
 //! All rights reserved.
 //!
 
+pub(crate) mod csv;
 pub(crate) mod json;
+pub(crate) mod logfmt;
 pub(crate) mod plain_text;
+pub(crate) mod table;
+pub(crate) mod template;
@@ -7,7 +7,10 @@
 use anyhow::{bail, Result};
 use btf::types::Btf;
 
-use crate::export_event::{data_dumper::json::dump_to_json, CheckedExportedMember};
+use crate::export_event::{
+    data_dumper::{json::dump_to_json, table::fit_to_width},
+    CheckedExportedMember,
+};
 
 pub(crate) fn dump_to_string(btf: &Btf, type_id: u32, data: &[u8], out: &mut String) -> Result<()> {
     out.push_str(&match dump_to_json(btf, type_id, data)? {
@@ -26,7 +29,9 @@ pub(crate) fn dump_to_string_with_checked_types(
     out: &mut String,
 ) -> Result<()> {
     for member in checked_types.iter() {
-        if member.output_header_offset > out.len() {
+        if member.output_width > 0 {
+            // In table mode, each value takes exactly the width of its column, followed by a space
+        } else if member.output_header_offset > out.len() {
             out.push_str(&" ".repeat(member.output_header_offset - out.len()));
         } else {
             out.push(' ');
@@ -56,12 +61,19 @@ pub(crate) fn dump_to_string_with_checked_types(
                 data.len()
             );
         }
-        dump_to_string(
-            btf,
-            member.type_id,
-            &data[offset..end],
-            out,
-        )?;
+        if member.output_width > 0 {
+            let mut value = String::default();
+            dump_to_string(btf, member.type_id, &data[offset..end], &mut value)?;
+            fit_to_width(&value, member.output_width, out);
+            out.push(' ');
+        } else {
+            dump_to_string(
+                btf,
+                member.type_id,
+                &data[offset..end],
+                out,
+            )?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,108 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use btf::types::{Btf, BtfConst, BtfIntEncoding, BtfRestrict, BtfType, BtfTypedef, BtfVolatile};
+
+use crate::export_event::CheckedExportedMember;
+
+/// Width used for types whose output length is not predictable, such as structs and non-char arrays
+const FALLBACK_COLUMN_WIDTH: usize = 16;
+
+/// The width that is able to hold most values of the type
+pub(crate) fn default_column_width(btf: &Btf, type_id: u32) -> usize {
+    let Some(ty) = btf.types().get(type_id as usize) else {
+        return FALLBACK_COLUMN_WIDTH;
+    };
+    match ty {
+        BtfType::Int(btf_int) => {
+            let signed = matches!(btf_int.encoding, BtfIntEncoding::Signed);
+            match (btf_int.bits, btf_int.encoding) {
+                (_, BtfIntEncoding::Bool) => 5,
+                (8, _) => 3 + signed as usize,
+                (16, _) => 5 + signed as usize,
+                (32, _) => 10 + signed as usize,
+                (64, _) => 20,
+                (128, _) => 39 + signed as usize,
+                _ => FALLBACK_COLUMN_WIDTH,
+            }
+        }
+        // Pointers are printed as decimal integers
+        BtfType::Ptr(_) => 20,
+        BtfType::Array(arr) => match btf.types().get(arr.val_type_id as usize) {
+            Some(elem) if elem.name() == "char" => arr.nelems as usize,
+            _ => FALLBACK_COLUMN_WIDTH,
+        },
+        BtfType::Enum(btf_enum) => btf_enum
+            .values
+            .iter()
+            .map(|v| format!("{}({})", v.name, v.value).len())
+            .max()
+            .unwrap_or(FALLBACK_COLUMN_WIDTH),
+        BtfType::Float(_) => 12,
+        BtfType::Typedef(BtfTypedef { type_id, .. })
+        | BtfType::Volatile(BtfVolatile { type_id })
+        | BtfType::Const(BtfConst { type_id })
+        | BtfType::Restrict(BtfRestrict { type_id }) => default_column_width(btf, *type_id),
+        _ => FALLBACK_COLUMN_WIDTH,
+    }
+}
+
+/// Set the column width of each member, and append their names to the header
+///
+/// A column is wide enough for both the field name and the type, but not wider than `max_width` (if not zero)
+pub(crate) fn get_plain_text_table_header(
+    btf: &Btf,
+    checked_member: &mut [CheckedExportedMember],
+    prev_header: impl AsRef<str>,
+    max_width: usize,
+) -> String {
+    let mut header = String::from(prev_header.as_ref());
+    for ty in checked_member.iter_mut() {
+        ty.output_header_offset = header.len();
+        let mut width = default_column_width(btf, ty.type_id).max(ty.field_name.len());
+        if max_width != 0 {
+            width = width.min(max_width);
+        }
+        ty.output_width = width;
+        let type_name = ty.field_name.to_ascii_uppercase();
+        fit_to_width(&type_name, width, &mut header);
+        header.push(' ');
+    }
+    header
+}
+
+/// Pad the text to `width` characters, or truncate it with an ellipsis if it's too long
+pub(crate) fn fit_to_width(text: &str, width: usize, out: &mut String) {
+    let len = text.chars().count();
+    if len <= width {
+        out.push_str(text);
+        out.push_str(&" ".repeat(width - len));
+    } else if width > 0 {
+        out.extend(text.chars().take(width - 1));
+        out.push('…');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fit_to_width;
+
+    #[test]
+    fn test_fit_to_width() {
+        let mut out = String::new();
+        fit_to_width("bash", 6, &mut out);
+        assert_eq!(out, "bash  ");
+        out.clear();
+        fit_to_width("/usr/lib/libc.so.6", 8, &mut out);
+        assert_eq!(out, "/usr/li…");
+        out.clear();
+        fit_to_width("中文名字", 3, &mut out);
+        assert_eq!(out, "中文…");
+        out.clear();
+        fit_to_width("abc", 3, &mut out);
+        assert_eq!(out, "abc");
+    }
+}
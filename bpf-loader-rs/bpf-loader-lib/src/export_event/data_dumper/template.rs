@@ -0,0 +1,286 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Local;
+use nix::errno::Errno;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Conversion {
+    None,
+    /// Print negative integers as errno names, such as `ENOENT`
+    Errno,
+    /// Print integers in hex, with `0x` prefix
+    Hex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Placeholder {
+    /// The text inside the braces, before the spec
+    name: String,
+    path: Vec<String>,
+    align: Align,
+    width: usize,
+    conversion: Conversion,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(Placeholder),
+}
+
+/// A parsed line template. See `meta::PlainTextFormatMeta` for the syntax
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LineTemplate {
+    segments: Vec<Segment>,
+}
+
+fn parse_placeholder(text: &str) -> Result<Placeholder> {
+    let mut parts = text.split(':');
+    let name = parts.next().unwrap_or_default().trim();
+    if name.is_empty() {
+        bail!("Empty field name in placeholder `{{{}}}`", text);
+    }
+    let path = parse_path(name)?;
+    let mut placeholder = Placeholder {
+        name: name.to_string(),
+        path,
+        align: Align::Left,
+        width: 0,
+        conversion: Conversion::None,
+    };
+    for part in parts {
+        match part {
+            "errno" => placeholder.conversion = Conversion::Errno,
+            "hex" => placeholder.conversion = Conversion::Hex,
+            _ => {
+                let (align, width) = match part.chars().next() {
+                    Some('<') => (Align::Left, &part[1..]),
+                    Some('>') => (Align::Right, &part[1..]),
+                    Some('^') => (Align::Center, &part[1..]),
+                    _ => (Align::Left, part),
+                };
+                placeholder.align = align;
+                if !width.is_empty() {
+                    placeholder.width = width
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid spec `{}` of field `{}`", part, name))?;
+                }
+            }
+        }
+    }
+    Ok(placeholder)
+}
+
+impl LineTemplate {
+    /// Parse a template like `{time} {comm:<16} {ret:errno}`
+    pub(crate) fn parse(template: &str) -> Result<Self> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => inner.push(c),
+                            None => bail!("Unclosed `{{` in template `{}`", template),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Field(parse_placeholder(&inner)?));
+                }
+                '}' => bail!("Unmatched `}}` in template `{}`", template),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+    /// Check that each placeholder refers to one of the provided top-level fields, or `time`
+    pub(crate) fn check_fields(&self, fields: &[&str]) -> Result<()> {
+        for segment in self.segments.iter() {
+            if let Segment::Field(placeholder) = segment {
+                let top = placeholder.path[0].as_str();
+                if !fields.contains(&top) && placeholder.name != "time" {
+                    bail!(
+                        "Field `{}` in the template is not exported. Available fields: {}",
+                        placeholder.name,
+                        fields.join(", ")
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Render the header, which replaces each placeholder with its uppercased field name
+    pub(crate) fn render_header(&self) -> String {
+        let mut out = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Field(placeholder) => {
+                    // Use the last segment of the path, unless it's an array index
+                    let last = placeholder.path.last().unwrap();
+                    let name = if last.parse::<usize>().is_ok() {
+                        placeholder.name.to_ascii_uppercase()
+                    } else {
+                        last.to_ascii_uppercase()
+                    };
+                    pad_into(&mut out, &name, placeholder.align, placeholder.width);
+                }
+            }
+        }
+        out
+    }
+    /// Render an event, which was decoded to json. `{time}` will be formatted with `time_format`
+    pub(crate) fn render(&self, event: &Value, time_format: &str, out: &mut String) -> Result<()> {
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Field(placeholder) => {
+                    let text = match lookup(event, &placeholder.path) {
+                        Some(v) => format_value(v, placeholder.conversion),
+                        None if placeholder.name == "time" => {
+                            Local::now().format(time_format).to_string()
+                        }
+                        None => bail!("Field `{}` not found in the event", placeholder.name),
+                    };
+                    pad_into(out, &text, placeholder.align, placeholder.width);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split the field name, such as `task.comm` or `args.0`, into a path for `lookup`
+pub(crate) fn parse_path(name: &str) -> Result<Vec<String>> {
+    let path = name.split('.').map(String::from).collect::<Vec<_>>();
+    if path.iter().any(|s| s.is_empty()) {
+        bail!("Invalid field name `{}`", name);
+    }
+    Ok(path)
+}
+
+/// Find the value at the path, where array elements are accessed by their indexes
+pub(crate) fn lookup<'a>(event: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(event, |v, key| match v {
+        Value::Object(obj) => obj.get(key),
+        Value::Array(arr) => arr.get(key.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+fn format_value(value: &Value, conversion: Conversion) -> String {
+    match (conversion, value) {
+        (Conversion::Errno, Value::Number(n)) => match n.as_i64() {
+            Some(v) if v < 0 && v > i32::MIN as i64 => match Errno::from_i32(-v as i32) {
+                Errno::UnknownErrno => v.to_string(),
+                errno => format!("{errno:?}"),
+            },
+            _ => n.to_string(),
+        },
+        (Conversion::Hex, Value::Number(n)) => match (n.as_u64(), n.as_i64()) {
+            (Some(v), _) => format!("{v:#x}"),
+            (None, Some(v)) => format!("{:#x}", v as u64),
+            _ => n.to_string(),
+        },
+        (_, Value::String(s)) => s.clone(),
+        (_, v) => v.to_string(),
+    }
+}
+
+fn pad_into(out: &mut String, text: &str, align: Align, width: usize) {
+    // SAFETY: It won't fail
+    match align {
+        Align::Left => write!(out, "{text:<width$}").unwrap(),
+        Align::Right => write!(out, "{text:>width$}").unwrap(),
+        Align::Center => write!(out, "{text:^width$}").unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::LineTemplate;
+
+    #[test]
+    fn test_render_template() {
+        let template = LineTemplate::parse(
+            "{comm}[{pid:>5}] opened {filename:<8}| -> {ret:errno} {{{flags:hex}}}",
+        )
+        .unwrap();
+        assert!(template
+            .check_fields(&["comm", "pid", "filename", "ret", "flags"])
+            .is_ok());
+        assert!(template.check_fields(&["comm"]).is_err());
+        let mut out = String::new();
+        template
+            .render(
+                &json!({"comm": "cat", "pid": 42, "filename": "/a", "ret": -2, "flags": 255}),
+                "%H:%M:%S",
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(out, "cat[   42] opened /a      | -> ENOENT {0xff}");
+        out.clear();
+        template
+            .render(
+                &json!({"comm": "cat", "pid": 42, "filename": "/a", "ret": 3, "flags": 0}),
+                "%H:%M:%S",
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(out, "cat[   42] opened /a      | -> 3 {0x0}");
+        assert_eq!(
+            template.render_header(),
+            "COMM[  PID] opened FILENAME| -> RET {FLAGS}"
+        );
+
+        let template = LineTemplate::parse("{key.pid:^5}:{value.count}").unwrap();
+        out.clear();
+        template
+            .render(
+                &json!({"key": {"pid": 1}, "value": {"count": 3}}),
+                "%H:%M:%S",
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(out, "  1  :3");
+
+        assert!(LineTemplate::parse("{pid").is_err());
+        assert!(LineTemplate::parse("pid}").is_err());
+        assert!(LineTemplate::parse("{pid:<x}").is_err());
+        assert!(LineTemplate::parse("{}").is_err());
+    }
+}
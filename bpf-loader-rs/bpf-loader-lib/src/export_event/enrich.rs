@@ -0,0 +1,444 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+//! # Process, user and container enrichment
+//!
+//! Events usually carry a pid, uid or cgroup id, which is not friendly to read. The enricher resolves them and adds extra fields to the event:
+//! - pid: `process` (from `/proc/<pid>/comm`), `cmdline` and `exe`
+//! - uid: `user`, from `/etc/passwd`
+//! - gid: `group`, from `/etc/group`
+//! - cgroup id or path: `container_id`, the 64-digit hex id in the cgroup path, which is used by docker, containerd, cri-o and podman
+//! - bpf cookie: `symbol`, the function which the cookie was assigned to in a multi-probe program
+//!
+//! A field is null if it couldn't be resolved, such as the process had exited. Results are cached, and will be resolved again after the ttl.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    hash::Hash,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::meta::EnrichMeta;
+
+use super::data_dumper::{
+    table::fit_to_width,
+    template::{lookup, parse_path},
+};
+
+/// Entries of a cache. Expired entries are purged once it's full
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// Width of the columns in table mode
+const COLUMN_WIDTHS: [(&str, usize); 7] = [
+    ("process", 16),
+    ("cmdline", 32),
+    ("exe", 32),
+    ("user", 12),
+    ("group", 12),
+    ("container_id", 12),
+    ("symbol", 24),
+];
+
+struct TtlCache<K, V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<K, (Instant, V)>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TtlCache<K, V> {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+    fn get_or_resolve(&self, key: &K, resolve: impl FnOnce(&K) -> V) -> V {
+        {
+            let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((time, value)) = entries.get(key) {
+                if time.elapsed() < self.ttl {
+                    return value.clone();
+                }
+            }
+        }
+        // Resolve without holding the lock, so that a slow lookup won't block the other events
+        let value = resolve(key);
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= MAX_CACHE_ENTRIES {
+            entries.retain(|_, (time, _)| time.elapsed() < self.ttl);
+            if entries.len() >= MAX_CACHE_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(key.clone(), (Instant::now(), value.clone()));
+        value
+    }
+}
+
+#[derive(Clone, Default)]
+struct ProcessInfo {
+    name: Option<String>,
+    cmdline: Option<String>,
+    exe: Option<String>,
+}
+
+/// Adds process, user and container info to events
+pub(crate) struct Enricher {
+    pid: Option<Vec<String>>,
+    uid: Option<Vec<String>>,
+    gid: Option<Vec<String>>,
+    cgroup: Option<Vec<String>>,
+    cookie: Option<Vec<String>>,
+    cookie_symbols: HashMap<u64, String>,
+    /// Where `/proc`, `/etc` and `/sys/fs/cgroup` are under
+    root: PathBuf,
+    container_id: Regex,
+    processes: TtlCache<u64, ProcessInfo>,
+    users: TtlCache<u64, Option<String>>,
+    groups: TtlCache<u64, Option<String>>,
+    containers: TtlCache<String, Option<String>>,
+    /// Cgroup directories by id, which is built once per ttl instead of walking the tree on every lookup
+    cgroup_index: TtlCache<(), Arc<HashMap<u64, PathBuf>>>,
+    /// Where the columns start in the plain text header
+    header_offsets: Vec<usize>,
+    table: bool,
+}
+
+/// Find the name of the id in a file formatted like `/etc/passwd` or `/etc/group`
+fn lookup_id_name(file: &Path, id: u64) -> Option<String> {
+    let content = std::fs::read_to_string(file).ok()?;
+    content.lines().find_map(|line| {
+        let mut parts = line.split(':');
+        let name = parts.next()?;
+        let line_id = parts.nth(1)?.parse::<u64>().ok()?;
+        (line_id == id).then(|| name.to_string())
+    })
+}
+
+/// Index the cgroup directories under `dir` by their inode numbers, which are the cgroup ids
+fn index_cgroups(dir: &Path, index: &mut HashMap<u64, PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_dir() {
+            continue;
+        }
+        index.insert(metadata.ino(), entry.path());
+        index_cgroups(&entry.path(), index);
+    }
+}
+
+impl Enricher {
+    pub(crate) fn new(meta: &EnrichMeta) -> Result<Self> {
+        Self::with_root(meta, "/")
+    }
+    pub(crate) fn with_root(meta: &EnrichMeta, root: impl AsRef<Path>) -> Result<Self> {
+        let path_of = |v: &Option<String>| v.as_deref().map(parse_path).transpose();
+        let ttl = Duration::from_millis(meta.ttl_ms as u64);
+        let result = Self {
+            pid: path_of(&meta.pid)?,
+            uid: path_of(&meta.uid)?,
+            gid: path_of(&meta.gid)?,
+            cgroup: path_of(&meta.cgroup)?,
+            cookie: path_of(&meta.cookie)?,
+            cookie_symbols: HashMap::new(),
+            root: root.as_ref().to_path_buf(),
+            // SAFETY: The regex is valid
+            container_id: Regex::new("[0-9a-f]{64}").unwrap(),
+            processes: TtlCache::new(ttl),
+            users: TtlCache::new(ttl),
+            groups: TtlCache::new(ttl),
+            containers: TtlCache::new(ttl),
+            cgroup_index: TtlCache::new(ttl),
+            header_offsets: vec![],
+            table: false,
+        };
+        if result.fields().is_empty() {
+            bail!("At least one of `pid`, `uid`, `gid`, `cgroup` and `cookie` should be provided to enrich");
+        }
+        Ok(result)
+    }
+    /// Set the functions of the cookies, which are used to resolve `symbol`
+    pub(crate) fn with_cookie_symbols(self, cookie_symbols: HashMap<u64, String>) -> Self {
+        Self {
+            cookie_symbols,
+            ..self
+        }
+    }
+    /// Check that the source fields refer to one of the provided top-level fields
+    pub(crate) fn check_fields(&self, fields: &[&str]) -> Result<()> {
+        for path in [&self.pid, &self.uid, &self.gid, &self.cgroup, &self.cookie]
+            .into_iter()
+            .flatten()
+        {
+            if !fields.contains(&path[0].as_str()) {
+                bail!(
+                    "Field `{}` to enrich is not exported. Available fields: {}",
+                    path.join("."),
+                    fields.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+    /// Names of the added fields
+    pub(crate) fn fields(&self) -> Vec<&'static str> {
+        let mut result = vec![];
+        if self.pid.is_some() {
+            result.extend(["process", "cmdline", "exe"]);
+        }
+        if self.uid.is_some() {
+            result.push("user");
+        }
+        if self.gid.is_some() {
+            result.push("group");
+        }
+        if self.cgroup.is_some() {
+            result.push("container_id");
+        }
+        if self.cookie.is_some() {
+            result.push("symbol");
+        }
+        result
+    }
+    fn resolve_process(&self, pid: u64) -> ProcessInfo {
+        self.processes.get_or_resolve(&pid, |pid| {
+            let dir = self.root.join("proc").join(pid.to_string());
+            let read = |name: &str| std::fs::read(dir.join(name)).ok();
+            ProcessInfo {
+                name: read("comm").map(|v| String::from_utf8_lossy(&v).trim_end().to_string()),
+                cmdline: read("cmdline")
+                    .map(|v| {
+                        String::from_utf8_lossy(&v)
+                            .split('\0')
+                            .filter(|s| !s.is_empty())
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    })
+                    // Kernel threads have an empty cmdline
+                    .filter(|v| !v.is_empty()),
+                exe: std::fs::read_link(dir.join("exe"))
+                    .ok()
+                    .map(|v| v.to_string_lossy().to_string()),
+            }
+        })
+    }
+    fn resolve_container(&self, cgroup: &Value) -> Option<String> {
+        let key = match cgroup {
+            Value::String(s) => s.clone(),
+            Value::Number(n) => n.as_u64()?.to_string(),
+            _ => return None,
+        };
+        self.containers.get_or_resolve(&key, |key| {
+            let path = match cgroup {
+                Value::String(_) => key.clone(),
+                _ => {
+                    let index = self.cgroup_index.get_or_resolve(&(), |_| {
+                        let mut index = HashMap::new();
+                        index_cgroups(&self.root.join("sys/fs/cgroup"), &mut index);
+                        Arc::new(index)
+                    });
+                    index.get(&key.parse().ok()?)?.to_string_lossy().to_string()
+                }
+            };
+            // The innermost id wins, such as the container in a pod
+            self.container_id
+                .find_iter(&path)
+                .last()
+                .map(|v| v.as_str().to_string())
+        })
+    }
+    /// Resolve the added fields of the event, in the order of `fields`
+    pub(crate) fn resolve(&self, event: &Value) -> Vec<(&'static str, Value)> {
+        let id_of = |path: &Vec<String>| lookup(event, path).and_then(Value::as_u64);
+        let mut result = vec![];
+        if let Some(path) = &self.pid {
+            let info = id_of(path)
+                .map(|pid| self.resolve_process(pid))
+                .unwrap_or_default();
+            result.push(("process", json!(info.name)));
+            result.push(("cmdline", json!(info.cmdline)));
+            result.push(("exe", json!(info.exe)));
+        }
+        if let Some(path) = &self.uid {
+            let name = id_of(path).and_then(|uid| {
+                self.users.get_or_resolve(&uid, |uid| {
+                    lookup_id_name(&self.root.join("etc/passwd"), *uid)
+                })
+            });
+            result.push(("user", json!(name)));
+        }
+        if let Some(path) = &self.gid {
+            let name = id_of(path).and_then(|gid| {
+                self.groups.get_or_resolve(&gid, |gid| {
+                    lookup_id_name(&self.root.join("etc/group"), *gid)
+                })
+            });
+            result.push(("group", json!(name)));
+        }
+        if let Some(path) = &self.cgroup {
+            let id = lookup(event, path).and_then(|v| self.resolve_container(v));
+            result.push(("container_id", json!(id)));
+        }
+        if let Some(path) = &self.cookie {
+            let symbol = id_of(path).and_then(|cookie| self.cookie_symbols.get(&cookie));
+            result.push(("symbol", json!(symbol)));
+        }
+        result
+    }
+    /// Add the fields to the event, if it's an object
+    pub(crate) fn enrich_json(&self, event: &mut Value) {
+        let fields = self.resolve(event);
+        if let Value::Object(obj) = event {
+            for (name, value) in fields.into_iter() {
+                obj.insert(name.into(), value);
+            }
+        }
+    }
+    /// Append the columns to the plain text header, and remember where they start
+    pub(crate) fn append_header(&mut self, mut header: String, table: bool) -> String {
+        self.table = table;
+        self.header_offsets.clear();
+        for name in self.fields() {
+            self.header_offsets.push(header.len());
+            let name = name.to_ascii_uppercase();
+            if table {
+                fit_to_width(&name, column_width(&name), &mut header);
+            } else {
+                header.push_str(&name);
+            }
+            header.push(' ');
+        }
+        header
+    }
+    /// Append the values of the added fields to a plain text line, aligned to the header
+    pub(crate) fn append_columns(&self, event: &Value, out: &mut String) {
+        for (idx, (name, value)) in self.resolve(event).into_iter().enumerate() {
+            let offset = self.header_offsets.get(idx).copied().unwrap_or_default();
+            if offset > out.len() {
+                out.push_str(&" ".repeat(offset - out.len()));
+            } else if !out.is_empty() && !out.ends_with(' ') {
+                out.push(' ');
+            }
+            let text = match value {
+                Value::String(s) => s,
+                _ => "-".to_string(),
+            };
+            if self.table {
+                fit_to_width(&text, column_width(name), out);
+                out.push(' ');
+            } else {
+                // SAFETY: It won't fail
+                write!(out, "{text}").unwrap();
+            }
+        }
+    }
+}
+
+fn column_width(name: &str) -> usize {
+    COLUMN_WIDTHS
+        .iter()
+        .find(|(v, _)| v.eq_ignore_ascii_case(name))
+        .map(|(_, w)| *w)
+        .unwrap_or(16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use serde_json::json;
+
+    use super::Enricher;
+    use crate::meta::EnrichMeta;
+
+    #[test]
+    fn test_enrich() {
+        let root = std::env::temp_dir().join(format!("eunomia-enrich-test-{}", std::process::id()));
+        let proc_dir = root.join("proc/42");
+        std::fs::create_dir_all(&proc_dir).unwrap();
+        std::fs::write(proc_dir.join("comm"), "nginx\n").unwrap();
+        std::fs::write(proc_dir.join("cmdline"), "nginx\0-g\0daemon off;\0").unwrap();
+        std::os::unix::fs::symlink("/usr/sbin/nginx", proc_dir.join("exe")).unwrap();
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(
+            root.join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\nwww:x:33:33::/var/www:/bin/false\n",
+        )
+        .unwrap();
+        let container = "0123456789abcdef".repeat(4);
+        let cgroup = root.join(format!(
+            "sys/fs/cgroup/system.slice/docker-{}.scope",
+            container
+        ));
+        std::fs::create_dir_all(&cgroup).unwrap();
+        let cgroup_id = std::fs::metadata(&cgroup).unwrap().ino();
+
+        let enricher = Enricher::with_root(
+            &EnrichMeta {
+                pid: Some("pid".into()),
+                uid: Some("uid".into()),
+                cgroup: Some("cgroup_id".into()),
+                ..Default::default()
+            },
+            &root,
+        )
+        .unwrap();
+        assert!(enricher.check_fields(&["pid", "uid", "cgroup_id"]).is_ok());
+        assert!(enricher.check_fields(&["pid"]).is_err());
+        let mut event = json!({"pid": 42, "uid": 33, "cgroup_id": cgroup_id});
+        enricher.enrich_json(&mut event);
+        assert_eq!(
+            event,
+            json!({
+                "pid": 42, "uid": 33, "cgroup_id": cgroup_id,
+                "process": "nginx",
+                "cmdline": "nginx -g daemon off;",
+                "exe": "/usr/sbin/nginx",
+                "user": "www",
+                "container_id": container,
+            })
+        );
+        // Results are cached
+        std::fs::write(proc_dir.join("comm"), "other\n").unwrap();
+        let mut event = json!({"pid": 42, "uid": 1000, "cgroup_id": 1});
+        enricher.enrich_json(&mut event);
+        assert_eq!(event["process"], "nginx");
+        assert_eq!(event["user"], serde_json::Value::Null);
+        assert_eq!(event["container_id"], serde_json::Value::Null);
+
+        let mut out = String::from("42 ");
+        enricher.append_columns(&json!({"pid": 42, "uid": 0}), &mut out);
+        assert_eq!(out, "42 nginx nginx -g daemon off; /usr/sbin/nginx root -");
+
+        let enricher = Enricher::new(&EnrichMeta {
+            cookie: Some("cookie".into()),
+            ..Default::default()
+        })
+        .unwrap()
+        .with_cookie_symbols([(1, "tcp_sendmsg".to_string())].into_iter().collect());
+        let mut event = json!({"cookie": 1});
+        enricher.enrich_json(&mut event);
+        assert_eq!(event, json!({"cookie": 1, "symbol": "tcp_sendmsg"}));
+        let mut event = json!({"cookie": 2});
+        enricher.enrich_json(&mut event);
+        assert_eq!(event["symbol"], serde_json::Value::Null);
+
+        assert!(Enricher::new(&EnrichMeta::default()).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
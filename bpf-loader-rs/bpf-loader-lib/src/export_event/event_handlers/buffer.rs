@@ -9,10 +9,11 @@ use std::sync::Weak;
 use crate::{
     export_event::{
         data_dumper::{
-            json::dump_to_json_with_checked_types, plain_text::dump_to_string_with_checked_types,
+            csv::dump_to_csv_row, logfmt::dump_to_logfmt,
+            plain_text::dump_to_string_with_checked_types,
         },
-        EventExporter, ExporterInternalImplementation, InternalBufferValueEventProcessor,
-        ReceivedEventData,
+        EventExporter, EventMeta, ExportFormatType, ExporterInternalImplementation,
+        InternalBufferValueEventProcessor, ReceivedEventData,
     },
     meta::StackTraceFieldMapping,
 };
@@ -22,9 +23,7 @@ use blazesym::{
     symbolize::{Kernel, Process, Source, Symbolizer},
     Addr,
 };
-use chrono::Local;
 use log::{debug, warn};
-use serde_json::json;
 
 use std::fmt::Write;
 
@@ -32,41 +31,91 @@ pub(crate) struct JsonExportEventHandler {
     pub(crate) exporter: Weak<EventExporter>,
 }
 impl InternalBufferValueEventProcessor for JsonExportEventHandler {
-    fn handle_event(&self, data: &[u8]) -> Result<()> {
+    fn handle_event(&self, meta: &EventMeta, data: &[u8]) -> Result<()> {
         let exporter = self.exporter.upgrade().unwrap();
-        let checked_export_value_member_types = match &exporter.internal_impl {
+        let Some(result) = exporter.process_json(meta, exporter.decode_buffer_json(data)?) else {
+            return Ok(());
+        };
+        exporter.dump_json_event(meta, result)
+    }
+}
+/// Folds the decoded json into the aggregator
+pub(crate) struct AggregateEventHandler {
+    pub(crate) exporter: Weak<EventExporter>,
+}
+
+impl InternalBufferValueEventProcessor for AggregateEventHandler {
+    fn handle_event(&self, meta: &EventMeta, data: &[u8]) -> Result<()> {
+        let exporter = self.exporter.upgrade().unwrap();
+        let Some(result) = exporter.process_json(meta, exporter.decode_buffer_json(data)?) else {
+            return Ok(());
+        };
+        if let Some(aggregator) = exporter.aggregator.as_ref() {
+            aggregator.add(&result);
+        }
+        Ok(())
+    }
+}
+
+/// Handles csv and logfmt, which are built from the decoded json
+pub(crate) struct StructuredTextExportEventHandler {
+    pub(crate) exporter: Weak<EventExporter>,
+}
+
+impl InternalBufferValueEventProcessor for StructuredTextExportEventHandler {
+    fn handle_event(&self, meta: &EventMeta, data: &[u8]) -> Result<()> {
+        let exporter = self.exporter.upgrade().unwrap();
+        let checked_types = match &exporter.internal_impl {
             ExporterInternalImplementation::BufferValueProcessor { checked_types, .. } => {
                 checked_types
             }
             _ => bail!("Unexpected"),
         };
-
-        let result = dump_to_json_with_checked_types(
-            exporter.btf_container.borrow_btf(),
-            checked_export_value_member_types,
-            data,
-        )?;
-        let str_out = serde_json::to_string(&json!(result))?;
-        if let Some(v) = exporter.user_export_event_handler.as_ref() {
-            v.handle_event(
-                exporter.user_ctx.clone(),
-                ReceivedEventData::JsonText(&str_out),
-            );
-        } else {
-            println!("{str_out}");
-        }
+        let Some(result) = exporter.process_json(meta, exporter.decode_buffer_json(data)?) else {
+            return Ok(());
+        };
+        let line = match exporter.export_format {
+            ExportFormatType::Csv => dump_to_csv_row(
+                checked_types
+                    .iter()
+                    .map(|v| result.get(&v.field_name))
+                    .chain(
+                        exporter
+                            .enriched_fields()
+                            .into_iter()
+                            .map(|v| result.get(v)),
+                    ),
+            ),
+            ExportFormatType::Logfmt => dump_to_logfmt(
+                checked_types
+                    .iter()
+                    .map(|v| v.field_name.as_str())
+                    .chain(exporter.enriched_fields())
+                    .filter_map(|name| result.get(name).map(|value| (name, value))),
+            ),
+            _ => bail!("Unexpected export format"),
+        };
+        exporter.dump_data_to_user_callback_or_stdout(meta, ReceivedEventData::PlainText(&line));
         Ok(())
     }
 }
+
 pub(crate) struct RawExportEventHandler {
     pub(crate) exporter: Weak<EventExporter>,
 }
 
 impl InternalBufferValueEventProcessor for RawExportEventHandler {
-    fn handle_event(&self, data: &[u8]) -> Result<()> {
+    fn handle_event(&self, meta: &EventMeta, data: &[u8]) -> Result<()> {
         let exporter = self.exporter.upgrade().unwrap();
+        if !exporter.filter_buffer(data)? {
+            return Ok(());
+        }
         if let Some(v) = exporter.user_export_event_handler.as_ref() {
-            v.handle_event(exporter.user_ctx.clone(), ReceivedEventData::Buffer(data));
+            v.handle_event_with_meta(
+                exporter.user_ctx.clone(),
+                ReceivedEventData::Buffer(data),
+                meta,
+            );
         } else {
             warn!("Raw export event handler expects that user provide an event handler. If not provided, the exported data will be dropped");
         }
@@ -79,27 +128,45 @@ pub(crate) struct PlainStringExportEventHandler {
 }
 
 impl InternalBufferValueEventProcessor for PlainStringExportEventHandler {
-    fn handle_event(&self, data: &[u8]) -> Result<()> {
+    fn handle_event(&self, meta: &EventMeta, data: &[u8]) -> Result<()> {
         let exporter = self.exporter.upgrade().unwrap();
+        if !exporter.filter_buffer(data)? {
+            return Ok(());
+        }
         let mut outbuf = String::default();
-        let now_str = Local::now().format("%H:%M:%S").to_string();
-        // SAFETY: It won't fail
-        write!(outbuf, "{now_str:<8} ").unwrap();
         let checked_export_value_member_types = match &exporter.internal_impl {
             ExporterInternalImplementation::BufferValueProcessor { checked_types, .. } => {
                 checked_types
             }
             _ => bail!("Unexpected"),
         };
-
-        dump_to_string_with_checked_types(
-            exporter.btf_container.borrow_btf(),
-            checked_export_value_member_types,
-            data,
-            &mut outbuf,
-        )?;
-        exporter
-            .dump_data_to_user_callback_or_stdout(ReceivedEventData::PlainText(outbuf.as_str()));
+        let plain_text = &exporter.plain_text;
+        // Only decode the event to json if it's needed
+        let event = if plain_text.template.is_some() || exporter.enricher.is_some() {
+            let mut event = exporter.decode_buffer_json(data)?;
+            exporter.enrich_json(&mut event);
+            Some(event)
+        } else {
+            None
+        };
+        if let (Some(template), Some(event)) = (plain_text.template.as_ref(), event.as_ref()) {
+            template.render(event, &plain_text.time_format, &mut outbuf)?;
+        } else {
+            let now_str = plain_text.format_time();
+            let width = plain_text.time_width;
+            // SAFETY: It won't fail
+            write!(outbuf, "{now_str:<width$} ").unwrap();
+            dump_to_string_with_checked_types(
+                exporter.btf_container.borrow_btf(),
+                checked_export_value_member_types,
+                data,
+                &mut outbuf,
+            )?;
+            if let (Some(enricher), Some(event)) = (exporter.enricher.as_ref(), event.as_ref()) {
+                enricher.append_columns(event, &mut outbuf);
+            }
+        }
+        exporter.dump_plain_text_line(meta, &outbuf);
 
         Ok(())
     }
@@ -148,20 +215,12 @@ macro_rules! extract_field {
 }
 
 impl InternalBufferValueEventProcessor for PlainTextStackTraceExportEventHandler {
-    fn handle_event(&self, data: &[u8]) -> Result<()> {
+    fn handle_event(&self, meta: &EventMeta, data: &[u8]) -> Result<()> {
         let exporter = self.exporter.upgrade().unwrap();
-        let checked_export_value_member_types = match &exporter.internal_impl {
-            ExporterInternalImplementation::BufferValueProcessor { checked_types, .. } => {
-                checked_types
-            }
-            _ => bail!("Unexpected"),
-        };
-
-        let result = dump_to_json_with_checked_types(
-            exporter.btf_container.borrow_btf(),
-            checked_export_value_member_types,
-            data,
-        )?;
+        let result = exporter.decode_buffer_json(data)?;
+        if !exporter.filter_json(&result) {
+            return Ok(());
+        }
         let pid = extract_field!(self.field_mapping.pid, result, "pid", u32)?;
         let cpu_id = extract_field!(self.field_mapping.cpu_id, result, "cpu_id", u32)?;
         let comm = extract_field!(self.field_mapping.comm, result, "comm", String)?;
@@ -213,14 +272,7 @@ impl InternalBufferValueEventProcessor for PlainTextStackTraceExportEventHandler
             writeln!(out_str, "No Userspace Stack").unwrap();
         }
 
-        if let Some(v) = exporter.user_export_event_handler.as_ref() {
-            v.handle_event(
-                exporter.user_ctx.clone(),
-                ReceivedEventData::PlainText(&out_str),
-            );
-        } else {
-            println!("{out_str}");
-        }
+        exporter.dump_data_to_user_callback_or_stdout(meta, ReceivedEventData::PlainText(&out_str));
         Ok(())
     }
 }
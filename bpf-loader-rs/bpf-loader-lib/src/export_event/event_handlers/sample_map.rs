@@ -6,20 +6,20 @@
 
 use std::sync::Weak;
 
-use anyhow::{anyhow, bail, Context, Result};
-use chrono::Local;
+use anyhow::{bail, Result};
 use log::warn;
-use serde_json::json;
 use std::fmt::Write;
 
 use crate::{
     export_event::{
         data_dumper::{
+            csv::dump_to_csv_row,
             json::dump_to_json_with_checked_types,
+            logfmt::dump_to_logfmt,
             plain_text::{dump_to_string, dump_to_string_with_checked_types},
         },
-        EventExporter, ExporterInternalImplementation, InternalSampleMapProcessor,
-        ReceivedEventData,
+        EventExporter, EventMeta, ExportFormatType, ExporterInternalImplementation,
+        InternalSampleMapProcessor, ReceivedEventData,
     },
     helper::log2_hist::print_log2_hist,
 };
@@ -29,9 +29,43 @@ pub(crate) struct JsonExportEventHandler {
 }
 
 impl InternalSampleMapProcessor for JsonExportEventHandler {
-    fn handle_event(&self, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
+    fn handle_event(&self, meta: &EventMeta, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
+        let exporter = self.exporter.upgrade().unwrap();
+        let event = exporter.decode_key_value_json(key_buffer, value_buffer)?;
+        let Some(final_json) = exporter.process_json(meta, event) else {
+            return Ok(());
+        };
+        exporter.dump_json_event(meta, final_json)
+    }
+}
+
+/// Folds the decoded json into the aggregator
+pub(crate) struct AggregateEventHandler {
+    pub(crate) exporter: Weak<EventExporter>,
+}
+
+impl InternalSampleMapProcessor for AggregateEventHandler {
+    fn handle_event(&self, meta: &EventMeta, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
+        let exporter = self.exporter.upgrade().unwrap();
+        let event = exporter.decode_key_value_json(key_buffer, value_buffer)?;
+        let Some(final_json) = exporter.process_json(meta, event) else {
+            return Ok(());
+        };
+        if let Some(aggregator) = exporter.aggregator.as_ref() {
+            aggregator.add(&final_json);
+        }
+        Ok(())
+    }
+}
+
+/// Handles csv and logfmt, which are built from the decoded json
+pub(crate) struct StructuredTextExportEventHandler {
+    pub(crate) exporter: Weak<EventExporter>,
+}
+
+impl InternalSampleMapProcessor for StructuredTextExportEventHandler {
+    fn handle_event(&self, meta: &EventMeta, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
         let exporter = self.exporter.upgrade().unwrap();
-        let btf = exporter.btf_container.borrow_btf();
         let (checked_key_types, checked_value_types) =
             if let ExporterInternalImplementation::KeyValueMapProcessor {
                 ref checked_key_types,
@@ -43,17 +77,33 @@ impl InternalSampleMapProcessor for JsonExportEventHandler {
             } else {
                 bail!("Unexpected internal implementation");
             };
-        let key_out = dump_to_json_with_checked_types(btf, checked_key_types, key_buffer)
-            .with_context(|| anyhow!("Failed to dump key type to json"))?;
-        let value_out = dump_to_json_with_checked_types(btf, checked_value_types, value_buffer)
-            .with_context(|| anyhow!("Failed to dump value type to json"))?;
-        let final_json = json!({
-            "key":key_out,
-            "value":value_out
-        });
-        let out_str = serde_json::to_string(&final_json)
-            .with_context(|| anyhow!("Failed to serialize json"))?;
-        exporter.dump_data_to_user_callback_or_stdout(ReceivedEventData::JsonText(&out_str));
+        let event = exporter.decode_key_value_json(key_buffer, value_buffer)?;
+        let Some(final_json) = exporter.process_json(meta, event) else {
+            return Ok(());
+        };
+        let enriched_fields = exporter.enriched_fields();
+        let line = match exporter.export_format {
+            // Columns are the key members followed by the value members, as the header
+            ExportFormatType::Csv => dump_to_csv_row(
+                checked_key_types
+                    .iter()
+                    .map(|v| final_json["key"].get(&v.field_name))
+                    .chain(
+                        checked_value_types
+                            .iter()
+                            .map(|v| final_json["value"].get(&v.field_name)),
+                    )
+                    .chain(enriched_fields.iter().map(|v| final_json.get(v))),
+            ),
+            ExportFormatType::Logfmt => dump_to_logfmt(
+                ["key", "value"]
+                    .into_iter()
+                    .chain(enriched_fields.iter().copied())
+                    .filter_map(|name| final_json.get(name).map(|value| (name, value))),
+            ),
+            _ => bail!("Unexpected export format"),
+        };
+        exporter.dump_data_to_user_callback_or_stdout(meta, ReceivedEventData::PlainText(&line));
         Ok(())
     }
 }
@@ -63,15 +113,19 @@ pub(crate) struct RawExportEventHandler {
 }
 
 impl InternalSampleMapProcessor for RawExportEventHandler {
-    fn handle_event(&self, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
+    fn handle_event(&self, meta: &EventMeta, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
         let exporter = self.exporter.upgrade().unwrap();
+        if !exporter.filter_key_value(key_buffer, value_buffer)? {
+            return Ok(());
+        }
         if let Some(callback) = exporter.user_export_event_handler.as_ref() {
-            callback.handle_event(
+            callback.handle_event_with_meta(
                 exporter.user_ctx.clone(),
                 ReceivedEventData::KeyValueBuffer {
                     key: key_buffer,
                     value: value_buffer,
                 },
+                meta,
             );
         } else {
             warn!("Raw map processor expects that a user-provided callback exists, or the data will be dropped");
@@ -85,8 +139,11 @@ pub(crate) struct DefaultKVStringExportEventHandler {
 }
 
 impl InternalSampleMapProcessor for DefaultKVStringExportEventHandler {
-    fn handle_event(&self, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
+    fn handle_event(&self, meta: &EventMeta, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
         let exporter = self.exporter.upgrade().unwrap();
+        if !exporter.filter_key_value(key_buffer, value_buffer)? {
+            return Ok(());
+        }
         let btf = exporter.btf_container.borrow_btf();
         let (checked_key_types, checked_value_types) =
             if let ExporterInternalImplementation::KeyValueMapProcessor {
@@ -99,18 +156,44 @@ impl InternalSampleMapProcessor for DefaultKVStringExportEventHandler {
             } else {
                 bail!("Unexpected internal implementation");
             };
-        let now_str = Local::now().format("%H:%M:%S").to_string();
+        let plain_text = &exporter.plain_text;
         let mut outbuf = String::default();
-        write!(outbuf, "{now_str:<8} ").unwrap();
-        write!(
-            outbuf,
-            "{} {}",
-            dump_to_json_with_checked_types(btf, checked_key_types, key_buffer)?,
-            dump_to_json_with_checked_types(btf, checked_value_types, value_buffer)?
-        )
-        .unwrap();
-        exporter
-            .dump_data_to_user_callback_or_stdout(ReceivedEventData::PlainText(outbuf.as_str()));
+        // Only decode the event to json if it's needed
+        let event = if plain_text.template.is_some() || exporter.enricher.is_some() {
+            let mut event = exporter.decode_key_value_json(key_buffer, value_buffer)?;
+            exporter.enrich_json(&mut event);
+            Some(event)
+        } else {
+            None
+        };
+        if let (Some(template), Some(event)) = (plain_text.template.as_ref(), event.as_ref()) {
+            template.render(event, &plain_text.time_format, &mut outbuf)?;
+        } else {
+            let now_str = plain_text.format_time();
+            let width = plain_text.time_width;
+            write!(outbuf, "{now_str:<width$} ").unwrap();
+            if plain_text.table {
+                dump_to_string_with_checked_types(btf, checked_key_types, key_buffer, &mut outbuf)?;
+                dump_to_string_with_checked_types(
+                    btf,
+                    checked_value_types,
+                    value_buffer,
+                    &mut outbuf,
+                )?;
+            } else {
+                write!(
+                    outbuf,
+                    "{} {}",
+                    dump_to_json_with_checked_types(btf, checked_key_types, key_buffer)?,
+                    dump_to_json_with_checked_types(btf, checked_value_types, value_buffer)?
+                )
+                .unwrap();
+            }
+            if let (Some(enricher), Some(event)) = (exporter.enricher.as_ref(), event.as_ref()) {
+                enricher.append_columns(event, &mut outbuf);
+            }
+        }
+        exporter.dump_plain_text_line(meta, &outbuf);
         Ok(())
     }
 }
@@ -120,8 +203,11 @@ pub(crate) struct Log2HistExportEventHandler {
 }
 
 impl InternalSampleMapProcessor for Log2HistExportEventHandler {
-    fn handle_event(&self, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
+    fn handle_event(&self, meta: &EventMeta, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()> {
         let exporter = self.exporter.upgrade().unwrap();
+        if !exporter.filter_key_value(key_buffer, value_buffer)? {
+            return Ok(());
+        }
         let btf = exporter.btf_container.borrow_btf();
         let (checked_key_types, checked_value_types, sample_map_config) =
             if let ExporterInternalImplementation::KeyValueMapProcessor {
@@ -171,7 +257,8 @@ impl InternalSampleMapProcessor for Log2HistExportEventHandler {
             length_in_u32: slot_size,
         }) = slots
         {
-            exporter.dump_data_to_user_callback_or_stdout(ReceivedEventData::PlainText(&outbuf));
+            exporter
+                .dump_data_to_user_callback_or_stdout(meta, ReceivedEventData::PlainText(&outbuf));
             let mut val_buf = vec![];
             for i in 0..slot_size {
                 val_buf.push(u32::from_le_bytes(
@@ -182,7 +269,8 @@ impl InternalSampleMapProcessor for Log2HistExportEventHandler {
             }
             outbuf.clear();
             print_log2_hist(&val_buf[..], &sample_map_config.unit, &mut outbuf);
-            exporter.dump_data_to_user_callback_or_stdout(ReceivedEventData::PlainText(&outbuf));
+            exporter
+                .dump_data_to_user_callback_or_stdout(meta, ReceivedEventData::PlainText(&outbuf));
         } else {
             bail!("No slots found!");
         }
@@ -0,0 +1,531 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+//! # Event filter
+//!
+//! A small expression language to filter exported events in userspace, evaluated over the decoded fields of an event.
+//!
+//! ```text
+//! comm == "nginx" && latency_ns > 1ms
+//! !(pid == 1) || task.comm =~ "^kworker/\d+"
+//! path ^= "/usr/lib" and args[0] != ""
+//! ```
+//!
+//! - Field paths: `a`, `a.b` for nested structs, `a[1]` for arrays. For sample maps, use `key.xxx` and `value.xxx`
+//! - Comparisons: `==`, `!=`, `<`, `<=`, `>`, `>=`. Numbers are compared numerically, strings lexicographically. Integers are compared exactly, and only compared as floats if either side is a float
+//! - String operators: `=~` (regex match), `^=` (prefix)
+//! - Boolean operators: `&&` / `and`, `||` / `or`, `!` / `not`, and parentheses
+//! - Literals: numbers, strings in `"` or `'`, `true`, `false`. Numbers may have a duration suffix (`ns`, `us`, `ms`, `s`), which will be converted to nanoseconds
+//! - A bare field path is true if the field is `true`, a non-zero number or a non-empty string
+//!
+//! A comparison on a missing field, or between values of different types, is false.
+
+use std::{cmp::Ordering, fmt::Display};
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde_json::Value;
+
+/// A parsed filter expression
+#[derive(Debug, Clone)]
+pub struct EventFilter {
+    source: String,
+    expr: Expr,
+}
+
+impl EventFilter {
+    /// Parse a filter expression
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens =
+            tokenize(source).with_context(|| anyhow!("Invalid filter expression `{}`", source))?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser
+            .parse_or()
+            .and_then(|expr| match parser.peek() {
+                None => Ok(expr),
+                Some(tok) => bail!("Unexpected token `{}`", tok),
+            })
+            .with_context(|| anyhow!("Invalid filter expression `{}`", source))?;
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+    /// Check whether the event matches this filter
+    pub fn matches(&self, event: &Value) -> bool {
+        self.expr.eval(event)
+    }
+}
+
+impl Display for EventFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// A number in the expression or the event. Integers are kept as is, so that large ones such as addresses won't lose precision
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+impl Number {
+    fn partial_cmp(self, other: Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => Some(a.cmp(&b)),
+            (a, b) => a.as_f64().partial_cmp(&b.as_f64()),
+        }
+    }
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(v) => v as f64,
+            Number::Float(v) => v,
+        }
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Number::Int(v) => write!(f, "{v}"),
+            Number::Float(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(Number),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Dot,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "{s}"),
+            Token::Str(s) => write!(f, "{s:?}"),
+            Token::Num(n) => write!(f, "{n}"),
+            Token::Op(op) => write!(f, "{op}"),
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+            Token::Dot => write!(f, "."),
+        }
+    }
+}
+
+/// Longer operators must come first
+const OPERATORS: &[&str] = &[
+    "==", "!=", "<=", ">=", "=~", "^=", "&&", "||", "<", ">", "!",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        match c {
+            '(' => tokens.push(Token::LParen),
+            ')' => tokens.push(Token::RParen),
+            '[' => tokens.push(Token::LBracket),
+            ']' => tokens.push(Token::RBracket),
+            '.' => tokens.push(Token::Dot),
+            '"' | '\'' => {
+                let mut s = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => bail!("Unterminated string literal"),
+                        Some(ch) if *ch == c => break,
+                        // Only the quote and backslash need escaping, so that regexes could be written as is
+                        Some('\\') if matches!(chars.get(i + 1), Some(ch) if *ch == c || *ch == '\\') =>
+                        {
+                            s.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(ch) => {
+                            s.push(*ch);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' && matches!(chars.get(i + 1), Some(d) if d.is_ascii_digit())) =>
+            {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text = chars[start..i].iter().collect::<String>();
+                let suffix_start = i;
+                while i < chars.len() && chars[i].is_ascii_alphabetic() {
+                    i += 1;
+                }
+                let scale: i128 = match chars[suffix_start..i].iter().collect::<String>().as_str() {
+                    "" | "ns" => 1,
+                    "us" => 1_000,
+                    "ms" => 1_000_000,
+                    "s" => 1_000_000_000,
+                    s => bail!("Unknown number suffix `{}`", s),
+                };
+                let num = if text.contains('.') {
+                    let v = text
+                        .parse::<f64>()
+                        .map_err(|e| anyhow!("Invalid number: {}", e))?;
+                    Number::Float(v * scale as f64)
+                } else {
+                    let v = text
+                        .parse::<i128>()
+                        .map_err(|e| anyhow!("Invalid number: {}", e))?;
+                    Number::Int(
+                        v.checked_mul(scale)
+                            .ok_or_else(|| anyhow!("Number `{}` is too large", text))?,
+                    )
+                };
+                tokens.push(Token::Num(num));
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let word = chars[start..i].iter().collect::<String>();
+                tokens.push(match word.as_str() {
+                    "and" => Token::Op("&&"),
+                    "or" => Token::Op("||"),
+                    "not" => Token::Op("!"),
+                    _ => Token::Ident(word),
+                });
+                continue;
+            }
+            _ => {
+                let rest = chars[i..].iter().take(2).collect::<String>();
+                let op = OPERATORS
+                    .iter()
+                    .find(|op| rest.starts_with(**op))
+                    .ok_or_else(|| anyhow!("Unexpected character `{}`", c))?;
+                tokens.push(Token::Op(op));
+                i += op.len();
+                continue;
+            }
+        }
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum PathSegment {
+    Field(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone)]
+enum Literal {
+    Num(Number),
+    Str(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Prefix,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Truthy(Vec<PathSegment>),
+    Compare(Vec<PathSegment>, CompareOp, Literal),
+    Regex(Vec<PathSegment>, Regex),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Result<Token> {
+        let tok = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unexpected end of expression"))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+    fn eat_op(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.eat_op("||") {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat_op("&&") {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.eat_op("!") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if let Some(Token::LParen) = self.peek() {
+            self.pos += 1;
+            let expr = self.parse_or()?;
+            match self.next()? {
+                Token::RParen => return Ok(expr),
+                tok => bail!("Expected `)`, found `{}`", tok),
+            }
+        }
+        let path = self.parse_path()?;
+        let op = match self.peek() {
+            Some(Token::Op(op)) if !matches!(*op, "&&" | "||" | "!") => *op,
+            _ => return Ok(Expr::Truthy(path)),
+        };
+        self.pos += 1;
+        let literal = match self.next()? {
+            Token::Num(n) => Literal::Num(n),
+            Token::Str(s) => Literal::Str(s),
+            Token::Ident(s) if s == "true" => Literal::Bool(true),
+            Token::Ident(s) if s == "false" => Literal::Bool(false),
+            tok => bail!("Expected a literal after `{}`, found `{}`", op, tok),
+        };
+        let op = match op {
+            "==" => CompareOp::Eq,
+            "!=" => CompareOp::Ne,
+            "<" => CompareOp::Lt,
+            "<=" => CompareOp::Le,
+            ">" => CompareOp::Gt,
+            ">=" => CompareOp::Ge,
+            "^=" => CompareOp::Prefix,
+            "=~" => {
+                let Literal::Str(pattern) = literal else {
+                    bail!("`=~` expects a string literal");
+                };
+                let regex =
+                    Regex::new(&pattern).with_context(|| anyhow!("Invalid regex `{}`", pattern))?;
+                return Ok(Expr::Regex(path, regex));
+            }
+            op => bail!("Unexpected operator `{}`", op),
+        };
+        if matches!(op, CompareOp::Prefix) && !matches!(literal, Literal::Str(_)) {
+            bail!("`^=` expects a string literal");
+        }
+        Ok(Expr::Compare(path, op, literal))
+    }
+    fn parse_path(&mut self) -> Result<Vec<PathSegment>> {
+        let mut path = match self.next()? {
+            Token::Ident(s) => vec![PathSegment::Field(s)],
+            tok => bail!("Expected a field name, found `{}`", tok),
+        };
+        loop {
+            match self.peek() {
+                Some(Token::Dot) => {
+                    self.pos += 1;
+                    match self.next()? {
+                        Token::Ident(s) => path.push(PathSegment::Field(s)),
+                        tok => bail!("Expected a field name after `.`, found `{}`", tok),
+                    }
+                }
+                Some(Token::LBracket) => {
+                    self.pos += 1;
+                    let index = match self.next()? {
+                        Token::Num(Number::Int(n)) if n >= 0 => n as usize,
+                        tok => bail!("Expected an array index, found `{}`", tok),
+                    };
+                    match self.next()? {
+                        Token::RBracket => path.push(PathSegment::Index(index)),
+                        tok => bail!("Expected `]`, found `{}`", tok),
+                    }
+                }
+                _ => return Ok(path),
+            }
+        }
+    }
+}
+
+fn lookup<'a>(event: &'a Value, path: &[PathSegment]) -> Option<&'a Value> {
+    path.iter().try_fold(event, |curr, seg| match seg {
+        PathSegment::Field(name) => curr.get(name),
+        PathSegment::Index(idx) => curr.get(idx),
+    })
+}
+
+/// Get a number from the value. 128-bit integers are dumped as strings, so also try to parse them
+fn as_number(value: &Value) -> Option<Number> {
+    match value {
+        Value::Number(n) => n
+            .as_i64()
+            .map(|v| Number::Int(v as i128))
+            .or_else(|| n.as_u64().map(|v| Number::Int(v as i128)))
+            .or_else(|| n.as_f64().map(Number::Float)),
+        Value::String(s) => s
+            .parse::<i128>()
+            .map(Number::Int)
+            .or_else(|_| s.parse::<f64>().map(Number::Float))
+            .ok(),
+        _ => None,
+    }
+}
+
+impl Expr {
+    fn eval(&self, event: &Value) -> bool {
+        match self {
+            Expr::And(lhs, rhs) => lhs.eval(event) && rhs.eval(event),
+            Expr::Or(lhs, rhs) => lhs.eval(event) || rhs.eval(event),
+            Expr::Not(expr) => !expr.eval(event),
+            Expr::Truthy(path) => match lookup(event, path) {
+                Some(Value::Bool(b)) => *b,
+                Some(Value::Number(n)) => n.as_f64().map(|v| v != 0.0).unwrap_or(false),
+                Some(Value::String(s)) => !s.is_empty(),
+                _ => false,
+            },
+            Expr::Regex(path, regex) => match lookup(event, path) {
+                Some(Value::String(s)) => regex.is_match(s),
+                _ => false,
+            },
+            Expr::Compare(path, op, literal) => {
+                let Some(value) = lookup(event, path) else {
+                    return false;
+                };
+                let ordering = match (literal, value) {
+                    (Literal::Str(lit), Value::String(s)) => {
+                        if let CompareOp::Prefix = op {
+                            return s.starts_with(lit.as_str());
+                        }
+                        Some(s.as_str().cmp(lit.as_str()))
+                    }
+                    (Literal::Bool(lit), Value::Bool(b)) => Some(b.cmp(lit)),
+                    (Literal::Num(lit), value) => {
+                        as_number(value).and_then(|v| v.partial_cmp(*lit))
+                    }
+                    _ => None,
+                };
+                match (op, ordering) {
+                    (_, None) => false,
+                    (CompareOp::Eq, Some(ord)) => ord == Ordering::Equal,
+                    (CompareOp::Ne, Some(ord)) => ord != Ordering::Equal,
+                    (CompareOp::Lt, Some(ord)) => ord == Ordering::Less,
+                    (CompareOp::Le, Some(ord)) => ord != Ordering::Greater,
+                    (CompareOp::Gt, Some(ord)) => ord == Ordering::Greater,
+                    (CompareOp::Ge, Some(ord)) => ord != Ordering::Less,
+                    (CompareOp::Prefix, Some(_)) => false,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::EventFilter;
+
+    #[test]
+    fn test_filter_expressions() {
+        let event = json!({
+            "comm": "nginx",
+            "pid": 1234,
+            "latency_ns": 2_500_000u64,
+            "big": "170141183460469231731687303715884105738",
+            "ok": true,
+            "task": {"parent": {"comm": "kworker/12"}},
+            "args": ["/usr/bin/ls", "-l"]
+        });
+        let check = |expr: &str| EventFilter::parse(expr).unwrap().matches(&event);
+        assert!(check(r#"comm == "nginx" && latency_ns > 1ms"#));
+        assert!(!check(r#"comm == "nginx" and latency_ns > 3ms"#));
+        assert!(check(r#"comm != 'bash' or pid < 0"#));
+        assert!(check(r#"task.parent.comm =~ "^kworker/\d+$""#));
+        assert!(check(r#"args[0] ^= "/usr/bin" && args[1] == "-l""#));
+        assert!(check(r#"!(pid == 1) && not ok == false"#));
+        assert!(check("ok && pid >= 1234 && pid <= 1234"));
+        assert!(check("big > 100000000000000000000"));
+        assert!(check("latency_ns == 2.5ms && latency_ns > 2.4ms"));
+        // Missing fields and mismatched types
+        assert!(!check("missing == 1"));
+        assert!(!check("missing != 1"));
+        assert!(!check(r#"pid == "1234""#));
+        assert!(!check(r#"pid != "1234""#));
+        assert!(!check("args[5]"));
+    }
+
+    #[test]
+    fn test_large_integers() {
+        // Both are the same in f64
+        let event = json!({
+            "addr": 18446744073709551615u64,
+            "offset": -9007199254740993i64,
+            "big": "85070591730234615865843651857942052864"
+        });
+        let check = |expr: &str| EventFilter::parse(expr).unwrap().matches(&event);
+        assert!(check("addr == 18446744073709551615"));
+        assert!(check("addr != 18446744073709551614"));
+        assert!(check("addr > 18446744073709551614"));
+        assert!(check("offset == -9007199254740993"));
+        assert!(check("offset < -9007199254740992"));
+        assert!(check("big > 85070591730234615865843651857942052863"));
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        for expr in [
+            "",
+            "comm ==",
+            "(pid > 1",
+            "pid > 1 pid",
+            r#"comm =~ "(""#,
+            "comm =~ 1",
+            "latency > 1min",
+            r#"comm == "abc"#,
+            "pid > 1 $",
+        ] {
+            assert!(EventFilter::parse(expr).is_err(), "{expr}");
+        }
+    }
+}
@@ -0,0 +1,577 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+//! # Prometheus metrics
+//!
+//! Publish what the bpf program exports as Prometheus metrics, in the text exposition format.
+//!
+//! - Sample maps are turned into metrics by `MetricsSink`, which receives the key-value pairs in json. Fields of the key become labels, numeric fields of the value become gauges named `<prefix>_<map>_<field>`, and the `slots` of log2 histograms become classic histograms. Slot `i` (except slot 0, which holds 0 and 1) holds integers in `[2^i, 2^(i+1))`, so the upper bound `le` of bucket `i` is `2^(i+1)-1`. Since the exact sum is unknown, `_sum` is estimated by the lower bound of each slot. When a sampling round ends, series of keys that were not sampled in it (such as keys deleted from the map) are removed
+//! - Event streams (ringbuf and perf event arrays) become counters, such as `<prefix>_events_total`, which are read from the `StatsHandle` of the skeleton when scraped. Gauges of maps whose names clash with them are dropped
+//! - `MetricsServer` serves the registry at `http://<address>/metrics`
+//!
+//! ```ignore
+//! let registry = MetricsRegistry::new().with_stats(skel.create_stats_handle());
+//! let _server = MetricsServer::serve("127.0.0.1:9100", registry.clone())?;
+//! skel.wait_and_poll_to_handler_with_metrics(ExportFormatType::PlainText, None, None, &registry)?;
+//! ```
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use serde_json::Value;
+
+use crate::skeleton::stats::{MapStats, StatsHandle};
+
+use super::{EventHandler, EventMeta, ReceivedEventData};
+
+/// Name, help and the value of a counter from the map statistics
+type StatsCounter = (&'static str, &'static str, fn(&MapStats) -> u64);
+
+/// Name of the field holding the slots of a log2 histogram
+const HIST_SLOTS_FIELD: &str = "slots";
+
+/// Counters of the export maps, whose names are prefixed like the other metrics
+const STATS_COUNTERS: [StatsCounter; 4] = [
+    ("events_total", "Events received from the map", |v| v.events),
+    (
+        "event_bytes_total",
+        "Bytes of the events received from the map",
+        |v| v.bytes,
+    ),
+    (
+        "decode_failures_total",
+        "Events failed to be decoded or handled",
+        |v| v.decode_failures,
+    ),
+    (
+        "lost_samples_total",
+        "Samples lost by the perf buffer",
+        |v| v.total_lost_samples(),
+    ),
+];
+
+enum SeriesValue {
+    Gauge(f64),
+    Histogram {
+        /// Count of each log2 slot, not cumulative
+        slots: Vec<u64>,
+        sum: f64,
+    },
+}
+
+struct Family {
+    /// The map that the metric comes from
+    map_name: String,
+    help: String,
+    histogram: bool,
+    /// The name clashes with a counter of the map statistics, so it's not rendered
+    clashed: bool,
+    /// Keyed by the rendered labels
+    series: BTreeMap<String, SeriesValue>,
+    /// Labels of the series set since the last round ended
+    refreshed: BTreeSet<String>,
+}
+
+/// Metrics to be scraped. It's cheap to clone, and all clones refer to the same metrics
+#[derive(Clone)]
+pub struct MetricsRegistry {
+    prefix: String,
+    families: Arc<Mutex<BTreeMap<String, Family>>>,
+    stats: Option<StatsHandle>,
+}
+
+impl Default for MetricsRegistry {
+    fn default() -> Self {
+        Self {
+            prefix: "eunomia".into(),
+            families: Default::default(),
+            stats: None,
+        }
+    }
+}
+
+/// Replace characters that are not allowed in metric names with `_`
+fn sanitize_name(name: &str) -> String {
+    let mut result = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if result.starts_with(|c: char| c.is_ascii_digit()) {
+        result.insert(0, '_');
+    }
+    result
+}
+
+fn render_labels(labels: &[(String, String)]) -> String {
+    let mut out = String::default();
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        // SAFETY: It won't fail
+        write!(out, "{}=\"{}\"", sanitize_name(name), value).unwrap();
+    }
+    out
+}
+
+/// Write `name{labels,extra} value`
+fn write_sample(out: &mut String, name: &str, labels: &str, extra: &str, value: impl ToString) {
+    let labels = match (labels.is_empty(), extra.is_empty()) {
+        (true, true) => String::default(),
+        (false, true) => format!("{{{labels}}}"),
+        (true, false) => format!("{{{extra}}}"),
+        (false, false) => format!("{{{labels},{extra}}}"),
+    };
+    // SAFETY: It won't fail
+    writeln!(out, "{}{} {}", name, labels, value.to_string()).unwrap();
+}
+
+/// The smallest value in the log2 slot
+fn slot_lower_bound(slot: usize) -> f64 {
+    if slot == 0 {
+        0.0
+    } else {
+        2f64.powi(slot as i32)
+    }
+}
+
+fn write_family_header(out: &mut String, name: &str, help: &str, ty: &str) {
+    // SAFETY: It won't fail
+    writeln!(out, "# HELP {name} {help}\n# TYPE {name} {ty}").unwrap();
+}
+
+impl MetricsRegistry {
+    /// Create an empty registry. Names of metrics are prefixed with `eunomia`
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Use another prefix for names of metrics
+    pub fn with_prefix(self, prefix: impl AsRef<str>) -> Self {
+        Self {
+            prefix: sanitize_name(prefix.as_ref()),
+            ..self
+        }
+    }
+    /// Publish the statistics of the export maps as counters, such as `eunomia_events_total`
+    pub fn with_stats(self, stats: StatsHandle) -> Self {
+        Self {
+            stats: Some(stats),
+            ..self
+        }
+    }
+    fn metric_name(&self, map_name: &str, field: &str) -> String {
+        sanitize_name(&format!("{}_{}_{}", self.prefix, map_name, field))
+    }
+    fn update(
+        &self,
+        map_name: &str,
+        name: String,
+        help: String,
+        histogram: bool,
+        labels: String,
+        value: SeriesValue,
+    ) {
+        let mut families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        let family = families.entry(name).or_insert_with_key(|name| {
+            let clashed = self.stats.is_some()
+                && STATS_COUNTERS
+                    .iter()
+                    .any(|(v, _, _)| name == &format!("{}_{}", self.prefix, v));
+            if clashed {
+                warn!(
+                    "Metric `{}` clashes with the counter of map statistics, so it's dropped",
+                    name
+                );
+            }
+            Family {
+                map_name: map_name.into(),
+                help,
+                histogram,
+                clashed,
+                series: BTreeMap::new(),
+                refreshed: BTreeSet::new(),
+            }
+        });
+        // A field may change its type, such as a number turning into null
+        if family.clashed || family.histogram != histogram {
+            return;
+        }
+        family.refreshed.insert(labels.clone());
+        family.series.insert(labels, value);
+    }
+    /// End a sampling round of the map. Series of the map that were not set since the last round ended are removed
+    pub fn end_round(&self, map_name: &str) {
+        let mut families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        families.retain(|_, family| {
+            if family.map_name != map_name {
+                return true;
+            }
+            let refreshed = std::mem::take(&mut family.refreshed);
+            family.series.retain(|labels, _| refreshed.contains(labels));
+            // Clashed families are kept, so that the clash is only warned once
+            family.clashed || !family.series.is_empty()
+        });
+    }
+    /// Set the gauge of a field of the map
+    pub fn set_gauge(&self, map_name: &str, field: &str, labels: &[(String, String)], value: f64) {
+        self.update(
+            map_name,
+            self.metric_name(map_name, field),
+            format!("Field `{field}` of map `{map_name}`"),
+            false,
+            render_labels(labels),
+            SeriesValue::Gauge(value),
+        );
+    }
+    /// Set the histogram of a field of the map, from counts of each log2 slot
+    pub fn set_log2_histogram(
+        &self,
+        map_name: &str,
+        field: &str,
+        labels: &[(String, String)],
+        slots: &[u64],
+    ) {
+        let sum = slots
+            .iter()
+            .enumerate()
+            .map(|(i, count)| slot_lower_bound(i) * *count as f64)
+            .sum::<f64>();
+        self.update(
+            map_name,
+            self.metric_name(map_name, field),
+            format!("Log2 histogram `{field}` of map `{map_name}`"),
+            true,
+            render_labels(labels),
+            SeriesValue::Histogram {
+                slots: slots.to_vec(),
+                sum,
+            },
+        );
+    }
+    /// Render all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::default();
+        if let Some(stats) = &self.stats {
+            let snapshot = stats.snapshot();
+            for (name, help, value_of) in STATS_COUNTERS {
+                let name = format!("{}_{}", self.prefix, name);
+                write_family_header(&mut out, &name, help, "counter");
+                for map in snapshot.iter() {
+                    let labels = render_labels(&[("map".into(), map.map_name.clone())]);
+                    write_sample(&mut out, &name, &labels, "", value_of(map));
+                }
+            }
+        }
+        let families = self.families.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, family) in families.iter().filter(|(_, v)| !v.clashed) {
+            if !family.histogram {
+                write_family_header(&mut out, name, &family.help, "gauge");
+                for (labels, value) in family.series.iter() {
+                    if let SeriesValue::Gauge(v) = value {
+                        write_sample(&mut out, name, labels, "", v);
+                    }
+                }
+                continue;
+            }
+            write_family_header(&mut out, name, &family.help, "histogram");
+            let bucket_name = format!("{name}_bucket");
+            for (labels, value) in family.series.iter() {
+                let SeriesValue::Histogram { slots, sum } = value else {
+                    continue;
+                };
+                let mut cumulative = 0u64;
+                for (i, count) in slots.iter().enumerate() {
+                    cumulative += count;
+                    let le = format!("le=\"{}\"", slot_lower_bound(i + 1) - 1.0);
+                    write_sample(&mut out, &bucket_name, labels, &le, cumulative);
+                }
+                write_sample(&mut out, &bucket_name, labels, "le=\"+Inf\"", cumulative);
+                write_sample(&mut out, &format!("{name}_sum"), labels, "", sum);
+                write_sample(&mut out, &format!("{name}_count"), labels, "", cumulative);
+            }
+        }
+        out
+    }
+}
+
+/// Turn a json value into a label value
+fn label_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// Receives key-value pairs of sample maps in json, and publishes them to the registry
+pub struct MetricsSink {
+    registry: MetricsRegistry,
+}
+
+impl MetricsSink {
+    /// Create a sink publishing to the registry. Sample maps should be exported in json, so that the sink could read the key and the value
+    pub fn new(registry: MetricsRegistry) -> Self {
+        Self { registry }
+    }
+    /// Publish a key-value pair of the map
+    pub fn handle_key_value(&self, map_name: &str, key: &Value, value: &Value) {
+        let labels = match key {
+            Value::Object(obj) => obj
+                .iter()
+                .map(|(k, v)| (k.clone(), label_value(v)))
+                .collect::<Vec<_>>(),
+            v => vec![("key".into(), label_value(v))],
+        };
+        let Value::Object(fields) = value else {
+            if let Some(v) = value.as_f64() {
+                self.registry.set_gauge(map_name, "value", &labels, v);
+            }
+            return;
+        };
+        for (field, v) in fields.iter() {
+            match v {
+                Value::Number(n) => {
+                    if let Some(n) = n.as_f64() {
+                        self.registry.set_gauge(map_name, field, &labels, n);
+                    }
+                }
+                Value::Bool(b) => {
+                    self.registry
+                        .set_gauge(map_name, field, &labels, *b as u8 as f64)
+                }
+                Value::Array(arr) if field == HIST_SLOTS_FIELD => {
+                    let slots = arr
+                        .iter()
+                        .map(|v| v.as_u64().unwrap_or_default())
+                        .collect::<Vec<_>>();
+                    self.registry
+                        .set_log2_histogram(map_name, field, &labels, &slots);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl EventHandler for MetricsSink {
+    fn handle_event(&self, context: Option<Arc<dyn Any>>, data: ReceivedEventData) {
+        self.handle_event_with_meta(context, data, &EventMeta::default());
+    }
+    fn handle_event_with_meta(
+        &self,
+        _context: Option<Arc<dyn Any>>,
+        data: ReceivedEventData,
+        meta: &EventMeta,
+    ) {
+        let ReceivedEventData::JsonText(text) = data else {
+            warn!("Metrics sink only accepts events exported in json");
+            return;
+        };
+        match serde_json::from_str::<Value>(text) {
+            Ok(event) => match (event.get("key"), event.get("value")) {
+                (Some(key), Some(value)) => self.handle_key_value(meta.map_name, key, value),
+                _ => debug!("Ignore an event without key and value: {}", text),
+            },
+            Err(e) => warn!("Failed to parse event: {}", e),
+        }
+    }
+    fn handle_sampling_round_end(&self, map_name: &str) {
+        self.registry.end_round(map_name);
+    }
+}
+
+/// Serves the metrics at `/metrics` over http, in a background thread. The server stops when dropped
+pub struct MetricsServer {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn handle_connection(mut stream: TcpStream, registry: &MetricsRegistry) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::default();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers
+    let mut line = String::default();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) if path == "/metrics" || path.starts_with("/metrics?") => {
+            ("200 OK", registry.render())
+        }
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+impl MetricsServer {
+    /// Listen at the address, such as `127.0.0.1:9100`
+    pub fn serve(address: impl ToSocketAddrs, registry: MetricsRegistry) -> Result<Self> {
+        let listener = TcpListener::bind(address)
+            .with_context(|| anyhow!("Failed to bind the metrics server"))?;
+        let address = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = stopped.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let result = stream.and_then(|v| handle_connection(v, &registry));
+                    if let Err(e) = result {
+                        debug!("Failed to serve metrics: {}", e);
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            address,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+    /// The address that the server is listening at
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wake the server up, so that it will see the flag
+        if TcpStream::connect(self.address).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use serde_json::json;
+
+    use super::{MetricsRegistry, MetricsServer, MetricsSink};
+    use crate::{
+        export_event::{EventHandler, EventMeta, ReceivedEventData},
+        skeleton::stats::StatsHandle,
+    };
+
+    #[test]
+    fn test_metrics() {
+        let stats = StatsHandle::default();
+        let rb = stats.register("rb");
+        rb.record_event(16);
+        let registry = MetricsRegistry::new().with_stats(stats);
+        let sink = MetricsSink::new(registry.clone());
+        let meta = EventMeta {
+            map_name: "hists",
+            ..Default::default()
+        };
+        let event = json!({"key": {"comm": "bash", "pid": 1}, "value": {"slots": [1, 2, 0, 3], "total": 6}});
+        sink.handle_event_with_meta(None, ReceivedEventData::JsonText(&event.to_string()), &meta);
+        let text = registry.render();
+        assert!(text
+            .contains("# TYPE eunomia_events_total counter\neunomia_events_total{map=\"rb\"} 1\n"));
+        assert!(text.contains("eunomia_hists_total{comm=\"bash\",pid=\"1\"} 6\n"));
+        assert!(text.contains("# TYPE eunomia_hists_slots histogram\n"));
+        assert!(text.contains("eunomia_hists_slots_bucket{comm=\"bash\",pid=\"1\",le=\"3\"} 3\n"));
+        assert!(text.contains("eunomia_hists_slots_bucket{comm=\"bash\",pid=\"1\",le=\"15\"} 6\n"));
+        assert!(
+            text.contains("eunomia_hists_slots_bucket{comm=\"bash\",pid=\"1\",le=\"+Inf\"} 6\n")
+        );
+        // 1 * 0 + 2 * 2 + 0 * 4 + 3 * 8
+        assert!(text.contains("eunomia_hists_slots_sum{comm=\"bash\",pid=\"1\"} 28\n"));
+        assert!(text.contains("eunomia_hists_slots_count{comm=\"bash\",pid=\"1\"} 6\n"));
+        // The gauge clashing with `eunomia_events_total` is dropped
+        let event = json!({"key": 1, "value": {"total": 2}});
+        sink.handle_key_value("events", &event["key"], &event["value"]);
+        let text = registry.render();
+        assert_eq!(text.matches("eunomia_events_total{").count(), 1);
+        sink.handle_sampling_round_end("hists");
+        sink.handle_sampling_round_end("events");
+        let text = registry.render();
+        assert!(text.contains("eunomia_hists_total{comm=\"bash\",pid=\"1\"} 6\n"));
+
+        let server = MetricsServer::serve("127.0.0.1:0", registry).unwrap();
+        let mut stream = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::default();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&text));
+        drop(server);
+    }
+
+    #[test]
+    fn test_remove_stale_series() {
+        let registry = MetricsRegistry::new();
+        let sink = MetricsSink::new(registry.clone());
+        let meta = EventMeta {
+            map_name: "counts",
+            ..Default::default()
+        };
+        let send = |pid: u32| {
+            let event = json!({"key": {"pid": pid}, "value": {"total": 1}});
+            sink.handle_event_with_meta(
+                None,
+                ReceivedEventData::JsonText(&event.to_string()),
+                &meta,
+            );
+        };
+        send(1);
+        send(2);
+        sink.handle_sampling_round_end("counts");
+        let text = registry.render();
+        assert!(text.contains("eunomia_counts_total{pid=\"1\"} 1\n"));
+        assert!(text.contains("eunomia_counts_total{pid=\"2\"} 1\n"));
+        // Key 2 was deleted from the map, so it's not sampled in the next round
+        send(1);
+        sink.handle_sampling_round_end("counts");
+        let text = registry.render();
+        assert!(text.contains("eunomia_counts_total{pid=\"1\"} 1\n"));
+        assert!(!text.contains("pid=\"2\""));
+        // Rounds of other maps don't affect it
+        sink.handle_sampling_round_end("others");
+        assert_eq!(registry.render(), text);
+        // The map is empty now
+        sink.handle_sampling_round_end("counts");
+        assert!(!registry.render().contains("eunomia_counts_total"));
+    }
+}
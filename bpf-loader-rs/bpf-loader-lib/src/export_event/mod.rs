@@ -17,10 +17,13 @@
 //!
 //! ## What will be produced
 //!
-//! You can get the data you want in one of the three formats:
+//! You can get the data you want in one of these formats:
 //! - Json
 //! - PlainText
 //! - RawEvent
+//! - Csv
+//! - Logfmt
+//! - Ndjson
 //!
 //! Each time the EventExporter received data from the ebpf program, it will convert the data to the format you want, and call the callback function to acknowledge you the data, or print that to stdout
 //! ## Json
@@ -31,30 +34,76 @@
 //!
 //! ## RawEvent
 //! It will call the callback with the original data received from ebpf program. If no callback was provided, it will do nothing.
+//!
+//! ## Csv, Logfmt and Ndjson
+//! They are built from the decoded json like Json. Csv and Logfmt are delivered as plain texts, one line per event (Csv starts with a header line of field names). Ndjson wraps each event in an envelope with the timestamp, map name and type name, and is delivered as `ReceivedEventData::NdjsonText`
 
 use crate::{
     btf_container::BtfContainer,
     export_event::checker::check_sample_types_btf,
-    meta::{BufferValueInterpreter, ExportedTypesStructMeta, MapSampleMeta, SampleMapType},
+    meta::{
+        AggregationMeta, BufferValueInterpreter, CorrelateMeta, EnrichMeta, ErrorPolicy,
+        ExportedTypesStructMeta, MapMeta, MapSampleMeta, PlainTextFormatMeta, SampleMapType,
+    },
 };
 use anyhow::{anyhow, bail, Context, Result};
+use chrono::{
+    format::{Item, StrftimeItems},
+    Datelike, Local, TimeZone,
+};
 use log::debug;
-use std::{any::Any, fmt::Display, sync::Arc};
+use serde_json::{json, Value};
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt::Display,
+    fmt::Write,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use self::{
+    aggregate::Aggregator,
     checker::check_export_types_btf,
+    correlate::CorrelationStage,
+    data_dumper::{
+        csv::get_csv_header,
+        json::{dump_to_json_with_checked_types, wrap_in_envelope},
+        table::get_plain_text_table_header,
+        template::LineTemplate,
+    },
+    enrich::Enricher,
     event_handlers::{buffer, get_plain_text_checked_types_header, sample_map},
+    filter::EventFilter,
+    pipeline::{PipelineStage, StageOutcome},
     type_descriptor::{CheckedExportedMember, TypeDescriptor},
 };
 
+pub(crate) mod aggregate;
+/// Build arrow schemas from BTF, and write events to parquet files
+#[cfg(feature = "parquet")]
+pub mod arrow_sink;
 pub(crate) mod checker;
+/// Pair enter and exit events up, and compute the durations between them
+pub mod correlate;
 pub(crate) mod data_dumper;
+pub(crate) mod enrich;
 pub(crate) mod event_handlers;
+/// Filter events with expressions over decoded fields
+pub mod filter;
+/// Publish sample maps and event counters as Prometheus metrics
+pub mod metrics;
+/// Compose event handlers into a pipeline
+pub mod pipeline;
 #[cfg(test)]
 mod tests;
 /// Contains utilities to describe where to obtain the export type of a map
 pub mod type_descriptor;
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 /// Describe the export format type
 pub enum ExportFormatType {
     /// Use human-readable texts to output
@@ -63,8 +112,33 @@ pub enum ExportFormatType {
     Json,
     /// Only call the callback with raw buffer
     RawEvent,
+    /// Comma-separated values, with a header of field names
+    Csv,
+    /// `key=value` pairs separated by spaces. Struct members are flattened to `field.member=value`
+    Logfmt,
+    /// One json object per line, which wraps the event with the timestamp, map name and type name
+    Ndjson,
 }
-#[derive(Debug)]
+
+impl FromStr for ExportFormatType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "plain" | "plain_text" => Self::PlainText,
+            "json" => Self::Json,
+            "raw" | "raw_event" => Self::RawEvent,
+            "csv" => Self::Csv,
+            "logfmt" => Self::Logfmt,
+            "ndjson" => Self::Ndjson,
+            s => bail!(
+                "Unknown export format `{}`. Valid ones are `plain`, `json`, `raw`, `csv`, `logfmt` and `ndjson`",
+                s
+            ),
+        })
+    }
+}
+#[derive(Debug, Clone, Copy)]
 /// Represents a sample data that the user will receive
 pub enum ReceivedEventData<'a> {
     /// Raw buffer. will be used on simple value sampling pairing with `ExportFormatType::RawEvent`
@@ -78,6 +152,8 @@ pub enum ReceivedEventData<'a> {
     PlainText(&'a str),
     // Json string. Will be used on `ExportFormatType::Json`
     JsonText(&'a str),
+    /// Json string of the envelope, whose `data` is the event. Will be used on `ExportFormatType::Ndjson`
+    NdjsonText(&'a str),
 }
 impl<'a> Display for ReceivedEventData<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -88,7 +164,9 @@ impl<'a> Display for ReceivedEventData<'a> {
             ReceivedEventData::KeyValueBuffer { key, value } => {
                 write!(f, "key: {key:?} value: {value:?}")?;
             }
-            ReceivedEventData::PlainText(s) | ReceivedEventData::JsonText(s) => {
+            ReceivedEventData::PlainText(s)
+            | ReceivedEventData::JsonText(s)
+            | ReceivedEventData::NdjsonText(s) => {
                 write!(f, "{s}")?;
             }
         }
@@ -104,13 +182,74 @@ impl<'a> ReceivedEventData<'a> {
             ReceivedEventData::KeyValueBuffer { value, .. } => value,
             ReceivedEventData::PlainText(txt) => txt.as_bytes(),
             ReceivedEventData::JsonText(txt) => txt.as_bytes(),
+            ReceivedEventData::NdjsonText(txt) => txt.as_bytes(),
         }
     }
 }
 
+/// Describes where and when an event was received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventMeta<'a> {
+    /// Name of the map that the event comes from
+    pub map_name: &'a str,
+    /// The CPU that submitted the event. Only available for perf event arrays
+    pub cpu: Option<i32>,
+    /// When the event was received, in nanoseconds since the unix epoch
+    pub timestamp_ns: u64,
+    /// Sequence number of the event in its map, starting from 0
+    pub sequence: u64,
+}
+
+impl<'a> EventMeta<'a> {
+    /// Create the meta of an event which was just received
+    pub(crate) fn now(map_name: &'a str, cpu: Option<i32>, sequence: u64) -> Self {
+        Self {
+            map_name,
+            cpu,
+            timestamp_ns: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|v| v.as_nanos() as u64)
+                .unwrap_or_default(),
+            sequence,
+        }
+    }
+    /// Convert to a json object. `cpu` will be absent if not available
+    pub fn to_json(&self) -> Value {
+        let mut result = serde_json::Map::new();
+        result.insert("map".into(), json!(self.map_name));
+        if let Some(cpu) = self.cpu {
+            result.insert("cpu".into(), json!(cpu));
+        }
+        result.insert("timestamp_ns".into(), json!(self.timestamp_ns));
+        result.insert("seq".into(), json!(self.sequence));
+        Value::Object(result)
+    }
+}
+
+/// The key under which the event meta will be put, if enabled in json output
+pub const JSON_EVENT_META_KEY: &str = "_meta";
+
 /// A handler to receive events provided by ebpf kernel program
 pub trait EventHandler {
     fn handle_event(&self, context: Option<Arc<dyn Any>>, data: ReceivedEventData);
+    /// Receive an event along with its meta, such as the map it comes from
+    /// By default, the meta is dropped and `handle_event` will be called
+    fn handle_event_with_meta(
+        &self,
+        context: Option<Arc<dyn Any>>,
+        data: ReceivedEventData,
+        _meta: &EventMeta,
+    ) {
+        self.handle_event(context, data);
+    }
+    /// Check whether this handler could work with events exported in `format`
+    /// It will be called when building the exporter. By default, every format is accepted
+    fn check_export_format(&self, _format: ExportFormatType) -> Result<()> {
+        Ok(())
+    }
+    /// Called after all key-value pairs of a sample map were delivered in a sampling round, even if the map is empty
+    /// By default, it does nothing
+    fn handle_sampling_round_end(&self, _map_name: &str) {}
 }
 
 pub(crate) enum ExporterInternalImplementation {
@@ -140,15 +279,293 @@ pub struct EventExporter {
     /// user-defined context
     pub(crate) user_ctx: Option<Arc<dyn Any>>,
     pub(crate) btf_container: Arc<BtfContainer>,
+    pub(crate) export_format: ExportFormatType,
+    /// what to do if an event failed to be processed
+    pub(crate) error_policy: ErrorPolicy,
+    /// whether to put the event meta into json output
+    pub(crate) json_event_meta: bool,
+    /// events not matching the filter will be dropped before formatting
+    pub(crate) filter: Option<EventFilter>,
+    /// options of the plain text output
+    pub(crate) plain_text: PlainTextState,
+    /// name of the exported type, used in the envelope of ndjson output
+    pub(crate) type_name: Option<String>,
+    /// if set, events are aggregated, and only the result of each window will be exported
+    pub(crate) aggregator: Option<Aggregator>,
+    /// if set, enter and exit events are paired up after filtering
+    pub(crate) correlator: Option<CorrelationStage>,
+    /// if set, process, user and container info is added to events after filtering
+    pub(crate) enricher: Option<Enricher>,
+}
+
+/// Options and states of the plain text output, which are used when handling events
+pub(crate) struct PlainTextState {
+    /// if set, events will be rendered with it
+    pub(crate) template: Option<LineTemplate>,
+    /// whether fields are rendered as a table
+    pub(crate) table: bool,
+    pub(crate) time_format: String,
+    /// width of the time column, which is the longest time the format could render
+    pub(crate) time_width: usize,
+    /// the header to print again every `header_interval` lines. None if the header is disabled
+    header: Option<String>,
+    header_interval: usize,
+    lines: AtomicUsize,
+}
+
+/// The width of the longest time rendered with the format, such as `Wednesday` for `%A`
+/// The last week of each month is tried, so that names of months and weekdays, and two-digit days are covered
+pub(crate) fn max_time_width(format: &str) -> usize {
+    let year = Local::now().year();
+    (1..=12)
+        .flat_map(|month| (22..=28).map(move |day| (month, day)))
+        .filter_map(|(month, day)| {
+            Local
+                .with_ymd_and_hms(year, month, day, 23, 59, 59)
+                .earliest()
+        })
+        .map(|v| v.format(format).to_string().chars().count())
+        .max()
+        .unwrap_or_default()
+}
+
+impl PlainTextState {
+    pub(crate) fn format_time(&self) -> String {
+        Local::now().format(&self.time_format).to_string()
+    }
+    /// The header of the time column, followed by a space
+    fn time_header(&self) -> String {
+        format!("{:<width$} ", "TIME", width = self.time_width)
+    }
 }
 
 impl EventExporter {
-    pub(crate) fn dump_data_to_user_callback_or_stdout(&self, data: ReceivedEventData) {
-        dump_data_to_user_callback_or_stdout(
-            self.user_export_event_handler.clone(),
-            self.user_ctx.clone(),
-            data,
-        );
+    /// Apply the error policy on an event that failed to be processed
+    /// key will be provided if the event comes from a sample map
+    /// Returns Err if the poller should stop
+    pub(crate) fn handle_decode_failure(
+        &self,
+        meta: &EventMeta,
+        error: anyhow::Error,
+        key: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<()> {
+        match self.error_policy {
+            ErrorPolicy::Abort => Err(error),
+            ErrorPolicy::Skip => {
+                debug!("Skipped an undecodable event: {:?}", error);
+                Ok(())
+            }
+            ErrorPolicy::EmitRaw => {
+                let message = format!("{error:#}");
+                match self.export_format {
+                    ExportFormatType::Json | ExportFormatType::Ndjson => {
+                        let mut result = serde_json::Map::new();
+                        result.insert("error".into(), json!(message));
+                        if let Some(key) = key {
+                            result.insert("raw_key".into(), json!(to_hex_string(key)));
+                        }
+                        result.insert("raw".into(), json!(to_hex_string(value)));
+                        self.dump_json_event(meta, Value::Object(result))?;
+                    }
+                    ExportFormatType::PlainText
+                    | ExportFormatType::Csv
+                    | ExportFormatType::Logfmt => {
+                        let mut outbuf = String::default();
+                        let now_str = self.plain_text.format_time();
+                        let width = self.plain_text.time_width;
+                        // SAFETY: It won't fail
+                        write!(outbuf, "{now_str:<width$} <decode error: {message}>").unwrap();
+                        if let Some(key) = key {
+                            write!(outbuf, " raw_key={}", to_hex_string(key)).unwrap();
+                        }
+                        write!(outbuf, " raw={}", to_hex_string(value)).unwrap();
+                        self.dump_data_to_user_callback_or_stdout(
+                            meta,
+                            ReceivedEventData::PlainText(&outbuf),
+                        );
+                    }
+                    // The raw bytes are exactly what the user wants
+                    ExportFormatType::RawEvent => self.dump_data_to_user_callback_or_stdout(
+                        meta,
+                        match key {
+                            Some(key) => ReceivedEventData::KeyValueBuffer { key, value },
+                            None => ReceivedEventData::Buffer(value),
+                        },
+                    ),
+                }
+                Ok(())
+            }
+        }
+    }
+    pub(crate) fn dump_data_to_user_callback_or_stdout(
+        &self,
+        meta: &EventMeta,
+        data: ReceivedEventData,
+    ) {
+        if let Some(callback) = self.user_export_event_handler.as_ref() {
+            callback.handle_event_with_meta(self.user_ctx.clone(), data, meta);
+        } else {
+            println!("{data}");
+        }
+    }
+    /// Dump a line of plain text, and print the header again if it's time to do so
+    pub(crate) fn dump_plain_text_line(&self, meta: &EventMeta, line: &str) {
+        let state = &self.plain_text;
+        let lines = state.lines.fetch_add(1, Ordering::Relaxed);
+        if let Some(header) = state.header.as_ref() {
+            // `checked_rem` returns None if the interval is zero, which means never reprinting
+            if lines > 0 && lines.checked_rem(state.header_interval) == Some(0) {
+                self.dump_data_to_user_callback_or_stdout(
+                    meta,
+                    ReceivedEventData::PlainText(header),
+                );
+            }
+        }
+        self.dump_data_to_user_callback_or_stdout(meta, ReceivedEventData::PlainText(line));
+    }
+    /// Tell the user-defined handler that a sampling round of the map has ended
+    pub(crate) fn end_sampling_round(&self, map_name: &str) {
+        if let Some(handler) = self.user_export_event_handler.as_ref() {
+            handler.handle_sampling_round_end(map_name);
+        }
+    }
+    /// Deliver a decoded event in json, or in the envelope of ndjson
+    pub(crate) fn dump_json_event(&self, meta: &EventMeta, mut event: Value) -> Result<()> {
+        match self.export_format {
+            ExportFormatType::Json => {
+                self.attach_meta_to_json(meta, &mut event);
+                let text = serde_json::to_string(&event)?;
+                self.dump_data_to_user_callback_or_stdout(meta, ReceivedEventData::JsonText(&text));
+            }
+            ExportFormatType::Ndjson => {
+                let envelope = wrap_in_envelope(meta, self.type_name.as_deref(), event);
+                let text = serde_json::to_string(&envelope)?;
+                self.dump_data_to_user_callback_or_stdout(
+                    meta,
+                    ReceivedEventData::NdjsonText(&text),
+                );
+            }
+            _ => bail!(
+                "Unexpected export format {:?} for json events",
+                self.export_format
+            ),
+        }
+        Ok(())
+    }
+    /// Decode a value-only event to json
+    pub(crate) fn decode_buffer_json(&self, data: &[u8]) -> Result<Value> {
+        let ExporterInternalImplementation::BufferValueProcessor { checked_types, .. } =
+            &self.internal_impl
+        else {
+            bail!("Unexpected internal implementation");
+        };
+        dump_to_json_with_checked_types(self.btf_container.borrow_btf(), checked_types, data)
+    }
+    /// Decode a key-value event to json, which is `{"key": <key>, "value": <value>}`
+    pub(crate) fn decode_key_value_json(&self, key: &[u8], value: &[u8]) -> Result<Value> {
+        let ExporterInternalImplementation::KeyValueMapProcessor {
+            checked_key_types,
+            checked_value_types,
+            ..
+        } = &self.internal_impl
+        else {
+            bail!("Unexpected internal implementation");
+        };
+        let btf = self.btf_container.borrow_btf();
+        let key = dump_to_json_with_checked_types(btf, checked_key_types, key)
+            .with_context(|| anyhow!("Failed to dump key type to json"))?;
+        let value = dump_to_json_with_checked_types(btf, checked_value_types, value)
+            .with_context(|| anyhow!("Failed to dump value type to json"))?;
+        Ok(json!({ "key": key, "value": value }))
+    }
+    /// Filter, correlate and enrich the decoded event. Returns None if it was dropped, or held back by the correlation
+    /// All formats built from the decoded json go through this, so that they treat events the same
+    pub(crate) fn process_json(&self, meta: &EventMeta, event: Value) -> Option<Value> {
+        if !self.filter_json(&event) {
+            return None;
+        }
+        let mut event = match self.correlator.as_ref() {
+            Some(correlator) => match correlator.process(event, meta) {
+                StageOutcome::Continue(v) | StageOutcome::Stop(Some(v)) => v,
+                StageOutcome::Drop | StageOutcome::Stop(None) => return None,
+            },
+            None => event,
+        };
+        self.enrich_json(&mut event);
+        Some(event)
+    }
+    /// Check the decoded event against the filter. Returns true if there is no filter
+    pub(crate) fn filter_json(&self, event: &Value) -> bool {
+        self.filter
+            .as_ref()
+            .map(|f| f.matches(event))
+            .unwrap_or(true)
+    }
+    /// Add the enriched fields to the decoded event, if enrichment was enabled
+    pub(crate) fn enrich_json(&self, event: &mut Value) {
+        if let Some(enricher) = self.enricher.as_ref() {
+            enricher.enrich_json(event);
+        }
+    }
+    /// Names of the fields added by the enricher
+    pub(crate) fn enriched_fields(&self) -> Vec<&'static str> {
+        self.enricher
+            .as_ref()
+            .map(|v| v.fields())
+            .unwrap_or_default()
+    }
+    /// Check a value-only event against the filter. The event will only be decoded if there is a filter
+    pub(crate) fn filter_buffer(&self, data: &[u8]) -> Result<bool> {
+        let Some(filter) = self.filter.as_ref() else {
+            return Ok(true);
+        };
+        Ok(filter.matches(&self.decode_buffer_json(data)?))
+    }
+    /// Check a key-value event against the filter. The event will only be decoded if there is a filter
+    pub(crate) fn filter_key_value(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let Some(filter) = self.filter.as_ref() else {
+            return Ok(true);
+        };
+        Ok(filter.matches(&self.decode_key_value_json(key, value)?))
+    }
+    /// Length of the aggregation window, if aggregation was enabled
+    pub(crate) fn aggregation_window(&self) -> Option<Duration> {
+        self.aggregator.as_ref().map(|v| v.window())
+    }
+    /// End the current aggregation window, and export its result unless no event arrived
+    pub(crate) fn flush_aggregation(&self, map_name: &str) -> Result<()> {
+        let Some(aggregator) = self.aggregator.as_ref() else {
+            return Ok(());
+        };
+        let window = aggregator.take_window();
+        if window.is_empty() {
+            return Ok(());
+        }
+        let meta = EventMeta::now(map_name, None, 0);
+        match self.export_format {
+            ExportFormatType::PlainText => {
+                let text = aggregator.window_to_plain_text(&window, &self.plain_text.time_format);
+                self.dump_data_to_user_callback_or_stdout(
+                    &meta,
+                    ReceivedEventData::PlainText(&text),
+                );
+            }
+            ExportFormatType::Json | ExportFormatType::Ndjson => {
+                self.dump_json_event(&meta, aggregator.window_to_json(&window))?;
+            }
+            _ => bail!("Unexpected export format for aggregation"),
+        }
+        Ok(())
+    }
+    /// Put the event meta into a json object, if enabled
+    pub(crate) fn attach_meta_to_json(&self, meta: &EventMeta, value: &mut Value) {
+        if !self.json_event_meta {
+            return;
+        }
+        if let Value::Object(obj) = value {
+            obj.insert(JSON_EVENT_META_KEY.into(), meta.to_json());
+        }
     }
 }
 pub(crate) fn dump_data_to_user_callback_or_stdout(
@@ -162,12 +579,32 @@ pub(crate) fn dump_data_to_user_callback_or_stdout(
         println!("{data}");
     }
 }
+fn to_hex_string(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len() * 2);
+    for byte in data.iter() {
+        // SAFETY: It won't fail
+        write!(result, "{byte:02x}").unwrap();
+    }
+    result
+}
+/// The top-level fields, followed by the duration added by the correlation and the fields added by the enricher
+fn with_added_fields<'a>(
+    fields: &[&'a str],
+    correlator: Option<&'a CorrelationStage>,
+    enricher: Option<&Enricher>,
+) -> Vec<&'a str> {
+    let mut result = fields.to_vec();
+    result.extend(correlator.map(|v| v.duration_field()));
+    result.extend(enricher.map(|v| v.fields()).unwrap_or_default());
+    result
+}
+
 pub(crate) trait InternalBufferValueEventProcessor {
-    fn handle_event(&self, data: &[u8]) -> Result<()>;
+    fn handle_event(&self, meta: &EventMeta, data: &[u8]) -> Result<()>;
 }
 
 pub(crate) trait InternalSampleMapProcessor {
-    fn handle_event(&self, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()>;
+    fn handle_event(&self, meta: &EventMeta, key_buffer: &[u8], value_buffer: &[u8]) -> Result<()>;
 }
 
 /// The builder of the EventExporter
@@ -175,6 +612,15 @@ pub struct EventExporterBuilder {
     export_format: ExportFormatType,
     export_event_handler: Option<Arc<dyn EventHandler>>,
     user_ctx: Option<Arc<dyn Any>>,
+    error_policy: ErrorPolicy,
+    json_event_meta: bool,
+    filter: Option<EventFilter>,
+    plain_text_format: PlainTextFormatMeta,
+    type_name: Option<String>,
+    aggregation: Option<AggregationMeta>,
+    enrichment: Option<EnrichMeta>,
+    correlation: Option<CorrelateMeta>,
+    cookie_symbols: HashMap<u64, String>,
 }
 
 impl Default for EventExporterBuilder {
@@ -183,6 +629,15 @@ impl Default for EventExporterBuilder {
             export_format: ExportFormatType::PlainText,
             export_event_handler: None,
             user_ctx: None,
+            error_policy: ErrorPolicy::Abort,
+            json_event_meta: false,
+            filter: None,
+            plain_text_format: PlainTextFormatMeta::default(),
+            type_name: None,
+            aggregation: None,
+            enrichment: None,
+            correlation: None,
+            cookie_symbols: HashMap::new(),
         }
     }
 }
@@ -213,6 +668,246 @@ impl EventExporterBuilder {
             ..self
         }
     }
+    /// Set what to do if an event failed to be decoded. Defaults to `ErrorPolicy::Abort`
+    pub fn set_error_policy(self, policy: ErrorPolicy) -> Self {
+        Self {
+            error_policy: policy,
+            ..self
+        }
+    }
+    /// Whether to put the event meta (map name, cpu, timestamp and sequence) into json output, under the key `_meta`
+    pub fn set_json_event_meta(self, enable: bool) -> Self {
+        Self {
+            json_event_meta: enable,
+            ..self
+        }
+    }
+    /// Only export events matching the filter. See `filter::EventFilter` for the syntax
+    /// For key-value events, fields should be accessed through `key.xxx` or `value.xxx`
+    pub fn set_filter(self, filter: EventFilter) -> Self {
+        Self {
+            filter: Some(filter),
+            ..self
+        }
+    }
+    /// Customize the plain text output, such as the line template and the header
+    pub fn set_plain_text_format(self, format: PlainTextFormatMeta) -> Self {
+        Self {
+            plain_text_format: format,
+            ..self
+        }
+    }
+    /// Aggregate events in windows, and export the result of each window instead of each event
+    /// Only applies to plain text, json and ndjson output of events decoded with BTF
+    pub fn set_aggregation(self, aggregation: AggregationMeta) -> Self {
+        Self {
+            aggregation: Some(aggregation),
+            ..self
+        }
+    }
+    /// Resolve pids, uids, gids, cgroups and cookies in events to process, user and container info, which are added as extra fields (or columns in plain text)
+    /// The fields are added after filtering, and could be referred in line templates and aggregations
+    pub fn set_enrichment(self, enrichment: EnrichMeta) -> Self {
+        Self {
+            enrichment: Some(enrichment),
+            ..self
+        }
+    }
+    /// Pair up enter and exit events, and export one merged event with the duration between them. See `correlate::CorrelationStage`
+    /// Only applies to json and ndjson output, or aggregations, of events decoded with BTF. The events are paired after filtering and before enrichment
+    pub fn set_correlation(self, correlation: CorrelateMeta) -> Self {
+        Self {
+            correlation: Some(correlation),
+            ..self
+        }
+    }
+    /// Apply the per-map options in the meta: plain text format, aggregation, correlation and enrichment
+    pub(crate) fn apply_map_meta(self, map_meta: &MapMeta) -> Self {
+        let builder = self.set_plain_text_format(map_meta.plain_text.clone().unwrap_or_default());
+        let builder = match &map_meta.aggregation {
+            Some(aggregation) => builder.set_aggregation(aggregation.clone()),
+            None => builder,
+        };
+        let builder = match &map_meta.correlate {
+            Some(correlate) => builder.set_correlation(correlate.clone()),
+            None => builder,
+        };
+        match &map_meta.enrich {
+            Some(enrich) => builder.set_enrichment(enrich.clone()),
+            None => builder,
+        }
+    }
+    /// Set the functions of bpf cookies, which are used when the enrichment resolves cookies
+    pub fn set_cookie_symbols(self, cookie_symbols: HashMap<u64, String>) -> Self {
+        Self {
+            cookie_symbols,
+            ..self
+        }
+    }
+    /// Check whether the user-defined event handler could work with the export format
+    fn check_event_handler(&self) -> Result<()> {
+        match self.export_event_handler.as_ref() {
+            Some(handler) => handler.check_export_format(self.export_format),
+            None => Ok(()),
+        }
+    }
+    /// Build the enricher if enrichment was enabled. `fields` are the available top-level fields, or None if the events are not decoded to json
+    fn build_enricher(&self, fields: Option<&[&str]>) -> Result<Option<Enricher>> {
+        let Some(meta) = &self.enrichment else {
+            return Ok(None);
+        };
+        let Some(fields) = fields else {
+            bail!("Enrichment only applies to events decoded with BTF");
+        };
+        if matches!(self.export_format, ExportFormatType::RawEvent) {
+            bail!("Enrichment could not be paired with raw events");
+        }
+        let enricher = Enricher::new(meta)
+            .with_context(|| anyhow!("Invalid enrichment config"))?
+            .with_cookie_symbols(self.cookie_symbols.clone());
+        enricher.check_fields(fields)?;
+        Ok(Some(enricher))
+    }
+    /// Build the correlation stage if correlation was enabled. `fields` are the available top-level fields, or None if the events are not decoded to json
+    fn build_correlator(&self, fields: Option<&[&str]>) -> Result<Option<CorrelationStage>> {
+        let Some(meta) = &self.correlation else {
+            return Ok(None);
+        };
+        let Some(fields) = fields else {
+            bail!("Correlation only applies to events decoded with BTF");
+        };
+        if self.aggregation.is_none()
+            && !matches!(
+                self.export_format,
+                ExportFormatType::Json | ExportFormatType::Ndjson
+            )
+        {
+            bail!(
+                "Correlation could only be paired with json or ndjson output, or with aggregation, not {:?}",
+                self.export_format
+            );
+        }
+        let builder = CorrelationStage::builder(
+            meta.discriminator.clone(),
+            meta.enter.clone(),
+            meta.exit.clone(),
+        );
+        let correlator = meta
+            .keys
+            .iter()
+            .fold(builder, |builder, key| builder.key(key.clone()))
+            .time_field(meta.time_field.clone())
+            .duration_field(meta.duration_field.clone())
+            .timeout(Duration::from_millis(meta.timeout_ms as u64))
+            .max_entries(meta.max_entries)
+            .build()
+            .with_context(|| anyhow!("Invalid correlation config"))?;
+        correlator.check_fields(fields)?;
+        Ok(Some(correlator))
+    }
+    /// Build the aggregator if aggregation was enabled. `fields` are the available top-level fields, or None if the events are not decoded to json
+    fn build_aggregator(&self, fields: Option<&[&str]>) -> Result<Option<Aggregator>> {
+        let Some(meta) = &self.aggregation else {
+            return Ok(None);
+        };
+        let Some(fields) = fields else {
+            bail!("Aggregation only applies to events decoded with BTF");
+        };
+        if !matches!(
+            self.export_format,
+            ExportFormatType::PlainText | ExportFormatType::Json | ExportFormatType::Ndjson
+        ) {
+            bail!(
+                "Aggregation could only be paired with plain text, json or ndjson output, not {:?}",
+                self.export_format
+            );
+        }
+        let aggregator =
+            Aggregator::new(meta).with_context(|| anyhow!("Invalid aggregation config"))?;
+        aggregator.check_fields(fields)?;
+        Ok(Some(aggregator))
+    }
+    /// Check the plain text format, and parse the line template (if it will be used), which should only refer to the available fields
+    fn build_plain_text_state(&self, template_fields: Option<&[&str]>) -> Result<PlainTextState> {
+        let format = &self.plain_text_format;
+        if StrftimeItems::new(&format.time_format).any(|item| matches!(item, Item::Error)) {
+            bail!("Invalid time format `{}`", format.time_format);
+        }
+        let template = match (&format.template, template_fields) {
+            (Some(template), Some(fields))
+                if matches!(self.export_format, ExportFormatType::PlainText) =>
+            {
+                let template = LineTemplate::parse(template)
+                    .with_context(|| anyhow!("Failed to parse plain text template"))?;
+                template.check_fields(fields)?;
+                Some(template)
+            }
+            _ => None,
+        };
+        Ok(PlainTextState {
+            template,
+            table: format.table,
+            time_format: format.time_format.clone(),
+            time_width: max_time_width(&format.time_format).max(4),
+            header: None,
+            header_interval: format.header_interval,
+            lines: AtomicUsize::new(0),
+        })
+    }
+    /// Append the names of the members to the header, and set where the members are printed
+    fn plain_text_columns_header(
+        &self,
+        btf_container: &BtfContainer,
+        checked_members: &mut [CheckedExportedMember],
+        prev_header: String,
+    ) -> String {
+        if self.plain_text_format.table {
+            get_plain_text_table_header(
+                btf_container.borrow_btf(),
+                checked_members,
+                prev_header,
+                self.plain_text_format.max_column_width,
+            )
+        } else {
+            get_plain_text_checked_types_header(checked_members, prev_header)
+        }
+    }
+    /// Print the csv header of the members and the enriched fields, unless it was disabled
+    fn print_csv_header<'a>(
+        &self,
+        members: impl Iterator<Item = &'a CheckedExportedMember>,
+        enricher: Option<&Enricher>,
+    ) {
+        if self.plain_text_format.print_header {
+            let mut header = get_csv_header(members);
+            for name in enricher.map(|v| v.fields()).unwrap_or_default() {
+                header.push(',');
+                header.push_str(name);
+            }
+            dump_data_to_user_callback_or_stdout(
+                self.export_event_handler.clone(),
+                self.user_ctx.clone(),
+                ReceivedEventData::PlainText(&header),
+            );
+        }
+    }
+    /// Decide the header of plain text output, and print it unless it was disabled
+    fn print_plain_text_header(&self, state: &mut PlainTextState, default_header: String) {
+        if !self.plain_text_format.print_header {
+            return;
+        }
+        let header = match (&self.plain_text_format.header, &state.template) {
+            (Some(header), _) => header.clone(),
+            (None, Some(template)) => template.render_header(),
+            (None, None) => default_header,
+        };
+        dump_data_to_user_callback_or_stdout(
+            self.export_event_handler.clone(),
+            self.user_ctx.clone(),
+            ReceivedEventData::PlainText(header.as_str()),
+        );
+        state.header = Some(header);
+    }
     /// Build an exporter use TypeDescriptor. Which can easily specify the source to obtain the value type
     pub fn build_for_single_value_with_type_descriptor(
         self,
@@ -220,6 +915,11 @@ impl EventExporterBuilder {
         btf_container: Arc<BtfContainer>,
         intepreter: &BufferValueInterpreter,
     ) -> Result<Arc<EventExporter>> {
+        self.check_event_handler()?;
+        let type_name = self
+            .type_name
+            .clone()
+            .or_else(|| export_type.type_name(btf_container.borrow_btf()));
         let mut checked_exported_members =
             export_type.build_checked_exported_members(btf_container.borrow_btf())?;
         if matches!(intepreter, BufferValueInterpreter::StackTrace { .. })
@@ -227,14 +927,31 @@ impl EventExporterBuilder {
         {
             bail!("Intepreter `stack_trace` could only be paired with plaintext export format");
         }
+        let fields = checked_exported_members
+            .iter()
+            .map(|v| v.field_name.as_str())
+            .collect::<Vec<_>>();
+        let fields = match intepreter {
+            BufferValueInterpreter::DefaultStruct => Some(&fields[..]),
+            BufferValueInterpreter::StackTrace { .. } => None,
+        };
+        let mut enricher = self.build_enricher(fields)?;
+        let correlator = self.build_correlator(fields)?;
+        let fields = fields.map(|v| with_added_fields(v, correlator.as_ref(), enricher.as_ref()));
+        let mut plain_text = self.build_plain_text_state(fields.as_deref())?;
+        let aggregator = self.build_aggregator(fields.as_deref())?;
         Ok(Arc::new_cyclic(move |me| {
             let internal_event_processor: Box<dyn InternalBufferValueEventProcessor> =
                 match (self.export_format, intepreter) {
-                    (ExportFormatType::Json, BufferValueInterpreter::DefaultStruct) => {
-                        Box::new(buffer::JsonExportEventHandler {
-                            exporter: me.clone(),
-                        })
-                    }
+                    _ if aggregator.is_some() => Box::new(buffer::AggregateEventHandler {
+                        exporter: me.clone(),
+                    }),
+                    (
+                        ExportFormatType::Json | ExportFormatType::Ndjson,
+                        BufferValueInterpreter::DefaultStruct,
+                    ) => Box::new(buffer::JsonExportEventHandler {
+                        exporter: me.clone(),
+                    }),
                     (
                         ExportFormatType::PlainText,
                         BufferValueInterpreter::StackTrace {
@@ -250,15 +967,18 @@ impl EventExporterBuilder {
                         })
                     }
                     (ExportFormatType::PlainText, BufferValueInterpreter::DefaultStruct) => {
-                        let header = get_plain_text_checked_types_header(
+                        let header = self.plain_text_columns_header(
+                            &btf_container,
                             &mut checked_exported_members,
-                            "TIME     ",
-                        );
-                        dump_data_to_user_callback_or_stdout(
-                            self.export_event_handler.clone(),
-                            self.user_ctx.clone(),
-                            ReceivedEventData::PlainText(header.as_str()),
+                            plain_text.time_header(),
                         );
+                        let header = match enricher.as_mut() {
+                            Some(enricher) => {
+                                enricher.append_header(header, self.plain_text_format.table)
+                            }
+                            None => header,
+                        };
+                        self.print_plain_text_header(&mut plain_text, header);
                         Box::new(buffer::PlainStringExportEventHandler {
                             exporter: me.clone(),
                         })
@@ -269,12 +989,35 @@ impl EventExporterBuilder {
                             exporter: me.clone(),
                         })
                     }
+                    (
+                        ExportFormatType::Csv | ExportFormatType::Logfmt,
+                        BufferValueInterpreter::DefaultStruct,
+                    ) => {
+                        if let ExportFormatType::Csv = self.export_format {
+                            self.print_csv_header(
+                                checked_exported_members.iter(),
+                                enricher.as_ref(),
+                            );
+                        }
+                        Box::new(buffer::StructuredTextExportEventHandler {
+                            exporter: me.clone(),
+                        })
+                    }
                     (_, _) => unreachable!("Unexpected exportformattype + intepreter"),
                 };
             EventExporter {
                 user_export_event_handler: self.export_event_handler,
                 user_ctx: self.user_ctx,
                 btf_container,
+                export_format: self.export_format,
+                error_policy: self.error_policy,
+                json_event_meta: self.json_event_meta,
+                filter: self.filter,
+                plain_text,
+                type_name,
+                aggregator,
+                correlator,
+                enricher,
                 internal_impl: ExporterInternalImplementation::BufferValueProcessor {
                     event_processor: internal_event_processor,
                     checked_types: checked_exported_members,
@@ -293,7 +1036,10 @@ impl EventExporterBuilder {
     ) -> Result<Arc<EventExporter>> {
         let checked_members = check_export_types_btf(export_type, btf_container.borrow_btf())?;
         Self::build_for_single_value_with_type_descriptor(
-            self,
+            Self {
+                type_name: Some(export_type.name.clone()),
+                ..self
+            },
             TypeDescriptor::CheckedMembers(checked_members),
             btf_container,
             intepreter,
@@ -307,6 +1053,11 @@ impl EventExporterBuilder {
         sample_config: &MapSampleMeta,
         btf_container: Arc<BtfContainer>,
     ) -> Result<Arc<EventExporter>> {
+        self.check_event_handler()?;
+        let type_name = self
+            .type_name
+            .clone()
+            .or_else(|| value_export_type.type_name(btf_container.borrow_btf()));
         let mut checked_key_types =
             key_export_type.build_checked_exported_members(btf_container.borrow_btf())?;
         let mut checked_value_types =
@@ -317,37 +1068,69 @@ impl EventExporterBuilder {
         {
             bail!("Linear hist sampling is not supported now");
         }
+        let fields: Option<&[&str]> = match sample_config.ty {
+            SampleMapType::DefaultKV => Some(&["key", "value"]),
+            _ => None,
+        };
+        let mut enricher = self.build_enricher(fields)?;
+        let correlator = self.build_correlator(fields)?;
+        let fields = fields.map(|v| with_added_fields(v, correlator.as_ref(), enricher.as_ref()));
+        let mut plain_text = self.build_plain_text_state(fields.as_deref())?;
+        let aggregator = self.build_aggregator(fields.as_deref())?;
         Ok(Arc::new_cyclic(move |me| {
             let internal_sample_map_processor: Box<dyn InternalSampleMapProcessor> = match self
                 .export_format
             {
+                _ if aggregator.is_some() => Box::new(sample_map::AggregateEventHandler {
+                    exporter: me.clone(),
+                }),
                 ExportFormatType::PlainText => match sample_config.ty {
                     SampleMapType::Log2Hist => Box::new(sample_map::Log2HistExportEventHandler {
                         exporter: me.clone(),
                     }),
                     SampleMapType::DefaultKV => {
-                        let header = String::from("TIME     ");
-                        let header =
-                            get_plain_text_checked_types_header(&mut checked_key_types, header);
-                        let header =
-                            get_plain_text_checked_types_header(&mut checked_value_types, header);
-                        dump_data_to_user_callback_or_stdout(
-                            self.export_event_handler.clone(),
-                            self.user_ctx.clone(),
-                            ReceivedEventData::PlainText(header.as_str()),
+                        let header = self.plain_text_columns_header(
+                            &btf_container,
+                            &mut checked_key_types,
+                            plain_text.time_header(),
                         );
+                        let header = self.plain_text_columns_header(
+                            &btf_container,
+                            &mut checked_value_types,
+                            header,
+                        );
+                        let header = match enricher.as_mut() {
+                            Some(enricher) => {
+                                enricher.append_header(header, self.plain_text_format.table)
+                            }
+                            None => header,
+                        };
+                        self.print_plain_text_header(&mut plain_text, header);
                         Box::new(sample_map::DefaultKVStringExportEventHandler {
                             exporter: me.clone(),
                         })
                     }
                     SampleMapType::LinearHist => unreachable!(),
                 },
-                ExportFormatType::Json => Box::new(sample_map::JsonExportEventHandler {
-                    exporter: me.clone(),
-                }),
+                ExportFormatType::Json | ExportFormatType::Ndjson => {
+                    Box::new(sample_map::JsonExportEventHandler {
+                        exporter: me.clone(),
+                    })
+                }
                 ExportFormatType::RawEvent => Box::new(sample_map::RawExportEventHandler {
                     exporter: me.clone(),
                 }),
+                ExportFormatType::Csv | ExportFormatType::Logfmt => {
+                    if let ExportFormatType::Csv = self.export_format {
+                        self.print_csv_header(
+                            checked_key_types.iter().chain(checked_value_types.iter()),
+                            enricher.as_ref(),
+                        );
+                    }
+                    Box::new(sample_map::StructuredTextExportEventHandler {
+                        exporter: me.clone(),
+                    })
+                }
             };
             EventExporter {
                 user_export_event_handler: self.export_event_handler,
@@ -359,6 +1142,15 @@ impl EventExporterBuilder {
                 },
                 user_ctx: self.user_ctx,
                 btf_container,
+                export_format: self.export_format,
+                error_policy: self.error_policy,
+                json_event_meta: self.json_event_meta,
+                filter: self.filter,
+                plain_text,
+                type_name,
+                aggregator,
+                correlator,
+                enricher,
             }
         }))
     }
@@ -382,7 +1174,11 @@ impl EventExporterBuilder {
         let checked_value_types =
             check_sample_types_btf(btf, value_type_id, Some(export_type.clone()))
                 .with_context(|| anyhow!("Failed to check value type"))?;
-        self.build_for_key_value_with_type_desc(
+        Self {
+            type_name: Some(export_type.name.clone()),
+            ..self
+        }
+        .build_for_key_value_with_type_desc(
             TypeDescriptor::CheckedMembers(checked_key_types),
             TypeDescriptor::CheckedMembers(checked_value_types),
             sample_config,
@@ -0,0 +1,466 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+//! # Handler pipeline
+//!
+//! `HandlerPipeline` is an `EventHandler` which runs each event through a list of stages, then delivers it to all of the sinks.
+//!
+//! - Stages (filter, map, or a custom `PipelineStage`) work on the decoded JSON, so they only apply to events exported in `ExportFormatType::Json` or `ExportFormatType::Ndjson`. For Ndjson, the stages run on the `data` of the envelope, and the result is wrapped back. Other events are delivered to the sinks directly.
+//! - Sinks are just `EventHandler`s, so a pipeline can also be a sink of another pipeline.
+//! - Building an exporter fails if a pipeline with stages (including the ones in its sinks) is paired with another format, since the stages would never run.
+//! - A stage may ask to stop polling. If a `PollingHandle` was bound, it will be terminated, and `wait_and_poll_to_handler` will return.
+
+use std::{
+    any::Any,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, Result};
+use log::{error, warn};
+use serde_json::Value;
+
+use crate::skeleton::handle::PollingHandle;
+
+use super::{EventHandler, EventMeta, ExportFormatType, ReceivedEventData};
+
+/// What a stage decides to do with an event
+pub enum StageOutcome {
+    /// Pass the (possibly modified) event to the next stage
+    Continue(Value),
+    /// Drop the event
+    Drop,
+    /// Stop polling. The event will still be delivered if provided
+    Stop(Option<Value>),
+}
+
+/// A stage in the pipeline, which processes the decoded JSON of an event
+pub trait PipelineStage {
+    fn process(&self, event: Value, meta: &EventMeta) -> StageOutcome;
+}
+
+impl<F: Fn(Value, &EventMeta) -> StageOutcome> PipelineStage for F {
+    fn process(&self, event: Value, meta: &EventMeta) -> StageOutcome {
+        self(event, meta)
+    }
+}
+
+struct FilterStage<F>(F);
+
+impl<F: Fn(&Value) -> bool> PipelineStage for FilterStage<F> {
+    fn process(&self, event: Value, _meta: &EventMeta) -> StageOutcome {
+        if (self.0)(&event) {
+            StageOutcome::Continue(event)
+        } else {
+            StageOutcome::Drop
+        }
+    }
+}
+
+struct MapStage<F>(F);
+
+impl<F: Fn(Value) -> Value> PipelineStage for MapStage<F> {
+    fn process(&self, event: Value, _meta: &EventMeta) -> StageOutcome {
+        StageOutcome::Continue((self.0)(event))
+    }
+}
+
+/// A handler which runs events through stages, and delivers them to multiple sinks
+pub struct HandlerPipeline {
+    stages: Vec<Box<dyn PipelineStage>>,
+    sinks: Vec<Arc<dyn EventHandler>>,
+    handle: Option<PollingHandle>,
+    stop_after: Option<u64>,
+    delivered: AtomicU64,
+    stopped: AtomicBool,
+}
+
+impl HandlerPipeline {
+    /// Create a builder
+    pub fn builder() -> HandlerPipelineBuilder {
+        HandlerPipelineBuilder::default()
+    }
+    /// Whether a stop was requested by a stage, or by the limit of events
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+    /// Count of events delivered to the sinks
+    pub fn delivered_events(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+    fn stop(&self) {
+        if !self.stopped.swap(true, Ordering::Relaxed) {
+            if let Some(handle) = self.handle.as_ref() {
+                handle.terminate();
+            } else {
+                warn!(
+                    "A pipeline stage requested to stop polling, but no polling handle was bound"
+                );
+            }
+        }
+    }
+    fn deliver(&self, context: Option<Arc<dyn Any>>, data: ReceivedEventData, meta: &EventMeta) {
+        for sink in self.sinks.iter() {
+            sink.handle_event_with_meta(context.clone(), data, meta);
+        }
+        let delivered = self.delivered.fetch_add(1, Ordering::Relaxed) + 1;
+        if matches!(self.stop_after, Some(limit) if delivered >= limit) {
+            self.stop();
+        }
+    }
+}
+
+impl EventHandler for HandlerPipeline {
+    fn handle_event(&self, context: Option<Arc<dyn Any>>, data: ReceivedEventData) {
+        self.handle_event_with_meta(context, data, &EventMeta::default());
+    }
+    fn handle_event_with_meta(
+        &self,
+        context: Option<Arc<dyn Any>>,
+        data: ReceivedEventData,
+        meta: &EventMeta,
+    ) {
+        // Events which arrive after stopping are dropped, so that the limit is exact
+        if self.is_stopped() {
+            return;
+        }
+        let (json_text, ndjson) = match data {
+            ReceivedEventData::JsonText(s) if !self.stages.is_empty() => (s, false),
+            ReceivedEventData::NdjsonText(s) if !self.stages.is_empty() => (s, true),
+            data => {
+                self.deliver(context, data, meta);
+                return;
+            }
+        };
+        let mut parsed = match serde_json::from_str::<Value>(json_text) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to parse event as json in the pipeline: {}", e);
+                return;
+            }
+        };
+        // Stages see the event itself, not the envelope around it
+        let mut event = if ndjson {
+            match parsed.get_mut("data") {
+                Some(data) => data.take(),
+                None => {
+                    error!("Ndjson envelope without `data` in the pipeline");
+                    return;
+                }
+            }
+        } else {
+            parsed.take()
+        };
+        let mut stop = false;
+        for stage in self.stages.iter() {
+            match stage.process(event, meta) {
+                StageOutcome::Continue(v) => event = v,
+                StageOutcome::Drop => return,
+                StageOutcome::Stop(None) => {
+                    self.stop();
+                    return;
+                }
+                StageOutcome::Stop(Some(v)) => {
+                    event = v;
+                    stop = true;
+                    break;
+                }
+            }
+        }
+        if ndjson {
+            parsed["data"] = event;
+        } else {
+            parsed = event;
+        }
+        match serde_json::to_string(&parsed) {
+            Ok(s) if ndjson => self.deliver(context, ReceivedEventData::NdjsonText(&s), meta),
+            Ok(s) => self.deliver(context, ReceivedEventData::JsonText(&s), meta),
+            Err(e) => error!("Failed to serialize event in the pipeline: {}", e),
+        }
+        if stop {
+            self.stop();
+        }
+    }
+    fn check_export_format(&self, format: ExportFormatType) -> Result<()> {
+        if !self.stages.is_empty()
+            && !matches!(format, ExportFormatType::Json | ExportFormatType::Ndjson)
+        {
+            bail!(
+                "Pipeline stages only work on json or ndjson events, but the export format is {:?}",
+                format
+            );
+        }
+        self.sinks
+            .iter()
+            .try_for_each(|sink| sink.check_export_format(format))
+    }
+    fn handle_sampling_round_end(&self, map_name: &str) {
+        for sink in self.sinks.iter() {
+            sink.handle_sampling_round_end(map_name);
+        }
+    }
+}
+
+/// The builder of `HandlerPipeline`
+#[derive(Default)]
+pub struct HandlerPipelineBuilder {
+    stages: Vec<Box<dyn PipelineStage>>,
+    sinks: Vec<Arc<dyn EventHandler>>,
+    handle: Option<PollingHandle>,
+    stop_after: Option<u64>,
+}
+
+impl HandlerPipelineBuilder {
+    /// Only pass events for which `f` returns true
+    pub fn filter(mut self, f: impl Fn(&Value) -> bool + 'static) -> Self {
+        self.stages.push(Box::new(FilterStage(f)));
+        self
+    }
+    /// Transform events with `f`
+    pub fn map(mut self, f: impl Fn(Value) -> Value + 'static) -> Self {
+        self.stages.push(Box::new(MapStage(f)));
+        self
+    }
+    /// Add a custom stage
+    pub fn stage(mut self, stage: impl PipelineStage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+    /// Deliver events to `sink`. Each event will be delivered to all sinks, in the order they were added
+    pub fn sink(mut self, sink: Arc<dyn EventHandler>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+    /// Stop polling after `count` events were delivered
+    pub fn stop_after(self, count: u64) -> Self {
+        Self {
+            stop_after: Some(count),
+            ..self
+        }
+    }
+    /// The handle to terminate, when a stop was requested. Get it through `BpfSkeleton::create_poll_handle`
+    pub fn bind_handle(self, handle: PollingHandle) -> Self {
+        Self {
+            handle: Some(handle),
+            ..self
+        }
+    }
+    /// Build the pipeline
+    pub fn build(self) -> HandlerPipeline {
+        HandlerPipeline {
+            stages: self.stages,
+            sinks: self.sinks,
+            handle: self.handle,
+            stop_after: self.stop_after,
+            delivered: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+        }
+    }
+}
+
+/// A sink which writes each event as a line
+pub struct WriterSink<W: Write> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> WriterSink<W> {
+    /// Create a sink writing to `writer`, such as stdout or a file
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write> EventHandler for WriterSink<W> {
+    fn handle_event(&self, _context: Option<Arc<dyn Any>>, data: ReceivedEventData) {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match data {
+            ReceivedEventData::PlainText(s)
+            | ReceivedEventData::JsonText(s)
+            | ReceivedEventData::NdjsonText(s) => {
+                writeln!(writer, "{s}")
+            }
+            ReceivedEventData::Buffer(buf) => writer.write_all(buf),
+            ReceivedEventData::KeyValueBuffer { key, value } => {
+                writer.write_all(key).and_then(|_| writer.write_all(value))
+            }
+        };
+        if let Err(e) = result.and_then(|_| writer.flush()) {
+            warn!("Failed to write event to the sink: {}", e);
+        }
+    }
+}
+
+/// A sink which only counts the events
+#[derive(Default)]
+pub struct CountingSink {
+    count: AtomicU64,
+}
+
+impl CountingSink {
+    /// Events received so far
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl EventHandler for CountingSink {
+    fn handle_event(&self, _context: Option<Arc<dyn Any>>, _data: ReceivedEventData) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::{json, Value};
+
+    use super::{CountingSink, HandlerPipeline, StageOutcome, WriterSink};
+    use crate::{
+        export_event::{EventHandler, EventMeta, ExportFormatType, ReceivedEventData},
+        skeleton::handle::{PollingHandle, PollingState},
+    };
+
+    #[derive(Default, Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn send_json(pipeline: &HandlerPipeline, value: Value) {
+        let s = value.to_string();
+        pipeline.handle_event(None, ReceivedEventData::JsonText(&s));
+    }
+
+    #[test]
+    fn test_filter_map_and_fan_out() {
+        let buffer = SharedBuffer::default();
+        let counter = Arc::new(CountingSink::default());
+        let pipeline = HandlerPipeline::builder()
+            .filter(|v| v["pid"].as_u64().unwrap() > 10)
+            .map(|v| json!({"comm": v["comm"]}))
+            .sink(Arc::new(WriterSink::new(buffer.clone())))
+            .sink(counter.clone())
+            .build();
+        send_json(&pipeline, json!({"pid": 1, "comm": "init"}));
+        send_json(&pipeline, json!({"pid": 100, "comm": "bash"}));
+        pipeline.handle_event(None, ReceivedEventData::PlainText("text"));
+        assert_eq!(counter.count(), 2);
+        assert_eq!(pipeline.delivered_events(), 2);
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output, "{\"comm\":\"bash\"}\ntext\n");
+    }
+
+    #[test]
+    fn test_ndjson_envelope() {
+        let buffer = SharedBuffer::default();
+        let pipeline = HandlerPipeline::builder()
+            .filter(|v| v["pid"].as_u64().unwrap() > 10)
+            .map(|v| json!({"comm": v["comm"]}))
+            .sink(Arc::new(WriterSink::new(buffer.clone())))
+            .build();
+        for pid in [1, 100] {
+            let s = json!({"map": "rb", "data": {"pid": pid, "comm": "bash"}}).to_string();
+            pipeline.handle_event(None, ReceivedEventData::NdjsonText(&s));
+        }
+        assert_eq!(pipeline.delivered_events(), 1);
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let envelope: Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(envelope, json!({"map": "rb", "data": {"comm": "bash"}}));
+    }
+
+    /// A sink which only accepts JSON events
+    struct JsonOnlySink;
+
+    impl EventHandler for JsonOnlySink {
+        fn handle_event(&self, _context: Option<Arc<dyn std::any::Any>>, _data: ReceivedEventData) {
+        }
+        fn check_export_format(&self, format: ExportFormatType) -> anyhow::Result<()> {
+            if !matches!(format, ExportFormatType::Json) {
+                anyhow::bail!("Unsupported format");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_check_export_format() {
+        let pipeline = HandlerPipeline::builder()
+            .filter(|v| v["pid"].as_u64().is_some())
+            .sink(Arc::new(CountingSink::default()))
+            .build();
+        assert!(pipeline.check_export_format(ExportFormatType::Json).is_ok());
+        assert!(pipeline
+            .check_export_format(ExportFormatType::Ndjson)
+            .is_ok());
+        assert!(pipeline
+            .check_export_format(ExportFormatType::PlainText)
+            .is_err());
+        // Sinks are checked as well
+        let pipeline = HandlerPipeline::builder()
+            .sink(Arc::new(JsonOnlySink))
+            .build();
+        assert!(pipeline.check_export_format(ExportFormatType::Json).is_ok());
+        assert!(pipeline
+            .check_export_format(ExportFormatType::Ndjson)
+            .is_err());
+        let pipeline = HandlerPipeline::builder()
+            .sink(Arc::new(CountingSink::default()))
+            .build();
+        assert!(pipeline.check_export_format(ExportFormatType::Csv).is_ok());
+    }
+
+    #[test]
+    fn test_stop_polling() {
+        let handle = PollingHandle::new().unwrap();
+        let counter = Arc::new(CountingSink::default());
+        let pipeline = HandlerPipeline::builder()
+            .sink(counter.clone())
+            .bind_handle(handle.clone())
+            .stop_after(2)
+            .build();
+        for _ in 0..3 {
+            send_json(&pipeline, json!({}));
+        }
+        assert!(pipeline.is_stopped());
+        assert_eq!(counter.count(), 2);
+        assert_eq!(handle.state(), PollingState::Terminated);
+
+        let handle = PollingHandle::new().unwrap();
+        let pipeline = HandlerPipeline::builder()
+            .stage(|v: Value, meta: &EventMeta| {
+                if meta.sequence == 1 {
+                    StageOutcome::Stop(Some(v))
+                } else {
+                    StageOutcome::Continue(v)
+                }
+            })
+            .sink(counter.clone())
+            .bind_handle(handle.clone())
+            .build();
+        let s = json!({}).to_string();
+        for sequence in 0..3 {
+            let meta = EventMeta {
+                sequence,
+                ..Default::default()
+            };
+            pipeline.handle_event_with_meta(None, ReceivedEventData::JsonText(&s), &meta);
+        }
+        assert_eq!(counter.count(), 4);
+        assert_eq!(handle.state(), PollingState::Terminated);
+    }
+}
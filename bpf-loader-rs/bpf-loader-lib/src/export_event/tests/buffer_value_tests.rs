@@ -4,16 +4,20 @@
 //! All rights reserved.
 //!
 
-use std::{cell::RefCell, rc::Rc, sync::Arc};
+use std::sync::Arc;
 
 use crate::{
     btf_container::BtfContainer,
     export_event::{
-        tests::{load_triple, RRC},
-        EventExporter, EventExporterBuilder, EventHandler, ExportFormatType,
+        filter::EventFilter,
+        tests::{load_triple, CollectingHandler},
+        EventExporter, EventExporterBuilder, EventHandler, EventMeta, ExportFormatType,
         ExporterInternalImplementation,
     },
-    meta::{BufferValueInterpreter, EunomiaObjectMeta},
+    meta::{
+        AggregationMeta, BufferValueInterpreter, EnrichMeta, ErrorPolicy, EunomiaObjectMeta,
+        MapMeta, PlainTextFormatMeta,
+    },
     tests::ExampleTestStruct,
 };
 
@@ -24,7 +28,9 @@ fn send_data(exporter: Arc<EventExporter>, data: &[u8]) {
         ExporterInternalImplementation::BufferValueProcessor {
             event_processor, ..
         } => {
-            event_processor.handle_event(&data).unwrap();
+            event_processor
+                .handle_event(&EventMeta::default(), data)
+                .unwrap();
         }
         _ => panic!("Unexpected internal implementation"),
     };
@@ -51,37 +57,11 @@ fn create_exporter(
 #[test]
 fn test_export_format_json() {
     let (btf, bin_data, skel) = load_triple();
-    let received_data = Rc::new(RefCell::new(String::default()));
-
-    struct MyEventHandler {
-        data: RRC<String>,
-    }
-    impl EventHandler for MyEventHandler {
-        fn handle_event(
-            &self,
-            _context: Option<std::sync::Arc<dyn std::any::Any>>,
-            data: crate::export_event::ReceivedEventData,
-        ) {
-            match data {
-                crate::export_event::ReceivedEventData::JsonText(s) => {
-                    self.data.replace(s.to_string());
-                }
-                _ => panic!("Unexpected data type"),
-            }
-        }
-    }
-    let exporter = create_exporter(
-        btf,
-        &skel,
-        ExportFormatType::Json,
-        Arc::new(MyEventHandler {
-            data: received_data.clone(),
-        }),
-    );
+    let handler = CollectingHandler::new();
+    let exporter = create_exporter(btf, &skel, ExportFormatType::Json, handler.clone());
 
     send_data(exporter.clone(), &bin_data[..]);
-    let inner_data = received_data.borrow();
-    let data: ExampleTestStruct = serde_json::from_str(inner_data.as_str()).unwrap();
+    let data: ExampleTestStruct = serde_json::from_str(&handler.lines()[0]).unwrap();
     data.test_with_example_data();
 }
 
@@ -91,36 +71,11 @@ const EXPECTED_PLAIN_TEXT_OUTPUT_LINE1:&str = "TIME     ARR1   STR    STR_ARR FT
 #[test]
 fn test_export_format_plain_text() {
     let (btf, bin_data, skel) = load_triple();
-    let received_data = Rc::new(RefCell::new(Vec::new()));
-
-    struct MyEventHandler {
-        data: RRC<Vec<String>>,
-    }
-    impl EventHandler for MyEventHandler {
-        fn handle_event(
-            &self,
-            _context: Option<std::sync::Arc<dyn std::any::Any>>,
-            data: crate::export_event::ReceivedEventData,
-        ) {
-            match data {
-                crate::export_event::ReceivedEventData::PlainText(s) => {
-                    self.data.borrow_mut().push(s.to_string());
-                }
-                _ => panic!("Unexpected data type"),
-            }
-        }
-    }
-    let exporter = create_exporter(
-        btf,
-        &skel,
-        ExportFormatType::PlainText,
-        Arc::new(MyEventHandler {
-            data: received_data.clone(),
-        }),
-    );
+    let handler = CollectingHandler::new();
+    let exporter = create_exporter(btf, &skel, ExportFormatType::PlainText, handler.clone());
 
     send_data(exporter.clone(), &bin_data[..]);
-    let inner_data = received_data.borrow();
+    let inner_data = handler.lines();
     println!("{:?}", inner_data);
     assert_eq!(inner_data[0], EXPECTED_PLAIN_TEXT_OUTPUT_LINE1);
     let output_without_time = inner_data[1].split_once("  ").unwrap().1;
@@ -131,37 +86,11 @@ fn test_export_format_plain_text() {
 #[test]
 fn test_export_format_raw() {
     let (btf, bin_data, skel) = load_triple();
-    let received_data = Rc::new(RefCell::new(Vec::<u8>::new()));
-
-    struct MyEventHandler {
-        data: RRC<Vec<u8>>,
-    }
-    impl EventHandler for MyEventHandler {
-        fn handle_event(
-            &self,
-            _context: Option<std::sync::Arc<dyn std::any::Any>>,
-            data: crate::export_event::ReceivedEventData,
-        ) {
-            match data {
-                crate::export_event::ReceivedEventData::Buffer(s) => {
-                    self.data.replace(s.to_vec());
-                }
-                _ => panic!("Unexpected data type"),
-            }
-        }
-    }
-    let exporter = create_exporter(
-        btf,
-        &skel,
-        ExportFormatType::RawEvent,
-        Arc::new(MyEventHandler {
-            data: received_data.clone(),
-        }),
-    );
+    let handler = CollectingHandler::new();
+    let exporter = create_exporter(btf, &skel, ExportFormatType::RawEvent, handler.clone());
 
     send_data(exporter.clone(), &bin_data[..]);
-    let inner_data = received_data.borrow();
-    assert_eq!(&inner_data[..], &bin_data[..]);
+    assert_eq!(handler.events()[0].bytes, bin_data);
 }
 
 const STACKTRACE_EXPECTED_OUTPUT:&str = "COMM: test-comm (pid=4660) @ CPU 22136\nKernel:\n  0 [<0000000000010000>]\n  1 [<0000000000010001>]\nUserspace:\n  0 [<0000000000010000>]\n  1 [<0000000000010001>]\n  2 [<0000000000010002>]\n  3 [<0000000000010003>]\n  4 [<0000000000010004>]\n  5 [<0000000000010005>]\n  6 [<0000000000010006>]\n  7 [<0000000000010007>]\n  8 [<0000000000010008>]\n  9 [<0000000000010009>]\n  10 [<000000000001000a>]\n  11 [<000000000001000b>]\n  12 [<000000000001000c>]\n  13 [<000000000001000d>]\n  14 [<000000000001000e>]\n  15 [<000000000001000f>]\n";
@@ -217,30 +146,10 @@ fn test_stacktrace_exporter() {
         "profile_test/test.bin",
         "profile_test/profile.skel.json",
     );
-    let received_data = Rc::new(RefCell::new(Vec::new()));
-
-    struct MyEventHandler {
-        data: RRC<Vec<String>>,
-    }
-    impl EventHandler for MyEventHandler {
-        fn handle_event(
-            &self,
-            _context: Option<std::sync::Arc<dyn std::any::Any>>,
-            data: crate::export_event::ReceivedEventData,
-        ) {
-            match data {
-                crate::export_event::ReceivedEventData::PlainText(s) => {
-                    self.data.borrow_mut().push(s.to_string());
-                }
-                _ => panic!("Unexpected data type"),
-            }
-        }
-    }
+    let handler = CollectingHandler::new();
 
     let exporter = EventExporterBuilder::new()
-        .set_export_event_handler(Arc::new(MyEventHandler {
-            data: received_data.clone(),
-        }))
+        .set_export_event_handler(handler.clone())
         .set_export_format(ExportFormatType::PlainText)
         .build_for_single_value(
             &skel.export_types[0],
@@ -250,6 +159,438 @@ fn test_stacktrace_exporter() {
         .unwrap();
 
     send_data(exporter.clone(), &dummy_bin[..]);
-    let inner_data = received_data.borrow()[0].clone();
+    let inner_data = handler.lines()[0].clone();
     assert_eq!(inner_data, STACKTRACE_EXPECTED_OUTPUT);
 }
+
+#[test]
+fn test_error_policy() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let create_with_policy = |policy: ErrorPolicy| {
+        EventExporterBuilder::new()
+            .set_export_event_handler(handler.clone())
+            .set_export_format(ExportFormatType::Json)
+            .set_error_policy(policy)
+            .build_for_single_value(
+                &skel.export_types[0],
+                btf.clone(),
+                &BufferValueInterpreter::DefaultStruct,
+            )
+            .unwrap()
+    };
+    // The buffer is too small to be decoded
+    let data = &bin_data[..4];
+    let decode = |exporter: &EventExporter| match &exporter.internal_impl {
+        ExporterInternalImplementation::BufferValueProcessor {
+            event_processor, ..
+        } => event_processor
+            .handle_event(&EventMeta::default(), data)
+            .or_else(|e| exporter.handle_decode_failure(&EventMeta::default(), e, None, data)),
+        _ => panic!("Unexpected internal implementation"),
+    };
+    assert!(decode(&create_with_policy(ErrorPolicy::Abort)).is_err());
+    assert!(decode(&create_with_policy(ErrorPolicy::Skip)).is_ok());
+    assert!(handler.lines().is_empty());
+    assert!(decode(&create_with_policy(ErrorPolicy::EmitRaw)).is_ok());
+    let inner_data = handler.lines();
+    assert_eq!(inner_data.len(), 1);
+    let value: serde_json::Value = serde_json::from_str(&inner_data[0]).unwrap();
+    assert!(value["error"].as_str().unwrap().contains("too small"));
+    assert_eq!(
+        value["raw"].as_str().unwrap(),
+        data.iter().map(|v| format!("{v:02x}")).collect::<String>()
+    );
+}
+
+#[test]
+fn test_non_utf8_string() {
+    let (btf, mut bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let exporter = create_exporter(btf, &skel, ExportFormatType::Json, handler.clone());
+    let str_offset = match &exporter.internal_impl {
+        ExporterInternalImplementation::BufferValueProcessor { checked_types, .. } => {
+            checked_types
+                .iter()
+                .find(|v| v.field_name == "str")
+                .unwrap()
+                .bit_offset as usize
+                / 8
+        }
+        _ => panic!("Unexpected internal implementation"),
+    };
+    bin_data[str_offset..str_offset + 4].copy_from_slice(&[b'A', 0xff, b'B', 0]);
+    send_data(exporter.clone(), &bin_data[..]);
+    let value: serde_json::Value = serde_json::from_str(&handler.lines()[0]).unwrap();
+    assert_eq!(value["str"].as_str().unwrap(), "A\u{FFFD}B");
+}
+
+#[test]
+fn test_event_meta() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let exporter = EventExporterBuilder::new()
+        .set_export_event_handler(handler.clone())
+        .set_export_format(ExportFormatType::Json)
+        .set_json_event_meta(true)
+        .build_for_single_value(
+            &skel.export_types[0],
+            btf,
+            &BufferValueInterpreter::DefaultStruct,
+        )
+        .unwrap();
+    let meta = EventMeta {
+        map_name: "events",
+        cpu: Some(3),
+        timestamp_ns: 1000,
+        sequence: 7,
+    };
+    match &exporter.internal_impl {
+        ExporterInternalImplementation::BufferValueProcessor {
+            event_processor, ..
+        } => event_processor.handle_event(&meta, &bin_data).unwrap(),
+        _ => panic!("Unexpected internal implementation"),
+    };
+    let inner_data = handler.events();
+    assert_eq!(inner_data[0].meta, Some(("events".to_string(), Some(3))));
+    let value: serde_json::Value = serde_json::from_str(&inner_data[0].text).unwrap();
+    assert_eq!(
+        value["_meta"],
+        serde_json::json!({"map": "events", "cpu": 3, "timestamp_ns": 1000, "seq": 7})
+    );
+    let data: ExampleTestStruct = serde_json::from_value(value).unwrap();
+    data.test_with_example_data();
+}
+
+#[test]
+fn test_filter() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    for (format, expr, expected) in [
+        (
+            ExportFormatType::Json,
+            r#"str == "A-String" && u8v > 10"#,
+            1,
+        ),
+        (ExportFormatType::Json, "u8v != 18", 0),
+        (ExportFormatType::RawEvent, r#"str_arr[9] ^= "hello""#, 1),
+        // The header will always be printed
+        (ExportFormatType::PlainText, "i8v > 0", 1),
+    ] {
+        handler.clear();
+        let exporter = EventExporterBuilder::new()
+            .set_export_event_handler(handler.clone())
+            .set_export_format(format)
+            .set_filter(EventFilter::parse(expr).unwrap())
+            .build_for_single_value(
+                &skel.export_types[0],
+                btf.clone(),
+                &BufferValueInterpreter::DefaultStruct,
+            )
+            .unwrap();
+        send_data(exporter, &bin_data[..]);
+        assert_eq!(handler.lines().len(), expected, "{expr}");
+    }
+}
+
+#[test]
+fn test_plain_text_template() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let build = |format: PlainTextFormatMeta| {
+        EventExporterBuilder::new()
+            .set_export_event_handler(handler.clone())
+            .set_plain_text_format(format)
+            .build_for_single_value(
+                &skel.export_types[0],
+                btf.clone(),
+                &BufferValueInterpreter::DefaultStruct,
+            )
+    };
+    let exporter = build(PlainTextFormatMeta {
+        template: Some("{str:>10}|{u8v:<4}|{i8v:errno}|{u16v:hex}|{str_arr.1}".into()),
+        ..Default::default()
+    })
+    .unwrap();
+    send_data(exporter, &bin_data[..]);
+    assert_eq!(
+        handler.lines(),
+        vec![
+            "       STR|U8V |I8V|U16V|STR_ARR.1".to_string(),
+            "  A-String|18  |EXDEV|0x1234|hello 1".to_string()
+        ]
+    );
+
+    handler.clear();
+    let exporter = build(PlainTextFormatMeta {
+        template: Some("{i32v:errno}".into()),
+        header: Some("custom header".into()),
+        ..Default::default()
+    })
+    .unwrap();
+    send_data(exporter, &bin_data[..]);
+    assert_eq!(handler.lines()[0], "custom header");
+
+    handler.clear();
+    let exporter = build(PlainTextFormatMeta {
+        print_header: false,
+        ..Default::default()
+    })
+    .unwrap();
+    send_data(exporter, &bin_data[..]);
+    assert_eq!(handler.lines().len(), 1);
+
+    assert!(build(PlainTextFormatMeta {
+        template: Some("{no_such_field}".into()),
+        ..Default::default()
+    })
+    .is_err());
+}
+
+#[test]
+fn test_plain_text_table() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let exporter = EventExporterBuilder::new()
+        .set_export_event_handler(handler.clone())
+        .set_plain_text_format(PlainTextFormatMeta {
+            table: true,
+            max_column_width: 12,
+            header_interval: 2,
+            time_format: "%Y".into(),
+            ..Default::default()
+        })
+        .build_for_single_value(
+            &skel.export_types[0],
+            btf.clone(),
+            &BufferValueInterpreter::DefaultStruct,
+        )
+        .unwrap();
+    for _ in 0..3 {
+        send_data(exporter.clone(), &bin_data[..]);
+    }
+    let lines = handler.lines();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], lines[3]);
+    assert_eq!(lines[0], "TIME ARR1         STR          STR_ARR      FT           DBL          U8V I8V  U16V  I16V   U32V       I32V        U64V         I64V         E      ");
+    for line in lines.iter() {
+        assert_eq!(line.chars().count(), lines[0].chars().count());
+    }
+    assert!(lines[1].contains("[[[0,1,2,3]…"));
+}
+
+#[test]
+fn test_structured_formats() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let build = |format: ExportFormatType| {
+        handler.clear();
+        EventExporterBuilder::new()
+            .set_export_event_handler(handler.clone())
+            .set_export_format(format)
+            .build_for_single_value(
+                &skel.export_types[0],
+                btf.clone(),
+                &BufferValueInterpreter::DefaultStruct,
+            )
+            .unwrap()
+    };
+    let meta = EventMeta {
+        map_name: "rb",
+        timestamp_ns: 42,
+        ..Default::default()
+    };
+
+    let exporter = build(ExportFormatType::Csv);
+    send_data(exporter, &bin_data[..]);
+    {
+        let lines = handler.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "arr1,str,str_arr,ft,dbl,u8v,i8v,u16v,i16v,u32v,i32v,u64v,i64v,e"
+        );
+        assert!(lines[1].starts_with(r#""[[[0,1,2,3],"#));
+        assert!(lines[1].ends_with(r#",18,-18,4660,-4660,305419896,-305419896,1311768467463790320,-1311768467463790320,E_A(0)"#));
+        assert!(lines[1].contains(r#",A-String,"[""hello 0"",""hello 1"","#));
+    }
+
+    let exporter = build(ExportFormatType::Logfmt);
+    send_data(exporter, &bin_data[..]);
+    {
+        let lines = handler.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(" str=A-String "));
+        assert!(lines[0].ends_with(" i64v=-1311768467463790320 e=E_A(0)"));
+    }
+
+    let exporter = build(ExportFormatType::Ndjson);
+    match &exporter.internal_impl {
+        ExporterInternalImplementation::BufferValueProcessor {
+            event_processor, ..
+        } => event_processor.handle_event(&meta, &bin_data[..]).unwrap(),
+        _ => panic!("Unreachable"),
+    }
+    let envelope: serde_json::Value = serde_json::from_str(&handler.lines()[0]).unwrap();
+    assert_eq!(envelope["timestamp_ns"], 42);
+    assert_eq!(envelope["map"], "rb");
+    assert_eq!(envelope["type"], skel.export_types[0].name.as_str());
+    assert_eq!(envelope["data"]["str"], "A-String");
+
+    assert_eq!(
+        "ndjson".parse::<ExportFormatType>().unwrap(),
+        ExportFormatType::Ndjson
+    );
+    assert!("xml".parse::<ExportFormatType>().is_err());
+}
+
+#[test]
+fn test_aggregation() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let exporter = EventExporterBuilder::new()
+        .set_export_event_handler(handler.clone())
+        .set_export_format(ExportFormatType::Json)
+        .set_aggregation(AggregationMeta {
+            group_by: vec!["str".into()],
+            aggregates: vec!["count".into(), "sum(u16v)".into(), "min(i8v)".into()],
+            ..Default::default()
+        })
+        .build_for_single_value(
+            &skel.export_types[0],
+            btf.clone(),
+            &BufferValueInterpreter::DefaultStruct,
+        )
+        .unwrap();
+    send_data(exporter.clone(), &bin_data[..]);
+    send_data(exporter.clone(), &bin_data[..]);
+    // Events are not exported until the window ends
+    assert!(handler.lines().is_empty());
+    exporter.flush_aggregation("rb").unwrap();
+    let result: serde_json::Value = serde_json::from_str(&handler.lines()[0]).unwrap();
+    assert_eq!(
+        result["groups"],
+        serde_json::json!([{"str": "A-String", "count": 2, "sum(u16v)": 9320, "min(i8v)": -18}])
+    );
+    // Empty windows are not exported
+    exporter.flush_aggregation("rb").unwrap();
+    assert_eq!(handler.lines().len(), 1);
+
+    // Fields are checked, and csv output is not supported
+    for (format, aggregates) in [
+        (ExportFormatType::Json, vec!["sum(not_a_field)".to_string()]),
+        (ExportFormatType::Csv, vec!["count".to_string()]),
+    ] {
+        assert!(EventExporterBuilder::new()
+            .set_export_format(format)
+            .set_aggregation(AggregationMeta {
+                aggregates,
+                ..Default::default()
+            })
+            .build_for_single_value(
+                &skel.export_types[0],
+                btf.clone(),
+                &BufferValueInterpreter::DefaultStruct,
+            )
+            .is_err());
+    }
+}
+
+#[test]
+fn test_enrichment() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let build = |format: ExportFormatType, uid: &str| {
+        handler.clear();
+        EventExporterBuilder::new()
+            .set_export_event_handler(handler.clone())
+            .set_export_format(format)
+            .set_enrichment(EnrichMeta {
+                uid: Some(uid.into()),
+                ..Default::default()
+            })
+            .build_for_single_value(
+                &skel.export_types[0],
+                btf.clone(),
+                &BufferValueInterpreter::DefaultStruct,
+            )
+    };
+    // No user has such a large uid
+    let exporter = build(ExportFormatType::Json, "u32v").unwrap();
+    send_data(exporter, &bin_data[..]);
+    let result: serde_json::Value = serde_json::from_str(&handler.lines()[0]).unwrap();
+    assert_eq!(result["u32v"], 305419896);
+    assert_eq!(result["user"], serde_json::Value::Null);
+
+    let exporter = build(ExportFormatType::Csv, "u32v").unwrap();
+    send_data(exporter, &bin_data[..]);
+    {
+        let lines = handler.lines();
+        assert!(lines[0].ends_with(",e,user"));
+        assert!(lines[1].ends_with(",E_A(0),"));
+    }
+
+    let exporter = build(ExportFormatType::PlainText, "u32v").unwrap();
+    send_data(exporter, &bin_data[..]);
+    {
+        let lines = handler.lines();
+        assert!(lines[0].ends_with(" USER "));
+        assert!(lines[1].ends_with(" -"));
+    }
+
+    assert!(build(ExportFormatType::Json, "not_a_field").is_err());
+    assert!(build(ExportFormatType::RawEvent, "u32v").is_err());
+}
+
+#[test]
+fn test_correlation_from_meta() {
+    let (btf, bin_data, skel) = load_triple();
+    let handler = CollectingHandler::new();
+    let build = |format: ExportFormatType, time_field: &str| {
+        let map_meta: MapMeta = serde_json::from_value(serde_json::json!({
+            "name": "rb",
+            "ident": "rb",
+            "correlate": {
+                "discriminator": "u8v",
+                "enter": 0x12,
+                "exit": 0x13,
+                "keys": ["str"],
+                "time_field": time_field,
+            }
+        }))
+        .unwrap();
+        EventExporterBuilder::new()
+            .set_export_event_handler(handler.clone())
+            .set_export_format(format)
+            .apply_map_meta(&map_meta)
+            .build_for_single_value(
+                &skel.export_types[0],
+                btf.clone(),
+                &BufferValueInterpreter::DefaultStruct,
+            )
+    };
+    let exporter = build(ExportFormatType::Json, "u64v").unwrap();
+    // Turn the event into an exit event, which happens 500ns later
+    let mut exit_data = bin_data.clone();
+    if let ExporterInternalImplementation::BufferValueProcessor { checked_types, .. } =
+        &exporter.internal_impl
+    {
+        let offset_of = |name: &str| {
+            let member = checked_types.iter().find(|v| v.field_name == name).unwrap();
+            member.bit_offset as usize / 8
+        };
+        exit_data[offset_of("u8v")] = 0x13;
+        let offset = offset_of("u64v");
+        let time = u64::from_le_bytes(exit_data[offset..offset + 8].try_into().unwrap());
+        exit_data[offset..offset + 8].copy_from_slice(&(time + 500).to_le_bytes());
+    }
+    send_data(exporter.clone(), &bin_data[..]);
+    assert!(handler.lines().is_empty());
+    send_data(exporter, &exit_data[..]);
+    let result: serde_json::Value = serde_json::from_str(&handler.lines()[0]).unwrap();
+    assert_eq!(result["u8v"], 0x13);
+    assert_eq!(result["duration_ns"], 500);
+
+    assert!(build(ExportFormatType::Json, "not_a_field").is_err());
+    assert!(build(ExportFormatType::Csv, "u64v").is_err());
+}
@@ -12,12 +12,15 @@ use serde::Deserialize;
 use crate::{
     btf_container::BtfContainer,
     export_event::{
-        tests::RRC, EventExporter, EventExporterBuilder, EventHandler, ExportFormatType,
+        EventExporter, EventExporterBuilder, EventHandler, EventMeta, ExportFormatType,
         ExporterInternalImplementation,
     },
     meta::{ComposedObject, MapMeta, SampleMapType},
     tests::get_assets_dir,
 };
+
+type RRC<T> = Rc<RefCell<T>>;
+
 struct LoadedThings {
     key_id: u32,
     value_id: u32,
@@ -138,7 +141,7 @@ fn send_data(exporter: Arc<EventExporter>, key_buffer: &[u8], value_buffer: &[u8
             event_processor, ..
         } => {
             event_processor
-                .handle_event(key_buffer, value_buffer)
+                .handle_event(&EventMeta::default(), key_buffer, value_buffer)
                 .unwrap();
         }
         _ => panic!("Unexpected internal implementation"),
@@ -4,12 +4,16 @@
 //! All rights reserved.
 //!
 
-use std::{cell::RefCell, rc::Rc, sync::Arc};
+use std::{
+    any::Any,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
 use crate::{
     btf_container::BtfContainer,
     export_event::{
-        EventExporterBuilder, EventHandler, ExportFormatType, ExporterInternalImplementation,
+        max_time_width, EventExporterBuilder, EventHandler, EventMeta, ExportFormatType,
+        ExporterInternalImplementation, ReceivedEventData,
     },
     meta::{BufferValueInterpreter, EunomiaObjectMeta},
     tests::get_assets_dir,
@@ -38,11 +42,65 @@ pub(crate) fn load_triple() -> (Arc<BtfContainer>, Vec<u8>, EunomiaObjectMeta) {
     )
 }
 
-pub(crate) type RRC<T> = Rc<RefCell<T>>;
+/// An event received by `CollectingHandler`
+pub(crate) struct ReceivedEvent {
+    /// The data, formatted with `Display`
+    pub(crate) text: String,
+    /// The data, from `trivally_to_plain_bytes`
+    pub(crate) bytes: Vec<u8>,
+    /// Map name and cpu of the meta, if the event was delivered with its meta
+    pub(crate) meta: Option<(String, Option<i32>)>,
+}
+
+/// A handler which collects every event it receives
+#[derive(Default)]
+pub(crate) struct CollectingHandler {
+    events: Mutex<Vec<ReceivedEvent>>,
+}
+
+impl CollectingHandler {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::default()
+    }
+    /// The received events
+    pub(crate) fn events(&self) -> MutexGuard<'_, Vec<ReceivedEvent>> {
+        self.events.lock().unwrap()
+    }
+    /// Texts of the received events
+    pub(crate) fn lines(&self) -> Vec<String> {
+        self.events().iter().map(|v| v.text.clone()).collect()
+    }
+    pub(crate) fn clear(&self) {
+        self.events().clear();
+    }
+    fn push(&self, data: ReceivedEventData, meta: Option<&EventMeta>) {
+        self.events().push(ReceivedEvent {
+            text: data.to_string(),
+            bytes: data.trivally_to_plain_bytes().to_vec(),
+            meta: meta.map(|v| (v.map_name.to_string(), v.cpu)),
+        });
+    }
+}
+
+impl EventHandler for CollectingHandler {
+    fn handle_event(&self, _context: Option<Arc<dyn Any>>, data: ReceivedEventData) {
+        self.push(data, None);
+    }
+    fn handle_event_with_meta(
+        &self,
+        _context: Option<Arc<dyn Any>>,
+        data: ReceivedEventData,
+        meta: &EventMeta,
+    ) {
+        self.push(data, Some(meta));
+    }
+}
 
 mod buffer_value_tests;
 #[cfg(not(feature = "no-load-bpf-tests"))]
 mod map_sampling_tests;
+#[cfg(feature = "parquet")]
+mod parquet_sink_tests;
 
 #[test]
 fn test_user_defined_state() {
@@ -77,8 +135,17 @@ fn test_user_defined_state() {
         ExporterInternalImplementation::BufferValueProcessor {
             event_processor, ..
         } => {
-            event_processor.handle_event(&bin_data).unwrap();
+            event_processor
+                .handle_event(&EventMeta::default(), &bin_data)
+                .unwrap();
         }
         _ => panic!("Unexpected internal implementation"),
     };
 }
+
+#[test]
+fn test_max_time_width() {
+    assert_eq!(max_time_width("%H:%M:%S"), 8);
+    assert_eq!(max_time_width("%A"), "Wednesday".len());
+    assert_eq!(max_time_width("%B %-d"), "September 28".len());
+}
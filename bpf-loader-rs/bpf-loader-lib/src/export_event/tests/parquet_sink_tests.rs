@@ -0,0 +1,113 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::{fs::File, sync::Arc};
+
+use arrow_array::{cast::AsArray, types::Int64Type};
+use arrow_schema::DataType;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+use crate::{
+    export_event::{
+        arrow_sink::{arrow_schema_for_single_value, ParquetSink},
+        tests::load_triple,
+        EventExporterBuilder, EventMeta, ExportFormatType, ExporterInternalImplementation,
+    },
+    meta::BufferValueInterpreter,
+};
+
+#[test]
+fn test_arrow_schema() {
+    let (btf, _, skel) = load_triple();
+    let schema = arrow_schema_for_single_value(btf.borrow_btf(), &skel.export_types[0]).unwrap();
+    let type_of = |name: &str| schema.field_with_name(name).unwrap().data_type().clone();
+    assert_eq!(type_of("str"), DataType::Utf8);
+    assert_eq!(type_of("u8v"), DataType::UInt8);
+    assert_eq!(type_of("i64v"), DataType::Int64);
+    assert_eq!(type_of("dbl"), DataType::Float64);
+    assert_eq!(type_of("e"), DataType::Utf8);
+    assert_eq!(
+        type_of("str_arr"),
+        DataType::new_list(DataType::Utf8, false)
+    );
+    assert_eq!(
+        type_of("arr1"),
+        DataType::new_list(
+            DataType::new_list(DataType::new_list(DataType::Int32, false), false),
+            false
+        )
+    );
+}
+
+#[test]
+fn test_parquet_sink() {
+    let (btf, bin_data, skel) = load_triple();
+    let dir = std::env::temp_dir().join(format!("eunomia-parquet-test-{}", std::process::id()));
+    let schema = arrow_schema_for_single_value(btf.borrow_btf(), &skel.export_types[0]).unwrap();
+    let sink = Arc::new(
+        ParquetSink::builder(schema, &dir)
+            .batch_rows(2)
+            .max_file_size(1)
+            .with_event_meta(true)
+            .build()
+            .unwrap(),
+    );
+    let exporter = EventExporterBuilder::new()
+        .set_export_format(ExportFormatType::Json)
+        .set_export_event_handler(sink.clone())
+        .build_for_single_value(
+            &skel.export_types[0],
+            btf,
+            &BufferValueInterpreter::DefaultStruct,
+        )
+        .unwrap();
+    let ExporterInternalImplementation::BufferValueProcessor {
+        event_processor, ..
+    } = &exporter.internal_impl
+    else {
+        panic!("Unexpected internal implementation");
+    };
+    for sequence in 0..3 {
+        let meta = EventMeta {
+            map_name: "rb",
+            sequence,
+            ..Default::default()
+        };
+        event_processor.handle_event(&meta, &bin_data).unwrap();
+    }
+    // The first two events are written to the first file, which is then closed due to the size limit
+    let files = sink.finish().unwrap();
+    assert_eq!(files.len(), 2);
+
+    let mut rows = 0;
+    for file in files.iter() {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(file).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        for batch in reader {
+            let batch = batch.unwrap();
+            let i64v = batch
+                .column_by_name("i64v")
+                .unwrap()
+                .as_primitive::<Int64Type>();
+            assert_eq!(i64v.value(0), -1311768467463790320);
+            let strs = batch.column_by_name("str").unwrap().as_string::<i32>();
+            assert_eq!(strs.value(0), "A-String");
+            let meta = batch.column_by_name("_meta").unwrap().as_struct();
+            assert_eq!(
+                meta.column_by_name("map")
+                    .unwrap()
+                    .as_string::<i32>()
+                    .value(0),
+                "rb"
+            );
+            rows += batch.num_rows();
+        }
+    }
+    assert_eq!(rows, 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -16,6 +16,8 @@ pub struct CheckedExportedMember {
     pub(crate) bit_offset: u32,
     pub(crate) size: usize,
     pub(crate) output_header_offset: usize,
+    /// Width of the column in table mode. Zero if not rendering as a table
+    pub(crate) output_width: usize,
 }
 /// Describe the source to obtain `Vec<CheckedExportedStructMember>` of a certain map
 pub enum TypeDescriptor {
@@ -28,6 +30,17 @@ pub enum TypeDescriptor {
 }
 
 impl TypeDescriptor {
+    /// Name of the described type, if it's available
+    pub(crate) fn type_name(&self, btf: &Btf) -> Option<String> {
+        match self {
+            Self::BtfType { type_id } => btf
+                .types()
+                .get(*type_id as usize)
+                .map(|ty| ty.name().to_string())
+                .filter(|name| !name.is_empty()),
+            Self::ManuallyOverride(_) | Self::CheckedMembers(_) => None,
+        }
+    }
     pub(crate) fn build_checked_exported_members(
         self,
         btf: &Btf,
@@ -55,6 +68,7 @@ impl TypeDescriptor {
                         bit_offset: (mem.offset * 8) as u32,
                         size: btf.get_size_of(mem.btf_type_id) as usize,
                         output_header_offset: 0,
+                        output_width: 0,
                     });
                 }
                 result
@@ -74,6 +88,7 @@ impl TypeDescriptor {
                             bit_offset: member.bit_offset,
                             field_name: member.name.to_string(),
                             output_header_offset: 0,
+                            output_width: 0,
                             size: btf.get_size_of(member.type_id) as usize,
                             type_id: member.type_id,
                         });
@@ -88,6 +103,7 @@ impl TypeDescriptor {
                         type_id,
                         field_name: "".to_string(),
                         output_header_offset: 0,
+                        output_width: 0,
                         size: btf.get_size_of(type_id) as usize,
                     }]
                 } else {
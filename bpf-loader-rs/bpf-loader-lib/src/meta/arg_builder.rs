@@ -8,12 +8,46 @@ use anyhow::{bail, Result};
 use clap::{Arg, ArgAction, Command};
 use serde_json::Value;
 
-use super::EunomiaObjectMeta;
+use super::{
+    section::{is_cgroup_section, parse_uprobe_section, UprobeKind},
+    EunomiaObjectMeta, ProgMeta,
+};
 
 const DEFAULT_DESCRIPTION: &str = "A simple eBPF program";
 const DEFAULT_VERSION: &str = "0.1.0";
 const DEFAULT_EPILOG: &str = "Built with eunomia-bpf framework.\nSee https://github.com/eunomia-bpf/eunomia-bpf for more information.";
 
+/// Fields of `UprobeProgExtraMeta` which could be overridden from the command line
+pub(crate) const UPROBE_ARG_FIELDS: [&str; 4] = ["binary", "symbol", "usdt", "pid"];
+
+/// Id of the argument which overrides the interfaces of tc and xdp programs
+pub(crate) const IFACE_ARG_ID: &str = "__iface";
+
+/// Whether the program is attached to network interfaces
+pub(crate) fn is_iface_prog(prog: &ProgMeta) -> bool {
+    prog.attach == "tc" || prog.attach == "xdp"
+}
+
+/// Id of the argument which sets the network namespace of tc and xdp programs
+pub(crate) const NETNS_ARG_ID: &str = "__netns";
+
+/// Id of the argument which disables the loading of programs
+pub(crate) const DISABLE_PROG_ARG_ID: &str = "__disable_prog";
+
+/// Id of the argument which only loads the given programs
+pub(crate) const ONLY_PROG_ARG_ID: &str = "__only_prog";
+
+/// Id of the argument which overrides the cgroup of cgroup programs
+pub(crate) const CGROUP_ARG_ID: &str = "__cgroup";
+
+/// Id of the argument which sets the process whose cgroup the cgroup programs are attached to
+pub(crate) const CGROUP_PID_ARG_ID: &str = "__cgroup_pid";
+
+/// Id of the argument which overrides a field of the uprobe extra meta
+pub(crate) fn uprobe_arg_id(prog: &str, field: &str) -> String {
+    format!("__uprobe_{prog}_{field}")
+}
+
 impl EunomiaObjectMeta {
     /// Build an argument parser use the `cmdarg` sections in .rodata/.bss variables.
     ///
@@ -29,6 +63,12 @@ impl EunomiaObjectMeta {
     /// The first will be used to set the value of the variable to `true`, second one will be used to set `false`
     ///
     /// Variables with other types will accept values. But values will be checked in `parse_arguments_and_fill_skeleton_variables`, so here the values input in the command line parser will be regarded as strings.
+    ///
+    /// If there are tc or xdp programs, `--iface` will be added, which overrides the interfaces they are attached to, along with `--netns` to attach them in another network namespace. Likewise, `--cgroup` and `--cgroup-pid` will be added for programs attached to cgroups.
+    ///
+    /// `--disable-prog` and `--only-prog` will be added to select the programs to load, by names.
+    ///
+    /// For each uprobe or USDT program, `--<PROG>-binary` and `--<PROG>-pid` will be added, along with `--<PROG>-symbol` for uprobes or `--<PROG>-usdt` for USDT programs. They override the target in the program meta.
     pub fn build_argument_parser(&self) -> Result<Command> {
         let cmd = Command::new(self.bpf_skel.obj_name.to_string());
 
@@ -161,6 +201,103 @@ impl EunomiaObjectMeta {
                 }
             }
         }
+        // Add arguments for selecting programs, unless variables took the names
+        if !cmd
+            .get_arguments()
+            .any(|v| matches!(v.get_long(), Some("disable-prog" | "only-prog")))
+        {
+            cmd = cmd
+                .arg(
+                    Arg::new(DISABLE_PROG_ARG_ID)
+                        .action(ArgAction::Append)
+                        .long("disable-prog")
+                        .value_delimiter(',')
+                        .conflicts_with(ONLY_PROG_ARG_ID)
+                        .help("Programs not to load or attach, by names"),
+                )
+                .arg(
+                    Arg::new(ONLY_PROG_ARG_ID)
+                        .action(ArgAction::Append)
+                        .long("only-prog")
+                        .value_delimiter(',')
+                        .help("Only load and attach these programs, by names"),
+                );
+        }
+        // Add the argument for interfaces, unless a variable took the name
+        if self.bpf_skel.progs.iter().any(is_iface_prog)
+            && !cmd.get_arguments().any(|v| v.get_long() == Some("iface"))
+        {
+            cmd = cmd.arg(
+                Arg::new(IFACE_ARG_ID)
+                    .action(ArgAction::Append)
+                    .long("iface")
+                    .value_delimiter(',')
+                    .help("Interfaces to attach tc and xdp programs to, by names or indexes. `all` means all interfaces except the loopback"),
+            );
+        }
+        if self.bpf_skel.progs.iter().any(is_iface_prog)
+            && !cmd.get_arguments().any(|v| v.get_long() == Some("netns"))
+        {
+            cmd = cmd.arg(
+                Arg::new(NETNS_ARG_ID)
+                    .action(ArgAction::Set)
+                    .long("netns")
+                    .help("Network namespace to attach tc and xdp programs in, by a pid or a path such as /var/run/netns/<NAME>"),
+            );
+        }
+        // Add the argument for the cgroup
+        if self
+            .bpf_skel
+            .progs
+            .iter()
+            .any(|v| is_cgroup_section(&v.attach))
+            && !cmd.get_arguments().any(|v| v.get_long() == Some("cgroup"))
+        {
+            cmd = cmd.arg(
+                Arg::new(CGROUP_ARG_ID)
+                    .action(ArgAction::Set)
+                    .long("cgroup")
+                    .help("Path to the cgroup to attach cgroup programs to, such as /sys/fs/cgroup for the root cgroup"),
+            );
+        }
+        if self
+            .bpf_skel
+            .progs
+            .iter()
+            .any(|v| is_cgroup_section(&v.attach))
+            && !cmd
+                .get_arguments()
+                .any(|v| v.get_long() == Some("cgroup-pid"))
+        {
+            cmd = cmd.arg(
+                Arg::new(CGROUP_PID_ARG_ID)
+                    .action(ArgAction::Set)
+                    .long("cgroup-pid")
+                    .help("Attach cgroup programs to the cgroup of this process. `--cgroup` is regarded as the mount point of cgroup v2 if provided"),
+            );
+        }
+        // Add arguments for uprobe targets
+        for prog in self.bpf_skel.progs.iter() {
+            let Some((kind, _)) = parse_uprobe_section(&prog.attach) else {
+                continue;
+            };
+            let target = match kind {
+                UprobeKind::Uprobe { .. } => ("symbol", "Function to probe"),
+                UprobeKind::Usdt => ("usdt", "USDT probe to attach, in `provider:name`"),
+            };
+            for (field, help) in [
+                ("binary", "Path to the binary or library to probe"),
+                target,
+                ("pid", "Only trace this process"),
+            ] {
+                cmd = cmd.arg(
+                    Arg::new(uprobe_arg_id(&prog.name, field))
+                        .action(ArgAction::Set)
+                        .long(format!("{}-{}", prog.name, field))
+                        .help(format!("{} for program `{}`", help, prog.name)),
+                );
+            }
+        }
         Ok(cmd)
     }
 }
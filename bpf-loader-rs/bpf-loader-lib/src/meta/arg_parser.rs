@@ -8,7 +8,14 @@ use anyhow::{anyhow, bail, Context, Result};
 use clap::ArgMatches;
 use serde_json::{json, Value};
 
-use super::EunomiaObjectMeta;
+use super::{
+    arg_builder::{
+        is_iface_prog, uprobe_arg_id, CGROUP_ARG_ID, CGROUP_PID_ARG_ID, DISABLE_PROG_ARG_ID,
+        IFACE_ARG_ID, NETNS_ARG_ID, ONLY_PROG_ARG_ID, UPROBE_ARG_FIELDS,
+    },
+    section::is_cgroup_section,
+    EunomiaObjectMeta,
+};
 
 /// What to do if we met a variable which neither has the default value or has been supplied from command argument
 pub enum UnpresentVariableAction {
@@ -28,6 +35,10 @@ impl EunomiaObjectMeta {
     /// If the `on_unpresent` behavior is `FillWithZero`, in this way if we find a command line argument with no values provided (this situation may only happen if the command line argument was created with no default values, a.k.a the `value` field is `None` in `DataSectionVariableMeta`), the `value` field will still be leaved `None`. In this way, the section_loader will fill zeros in the corresponding memory areas.
     ///
     /// If the `on_unpresent` behavior is `ReportError`, in this way if we find a command line argument with no values, we'll report an error.
+    ///
+    /// Programs selected by `--disable-prog` or `--only-prog` will have `autoload` set accordingly.
+    ///
+    /// Interfaces, network namespaces, cgroups and uprobe targets provided from the command line will be written into the `others` field of the corresponding `ProgMeta`.
     pub fn parse_arguments_and_fill_skeleton_variables(
         &mut self,
         args: &ArgMatches,
@@ -65,9 +76,108 @@ impl EunomiaObjectMeta {
                 }
             }
         }
+        let ifaces = args
+            .try_get_many::<String>(IFACE_ARG_ID)
+            .ok()
+            .flatten()
+            .map(|v| v.cloned().collect::<Vec<_>>());
+        self.select_programs(args)?;
+        let netns = args.try_get_one::<String>(NETNS_ARG_ID).ok().flatten();
+        let cgroup = args.try_get_one::<String>(CGROUP_ARG_ID).ok().flatten();
+        let cgroup_pid = match args.try_get_one::<String>(CGROUP_PID_ARG_ID).ok().flatten() {
+            Some(pid) => Some(
+                pid.parse::<i32>()
+                    .with_context(|| anyhow!("Invalid pid `{}` for cgroup programs", pid))?,
+            ),
+            None => None,
+        };
+        for prog in self.bpf_skel.progs.iter_mut() {
+            if is_cgroup_section(&prog.attach) {
+                if !prog.others.is_object() {
+                    prog.others = json!({});
+                }
+                if let Some(cgroup) = cgroup {
+                    prog.others["cgroup"] = json!(cgroup);
+                }
+                if let Some(pid) = cgroup_pid {
+                    prog.others["pid"] = json!(pid);
+                }
+            }
+            if let Some(netns) = netns.filter(|_| is_iface_prog(prog)) {
+                if !prog.others.is_object() {
+                    prog.others = json!({});
+                }
+                prog.others["netns"] = json!(netns);
+            }
+            if let Some(ifaces) = ifaces.as_ref().filter(|_| is_iface_prog(prog)) {
+                if !prog.others.is_object() {
+                    prog.others = json!({});
+                }
+                let target = if prog.attach == "tc" {
+                    let tchook = &mut prog.others["tchook"];
+                    if !tchook.is_object() {
+                        *tchook = json!({});
+                    }
+                    tchook
+                } else {
+                    &mut prog.others
+                };
+                target["iface"] = json!(ifaces);
+            }
+            for field in UPROBE_ARG_FIELDS {
+                // Arguments only exist for uprobe programs
+                let Ok(Some(user_value)) =
+                    args.try_get_one::<String>(&uprobe_arg_id(&prog.name, field))
+                else {
+                    continue;
+                };
+                let value = if field == "pid" {
+                    json!(user_value.parse::<i32>().with_context(|| anyhow!(
+                        "Invalid pid `{}` for program `{}`",
+                        user_value,
+                        prog.name
+                    ))?)
+                } else {
+                    json!(user_value)
+                };
+                if !prog.others.is_object() {
+                    prog.others = json!({});
+                }
+                prog.others[field] = value;
+            }
+        }
         self.debug_verbose = args.get_flag("verbose");
         Ok(())
     }
+
+    fn select_programs(&mut self, args: &ArgMatches) -> Result<()> {
+        let get_names = |id| {
+            args.try_get_many::<String>(id)
+                .ok()
+                .flatten()
+                .map(|v| v.cloned().collect::<Vec<_>>())
+        };
+        let (names, selected) = match (get_names(DISABLE_PROG_ARG_ID), get_names(ONLY_PROG_ARG_ID))
+        {
+            (Some(names), _) => (names, false),
+            (None, Some(names)) => (names, true),
+            (None, None) => return Ok(()),
+        };
+        for name in names.iter() {
+            if !self.bpf_skel.progs.iter().any(|v| &v.name == name) {
+                bail!("Program named `{}` not found", name);
+            }
+        }
+        for prog in self.bpf_skel.progs.iter_mut() {
+            if selected {
+                prog.autoload = names.contains(&prog.name);
+            } else if names.contains(&prog.name) {
+                // Programs not mentioned keep the defaults in the meta
+                prog.autoload = false;
+            }
+        }
+        Ok(())
+    }
 }
 
 macro_rules! parse_value_decl {
@@ -118,20 +228,36 @@ mod tests {
     use serde_json::json;
 
     use crate::{
-        meta::{arg_parser::UnpresentVariableAction, EunomiaObjectMeta},
-        tests::get_assets_dir,
+        meta::{
+            arg_parser::UnpresentVariableAction, CgroupProgExtraMeta, EunomiaObjectMeta, ProgMeta,
+            TCProgExtraMeta, XDPProgExtraMeta,
+        },
+        tests::{get_assets_dir, prog},
     };
 
-    #[test]
-    fn test_arg_parser() {
-        let mut skel = serde_json::from_str::<EunomiaObjectMeta>(
+    fn load_test_skel() -> EunomiaObjectMeta {
+        serde_json::from_str::<EunomiaObjectMeta>(
             &std::fs::read_to_string(get_assets_dir().join("arg_builder_test").join("skel.json"))
                 .unwrap(),
         )
-        .unwrap();
-        let cmd = skel.build_argument_parser().unwrap();
-        let matches = cmd
-            .try_get_matches_from([
+        .unwrap()
+    }
+
+    /// Build the argument parser of the skeleton, and fill the skeleton with the parsed arguments
+    fn parse(skel: &mut EunomiaObjectMeta, args: &[&str]) -> anyhow::Result<()> {
+        let matches = skel.build_argument_parser()?.try_get_matches_from(args)?;
+        skel.parse_arguments_and_fill_skeleton_variables(
+            &matches,
+            UnpresentVariableAction::FillWithZero,
+        )
+    }
+
+    #[test]
+    fn test_arg_parser() {
+        let mut skel = load_test_skel();
+        parse(
+            &mut skel,
+            &[
                 "myprog",
                 "-1",
                 "1234",
@@ -142,11 +268,7 @@ mod tests {
                 "--boolflag",
                 "--bss_val_1",
                 "7890",
-            ])
-            .unwrap();
-        skel.parse_arguments_and_fill_skeleton_variables(
-            &matches,
-            UnpresentVariableAction::FillWithZero,
+            ],
         )
         .unwrap();
         println!("{:#?}", skel.bpf_skel.data_sections);
@@ -160,56 +282,159 @@ mod tests {
     #[test]
     #[should_panic = "Failed to parse `abcdefg` into i32"]
     fn test_arg_parser_with_invalid_value_1() {
-        let mut skel = serde_json::from_str::<EunomiaObjectMeta>(
-            &std::fs::read_to_string(get_assets_dir().join("arg_builder_test").join("skel.json"))
-                .unwrap(),
-        )
-        .unwrap();
-        let cmd = skel.build_argument_parser().unwrap();
-        let matches = cmd
-            .try_get_matches_from(["myprog", "-1", "abcdefg"])
-            .unwrap();
-        skel.parse_arguments_and_fill_skeleton_variables(
-            &matches,
-            UnpresentVariableAction::FillWithZero,
-        )
-        .unwrap();
+        parse(&mut load_test_skel(), &["myprog", "-1", "abcdefg"]).unwrap();
     }
     #[test]
     #[should_panic = "Failed to parse `111111111111111111` into i32"]
     fn test_arg_parser_with_invalid_value_2() {
-        let mut skel = serde_json::from_str::<EunomiaObjectMeta>(
-            &std::fs::read_to_string(get_assets_dir().join("arg_builder_test").join("skel.json"))
-                .unwrap(),
+        parse(
+            &mut load_test_skel(),
+            &["myprog", "-1", "111111111111111111"],
         )
         .unwrap();
-        let cmd = skel.build_argument_parser().unwrap();
-        let matches = cmd
-            .try_get_matches_from(["myprog", "-1", "111111111111111111"])
-            .unwrap();
-        skel.parse_arguments_and_fill_skeleton_variables(
-            &matches,
-            UnpresentVariableAction::FillWithZero,
+    }
+    #[test]
+    fn test_arg_parser_with_true_boolflag() {
+        let mut skel = load_test_skel();
+        parse(&mut skel, &["myprog"]).unwrap();
+        assert_eq!(
+            skel.bpf_skel.data_sections[0].variables[4].value,
+            Some(json!(true))
+        );
+    }
+    #[test]
+    fn test_arg_parser_with_uprobe_target() {
+        let mut skel = load_test_skel();
+        skel.bpf_skel.progs.push(prog(
+            "readline",
+            "uretprobe//bin/bash:readline",
+            json!({"cookie": 1}),
+        ));
+        parse(
+            &mut skel,
+            &[
+                "myprog",
+                "--readline-binary",
+                "/usr/bin/bash",
+                "--readline-pid",
+                "42",
+            ],
         )
         .unwrap();
+        assert_eq!(
+            skel.bpf_skel.progs[1].others,
+            json!({"cookie": 1, "binary": "/usr/bin/bash", "pid": 42})
+        );
+        // Programs other than uprobes have no such arguments
+        assert_eq!(skel.bpf_skel.progs[0].others, json!({}));
+        assert!(parse(&mut skel, &["myprog", "--readline-usdt", "a:b"]).is_err());
     }
     #[test]
-    fn test_arg_parser_with_true_boolflag() {
-        let mut skel = serde_json::from_str::<EunomiaObjectMeta>(
-            &std::fs::read_to_string(get_assets_dir().join("arg_builder_test").join("skel.json"))
-                .unwrap(),
+    fn test_arg_parser_with_iface() {
+        let mut skel = load_test_skel();
+        // No tc or xdp programs
+        assert!(parse(&mut skel.clone(), &["myprog", "--iface", "eth0"]).is_err());
+        skel.bpf_skel.progs.push(ProgMeta {
+            link: false,
+            ..prog("tc_ingress", "tc", json!({"tchook": {"ifindex": 2}}))
+        });
+        skel.bpf_skel.progs.push(ProgMeta {
+            link: false,
+            ..prog("xdp_pass", "xdp", json!(null))
+        });
+        parse(
+            &mut skel,
+            &["myprog", "--iface", "eth0,eth1", "--iface", "3"],
         )
         .unwrap();
-        let cmd = skel.build_argument_parser().unwrap();
-        let matches = cmd.try_get_matches_from(["myprog"]).unwrap();
-        skel.parse_arguments_and_fill_skeleton_variables(
-            &matches,
-            UnpresentVariableAction::FillWithZero,
+        assert_eq!(
+            skel.bpf_skel.progs[1].others,
+            json!({"tchook": {"ifindex": 2, "iface": ["eth0", "eth1", "3"]}})
+        );
+        assert_eq!(
+            skel.bpf_skel.progs[2].others,
+            json!({"iface": ["eth0", "eth1", "3"]})
+        );
+        let meta: XDPProgExtraMeta =
+            serde_json::from_value(skel.bpf_skel.progs[2].others.clone()).unwrap();
+        assert_eq!(meta.iface, vec!["eth0", "eth1", "3"]);
+        let meta: XDPProgExtraMeta = serde_json::from_value(json!({"iface": "all"})).unwrap();
+        assert_eq!(meta.iface, vec!["all"]);
+    }
+    #[test]
+    fn test_arg_parser_with_cgroup() {
+        let mut skel = load_test_skel();
+        skel.bpf_skel
+            .progs
+            .push(prog("block_connect", "cgroup/connect4", json!({})));
+        parse(
+            &mut skel,
+            &["myprog", "--cgroup", "/sys/fs/cgroup/app.slice"],
+        )
+        .unwrap();
+        let meta: CgroupProgExtraMeta =
+            serde_json::from_value(skel.bpf_skel.progs[1].others.clone()).unwrap();
+        assert_eq!(meta.cgroup.as_deref(), Some("/sys/fs/cgroup/app.slice"));
+        assert_eq!(skel.bpf_skel.progs[0].others, json!({}));
+        assert_eq!(CgroupProgExtraMeta::default().cgroup, None);
+    }
+
+    #[test]
+    fn test_arg_parser_with_target_namespace() {
+        let mut skel = load_test_skel();
+        skel.bpf_skel.progs.push(ProgMeta {
+            link: false,
+            ..prog("tc_ingress", "tc", json!({}))
+        });
+        skel.bpf_skel
+            .progs
+            .push(prog("block_connect", "cgroup/connect4", json!({})));
+        assert!(parse(&mut skel.clone(), &["myprog", "--cgroup-pid", "abc"]).is_err());
+        parse(
+            &mut skel,
+            &["myprog", "--netns", "1234", "--cgroup-pid", "1234"],
         )
         .unwrap();
+        let meta: TCProgExtraMeta =
+            serde_json::from_value(skel.bpf_skel.progs[1].others.clone()).unwrap();
+        assert_eq!(meta.netns.as_deref(), Some("1234"));
+        let meta: CgroupProgExtraMeta =
+            serde_json::from_value(skel.bpf_skel.progs[2].others.clone()).unwrap();
+        assert_eq!(meta.pid, Some(1234));
+        assert_eq!(meta.cgroup, None);
+        assert_eq!(skel.bpf_skel.progs[0].others, json!({}));
+    }
+
+    #[test]
+    fn test_arg_parser_with_program_selection() {
+        let mut skel = load_test_skel();
+        for (name, autoload) in [("fentry_open", true), ("kprobe_open", false)] {
+            skel.bpf_skel.progs.push(ProgMeta {
+                autoload,
+                ..prog(name, "kprobe/do_sys_openat2", json!({}))
+            });
+        }
+        let autoload = |args: &[&str]| {
+            let mut skel = skel.clone();
+            parse(&mut skel, args)?;
+            anyhow::Ok(
+                skel.bpf_skel
+                    .progs
+                    .iter()
+                    .map(|v| v.autoload)
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(autoload(&["myprog"]).unwrap(), vec![true, true, false]);
         assert_eq!(
-            skel.bpf_skel.data_sections[0].variables[4].value,
-            Some(json!(true))
+            autoload(&["myprog", "--disable-prog", "fentry_open"]).unwrap(),
+            vec![true, false, false]
+        );
+        assert_eq!(
+            autoload(&["myprog", "--only-prog", "kprobe_open"]).unwrap(),
+            vec![false, false, true]
         );
+        assert!(autoload(&["myprog", "--only-prog", "no_such_prog"]).is_err());
+        assert!(autoload(&["myprog", "--only-prog", "a", "--disable-prog", "b"]).is_err());
     }
 }
@@ -64,12 +64,15 @@
 //!
 //! Describes an eBPF program
 
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
 use base64::Engine;
 use deflate::deflate_bytes_zlib;
 use libbpf_rs::libbpf_sys::{BPF_TC_CUSTOM, BPF_TC_EGRESS, BPF_TC_INGRESS};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use serde_with::DefaultOnNull;
+use serde_with::{DefaultOnNull, OneOrMany};
 /// Describe a struct member in an exported type
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct ExportedTypesStructMemberMeta {
@@ -107,6 +110,21 @@ pub enum SampleMapType {
     DefaultKV,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// What to do if an event received from an export map could not be decoded or handled
+pub enum ErrorPolicy {
+    #[serde(rename = "abort")]
+    #[default]
+    /// stop polling and return the error
+    Abort,
+    #[serde(rename = "skip")]
+    /// drop the event and keep polling. The failure is still counted in the statistics
+    Skip,
+    #[serde(rename = "emit_raw")]
+    /// emit the undecodable bytes in hex, along with the error message, then keep polling
+    EmitRaw,
+}
+
 /// Extra info for a map which will be used for sampling
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct MapSampleMeta {
@@ -123,6 +141,140 @@ pub struct MapSampleMeta {
     pub clear_map: bool,
 }
 
+/// Customize how events of a map are printed in plain text
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlainTextFormatMeta {
+    /// Template of each line, such as `{time} {comm}[{pid}] opened {filename} -> {ret:errno}`
+    ///
+    /// A placeholder looks like `{field}` or `{field:spec}`, where spec is a list of
+    /// `<8`, `>8`, `^8` (alignment and width) or `errno`, `hex` (conversion), separated by `:`.
+    /// `{time}` is the receiving time if no field is named `time`. Use `{{` and `}}` for braces.
+    /// For sample maps, fields are accessed through `key.xxx` or `value.xxx`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// Use this line as the header, instead of the generated one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    /// Whether to print the header
+    #[serde(default = "default_helpers::default_bool::<true>")]
+    pub print_header: bool,
+    /// Print the header again every such lines. 0 means only print it once
+    #[serde(default = "default_helpers::default_usize::<0>")]
+    pub header_interval: usize,
+    /// Render fields as a table, whose column widths are taken from the BTF types (such as 16 for `char[16]`, 10 for `u32`).
+    /// Values longer than the column will be truncated with an ellipsis. Doesn't apply if a template is provided
+    #[serde(default = "default_helpers::default_bool::<false>")]
+    pub table: bool,
+    /// Max width of a column in table mode. 0 means no limit
+    #[serde(default = "default_helpers::default_usize::<32>")]
+    pub max_column_width: usize,
+    /// Format of the time column, in strftime syntax. The column is padded to the longest time it could render, such as `Wednesday` for `%A`
+    #[serde(default = "default_helpers::time_format_default")]
+    pub time_format: String,
+}
+
+impl Default for PlainTextFormatMeta {
+    fn default() -> Self {
+        Self {
+            template: None,
+            header: None,
+            print_header: true,
+            header_interval: 0,
+            table: false,
+            max_column_width: 32,
+            time_format: default_helpers::time_format_default(),
+        }
+    }
+}
+
+/// Aggregate events of a map in tumbling windows
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AggregationMeta {
+    /// Fields to group events by, such as `comm` or `key.pid`. All events are in one group if empty
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    /// Aggregate functions over each group: `count`, `sum(field)`, `min(field)`, `max(field)` or `hist(field)`, which is a log2 histogram
+    #[serde(default = "default_helpers::aggregates_default")]
+    pub aggregates: Vec<String>,
+    /// Length of the window, in milliseconds
+    #[serde(default = "default_helpers::default_usize::<1000>")]
+    pub window_ms: usize,
+    /// Only export the first such groups, ordered by the first aggregate descending. 0 means all groups
+    #[serde(default = "default_helpers::default_usize::<0>")]
+    pub top: usize,
+}
+
+impl Default for AggregationMeta {
+    fn default() -> Self {
+        Self {
+            group_by: vec![],
+            aggregates: default_helpers::aggregates_default(),
+            window_ms: 1000,
+            top: 0,
+        }
+    }
+}
+
+/// Resolve ids in events of a map to friendly names, which are exported as extra fields
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct EnrichMeta {
+    /// Field holding a pid. Adds `process`, `cmdline` and `exe`
+    #[serde(default)]
+    pub pid: Option<String>,
+    /// Field holding a uid. Adds `user`
+    #[serde(default)]
+    pub uid: Option<String>,
+    /// Field holding a gid. Adds `group`
+    #[serde(default)]
+    pub gid: Option<String>,
+    /// Field holding a cgroup id or a cgroup path. Adds `container_id`
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    /// Field holding a bpf cookie. Adds `symbol`, the function whose cookie it is in the `cookies` of multi-probe programs
+    #[serde(default)]
+    pub cookie: Option<String>,
+    /// How long a resolved name is cached, in milliseconds
+    #[serde(default = "default_helpers::default_usize::<5000>")]
+    pub ttl_ms: usize,
+}
+
+impl Default for EnrichMeta {
+    fn default() -> Self {
+        Self {
+            pid: None,
+            uid: None,
+            gid: None,
+            cgroup: None,
+            cookie: None,
+            ttl_ms: 5000,
+        }
+    }
+}
+
+/// Pair up enter and exit events of a map, and export one merged event with the duration between them
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CorrelateMeta {
+    /// Field telling enter and exit events apart, such as `exit_event`
+    pub discriminator: String,
+    /// Value of the discriminator on enter events
+    pub enter: Value,
+    /// Value of the discriminator on exit events
+    pub exit: Value,
+    /// Fields to pair events by, such as `pid` and `tid`
+    pub keys: Vec<String>,
+    /// Field holding the kernel timestamp of events in nanoseconds, such as one from `bpf_ktime_get_ns()`
+    pub time_field: String,
+    /// Name of the field holding the duration in the merged event
+    #[serde(default = "default_helpers::duration_field_default")]
+    pub duration_field: String,
+    /// Drop enter events which waited longer than this, in milliseconds
+    #[serde(default = "default_helpers::default_usize::<10000>")]
+    pub timeout_ms: usize,
+    /// Max count of enter events waiting for their exit events
+    #[serde(default = "default_helpers::default_usize::<10240>")]
+    pub max_entries: usize,
+}
+
 /// Describe a member of an overriding struct
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct OverridedStructMember {
@@ -216,6 +368,18 @@ pub struct MapMeta {
     /// How to intepreter the buffer value of this map. Only applies if this map if a buffer value map (perf event or ringbuf)
     #[serde(default)]
     pub intepreter: BufferValueInterpreter,
+    /// How to print the events of this map in plain text. If not provided, all fields will be printed below a header of field names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plain_text: Option<PlainTextFormatMeta>,
+    /// If set, events of this map will be aggregated in windows, and one table will be exported for each window instead of each event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregation: Option<AggregationMeta>,
+    /// If set, process, user and container info will be added to events of this map
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enrich: Option<EnrichMeta>,
+    /// If set, enter and exit events of this map will be paired up. See `export_event::correlate` for details
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlate: Option<CorrelateMeta>,
 }
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 /// Describe the meta of a bpf program
@@ -226,23 +390,64 @@ pub struct ProgMeta {
     pub attach: String,
     /// Whether the attaching of this program will generate a bpf_link
     pub link: bool,
+    #[serde(default = "default_helpers::default_bool::<true>")]
+    /// Whether to load this program. Programs not loaded are not attached either. Useful to carry alternative programs, such as fentry and kprobe ones for older kernels
+    pub autoload: bool,
+    #[serde(default = "default_helpers::default_bool::<true>")]
+    /// Whether to attach this program after loading. Programs not attached could still be used, such as through tail calls
+    pub autoattach: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Names of programs to use in order if this one is not supported by the kernel, such as a kprobe one for a fentry program. Only the first supported program in the group will be loaded
+    pub fallbacks: Vec<String>,
     #[serde(flatten)]
     /// Other fields
     pub others: Value,
 }
 
+#[serde_with::serde_as]
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 /// Extra fields in prog meta for XDP programs
 pub struct XDPProgExtraMeta {
     #[serde(default = "default_helpers::default_i32::<1>")]
     /// Which interface to hook
     pub ifindex: i32,
+    #[serde_as(as = "OneOrMany<_>")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Interfaces to hook, by names or indexes, such as `eth0` or `["eth0", "eth1"]`. `all` stands for all interfaces except the loopback. Overrides `ifindex` if provided
+    pub iface: Vec<String>,
     #[serde(default = "default_helpers::default_u32::<0>")]
     /// XDP hook flags
     pub flags: u32,
     #[serde(default)]
+    /// How to attach the program. The flags of the mode are added to `flags`
+    pub mode: XDPMode,
+    #[serde(default)]
     /// XDP Hook options
     pub xdpopts: XDPOpts,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Network namespace to attach in, by a pid or a path such as `/var/run/netns/<name>`. Interfaces are resolved in it, so veths inside a container could be hooked
+    pub netns: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// How to attach a XDP program
+pub enum XDPMode {
+    #[serde(rename = "auto")]
+    #[default]
+    /// Let the kernel choose, native mode is preferred
+    Auto,
+    #[serde(rename = "skb")]
+    /// Generic mode, XDP_FLAGS_SKB_MODE
+    Skb,
+    #[serde(rename = "drv")]
+    /// Native mode in the driver, XDP_FLAGS_DRV_MODE
+    Drv,
+    #[serde(rename = "hw")]
+    /// Offloaded to the NIC, XDP_FLAGS_HW_MODE
+    Hw,
+    #[serde(rename = "replace")]
+    /// Replace the program already attached to the interface, if any. Otherwise attaching fails if there is one
+    Replace,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
@@ -254,6 +459,251 @@ pub struct XDPOpts {
     pub old_prog_fd: i32,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+/// Extra fields in prog meta for uprobe, uretprobe and USDT programs
+///
+/// Fields not provided are taken from the section name, such as `uprobe//bin/bash:readline` or `usdt/libc.so.6:libc:setjmp`
+pub struct UprobeProgExtraMeta {
+    #[serde(default)]
+    /// Path to the binary or the shared library. Binaries in a container could be accessed through `/proc/<pid>/root/...`
+    pub binary: Option<String>,
+    #[serde(default)]
+    /// Function to probe, such as `readline` or `malloc@LIBC`. Only for uprobes
+    pub symbol: Option<String>,
+    #[serde(default)]
+    /// Offset in the function if `symbol` is provided, or in the binary otherwise. Only for uprobes
+    pub offset: usize,
+    #[serde(default)]
+    /// The USDT probe, in `provider:name`. Only for USDT programs
+    pub usdt: Option<String>,
+    #[serde(default = "default_helpers::default_i32::<-1>")]
+    /// Only trace this process. -1 means all processes
+    pub pid: i32,
+    #[serde(default)]
+    /// The value returned by `bpf_get_attach_cookie` or `bpf_usdt_cookie`
+    pub cookie: u64,
+}
+
+impl Default for UprobeProgExtraMeta {
+    fn default() -> Self {
+        Self {
+            binary: None,
+            symbol: None,
+            offset: 0,
+            usdt: None,
+            pid: -1,
+            cookie: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+/// Extra fields in prog meta for attaching a program to a group of functions
+///
+/// For `kprobe.multi` and `kretprobe.multi` programs, functions are kernel functions, which are attached through a single link.
+///
+/// For `uprobe`, `uretprobe`, `uprobe.multi` and `uretprobe.multi` programs, functions are in `binary`, and the program is attached to each of them. The binary and the pattern could also be in the section name of `uprobe.multi` programs, such as `uprobe.multi//usr/lib/libc.so.6:malloc*`
+pub struct MultiProbeProgExtraMeta {
+    #[serde(default)]
+    /// Glob pattern of the functions, such as `tcp_*`. Supports `*` and `?`
+    pub pattern: Option<String>,
+    #[serde(default)]
+    /// Names of the functions. Could not be used along with `pattern`
+    pub symbols: Vec<String>,
+    #[serde(default)]
+    /// Cookie of each function in `symbols`, which is returned by `bpf_get_attach_cookie`. Empty means no cookies
+    ///
+    /// Functions of the cookies are only exported if `enrich.cookie` of the map is set to the field holding the cookie
+    pub cookies: Vec<u64>,
+    #[serde(default)]
+    /// Path to the binary or the shared library. Only for uprobes
+    pub binary: Option<String>,
+    #[serde(default = "default_helpers::default_i32::<-1>")]
+    /// Only trace this process. -1 means all processes. Only for uprobes
+    pub pid: i32,
+}
+
+impl Default for MultiProbeProgExtraMeta {
+    fn default() -> Self {
+        Self {
+            pattern: None,
+            symbols: vec![],
+            cookies: vec![],
+            binary: None,
+            pid: -1,
+        }
+    }
+}
+
+impl MultiProbeProgExtraMeta {
+    /// Whether functions to attach were provided
+    pub fn has_target(&self) -> bool {
+        self.pattern.is_some() || !self.symbols.is_empty()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+/// Extra fields in prog meta for programs attached to cgroups, such as `cgroup_skb/ingress`, `cgroup/connect4` and `sockops`
+pub struct CgroupProgExtraMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Path to the cgroup, such as `/sys/fs/cgroup/system.slice/docker-<id>.scope`. Either this or `pid` is required, use `/sys/fs/cgroup` to attach to the root cgroup
+    pub cgroup: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Attach to the cgroup v2 of this process, such as the init process of a container. `cgroup` is regarded as the mount point of cgroup v2 if provided, which defaults to `/sys/fs/cgroup`
+    pub pid: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+/// Extra fields in prog meta for `socket` programs, which are attached to a raw packet socket opened by the loader
+pub struct SocketFilterProgExtraMeta {
+    #[serde(default)]
+    /// Only receive packets from this interface, by name or index. Packets from all interfaces are received if not provided
+    pub iface: Option<String>,
+    #[serde(default = "default_helpers::default_u16::<0x0003>")]
+    /// Ethernet protocol of packets to receive, such as 0x0800 for IPv4. Defaults to all protocols (ETH_P_ALL)
+    pub protocol: u16,
+}
+
+impl Default for SocketFilterProgExtraMeta {
+    fn default() -> Self {
+        Self {
+            iface: None,
+            protocol: 0x0003,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+/// Extra fields in prog meta for `sk_lookup` and `flow_dissector` programs, which are attached to a network namespace
+pub struct NetnsProgExtraMeta {
+    #[serde(default = "default_helpers::netns_default")]
+    /// Network namespace to attach to, by a pid or a path such as `/var/run/netns/<name>`. Defaults to the current one
+    pub netns: String,
+}
+
+impl Default for NetnsProgExtraMeta {
+    fn default() -> Self {
+        Self {
+            netns: default_helpers::netns_default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+/// Extra fields in prog meta for `netfilter` programs
+pub struct NetfilterProgExtraMeta {
+    #[serde(default)]
+    /// Protocol family of the hook
+    pub family: NetfilterFamily,
+    #[serde(default)]
+    /// Which hook to attach to
+    pub hook: NetfilterHook,
+    #[serde(default)]
+    /// Priority of the hook. Hooks with lower values are called earlier
+    pub priority: i32,
+    #[serde(default)]
+    /// Defragment packets before they reach the program, BPF_F_NETFILTER_IP_DEFRAG. Requires kernel 6.6+
+    pub defrag: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Protocol family of netfilter hooks
+pub enum NetfilterFamily {
+    #[serde(rename = "ipv4")]
+    #[default]
+    /// NFPROTO_IPV4
+    Ipv4,
+    #[serde(rename = "ipv6")]
+    /// NFPROTO_IPV6
+    Ipv6,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Netfilter hooks
+pub enum NetfilterHook {
+    #[serde(rename = "prerouting")]
+    /// NF_INET_PRE_ROUTING
+    Prerouting,
+    #[serde(rename = "local_in")]
+    #[default]
+    /// NF_INET_LOCAL_IN
+    LocalIn,
+    #[serde(rename = "forward")]
+    /// NF_INET_FORWARD
+    Forward,
+    #[serde(rename = "local_out")]
+    /// NF_INET_LOCAL_OUT
+    LocalOut,
+    #[serde(rename = "postrouting")]
+    /// NF_INET_POST_ROUTING
+    Postrouting,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+/// Extra fields in prog meta for perf event programs
+pub struct PerfEventProgExtraMeta {
+    #[serde(default)]
+    /// Type of the perf event
+    pub event_type: PerfEventType,
+    #[serde(default)]
+    /// Which event of the type, such as `PERF_COUNT_HW_CPU_CYCLES` (0) for hardware events, or `PERF_COUNT_SW_CPU_CLOCK` (0) for software events
+    pub config: u64,
+    #[serde(default)]
+    /// Sample at this frequency, in Hz. Conflicts with `sample_period`. Defaults to 1Hz if neither is provided
+    pub sample_freq: Option<u64>,
+    #[serde(default)]
+    /// Sample once every such events
+    pub sample_period: Option<u64>,
+    #[serde(default)]
+    /// Only sample this process. Samples all processes if not provided
+    pub pid: Option<i32>,
+    #[serde(default)]
+    /// Only sample processes in this cgroup (path to the cgroup directory, in cgroup v2). Conflicts with `pid`
+    pub cgroup: Option<String>,
+    #[serde(default)]
+    /// CPUs to sample on, such as `0-3,8`. Defaults to all online CPUs, or any CPU if `pid` is provided
+    pub cpus: Option<String>,
+    #[serde(default = "default_helpers::default_bool::<true>")]
+    /// Whether to use the software `cpu-clock` event if hardware events are not available, such as in VMs without PMUs
+    pub fallback_to_cpu_clock: bool,
+}
+
+impl Default for PerfEventProgExtraMeta {
+    fn default() -> Self {
+        Self {
+            event_type: PerfEventType::default(),
+            config: 0,
+            sample_freq: None,
+            sample_period: None,
+            pid: None,
+            cgroup: None,
+            cpus: None,
+            fallback_to_cpu_clock: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Types of perf events
+pub enum PerfEventType {
+    #[serde(rename = "hardware")]
+    #[default]
+    /// PERF_TYPE_HARDWARE
+    Hardware,
+    #[serde(rename = "software")]
+    /// PERF_TYPE_SOFTWARE
+    Software,
+    #[serde(rename = "tracepoint")]
+    /// PERF_TYPE_TRACEPOINT. The config is the id of the tracepoint
+    Tracepoint,
+    #[serde(rename = "hw_cache")]
+    /// PERF_TYPE_HW_CACHE
+    HwCache,
+    #[serde(rename = "raw")]
+    /// PERF_TYPE_RAW
+    Raw,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 /// Extra fields in prog meta for TC programs
 pub struct TCProgExtraMeta {
@@ -263,14 +713,40 @@ pub struct TCProgExtraMeta {
     #[serde(default)]
     /// TC Hook options
     pub tcopts: TCOpts,
+    #[serde(default)]
+    /// How to attach the program
+    pub mode: TCMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Network namespace to attach in, same as the one in `XDPProgExtraMeta`
+    pub netns: Option<String>,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// How to attach a TC program
+pub enum TCMode {
+    #[serde(rename = "auto")]
+    #[default]
+    /// Use a tcx link if the kernel supports it (6.6+), or fall back to netlink
+    Auto,
+    #[serde(rename = "tcx")]
+    /// Only use a tcx link
+    Tcx,
+    #[serde(rename = "netlink")]
+    /// Only use a cls_bpf filter created through netlink. `tcopts` only applies to this mode
+    Netlink,
+}
+
+#[serde_with::serde_as]
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 /// TC Hook options
 pub struct TCHook {
     #[serde(default = "default_helpers::default_i32::<1>")]
     /// Which interface to hook
     pub ifindex: i32,
+    #[serde_as(as = "OneOrMany<_>")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Interfaces to hook, same as the one in `XDPProgExtraMeta`. Overrides `ifindex` if provided
+    pub iface: Vec<String>,
     #[serde(default)]
     /// Hook point
     pub attach_point: TCAttachPoint,
@@ -279,6 +755,7 @@ impl Default for TCHook {
     fn default() -> Self {
         Self {
             ifindex: 1,
+            iface: vec![],
             attach_point: TCAttachPoint::default(),
         }
     }
@@ -323,12 +800,16 @@ pub struct TCOpts {
     #[serde(default = "default_helpers::default_u32::<1>")]
     ///
     pub priority: u32,
+    #[serde(default)]
+    /// Replace the filter with the same handle and priority, if any. Otherwise attaching fails if there is one
+    pub replace: bool,
 }
 impl Default for TCOpts {
     fn default() -> Self {
         Self {
             handle: 1,
             priority: 1,
+            replace: false,
         }
     }
 }
@@ -414,6 +895,39 @@ impl BpfSkeletonMeta {
         let str_ref = ident.as_ref();
         self.maps.iter().find(|s| s.ident == str_ref)
     }
+    /// Collect the functions of cookies in multi-probe programs
+    pub fn cookie_symbols(&self) -> Result<HashMap<u64, String>> {
+        let mut result = HashMap::<u64, String>::new();
+        for prog in self.progs.iter() {
+            let Ok(extra_meta) =
+                serde_json::from_value::<MultiProbeProgExtraMeta>(prog.others.clone())
+            else {
+                continue;
+            };
+            if extra_meta.cookies.is_empty() {
+                continue;
+            }
+            if extra_meta.cookies.len() != extra_meta.symbols.len() {
+                bail!(
+                    "Program `{}` has {} symbols but {} cookies",
+                    prog.name,
+                    extra_meta.symbols.len(),
+                    extra_meta.cookies.len()
+                );
+            }
+            for (symbol, cookie) in extra_meta.symbols.into_iter().zip(extra_meta.cookies) {
+                match result.get(&cookie) {
+                    Some(v) if *v != symbol => {
+                        bail!("Cookie {} is used by both `{}` and `{}`", cookie, v, symbol)
+                    }
+                    _ => {
+                        result.insert(cookie, symbol);
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
 }
 
 /// global meta data config
@@ -448,6 +962,18 @@ pub struct EunomiaObjectMeta {
     /// and the `export_types` field will be ignored
     #[serde(default = "default_helpers::default_bool::<false>")]
     pub enable_multiple_export_types: bool,
+    /// If not zero, the delivery statistics of export maps will be logged every such ms when polling
+    #[serde(default = "default_helpers::default_usize::<0>")]
+    pub stats_interval_ms: usize,
+    /// What to do if an exported event could not be decoded
+    #[serde(default)]
+    pub error_policy: ErrorPolicy,
+    /// Whether to put the event meta (source map, cpu, timestamp and sequence) into json output
+    #[serde(default = "default_helpers::default_bool::<false>")]
+    pub json_event_meta: bool,
+    /// If set, only events matching this expression will be exported. See `export_event::filter` for the syntax
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_filter: Option<String>,
 }
 #[derive(Deserialize, Serialize, PartialEq, Eq)]
 pub(crate) struct ComposedObjectInner {
@@ -543,15 +1069,32 @@ pub(crate) mod default_helpers {
     pub(crate) fn default_u32<const V: u32>() -> u32 {
         V
     }
+    pub(crate) fn default_u16<const V: u16>() -> u16 {
+        V
+    }
 
     pub(crate) fn map_unit_default() -> String {
         "(unit)".into()
     }
+    pub(crate) fn time_format_default() -> String {
+        "%H:%M:%S".into()
+    }
+    pub(crate) fn aggregates_default() -> Vec<String> {
+        vec!["count".into()]
+    }
+    pub(crate) fn duration_field_default() -> String {
+        "duration_ns".into()
+    }
+    pub(crate) fn netns_default() -> String {
+        "/proc/self/ns/net".into()
+    }
 }
 
 /// The builder of `Command`
 pub mod arg_builder;
 /// A parser that can parse values from command line
 pub mod arg_parser;
+/// Classify programs by their section names
+pub(crate) mod section;
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,140 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+/// Whether programs in the section are attached to cgroups, such as `cgroup_skb/ingress`, `cgroup/connect4` and `sockops`
+/// `cgroup/skb` is excluded, since the direction is unknown
+pub(crate) fn is_cgroup_section(section: &str) -> bool {
+    section == "sockops"
+        || section.starts_with("cgroup_skb/")
+        || (section.starts_with("cgroup/") && section != "cgroup/skb")
+}
+
+/// Whether programs in the section are attached to a network namespace
+pub(crate) fn is_netns_section(section: &str) -> bool {
+    section == "sk_lookup" || section.starts_with("sk_lookup/") || section == "flow_dissector"
+}
+
+/// Kind of the programs in `uprobe`, `uretprobe` and `usdt` sections
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum UprobeKind {
+    Uprobe { retprobe: bool },
+    Usdt,
+}
+
+/// Split the section name into the kind of the probe, and the target (the part after `/`) if provided
+pub(crate) fn parse_uprobe_section(section: &str) -> Option<(UprobeKind, Option<&str>)> {
+    let (prefix, target) = match section.split_once('/') {
+        Some((prefix, target)) => (prefix, Some(target).filter(|v| !v.is_empty())),
+        None => (section, None),
+    };
+    let kind = match prefix {
+        "uprobe" | "uprobe.s" => UprobeKind::Uprobe { retprobe: false },
+        "uretprobe" | "uretprobe.s" => UprobeKind::Uprobe { retprobe: true },
+        "usdt" | "usdt.s" => UprobeKind::Usdt,
+        _ => return None,
+    };
+    Some((kind, target))
+}
+
+/// Whether the section is a `kprobe.multi` or `kretprobe.multi` one. Returns whether it's a retprobe, and the pattern (the part after `/`) if provided
+pub(crate) fn parse_kprobe_multi_section(section: &str) -> Option<(bool, Option<&str>)> {
+    let (prefix, pattern) = match section.split_once('/') {
+        Some((prefix, pattern)) => (prefix, Some(pattern).filter(|v| !v.is_empty())),
+        None => (section, None),
+    };
+    match prefix {
+        "kprobe.multi" => Some((false, pattern)),
+        "kretprobe.multi" => Some((true, pattern)),
+        _ => None,
+    }
+}
+
+/// Whether the section is a `uprobe.multi` or `uretprobe.multi` one, or the sleepable `.s` variants. Returns whether it's a retprobe, and the target (the part after `/`) if provided
+pub(crate) fn parse_uprobe_multi_section(section: &str) -> Option<(bool, Option<&str>)> {
+    let (prefix, target) = match section.split_once('/') {
+        Some((prefix, target)) => (prefix, Some(target).filter(|v| !v.is_empty())),
+        None => (section, None),
+    };
+    match prefix.strip_suffix(".s").unwrap_or(prefix) {
+        "uprobe.multi" => Some((false, target)),
+        "uretprobe.multi" => Some((true, target)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_cgroup_section, parse_kprobe_multi_section, parse_uprobe_multi_section,
+        parse_uprobe_section, UprobeKind,
+    };
+
+    #[test]
+    fn test_is_cgroup_section() {
+        for section in [
+            "cgroup_skb/ingress",
+            "cgroup_skb/egress",
+            "cgroup/connect4",
+            "cgroup/connect6",
+            "cgroup/sock_create",
+            "cgroup/sysctl",
+            "cgroup/dev",
+            "sockops",
+        ] {
+            assert!(is_cgroup_section(section), "{}", section);
+        }
+        for section in ["cgroup/skb", "tc", "sk_lookup", "kprobe/cgroup_mkdir"] {
+            assert!(!is_cgroup_section(section), "{}", section);
+        }
+    }
+
+    #[test]
+    fn test_parse_uprobe_section() {
+        assert_eq!(
+            parse_uprobe_section("uretprobe//bin/bash:readline"),
+            Some((
+                UprobeKind::Uprobe { retprobe: true },
+                Some("/bin/bash:readline")
+            ))
+        );
+        assert_eq!(
+            parse_uprobe_section("uprobe"),
+            Some((UprobeKind::Uprobe { retprobe: false }, None))
+        );
+        assert_eq!(
+            parse_uprobe_section("usdt/libc.so.6:libc:setjmp"),
+            Some((UprobeKind::Usdt, Some("libc.so.6:libc:setjmp")))
+        );
+        assert_eq!(parse_uprobe_section("uprobe.multi/a:b"), None);
+        assert_eq!(parse_uprobe_section("tp/sched/sched_process_exec"), None);
+    }
+
+    #[test]
+    fn test_parse_multi_section() {
+        assert_eq!(
+            parse_kprobe_multi_section("kretprobe.multi/tcp_*"),
+            Some((true, Some("tcp_*")))
+        );
+        assert_eq!(
+            parse_kprobe_multi_section("kprobe.multi"),
+            Some((false, None))
+        );
+        assert_eq!(parse_kprobe_multi_section("kprobe/tcp_sendmsg"), None);
+
+        assert_eq!(
+            parse_uprobe_multi_section("uprobe.multi//usr/lib/libc.so.6:malloc*"),
+            Some((false, Some("/usr/lib/libc.so.6:malloc*")))
+        );
+        assert_eq!(
+            parse_uprobe_multi_section("uretprobe.multi.s"),
+            Some((true, None))
+        );
+        assert_eq!(
+            parse_uprobe_multi_section("uprobe//bin/bash:readline"),
+            None
+        );
+    }
+}
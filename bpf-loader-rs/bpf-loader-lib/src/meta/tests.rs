@@ -9,9 +9,10 @@ use serde_json::{json, Value};
 use crate::{
     meta::{
         DataSectionMeta, DataSectionVariableMeta, ExportedTypesStructMemberMeta,
-        ExportedTypesStructMeta, MapExportConfig, MapMeta, ProgMeta,
+        ExportedTypesStructMeta, MapExportConfig, MapMeta, NetfilterFamily, NetfilterHook,
+        NetfilterProgExtraMeta, NetnsProgExtraMeta, SocketFilterProgExtraMeta,
     },
-    tests::get_assets_dir,
+    tests::{get_assets_dir, prog},
 };
 
 use super::ComposedObject;
@@ -55,7 +56,11 @@ fn test_deserialize_meta() {
         mmaped: false,
         sample: None,
         export_config: MapExportConfig::NoExport,
-        intepreter: crate::meta::BufferValueInterpreter::DefaultStruct
+        intepreter: crate::meta::BufferValueInterpreter::DefaultStruct,
+        plain_text: None,
+        aggregation: None,
+        enrich: None,
+        correlate: None
     }));
     assert!(maps.contains(&MapMeta {
         ident: "rb".into(),
@@ -63,7 +68,11 @@ fn test_deserialize_meta() {
         mmaped: false,
         sample: None,
         export_config: MapExportConfig::NoExport,
-        intepreter: crate::meta::BufferValueInterpreter::DefaultStruct
+        intepreter: crate::meta::BufferValueInterpreter::DefaultStruct,
+        plain_text: None,
+        aggregation: None,
+        enrich: None,
+        correlate: None
     }));
     assert!(maps.contains(&MapMeta {
         ident: "rodata".into(),
@@ -71,7 +80,11 @@ fn test_deserialize_meta() {
         mmaped: true,
         sample: None,
         export_config: MapExportConfig::NoExport,
-        intepreter: crate::meta::BufferValueInterpreter::DefaultStruct
+        intepreter: crate::meta::BufferValueInterpreter::DefaultStruct,
+        plain_text: None,
+        aggregation: None,
+        enrich: None,
+        correlate: None
     }));
     assert!(maps.contains(&MapMeta {
         ident: "bss".into(),
@@ -79,23 +92,25 @@ fn test_deserialize_meta() {
         mmaped: true,
         sample: None,
         export_config: MapExportConfig::NoExport,
-        intepreter: crate::meta::BufferValueInterpreter::DefaultStruct
+        intepreter: crate::meta::BufferValueInterpreter::DefaultStruct,
+        plain_text: None,
+        aggregation: None,
+        enrich: None,
+        correlate: None
     }));
     assert_eq!(bpf_skel.obj_name, "client_bpf");
     let progs = &bpf_skel.progs;
     assert_eq!(progs.len(), 2);
-    assert!(progs.contains(&ProgMeta {
-        name: "handle_exec".into(),
-        link: true,
-        attach: "tp/sched/sched_process_exec".into(),
-        others: json!({})
-    }));
-    assert!(progs.contains(&ProgMeta {
-        name: "handle_exit".into(),
-        link: true,
-        attach: "tp/sched/sched_process_exit".into(),
-        others: json!({})
-    }));
+    assert!(progs.contains(&prog(
+        "handle_exec",
+        "tp/sched/sched_process_exec",
+        json!({})
+    )));
+    assert!(progs.contains(&prog(
+        "handle_exit",
+        "tp/sched/sched_process_exit",
+        json!({})
+    )));
     let export_types = &decoded.meta.export_types;
     assert_eq!(export_types.len(), 1);
     assert!(export_types.contains(&ExportedTypesStructMeta {
@@ -138,3 +153,38 @@ fn test_deserialize_meta() {
         type_id: 613
     }));
 }
+
+#[test]
+fn test_deserialize_net_prog_extra_meta() {
+    let meta: SocketFilterProgExtraMeta = serde_json::from_value(json!({})).unwrap();
+    assert_eq!(meta, SocketFilterProgExtraMeta::default());
+    assert_eq!(meta.protocol, 0x0003);
+    assert_eq!(meta.iface, None);
+    let meta: SocketFilterProgExtraMeta =
+        serde_json::from_value(json!({"iface": "eth0", "protocol": 0x0800})).unwrap();
+    assert_eq!(meta.iface.as_deref(), Some("eth0"));
+    assert_eq!(meta.protocol, 0x0800);
+
+    let meta: NetnsProgExtraMeta = serde_json::from_value(json!({})).unwrap();
+    assert_eq!(meta.netns, "/proc/self/ns/net");
+    let meta: NetnsProgExtraMeta =
+        serde_json::from_value(json!({"netns": "/var/run/netns/test"})).unwrap();
+    assert_eq!(meta.netns, "/var/run/netns/test");
+
+    let meta: NetfilterProgExtraMeta = serde_json::from_value(json!({})).unwrap();
+    assert_eq!(meta, NetfilterProgExtraMeta::default());
+    assert_eq!(meta.family, NetfilterFamily::Ipv4);
+    assert_eq!(meta.hook, NetfilterHook::LocalIn);
+    let meta: NetfilterProgExtraMeta = serde_json::from_value(json!({
+        "family": "ipv6",
+        "hook": "postrouting",
+        "priority": -100,
+        "defrag": true
+    }))
+    .unwrap();
+    assert_eq!(meta.family, NetfilterFamily::Ipv6);
+    assert_eq!(meta.hook, NetfilterHook::Postrouting);
+    assert_eq!(meta.priority, -100);
+    assert!(meta.defrag);
+    assert!(serde_json::from_value::<NetfilterProgExtraMeta>(json!({"hook": "input"})).is_err());
+}
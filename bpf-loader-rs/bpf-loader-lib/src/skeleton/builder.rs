@@ -32,7 +32,7 @@ use libbpf_rs::{
     ObjectBuilder, OpenObject,
 };
 
-use super::preload::PreLoadBpfSkeleton;
+use super::preload::{attach::prepare_programs, PreLoadBpfSkeleton};
 
 /// Builder of BpfSkeleton
 pub struct BpfSkeletonBuilder<'a> {
@@ -181,6 +181,8 @@ impl<'a> BpfSkeletonBuilder<'a> {
             }
             sizes
         };
+        // SAFETY: open_result is opened but not loaded
+        unsafe { prepare_programs(open_result) };
         // SAFETY: The pointer won't be used by us anymore, and we also checked if it's null
         let open_object = unsafe { OpenObject::from_ptr(NonNull::new_unchecked(open_result)) }?;
 
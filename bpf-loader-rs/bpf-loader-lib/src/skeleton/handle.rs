@@ -4,48 +4,195 @@
 //! All rights reserved.
 //!
 
-use std::sync::{
-    atomic::{AtomicU8, Ordering},
-    Arc,
+use std::{
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
 };
 
-const PAUSE_BIT: u8 = 1 << 0;
-const TERMINATING_BIT: u8 = 1 << 1;
+use anyhow::{anyhow, Result};
+use nix::{
+    sys::eventfd::{eventfd, EfdFlags},
+    unistd::{read, write},
+};
+
+/// The state of a polling process, which can be queried through `PollingHandle::state`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PollingState {
+    /// The program is running, and the poller delivers events (or is going to)
+    Running,
+    /// The poller is paused
+    Paused,
+    /// The poller has exited, or a termination was requested
+    Terminated,
+    /// The poller has exited with an error
+    Failed(String),
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    terminating: bool,
+    /// Set when the polling function returns
+    exited: Option<Result<(), String>>,
+}
+
+#[derive(Debug)]
+struct HandleInner {
+    state: Mutex<ControlState>,
+    cond: Condvar,
+    /// An eventfd, which will be written on every state change, to wake up the event loop blocking on epoll
+    wakeup: OwnedFd,
+}
 
 #[derive(Debug, Clone)]
 #[repr(transparent)]
 /// A handle to control the polling process
 pub struct PollingHandle {
-    state: Arc<AtomicU8>,
+    inner: Arc<HandleInner>,
 }
 impl PollingHandle {
-    pub(crate) fn new() -> Self {
-        Self {
-            state: Arc::new(AtomicU8::new(0)),
-        }
+    pub(crate) fn new() -> Result<Self> {
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK)
+            .map_err(|e| anyhow!("Failed to create eventfd: {}", e))?;
+        Ok(Self {
+            inner: Arc::new(HandleInner {
+                state: Mutex::new(ControlState::default()),
+                cond: Condvar::new(),
+                // SAFETY: The fd was just created by us
+                wakeup: unsafe { OwnedFd::from_raw_fd(fd) },
+            }),
+        })
     }
-    pub(crate) fn reset(&self) {
-        self.state.store(0, Ordering::Release);
+    fn lock(&self) -> MutexGuard<'_, ControlState> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
     }
-    #[inline]
-    pub(crate) fn should_pause(&self) -> bool {
-        let t = self.state.load(Ordering::SeqCst);
-        t & PAUSE_BIT != 0
+    /// Notify the threads waiting on the condvar, and the event loop waiting on epoll
+    fn notify(&self) {
+        self.inner.cond.notify_all();
+        // It only fails if the counter overflows, in that case the eventfd is already readable
+        write(self.inner.wakeup.as_raw_fd(), &1u64.to_ne_bytes()).ok();
+    }
+    pub(crate) fn reset(&self) {
+        *self.lock() = ControlState::default();
+        self.clear_wakeup();
     }
     #[inline]
     pub(crate) fn should_terminate(&self) -> bool {
-        self.state.load(Ordering::SeqCst) & TERMINATING_BIT != 0
+        self.lock().terminating
+    }
+    /// Block the current thread until the poller is resumed or terminated
+    pub(crate) fn wait_while_paused(&self) {
+        let guard = self.lock();
+        drop(
+            self.inner
+                .cond
+                .wait_while(guard, |s| s.paused && !s.terminating)
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+    }
+    /// Block the current thread until a termination is requested
+    pub(crate) fn wait_for_terminate(&self) {
+        let guard = self.lock();
+        drop(
+            self.inner
+                .cond
+                .wait_while(guard, |s| !s.terminating)
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+    }
+    /// The fd that becomes readable once the state of the handle was changed
+    pub(crate) fn wakeup_fd(&self) -> RawFd {
+        self.inner.wakeup.as_raw_fd()
+    }
+    /// Reset the readable state of the wakeup fd
+    pub(crate) fn clear_wakeup(&self) {
+        // EAGAIN means the counter is already zero
+        read(self.inner.wakeup.as_raw_fd(), &mut [0u8; 8]).ok();
+    }
+    /// Record the result of the polling function, and wake up those who are waiting for it
+    pub(crate) fn set_exited<T>(&self, result: &Result<T>) {
+        self.lock().exited = Some(match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("{:?}", e)),
+        });
+        self.inner.cond.notify_all();
     }
     /// Set the pause state of the poller
     pub fn set_pause(&self, pause: bool) {
-        if pause {
-            self.state.fetch_or(PAUSE_BIT, Ordering::Relaxed);
-        } else {
-            self.state.fetch_and(!PAUSE_BIT, Ordering::Relaxed);
-        }
+        self.lock().paused = pause;
+        self.notify();
     }
     /// Terminate the poller. It will allow `wait_and_poll_to_handler` to return
     pub fn terminate(&self) {
-        self.state.fetch_or(TERMINATING_BIT, Ordering::Relaxed);
+        self.lock().terminating = true;
+        self.notify();
+    }
+    /// Get the current state of the poller
+    pub fn state(&self) -> PollingState {
+        let guard = self.lock();
+        match &guard.exited {
+            Some(Err(e)) => PollingState::Failed(e.clone()),
+            Some(Ok(_)) => PollingState::Terminated,
+            None if guard.terminating => PollingState::Terminated,
+            None if guard.paused => PollingState::Paused,
+            None => PollingState::Running,
+        }
+    }
+    /// Block the current thread until the polling function returns, and get the final state
+    ///
+    /// The returned state will either be `Terminated` or `Failed`
+    pub fn wait_terminated(&self) -> PollingState {
+        let guard = self.lock();
+        drop(
+            self.inner
+                .cond
+                .wait_while(guard, |s| s.exited.is_none())
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+        self.state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use anyhow::anyhow;
+
+    use super::{PollingHandle, PollingState};
+
+    #[test]
+    fn test_state_transitions() {
+        let handle = PollingHandle::new().unwrap();
+        assert_eq!(handle.state(), PollingState::Running);
+        handle.set_pause(true);
+        assert_eq!(handle.state(), PollingState::Paused);
+        handle.set_pause(false);
+        assert_eq!(handle.state(), PollingState::Running);
+        handle.terminate();
+        assert_eq!(handle.state(), PollingState::Terminated);
+        handle.reset();
+        assert_eq!(handle.state(), PollingState::Running);
+        handle.set_exited::<()>(&Err(anyhow!("Something went wrong")));
+        assert!(matches!(handle.state(), PollingState::Failed(e) if e.contains("went wrong")));
+    }
+
+    #[test]
+    fn test_wait_terminated() {
+        let handle = PollingHandle::new().unwrap();
+        handle.set_pause(true);
+        let worker = {
+            let handle = handle.clone();
+            thread::spawn(move || {
+                handle.wait_while_paused();
+                handle.wait_for_terminate();
+                handle.set_exited(&Ok(()));
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        handle.set_pause(false);
+        handle.terminate();
+        assert_eq!(handle.wait_terminated(), PollingState::Terminated);
+        worker.join().unwrap();
     }
 }
@@ -40,12 +40,19 @@ use std::{any::Any, sync::Arc};
 use libbpf_rs::{Map, MapType, Object};
 use log::{debug, warn};
 
-use self::{handle::PollingHandle, poller::Poller, preload::attach::AttachLink};
+use self::{
+    handle::PollingHandle,
+    poller::ExportSource,
+    preload::attach::AttachLink,
+    stats::{MapStats, StatsHandle},
+};
 use crate::{
     btf_container::BtfContainer,
     export_event::{
-        type_descriptor::TypeDescriptor, EventExporter, EventExporterBuilder, EventHandler,
-        ExportFormatType,
+        filter::EventFilter,
+        metrics::{MetricsRegistry, MetricsSink},
+        type_descriptor::TypeDescriptor,
+        EventExporter, EventExporterBuilder, EventHandler, ExportFormatType,
     },
     meta::{EunomiaObjectMeta, MapExportConfig, MapMeta, MapSampleMeta, RunnerConfig},
     program_poll_loop,
@@ -62,6 +69,8 @@ pub mod handle;
 pub(crate) mod poller;
 /// The preloaded skeleton
 pub mod preload;
+/// Delivery statistics of export maps
+pub mod stats;
 
 #[cfg(test)]
 #[cfg(not(feature = "no-load-bpf-tests"))]
@@ -69,6 +78,8 @@ mod tests;
 /// Represents a polling-ready bpf skeleton. With you can control the ebpf program and poll from it.
 pub struct BpfSkeleton {
     pub(crate) handle: PollingHandle,
+    /// statistics of the export maps being polled
+    pub(crate) stats: StatsHandle,
     ///   data storage
     /// meta data control the behavior of ebpf program:
     /// eg. types of the eBPF maps and prog, export data types
@@ -95,6 +106,15 @@ impl BpfSkeleton {
     pub fn create_poll_handle(&self) -> PollingHandle {
         self.handle.clone()
     }
+    /// Create a handle to read the delivery statistics of export maps
+    /// It could be sent to another thread, and be read while polling
+    pub fn create_stats_handle(&self) -> StatsHandle {
+        self.stats.clone()
+    }
+    /// Get the delivery statistics of each export map in the current (or the last) polling
+    pub fn stats(&self) -> Vec<MapStats> {
+        self.stats.snapshot()
+    }
     /// Get the name of the loaded program
     pub fn get_program_name(&self) -> &str {
         &self.meta.bpf_skel.obj_name
@@ -110,34 +130,38 @@ impl BpfSkeleton {
         self.prog.prog(name).map(|p| p.fd())
     }
 
-    fn build_poller_from_exporter<'a>(
-        &self,
+    /// Create an exporter builder for the map, with options from the skeleton meta applied
+    fn default_exporter_builder(&self, map_meta: &MapMeta) -> Result<EventExporterBuilder> {
+        let builder = EventExporterBuilder::new()
+            .set_error_policy(self.meta.error_policy)
+            .set_json_event_meta(self.meta.json_event_meta)
+            .apply_map_meta(map_meta);
+        let builder = if map_meta.enrich.is_some() {
+            builder.set_cookie_symbols(self.meta.bpf_skel.cookie_symbols()?)
+        } else {
+            builder
+        };
+        Ok(match &self.meta.event_filter {
+            Some(expr) => builder.set_filter(EventFilter::parse(expr)?),
+            None => builder,
+        })
+    }
+
+    fn export_source_from_exporter<'a>(
         exporter: Arc<EventExporter>,
         export_type: ExportMapType<'a>,
         bpf_map: &'a Map,
-    ) -> Result<Poller<'a>> {
-        let ret = match export_type {
-            ExportMapType::RingBuffer => Poller::RingBuf(
-                self.build_ringbuf_poller(bpf_map, exporter)
-                    .with_context(|| anyhow!("Failed to build ringbuf poller"))?,
-            ),
-            ExportMapType::PerfEventArray => Poller::PerfEvent(
-                self.build_perfevent_poller(bpf_map, exporter)
-                    .with_context(|| anyhow!("Failed to builf perfevent poller"))?,
-            ),
-            ExportMapType::Sample(sp) => Poller::SampleMap(
-                self.build_sample_map_poller(bpf_map, exporter, sp)
-                    .with_context(|| anyhow!("Failed to build sample map poller"))?,
-            ),
-        };
-        Ok(ret)
+    ) -> ExportSource<'a> {
+        match export_type {
+            ExportMapType::RingBuffer => ExportSource::RingBuf(bpf_map, exporter),
+            ExportMapType::PerfEventArray => ExportSource::PerfEvent(bpf_map, exporter),
+            ExportMapType::Sample(sp) => ExportSource::SampleMap(bpf_map, exporter, sp),
+        }
     }
 
     fn wait_and_poll_with_old_single_export(
         &self,
-        export_format_type: ExportFormatType,
-        export_event_handler: Option<Arc<dyn EventHandler>>,
-        user_context: Option<Arc<dyn Any>>,
+        configure: impl Fn(&str, EventExporterBuilder) -> EventExporterBuilder,
     ) -> Result<()> {
         let mut export_map: Option<(&MapMeta, ExportMapType)> = None;
         for map_meta in self.meta.bpf_skel.maps.iter() {
@@ -167,7 +191,7 @@ impl BpfSkeleton {
                 .map(&map_meta.name)
                 .ok_or_else(|| anyhow!("Invalid map name: {}", map_meta.name))?;
             let exporter_builder =
-                create_exporter_builder(export_format_type, export_event_handler, user_context);
+                configure(&map_meta.name, self.default_exporter_builder(map_meta)?);
             if self.meta.export_types.is_empty() {
                 bail!(
                     "Export map named `{}` found, but no export type is provided",
@@ -198,10 +222,13 @@ impl BpfSkeleton {
                     )?
                 }
             };
-            let poller = self.build_poller_from_exporter(exporter, export_type, bpf_map)?;
-            self.handle.reset();
+            let event_loop = self.build_event_loop(vec![Self::export_source_from_exporter(
+                exporter,
+                export_type,
+                bpf_map,
+            )])?;
             program_poll_loop!(&self.handle, {
-                poller.poll()?;
+                event_loop.poll()?;
             });
         } else {
             self.wait_for_no_export_program()
@@ -211,6 +238,7 @@ impl BpfSkeleton {
     }
     /// Start poll with each map corresponding to a different exporter
     /// The function `exporter_provider` should return the ExportFormatType, EventHandler, and UserContext(if applies) for the given map name (If you want to set the exporter)
+    /// Note: this function will set paused and terminating to false before polling.
     pub fn wait_and_poll_to_handler_with_multiple_exporter(
         &self,
         exporter_provider: impl Fn(
@@ -224,6 +252,70 @@ impl BpfSkeleton {
         if !self.meta.enable_multiple_export_types {
             bail!("This function only supports multiple export types");
         }
+        self.wait_and_poll_to_handler_with_exporter_builder(|name, builder| {
+            if let Some((ty, handler, ctx)) = exporter_provider(name) {
+                builder
+                    .set_export_format(ty)
+                    .set_export_event_handler(handler)
+                    .set_user_context(ctx)
+            } else {
+                builder
+            }
+        })
+    }
+    /// Start poll with each map configuring its own exporter
+    /// The function `configure` receives the name of each export map, and a builder with options from the skeleton meta (such as the error policy and the filter) applied.
+    /// It should return the builder with the format, handler, filter or anything else set for that map.
+    /// If multiple export types are not enabled, it will only be called for the single export map.
+    /// Note: this function will set paused and terminating to false before polling.
+    pub fn wait_and_poll_to_handler_with_exporter_builder(
+        &self,
+        configure: impl Fn(&str, EventExporterBuilder) -> EventExporterBuilder,
+    ) -> Result<()> {
+        self.handle.reset();
+        let ret = if self.meta.enable_multiple_export_types {
+            self.poll_with_multiple_exporter(configure)
+        } else {
+            self.wait_and_poll_with_old_single_export(configure)
+        };
+        self.handle.set_exited(&ret);
+        ret
+    }
+    /// Poll like `wait_and_poll_to_handler`, but key-value pairs of sample maps are published to the metrics registry instead. See `export_event::metrics` for details
+    /// Note: this function will set paused and terminating to false before polling.
+    pub fn wait_and_poll_to_handler_with_metrics(
+        &self,
+        export_format_type: ExportFormatType,
+        export_event_handler: Option<Arc<dyn EventHandler>>,
+        user_context: Option<Arc<dyn Any>>,
+        registry: &MetricsRegistry,
+    ) -> Result<()> {
+        let sink: Arc<dyn EventHandler> = Arc::new(MetricsSink::new(registry.clone()));
+        self.wait_and_poll_to_handler_with_exporter_builder(|name, builder| {
+            let is_sample_map = self
+                .meta
+                .bpf_skel
+                .maps
+                .iter()
+                .any(|v| v.name == name && v.sample.is_some());
+            if is_sample_map {
+                builder
+                    .set_export_format(ExportFormatType::Json)
+                    .set_export_event_handler(sink.clone())
+            } else {
+                create_exporter_builder(
+                    builder,
+                    export_format_type,
+                    export_event_handler.clone(),
+                    user_context.clone(),
+                )
+            }
+        })
+    }
+    fn poll_with_multiple_exporter(
+        &self,
+        configure: impl Fn(&str, EventExporterBuilder) -> EventExporterBuilder,
+    ) -> Result<()> {
         let mut export_maps: Vec<(&MapMeta, ExportMapType)> = vec![];
         for map_meta in self
             .meta
@@ -255,13 +347,11 @@ impl BpfSkeleton {
         }
         debug!("Export maps: {:#?}", export_maps);
 
-        // Before polling, we should reset the control flags
-        self.handle.reset();
         if export_maps.is_empty() {
             self.wait_for_no_export_program()
                 .with_context(|| anyhow!("Failed to wait for a non-export program"))?;
         } else {
-            let mut pollers = vec![];
+            let mut sources = vec![];
             for (map_meta, export_map_type) in export_maps.into_iter() {
                 let bpf_map = self
                     .prog
@@ -290,15 +380,7 @@ impl BpfSkeleton {
                     }
                     MapExportConfig::NoExport => unreachable!("How could you reach here?"),
                 };
-                let builder = EventExporterBuilder::new();
-                let builder = if let Some((ty, handler, ctx)) = exporter_provider(&map_meta.name) {
-                    builder
-                        .set_export_format(ty)
-                        .set_export_event_handler(handler)
-                        .set_user_context(ctx)
-                } else {
-                    builder
-                };
+                let builder = configure(&map_meta.name, self.default_exporter_builder(map_meta)?);
                 match export_map_type {
                     ExportMapType::RingBuffer => {
                         let exporter = builder
@@ -308,9 +390,7 @@ impl BpfSkeleton {
                                 &map_meta.intepreter,
                             )
                             .with_context(|| anyhow!("Failed to build ringbuf exporter"))?;
-                        pollers.push(Poller::RingBuf(
-                            self.build_ringbuf_poller(bpf_map, exporter)?,
-                        ));
+                        sources.push(ExportSource::RingBuf(bpf_map, exporter));
                     }
                     ExportMapType::PerfEventArray => {
                         let exporter = builder
@@ -320,9 +400,7 @@ impl BpfSkeleton {
                                 &map_meta.intepreter,
                             )
                             .with_context(|| anyhow!("Failed to build perf event exporter"))?;
-                        pollers.push(Poller::PerfEvent(
-                            self.build_perfevent_poller(bpf_map, exporter)?,
-                        ));
+                        sources.push(ExportSource::PerfEvent(bpf_map, exporter));
                     }
                     ExportMapType::Sample(cfg) => {
                         let exporter = builder
@@ -340,16 +418,14 @@ impl BpfSkeleton {
                                     bpf_map.name()
                                 )
                             })?;
-                        pollers.push(Poller::SampleMap(
-                            self.build_sample_map_poller(bpf_map, exporter, cfg)?,
-                        ));
+                        sources.push(ExportSource::SampleMap(bpf_map, exporter, cfg));
                     }
                 }
             }
+            // All export maps are driven by a single epoll set, so that each of them gets serviced on time
+            let event_loop = self.build_event_loop(sources)?;
             program_poll_loop!(&self.handle, {
-                for poller in pollers.iter() {
-                    poller.poll()?;
-                }
+                event_loop.poll()?;
             });
         }
         Ok(())
@@ -365,18 +441,27 @@ impl BpfSkeleton {
         export_event_handler: Option<Arc<dyn EventHandler>>,
         user_context: Option<Arc<dyn Any>>,
     ) -> Result<()> {
-        if !self.meta.enable_multiple_export_types {
-            return self.wait_and_poll_with_old_single_export(
-                export_format_type,
-                export_event_handler,
-                user_context,
-            );
-        }
-        self.wait_and_poll_to_handler_with_multiple_exporter(|_| {
-            export_event_handler
-                .clone()
-                .map(|v| (export_format_type, v, user_context.clone()))
-        })
+        self.handle.reset();
+        let ret = if !self.meta.enable_multiple_export_types {
+            self.wait_and_poll_with_old_single_export(|_, builder| {
+                create_exporter_builder(
+                    builder,
+                    export_format_type,
+                    export_event_handler.clone(),
+                    user_context.clone(),
+                )
+            })
+        } else {
+            self.poll_with_multiple_exporter(|_, builder| match export_event_handler.clone() {
+                Some(handler) => builder
+                    .set_export_format(export_format_type)
+                    .set_export_event_handler(handler)
+                    .set_user_context(user_context.clone()),
+                None => builder,
+            })
+        };
+        self.handle.set_exited(&ret);
+        ret
     }
 }
 
@@ -401,11 +486,12 @@ enum ExportMapType<'a> {
 }
 
 fn create_exporter_builder(
+    builder: EventExporterBuilder,
     export_format: ExportFormatType,
     event_handler: Option<Arc<dyn EventHandler>>,
     ctx: Option<Arc<dyn Any>>,
 ) -> EventExporterBuilder {
-    let exporter_builder = EventExporterBuilder::new().set_export_format(export_format);
+    let exporter_builder = builder.set_export_format(export_format);
     let exporter_builder = if let Some(hdl) = event_handler.clone() {
         exporter_builder.set_export_event_handler(hdl)
     } else {
@@ -5,6 +5,7 @@
 //!
 
 use std::{
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -14,28 +15,43 @@ use std::{
 
 use crate::{
     export_event::{
-        EventExporter, ExporterInternalImplementation, InternalBufferValueEventProcessor,
-        InternalSampleMapProcessor,
+        EventExporter, EventMeta, ExporterInternalImplementation,
+        InternalBufferValueEventProcessor, InternalSampleMapProcessor,
     },
     meta::MapSampleMeta,
 };
 use anyhow::anyhow;
 use anyhow::{bail, Context, Result};
 use libbpf_rs::{Map, MapFlags, PerfBuffer, PerfBufferBuilder, RingBuffer, RingBufferBuilder};
-use log::error;
+use log::{debug, error, info, warn};
+use nix::{
+    errno::Errno,
+    sys::{
+        epoll::{
+            epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+        },
+        time::TimeSpec,
+        timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags},
+    },
+};
+
+use super::{
+    handle::PollingHandle,
+    stats::{MapStatsCounter, StatsHandle},
+    BpfSkeleton,
+};
+
+/// Maximum number of ready sources handled in a single `epoll_wait`
+const MAX_EPOLL_EVENTS: usize = 32;
 
-use super::BpfSkeleton;
 #[macro_export]
 macro_rules! program_poll_loop {
     ($handle: expr, $blk: block) => {{
         use log::info;
-        use std::time::Duration;
         info!("Running ebpf program...");
         while !$handle.should_terminate() {
-            while $handle.should_pause() {
-                std::hint::spin_loop();
-                std::thread::sleep(Duration::from_millis(1));
-            }
+            // Sleeps on the condvar, so a paused poller uses no CPU
+            $handle.wait_while_paused();
             if $handle.should_terminate() {
                 info!("Program terminated");
                 break;
@@ -45,15 +61,14 @@ macro_rules! program_poll_loop {
         info!("Program exited");
     }};
 }
+
+/// All ringbuf maps share one `RingBuffer`, so that they could be consumed through a single epoll fd
 #[ouroboros::self_referencing]
-pub(crate) struct RingBufPollerContext {
-    exporter: Arc<EventExporter>,
-    #[borrows(exporter)]
-    event_processor: &'this dyn InternalBufferValueEventProcessor,
-    #[borrows(event_processor)]
+pub(crate) struct RingBufPollerContext<'a> {
+    exporters: Vec<(&'a Map, Arc<EventExporter>, Arc<MapStatsCounter>)>,
+    #[borrows(exporters)]
     #[covariant]
     ringbuf: RingBuffer<'this>,
-    poll_timeout_ms: u64,
 }
 
 #[ouroboros::self_referencing]
@@ -62,10 +77,9 @@ pub(crate) struct PerfEventPollerContext {
     #[borrows(exporter)]
     event_processor: &'this dyn InternalBufferValueEventProcessor,
     error_flag: AtomicBool,
-    #[borrows(event_processor, error_flag)]
+    #[borrows(exporter, event_processor, error_flag)]
     #[covariant]
     perf: PerfBuffer<'this>,
-    poll_timeout_ms: u64,
 }
 #[ouroboros::self_referencing]
 pub(crate) struct SampleMapPollerContext<'a> {
@@ -76,121 +90,307 @@ pub(crate) struct SampleMapPollerContext<'a> {
     event_processor: &'this dyn InternalSampleMapProcessor,
 }
 
-pub(crate) enum Poller<'a> {
-    RingBuf(RingBufPollerContext),
-    PerfEvent(PerfEventPollerContext),
-    SampleMap(SampleMapPollerContext<'a>),
+/// A sample map, along with the timer that tells when to sample it
+pub(crate) struct SampleMapSource<'a> {
+    ctx: SampleMapPollerContext<'a>,
+    timer: TimerFd,
+    stats: Arc<MapStatsCounter>,
 }
 
-impl<'a> Drop for Poller<'a> {
+impl<'a> Drop for SampleMapSource<'a> {
     fn drop(&mut self) {
-        if let Poller::SampleMap(ctx) = self {
-            if ctx.borrow_sample_config().clear_map {
-                // Clean up the map
-                let keys = ctx.borrow_map().keys().collect::<Vec<_>>();
-                for key in keys.into_iter() {
-                    ctx.borrow_map().delete(&key).ok();
-                }
+        if self.ctx.borrow_sample_config().clear_map {
+            // Clean up the map
+            let keys = self.ctx.borrow_map().keys().collect::<Vec<_>>();
+            for key in keys.into_iter() {
+                self.ctx.borrow_map().delete(&key).ok();
             }
         }
     }
 }
 
-impl<'a> Poller<'a> {
-    pub(crate) fn poll(&self) -> Result<()> {
-        match self {
-            Poller::RingBuf(rb) => {
-                rb.borrow_ringbuf()
-                    .poll(Duration::from_millis(*rb.borrow_poll_timeout_ms()))
-                    .map_err(|e| anyhow!("Failed to poll ringbuf: {}, see logs for details", e))?;
+impl<'a> SampleMapSource<'a> {
+    fn sample(&self) -> Result<()> {
+        // Acknowledge the expiration, or the timerfd will stay readable
+        self.timer
+            .wait()
+            .map_err(|e| anyhow!("Failed to read the sample timer: {}", e))?;
+        let ctx = &self.ctx;
+        for key in ctx.borrow_map().keys() {
+            let value = ctx
+                .borrow_map()
+                .lookup(&key, MapFlags::empty())
+                .map_err(|e| anyhow!("Failed to lookup value of the key `{:?}`: {}", key, e))?
+                .ok_or_else(|| anyhow!("Value of key `{:?}` should exist", key))?;
+            let sequence = self.stats.record_event(key.len() + value.len());
+            let meta = EventMeta::now(ctx.borrow_map().name(), None, sequence);
+            if let Err(e) = ctx
+                .borrow_event_processor()
+                .handle_event(&meta, &key, &value)
+            {
+                self.stats.record_decode_failure();
+                ctx.borrow_exporter()
+                    .handle_decode_failure(&meta, e, Some(&key), &value)
+                    .with_context(|| anyhow!("Failed to handle event"))?;
             }
-            Poller::PerfEvent(ctx) => {
-                ctx.borrow_perf()
-                    .poll(Duration::from_millis(*ctx.borrow_poll_timeout_ms()))
-                    .map_err(|e| anyhow!("Failed to poll perf event: {}", e))?;
-                if ctx.borrow_error_flag().load(Ordering::Relaxed) {
-                    bail!("Failed to poll perf event. See log for details");
+        }
+        ctx.borrow_exporter()
+            .end_sampling_round(ctx.borrow_map().name());
+        Ok(())
+    }
+}
+
+/// An exporter with aggregation enabled, along with the timer that tells when its window ends
+pub(crate) struct AggregationWindowSource {
+    map_name: String,
+    exporter: Arc<EventExporter>,
+    timer: TimerFd,
+}
+
+impl AggregationWindowSource {
+    fn flush(&self) -> Result<()> {
+        self.timer
+            .wait()
+            .map_err(|e| anyhow!("Failed to read the aggregation timer: {}", e))?;
+        self.exporter
+            .flush_aggregation(&self.map_name)
+            .with_context(|| anyhow!("Failed to export the aggregation of `{}`", self.map_name))
+    }
+}
+
+/// Where an export map delivers its data from
+pub(crate) enum ExportSource<'a> {
+    RingBuf(&'a Map, Arc<EventExporter>),
+    PerfEvent(&'a Map, Arc<EventExporter>),
+    SampleMap(&'a Map, Arc<EventExporter>, &'a MapSampleMeta),
+}
+
+/// What an epoll event refers to. The epoll data field is an index into `EventLoop::tokens`
+#[derive(Clone, Copy)]
+enum PollToken {
+    /// The state of the polling handle was changed
+    Wakeup,
+    /// It's time to log the statistics
+    StatsLog,
+    RingBuf,
+    PerfEvent(usize),
+    SampleMap(usize),
+    /// An aggregation window ended
+    AggregationWindow(usize),
+}
+
+/// Drives all export maps of a program with one epoll set
+///
+/// - All ringbufs are merged into one `RingBuffer`, whose epoll fd is registered
+/// - Each perf buffer registers its own epoll fd
+/// - Each sample map registers a timerfd, which expires every `interval` ms
+/// - Each exporter with aggregation registers a timerfd, which expires at the end of every window
+///
+/// So every source will be serviced as soon as it's ready, and a slow sample map won't stall the others.
+pub(crate) struct EventLoop<'a> {
+    epoll: OwnedFd,
+    tokens: Vec<PollToken>,
+    handle: PollingHandle,
+    stats: StatsHandle,
+    /// Expires every `stats_interval_ms`, if it was set
+    stats_timer: Option<TimerFd>,
+    ringbuf: Option<RingBufPollerContext<'a>>,
+    perf: Vec<PerfEventPollerContext>,
+    sample: Vec<SampleMapSource<'a>>,
+    windows: Vec<AggregationWindowSource>,
+    poll_timeout_ms: isize,
+}
+
+impl<'a> EventLoop<'a> {
+    fn register(&mut self, fd: RawFd, token: PollToken) -> Result<()> {
+        let mut event = EpollEvent::new(EpollFlags::EPOLLIN, self.tokens.len() as u64);
+        epoll_ctl(self.epoll.as_raw_fd(), EpollOp::EpollCtlAdd, fd, &mut event)
+            .map_err(|e| anyhow!("Failed to add fd {} to epoll: {}", fd, e))?;
+        self.tokens.push(token);
+        Ok(())
+    }
+    /// Wait until any of the sources is ready or the timeout expires, then service the ready ones
+    pub(crate) fn poll(&self) -> Result<()> {
+        let mut events = [EpollEvent::empty(); MAX_EPOLL_EVENTS];
+        let count = match epoll_wait(self.epoll.as_raw_fd(), &mut events, self.poll_timeout_ms) {
+            Ok(v) => v,
+            Err(Errno::EINTR) => return Ok(()),
+            Err(e) => bail!("Failed to wait on epoll: {}", e),
+        };
+        for event in events[..count].iter() {
+            match self.tokens[event.data() as usize] {
+                // Just return, so that the caller could check the new state
+                PollToken::Wakeup => self.handle.clear_wakeup(),
+                PollToken::StatsLog => self.log_stats()?,
+                PollToken::RingBuf => {
+                    if let Some(rb) = self.ringbuf.as_ref() {
+                        rb.borrow_ringbuf().consume().map_err(|e| {
+                            anyhow!("Failed to poll ringbuf: {}, see logs for details", e)
+                        })?;
+                    }
                 }
-            }
-            Poller::SampleMap(ctx) => {
-                for key in ctx.borrow_map().keys() {
-                    let value = ctx
-                        .borrow_map()
-                        .lookup(&key, MapFlags::empty())
-                        .map_err(|e| {
-                            anyhow!("Failed to lookup value of the key `{:?}`: {}", key, e)
-                        })?
-                        .ok_or_else(|| anyhow!("Value of key `{:?}` should exist", key))?;
-                    ctx.borrow_event_processor()
-                        .handle_event(&key, &value)
-                        .with_context(|| anyhow!("Failed to handle event"))?;
+                PollToken::PerfEvent(idx) => {
+                    let ctx = &self.perf[idx];
+                    ctx.borrow_perf()
+                        .consume()
+                        .map_err(|e| anyhow!("Failed to poll perf event: {}", e))?;
+                    if ctx.borrow_error_flag().load(Ordering::Relaxed) {
+                        bail!("Failed to poll perf event. See log for details");
+                    }
                 }
-                std::thread::sleep(Duration::from_millis(
-                    ctx.borrow_sample_config().interval as u64,
-                ));
+                PollToken::SampleMap(idx) => self.sample[idx].sample()?,
+                PollToken::AggregationWindow(idx) => self.windows[idx].flush()?,
             }
-        };
+        }
+        Ok(())
+    }
+    fn log_stats(&self) -> Result<()> {
+        if let Some(timer) = self.stats_timer.as_ref() {
+            timer
+                .wait()
+                .map_err(|e| anyhow!("Failed to read the stats timer: {}", e))?;
+        }
+        for stat in self.stats.snapshot() {
+            info!(
+                "Map `{}`: {} events, {} bytes, {} decode failures, {} lost samples, {} ringbuf errors",
+                stat.map_name,
+                stat.events,
+                stat.bytes,
+                stat.decode_failures,
+                stat.total_lost_samples(),
+                stat.ringbuf_errors
+            );
+        }
         Ok(())
     }
 }
 
+impl<'a> Drop for EventLoop<'a> {
+    fn drop(&mut self) {
+        // Export the last window, which is usually cut short by the termination
+        for window in self.windows.iter() {
+            if let Err(e) = window.exporter.flush_aggregation(&window.map_name) {
+                warn!("Failed to export the last aggregation window: {:?}", e);
+            }
+        }
+    }
+}
+
 impl BpfSkeleton {
     #[inline]
     pub(crate) fn wait_for_no_export_program(&self) -> Result<()> {
         program_poll_loop!(self.handle, {
-            std::hint::spin_loop();
-            std::thread::sleep(Duration::from_millis(1));
+            // There is nothing to poll, so just sleep until being terminated
+            self.handle.wait_for_terminate();
         });
         Ok(())
     }
 
-    pub(crate) fn build_ringbuf_poller(
+    /// Build an event loop which polls all the provided sources
+    pub(crate) fn build_event_loop<'a>(
         &self,
-        map: &Map,
-        exporter: Arc<EventExporter>,
-    ) -> Result<RingBufPollerContext> {
-        let ctx = RingBufPollerContextTryBuilder {
-            exporter,
-            event_processor_builder: |v: &Arc<EventExporter>| {
-                let event_processor = match &v.internal_impl {
-                    ExporterInternalImplementation::BufferValueProcessor {
-                        event_processor,
-                        ..
-                    } => &**event_processor,
-                    _ => bail!("Expected the exporter uses ringbuf processor"),
-                };
-                Ok(event_processor)
-            },
-            ringbuf_builder: |event_processor| {
-                let mut builder = RingBufferBuilder::new();
-                builder
-                    .add(map, |data: &[u8]| {
-                        if let Err(e) = event_processor.handle_event(data) {
-                            error!("Failed to process event: \n{:?}", e);
-                            -1
-                        } else {
-                            0
-                        }
-                    })
-                    .with_context(|| anyhow!("Failed to add ringbuf callback"))?;
-
-                let ringbuf = builder
-                    .build()
-                    .with_context(|| anyhow!("Failed to build ringbuf poller"))?;
-                Ok(ringbuf)
-            },
-            poll_timeout_ms: self.meta.poll_timeout_ms as u64,
+        sources: Vec<ExportSource<'a>>,
+    ) -> Result<EventLoop<'a>> {
+        let epoll = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC)
+            .map_err(|e| anyhow!("Failed to create epoll fd: {}", e))?;
+        let mut event_loop = EventLoop {
+            // SAFETY: The fd was just created by us, and will only be owned here
+            epoll: unsafe { OwnedFd::from_raw_fd(epoll) },
+            tokens: vec![],
+            handle: self.handle.clone(),
+            stats: self.stats.clone(),
+            stats_timer: None,
+            ringbuf: None,
+            perf: vec![],
+            sample: vec![],
+            windows: vec![],
+            poll_timeout_ms: self.meta.poll_timeout_ms as isize,
+        };
+        event_loop.register(self.handle.wakeup_fd(), PollToken::Wakeup)?;
+        if self.meta.stats_interval_ms != 0 {
+            let timer = build_interval_timer(self.meta.stats_interval_ms)
+                .with_context(|| anyhow!("Failed to create stats timer"))?;
+            event_loop.register(timer.as_raw_fd(), PollToken::StatsLog)?;
+            event_loop.stats_timer = Some(timer);
         }
-        .try_build()?;
-
-        Ok(ctx)
+        // Statistics only describe the maps being polled now
+        self.stats.clear();
+        let mut ringbuf_exporters = vec![];
+        for source in sources.into_iter() {
+            let (map, exporter) = match &source {
+                ExportSource::RingBuf(map, exporter)
+                | ExportSource::PerfEvent(map, exporter)
+                | ExportSource::SampleMap(map, exporter, _) => (map, exporter),
+            };
+            if let Some(window) = exporter.aggregation_window() {
+                let timer =
+                    build_interval_timer(window.as_millis() as usize).with_context(|| {
+                        anyhow!("Failed to create aggregation timer for `{}`", map.name())
+                    })?;
+                let fd = timer.as_raw_fd();
+                event_loop.windows.push(AggregationWindowSource {
+                    map_name: map.name().to_string(),
+                    exporter: exporter.clone(),
+                    timer,
+                });
+                event_loop.register(
+                    fd,
+                    PollToken::AggregationWindow(event_loop.windows.len() - 1),
+                )?;
+            }
+            match source {
+                ExportSource::RingBuf(map, exporter) => {
+                    let stats = self.stats.register(map.name());
+                    ringbuf_exporters.push((map, exporter, stats));
+                }
+                ExportSource::PerfEvent(map, exporter) => {
+                    let stats = self.stats.register(map.name());
+                    let ctx = self
+                        .build_perfevent_poller(map, exporter, stats)
+                        .with_context(|| anyhow!("Failed to build perfevent poller"))?;
+                    let fd = ctx.borrow_perf().epoll_fd();
+                    event_loop.register(fd, PollToken::PerfEvent(event_loop.perf.len()))?;
+                    event_loop.perf.push(ctx);
+                }
+                ExportSource::SampleMap(map, exporter, sample_config) => {
+                    let ctx = self
+                        .build_sample_map_poller(map, exporter, sample_config)
+                        .with_context(|| anyhow!("Failed to build sample map poller"))?;
+                    let timer =
+                        build_interval_timer(sample_config.interval).with_context(|| {
+                            anyhow!("Failed to create sample timer for `{}`", map.name())
+                        })?;
+                    let fd = timer.as_raw_fd();
+                    let stats = self.stats.register(map.name());
+                    event_loop
+                        .sample
+                        .push(SampleMapSource { ctx, timer, stats });
+                    event_loop.register(fd, PollToken::SampleMap(event_loop.sample.len() - 1))?;
+                }
+            }
+        }
+        if !ringbuf_exporters.is_empty() {
+            let ctx = build_ringbuf_poller(ringbuf_exporters)
+                .with_context(|| anyhow!("Failed to build ringbuf poller"))?;
+            let fd = ctx.borrow_ringbuf().epoll_fd();
+            event_loop.ringbuf = Some(ctx);
+            event_loop.register(fd, PollToken::RingBuf)?;
+        }
+        debug!(
+            "Event loop built with {} polled fds",
+            event_loop.tokens.len()
+        );
+        Ok(event_loop)
     }
+
     #[inline]
     pub(crate) fn build_perfevent_poller(
         &self,
         map: &Map,
         exporter: Arc<EventExporter>,
+        stats: Arc<MapStatsCounter>,
     ) -> Result<PerfEventPollerContext> {
+        let map_name = map.name().to_string();
         let ctx = PerfEventPollerContextTryBuilder {
             exporter,
             error_flag: AtomicBool::new(false),
@@ -204,19 +404,29 @@ impl BpfSkeleton {
                 };
                 Ok(event_processor)
             },
-            perf_builder: |processor, error_flag: &AtomicBool| {
+            perf_builder: |exporter: &Arc<EventExporter>, processor, error_flag: &AtomicBool| {
+                let sample_stats = stats.clone();
+                let sample_map_name = map_name.clone();
                 let perf = PerfBufferBuilder::new(map)
-                    .sample_cb(|_cpu: i32, data: &[u8]| {
-                        if let Err(e) = processor.handle_event(data) {
-                            error!("Failed to handle event for perf array: \n{:?}", e);
-                            error_flag.store(true, Ordering::Relaxed);
+                    .sample_cb(move |cpu: i32, data: &[u8]| {
+                        let sequence = sample_stats.record_event(data.len());
+                        let meta = EventMeta::now(&sample_map_name, Some(cpu), sequence);
+                        if let Err(e) = processor.handle_event(&meta, data) {
+                            sample_stats.record_decode_failure();
+                            if let Err(e) = exporter.handle_decode_failure(&meta, e, None, data) {
+                                error!("Failed to handle event for perf array: \n{:?}", e);
+                                error_flag.store(true, Ordering::Relaxed);
+                            }
                         }
                     })
+                    .lost_cb(move |cpu: i32, count: u64| {
+                        warn!("Lost {} events on CPU #{} of `{}`", count, cpu, map_name);
+                        stats.record_lost(cpu, count);
+                    })
                     .build()
                     .with_context(|| anyhow!("Failed to build perf event"))?;
                 Ok(perf)
             },
-            poll_timeout_ms: self.meta.poll_timeout_ms as u64,
         }
         .try_build()?;
         Ok(ctx)
@@ -247,3 +457,63 @@ impl BpfSkeleton {
         Ok(ctx)
     }
 }
+
+fn build_ringbuf_poller(
+    exporters: Vec<(&Map, Arc<EventExporter>, Arc<MapStatsCounter>)>,
+) -> Result<RingBufPollerContext<'_>> {
+    let ctx = RingBufPollerContextTryBuilder {
+        exporters,
+        ringbuf_builder: |exporters| {
+            let mut builder = RingBufferBuilder::new();
+            for (map, exporter, stats) in exporters.iter() {
+                let event_processor = match &exporter.internal_impl {
+                    ExporterInternalImplementation::BufferValueProcessor {
+                        event_processor,
+                        ..
+                    } => &**event_processor,
+                    _ => bail!("Expected the exporter uses ringbuf processor"),
+                };
+                builder
+                    .add(map, move |data: &[u8]| {
+                        let sequence = stats.record_event(data.len());
+                        let meta = EventMeta::now(map.name(), None, sequence);
+                        if let Err(e) = event_processor.handle_event(&meta, data) {
+                            stats.record_decode_failure();
+                            if let Err(e) = exporter.handle_decode_failure(&meta, e, None, data) {
+                                error!("Failed to process event: \n{:?}", e);
+                                // Returning an error stops the consumption of the ringbuf
+                                stats.record_ringbuf_error();
+                                return -1;
+                            }
+                        }
+                        0
+                    })
+                    .with_context(|| {
+                        anyhow!("Failed to add ringbuf callback for `{}`", map.name())
+                    })?;
+            }
+            let ringbuf = builder
+                .build()
+                .with_context(|| anyhow!("Failed to build ringbuf poller"))?;
+            Ok(ringbuf)
+        },
+    }
+    .try_build()?;
+    Ok(ctx)
+}
+
+fn build_interval_timer(interval_ms: usize) -> Result<TimerFd> {
+    // A zero interval would disarm the timer, while it used to mean sampling as often as possible
+    let interval_ms = interval_ms.max(1);
+    let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::TFD_CLOEXEC)
+        .map_err(|e| anyhow!("Failed to create timerfd: {}", e))?;
+    timer
+        .set(
+            Expiration::Interval(TimeSpec::from_duration(Duration::from_millis(
+                interval_ms as u64,
+            ))),
+            TimerSetTimeFlags::empty(),
+        )
+        .map_err(|e| anyhow!("Failed to arm timerfd: {}", e))?;
+    Ok(timer)
+}
@@ -0,0 +1,96 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::{fs::File, os::fd::AsRawFd, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use libbpf_rs::Program;
+use log::debug;
+
+use crate::meta::{CgroupProgExtraMeta, ProgMeta};
+
+use super::AttachLink;
+
+/// Where cgroup v2 is usually mounted
+const CGROUP_MOUNT_POINT: &str = "/sys/fs/cgroup";
+
+/// Find the path of the cgroup v2 in the content of `/proc/<pid>/cgroup`, such as `/system.slice/docker-<id>.scope`
+fn parse_cgroup_v2_path(content: &str) -> Option<&str> {
+    content.lines().find_map(|v| v.strip_prefix("0::"))
+}
+
+/// Resolve the cgroup to attach to. If the pid is provided, the cgroup of the process under the mount point is used
+/// There is no default, since attaching to the root cgroup affects every process
+fn resolve_cgroup(extra_meta: &CgroupProgExtraMeta) -> Result<String> {
+    let Some(pid) = extra_meta.pid else {
+        return extra_meta.cgroup.clone().ok_or_else(|| {
+            anyhow!("No cgroup to attach to. Provide `cgroup` or `pid` in the meta, or `--cgroup` or `--cgroup-pid` from the command line")
+        });
+    };
+    let mount_point = extra_meta.cgroup.as_deref().unwrap_or(CGROUP_MOUNT_POINT);
+    let content = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .with_context(|| anyhow!("Failed to read cgroups of process {}", pid))?;
+    let Some(path) = parse_cgroup_v2_path(&content) else {
+        bail!("Process {} is not in a cgroup v2", pid);
+    };
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return Ok(mount_point.to_string());
+    }
+    Ok(Path::new(mount_point)
+        .join(path)
+        .to_string_lossy()
+        .to_string())
+}
+
+pub(crate) fn attach_cgroup(program: &mut Program, meta: &ProgMeta) -> Result<AttachLink> {
+    let extra_meta = serde_json::from_value::<CgroupProgExtraMeta>(meta.others.clone())
+        .with_context(|| anyhow!("Failed to deserialize cgroup extra meta"))?;
+    let path = resolve_cgroup(&extra_meta)?;
+    // The link holds the cgroup, so the fd could be closed once attached
+    let cgroup = File::open(&path).with_context(|| anyhow!("Failed to open cgroup `{}`", path))?;
+    debug!("Attaching {} to cgroup {}", program.section(), path);
+    let link = program
+        .attach_cgroup(cgroup.as_raw_fd())
+        .with_context(|| anyhow!("Failed to attach to cgroup `{}`", path))?;
+    Ok(AttachLink::BpfLink(link))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cgroup_v2_path, resolve_cgroup};
+    use crate::meta::CgroupProgExtraMeta;
+
+    #[test]
+    fn test_resolve_cgroup() {
+        assert_eq!(
+            parse_cgroup_v2_path("12:pids:/docker/abc\n0::/system.slice/docker-abc.scope\n"),
+            Some("/system.slice/docker-abc.scope")
+        );
+        assert_eq!(parse_cgroup_v2_path("12:pids:/docker/abc\n"), None);
+        let meta = CgroupProgExtraMeta {
+            cgroup: Some("/sys/fs/cgroup/app.slice".into()),
+            pid: None,
+        };
+        assert_eq!(resolve_cgroup(&meta).unwrap(), "/sys/fs/cgroup/app.slice");
+        // The root cgroup should be explicit
+        assert!(resolve_cgroup(&CgroupProgExtraMeta::default()).is_err());
+
+        let meta = CgroupProgExtraMeta {
+            cgroup: None,
+            pid: Some(std::process::id() as i32),
+        };
+        let content = std::fs::read_to_string("/proc/self/cgroup").unwrap();
+        match parse_cgroup_v2_path(&content) {
+            Some("/") => assert_eq!(resolve_cgroup(&meta).unwrap(), "/sys/fs/cgroup"),
+            Some(path) => assert_eq!(
+                resolve_cgroup(&meta).unwrap(),
+                format!("/sys/fs/cgroup{}", path)
+            ),
+            None => assert!(resolve_cgroup(&meta).is_err()),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use nix::net::if_::{if_nameindex, if_nametoindex};
+
+/// Stands for all interfaces except the loopback
+pub(crate) const ALL_INTERFACES: &str = "all";
+
+/// Resolve the interfaces, which are names, indexes or `all`, into indexes without duplicates. `ifindex` is used if no interface was provided
+pub(crate) fn resolve_interfaces(ifaces: &[String], ifindex: i32) -> Result<Vec<i32>> {
+    if ifaces.is_empty() {
+        return Ok(vec![ifindex]);
+    }
+    let mut result = vec![];
+    for iface in ifaces.iter() {
+        let indexes = if iface == ALL_INTERFACES {
+            if_nameindex()
+                .with_context(|| anyhow!("Failed to list interfaces"))?
+                .iter()
+                .filter(|v| v.name().to_bytes() != b"lo")
+                .map(|v| v.index() as i32)
+                .collect()
+        } else if let Ok(index) = iface.parse::<i32>() {
+            vec![index]
+        } else {
+            vec![if_nametoindex(iface.as_str())
+                .with_context(|| anyhow!("Interface `{}` not found", iface))?
+                as i32]
+        };
+        for index in indexes {
+            if !result.contains(&index) {
+                result.push(index);
+            }
+        }
+    }
+    if result.is_empty() {
+        bail!("No interface to hook in {:?}", ifaces);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_interfaces;
+
+    #[test]
+    fn test_resolve_interfaces() {
+        assert_eq!(resolve_interfaces(&[], 3).unwrap(), vec![3]);
+        assert_eq!(
+            resolve_interfaces(&["2".into(), "lo".into(), "1".into()], 3).unwrap(),
+            vec![2, 1]
+        );
+        assert!(resolve_interfaces(&["no-such-iface0".into()], 1).is_err());
+        assert!(!resolve_interfaces(&["all".into()], 1).unwrap().contains(&0));
+    }
+}
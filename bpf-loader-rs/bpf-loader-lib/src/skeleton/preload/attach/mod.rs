@@ -4,39 +4,84 @@
 //! All rights reserved.
 //!
 
-use std::os::fd::{self, FromRawFd};
+use std::{
+    ffi::CStr,
+    fs::File,
+    os::fd::{self, FromRawFd, OwnedFd},
+};
 
 use libbpf_rs::{
-    libbpf_sys::{bpf_tc_hook, bpf_tc_hook_destroy, bpf_xdp_attach_opts, bpf_xdp_detach},
+    libbpf_sys::{
+        bpf_object, bpf_object__next_program, bpf_program__section_name, bpf_tc_detach,
+        bpf_tc_hook, bpf_tc_opts, bpf_xdp_attach_opts, bpf_xdp_detach,
+    },
     Link,
 };
 use log::{debug, error};
 
+pub(crate) mod cgroup;
+pub(crate) mod iface;
+pub(crate) mod multi;
+pub(crate) mod netfilter;
+pub(crate) mod netns_prog;
 pub(crate) mod perf;
+pub(crate) mod setns;
+pub(crate) mod socket;
 pub(crate) mod tc;
+pub(crate) mod uprobe;
 pub(crate) mod xdp;
 
+pub(crate) use cgroup::attach_cgroup;
+pub(crate) use multi::{attach_kprobe_multi, attach_uprobe_multi, has_multi_target};
+pub(crate) use netfilter::attach_netfilter;
+pub(crate) use netns_prog::attach_netns;
 pub(crate) use perf::attach_perf_event;
+pub(crate) use socket::attach_socket_filter;
 pub(crate) use tc::attach_tc;
+pub(crate) use uprobe::{attach_uprobe, has_uprobe_target};
 pub(crate) use xdp::attach_xdp;
 
+/// Fix up programs whose sections are unknown to the libbpf in use, such as `netfilter` and `uprobe.multi`
+///
+/// # Safety
+/// `object` should point to an opened but not loaded object
+pub(crate) unsafe fn prepare_programs(object: *mut bpf_object) {
+    let mut prog = std::ptr::null_mut();
+    loop {
+        prog = bpf_object__next_program(object, prog);
+        if prog.is_null() {
+            break;
+        }
+        let Ok(section) = CStr::from_ptr(bpf_program__section_name(prog)).to_str() else {
+            continue;
+        };
+        netfilter::prepare_netfilter_program(prog, section);
+        multi::prepare_uprobe_multi_program(prog, section);
+    }
+}
+
 pub(crate) enum AttachLink {
     BpfLink(Link),
-    TCAttach(Box<bpf_tc_hook>),
+    TCAttach(Box<bpf_tc_hook>, Box<bpf_tc_opts>),
+    /// Detached when the fd is closed, such as a tcx link, or a socket with a filter
+    Fd(OwnedFd),
     XDPAttach(i32, u32, Box<bpf_xdp_attach_opts>),
     PerfEventAttachWithFd(Link, i32),
+    /// Links attached in another network namespace, which are detached in it
+    InNetns(Vec<AttachLink>, File),
 }
 
 impl Drop for AttachLink {
     fn drop(&mut self) {
         match self {
             AttachLink::BpfLink(_link) => {}
-            AttachLink::TCAttach(hook) => {
-                let err = unsafe { bpf_tc_hook_destroy(&mut **hook) };
+            AttachLink::TCAttach(hook, opts) => {
+                let err = unsafe { bpf_tc_detach(&**hook, &**opts) };
                 if err != 0 {
-                    error!("Failed to destroy tc hook: \n{:?}", err);
+                    error!("Failed to detach tc: \n{:?}", err);
                 }
             }
+            AttachLink::Fd(_fd) => {}
             AttachLink::XDPAttach(ifindex, flags, opts) => {
                 let err = unsafe { bpf_xdp_detach(*ifindex, *flags, &**opts) };
                 if err != 0 {
@@ -48,6 +93,18 @@ impl Drop for AttachLink {
                 // SAFETY: fds are created by us, they are gurateended to be correct
                 let _ = unsafe { fd::OwnedFd::from_raw_fd(*fd as _) };
             }
+            AttachLink::InNetns(links, netns) => match setns::NetnsGuard::enter(netns) {
+                Ok(_guard) => links.clear(),
+                Err(e) => {
+                    // tc and xdp links are detached by ifindex, which may refer to an unrelated interface in the current namespace. So leak them instead
+                    error!(
+                        "Failed to enter the network namespace, leaking {} links: {:?}",
+                        links.len(),
+                        e
+                    );
+                    std::mem::forget(std::mem::take(links));
+                }
+            },
         }
     }
 }
@@ -0,0 +1,329 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::{
+    collections::BTreeSet,
+    ffi::{c_char, CString},
+    ptr::NonNull,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use libbpf_rs::{
+    libbpf_sys::{
+        bpf_kprobe_multi_opts, bpf_object, bpf_object__find_program_by_name, bpf_program,
+        bpf_program__attach_kprobe_multi_opts, bpf_program__flags, bpf_program__set_flags,
+        bpf_program__set_type, BPF_F_SLEEPABLE, BPF_PROG_TYPE_KPROBE,
+    },
+    Link, Program, UprobeOpts,
+};
+use log::debug;
+use object::{Object, SymbolKind};
+
+use crate::meta::{
+    section::{
+        parse_kprobe_multi_section, parse_uprobe_multi_section, parse_uprobe_section, UprobeKind,
+    },
+    MultiProbeProgExtraMeta, ProgMeta,
+};
+
+use super::{uprobe::parse_uprobe_target, AttachLink};
+
+/// Split the target of `uprobe.multi` sections, `<binary>:<pattern>`, into the binary and the pattern
+fn parse_uprobe_multi_target(target: &str) -> (Option<&str>, Option<&str>) {
+    let (binary, pattern) = match target.rsplit_once(':') {
+        Some((binary, pattern)) => (binary, Some(pattern)),
+        None => (target, None),
+    };
+    (
+        Some(binary).filter(|v| !v.is_empty()),
+        pattern.filter(|v| !v.is_empty()),
+    )
+}
+
+/// The libbpf in use doesn't know `uprobe.multi` sections. Such programs are attached to each function as plain uprobes, so they are set as kprobe programs
+///
+/// # Safety
+/// `prog` should point to a program in an opened but not loaded object
+pub(crate) unsafe fn prepare_uprobe_multi_program(prog: *mut bpf_program, section: &str) {
+    if parse_uprobe_multi_section(section).is_none() {
+        return;
+    }
+    debug!("Setting the type of uprobe.multi program in {}", section);
+    bpf_program__set_type(prog, BPF_PROG_TYPE_KPROBE);
+    if section
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .ends_with(".s")
+    {
+        bpf_program__set_flags(prog, bpf_program__flags(prog) | BPF_F_SLEEPABLE);
+    }
+}
+
+/// Whether the program is a kprobe.multi or uprobe program, and the functions to attach were provided in the meta, or it's a uprobe.multi program
+/// Such programs are attached manually, even if the section name could be auto-attached
+pub(crate) fn has_multi_target(section: &str, meta: &ProgMeta) -> bool {
+    // uprobe.multi programs could only be attached by us
+    if parse_uprobe_multi_section(section).is_some() {
+        return true;
+    }
+    let is_multi = parse_kprobe_multi_section(section).is_some()
+        || matches!(
+            parse_uprobe_section(section),
+            Some((UprobeKind::Uprobe { .. }, _))
+        );
+    is_multi
+        && serde_json::from_value::<MultiProbeProgExtraMeta>(meta.others.clone())
+            .map(|v| v.has_target())
+            .unwrap_or(false)
+}
+
+/// Match the string with a glob pattern, which supports `*` and `?`
+pub(crate) fn glob_match(pattern: &str, s: &str) -> bool {
+    let (pattern, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut p, mut i) = (0, 0);
+    // Where the last `*` was, and where the string was when it was met
+    let mut backtrack = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, i));
+                p += 1;
+                continue;
+            }
+            Some(c) if *c == b'?' || *c == s[i] => {
+                p += 1;
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        // Let the last `*` consume one more char
+        match backtrack {
+            Some((star, start)) => {
+                backtrack = Some((star, start + 1));
+                p = star + 1;
+                i = start + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn load_extra_meta(meta: &ProgMeta) -> Result<MultiProbeProgExtraMeta> {
+    let extra_meta = serde_json::from_value::<MultiProbeProgExtraMeta>(meta.others.clone())
+        .with_context(|| anyhow!("Failed to deserialize multi-probe extra meta"))?;
+    if extra_meta.pattern.is_some() && !extra_meta.symbols.is_empty() {
+        bail!("`pattern` and `symbols` could not be provided at the same time");
+    }
+    if !extra_meta.cookies.is_empty() && extra_meta.cookies.len() != extra_meta.symbols.len() {
+        bail!(
+            "Got {} symbols but {} cookies",
+            extra_meta.symbols.len(),
+            extra_meta.cookies.len()
+        );
+    }
+    Ok(extra_meta)
+}
+
+pub(crate) fn attach_kprobe_multi(
+    object: NonNull<bpf_object>,
+    program: &Program,
+    meta: &ProgMeta,
+) -> Result<AttachLink> {
+    let extra_meta = load_extra_meta(meta)?;
+    let Some((retprobe, sec_pattern)) = parse_kprobe_multi_section(program.section()) else {
+        bail!("`{}` is not a kprobe.multi section", program.section());
+    };
+    let name = CString::new(program.name())?;
+    // SAFETY: The object is loaded, and the name lives during the call
+    let prog_ptr = unsafe { bpf_object__find_program_by_name(object.as_ptr(), name.as_ptr()) };
+    if prog_ptr.is_null() {
+        bail!("Program named `{}` not found in libbpf", meta.name);
+    }
+    let mut opts = bpf_kprobe_multi_opts {
+        sz: std::mem::size_of::<bpf_kprobe_multi_opts>() as _,
+        retprobe,
+        ..Default::default()
+    };
+    let pattern = match (&extra_meta.pattern, sec_pattern) {
+        (Some(pattern), _) => Some(CString::new(pattern.as_str())?),
+        (None, Some(pattern)) if extra_meta.symbols.is_empty() => Some(CString::new(pattern)?),
+        _ => None,
+    };
+    let symbols = extra_meta
+        .symbols
+        .iter()
+        .map(|v| CString::new(v.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut symbol_ptrs = symbols.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
+    if pattern.is_none() {
+        if symbols.is_empty() {
+            bail!("Neither pattern nor symbols of the kprobe.multi program is provided");
+        }
+        opts.syms = symbol_ptrs.as_mut_ptr();
+        opts.cnt = symbol_ptrs.len() as _;
+        if !extra_meta.cookies.is_empty() {
+            opts.cookies = extra_meta.cookies.as_ptr();
+        }
+    }
+    debug!(
+        "Attaching kprobe.multi to {:?}{:?}, retprobe {}",
+        pattern, extra_meta.symbols, retprobe
+    );
+    // SAFETY: The program is valid, and the opts along with the pointers in it live during the call
+    let link = unsafe {
+        bpf_program__attach_kprobe_multi_opts(
+            prog_ptr,
+            pattern
+                .as_ref()
+                .map(|v| v.as_ptr())
+                .unwrap_or(std::ptr::null::<c_char>()),
+            &opts,
+        )
+    };
+    let Some(link) = NonNull::new(link) else {
+        return Err(anyhow!(std::io::Error::last_os_error()))
+            .with_context(|| anyhow!("Failed to attach kprobe.multi"));
+    };
+    // SAFETY: The link was just created by libbpf
+    Ok(AttachLink::BpfLink(unsafe { Link::from_ptr(link) }))
+}
+
+/// Find the functions matching the pattern in the binary
+fn find_functions(binary: &str, pattern: &str) -> Result<Vec<String>> {
+    let data =
+        std::fs::read(binary).with_context(|| anyhow!("Failed to read binary `{}`", binary))?;
+    let file = object::File::parse(&data)
+        .map_err(|e| anyhow!("Failed to parse binary `{}`: {}", binary, e))?;
+    let result = file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .filter(|v| v.kind() == SymbolKind::Text && !v.is_undefined())
+        .filter_map(|v| v.name())
+        .filter(|v| glob_match(pattern, v))
+        .map(String::from)
+        .collect::<BTreeSet<_>>();
+    Ok(result.into_iter().collect())
+}
+
+/// The libbpf in use has no uprobe.multi links, so the program is attached to each of the functions
+pub(crate) fn attach_uprobe_multi(
+    program: &mut Program,
+    meta: &ProgMeta,
+) -> Result<Vec<AttachLink>> {
+    let extra_meta = load_extra_meta(meta)?;
+    let section = program.section().to_string();
+    let (retprobe, sec_binary, sec_pattern) = match parse_uprobe_multi_section(&section) {
+        Some((retprobe, target)) => {
+            let (binary, pattern) = target.map(parse_uprobe_multi_target).unwrap_or_default();
+            (retprobe, binary, pattern)
+        }
+        None => {
+            let Some((UprobeKind::Uprobe { retprobe }, target)) = parse_uprobe_section(&section)
+            else {
+                bail!("`{}` is not a uprobe section", section);
+            };
+            let binary = match target {
+                Some(target) => Some(parse_uprobe_target(target)?.0).filter(|v| !v.is_empty()),
+                None => None,
+            };
+            (retprobe, binary, None)
+        }
+    };
+    let binary = extra_meta
+        .binary
+        .as_deref()
+        .or(sec_binary)
+        .ok_or_else(|| anyhow!("Binary of the uprobe is not provided"))?;
+    // The pattern in the meta takes precedence over the one in the section name
+    let pattern = match (&extra_meta.pattern, sec_pattern) {
+        (Some(pattern), _) => Some(pattern.as_str()),
+        (None, Some(pattern)) if extra_meta.symbols.is_empty() => Some(pattern),
+        _ => None,
+    };
+    let symbols = match pattern {
+        Some(pattern) => {
+            let symbols = find_functions(binary, pattern)?;
+            if symbols.is_empty() {
+                bail!("No function in `{}` matches `{}`", binary, pattern);
+            }
+            symbols
+        }
+        None if extra_meta.symbols.is_empty() => {
+            bail!("Neither pattern nor symbols of the uprobe program is provided")
+        }
+        None => extra_meta.symbols.clone(),
+    };
+    let mut links = vec![];
+    for (idx, symbol) in symbols.iter().enumerate() {
+        let cookie = extra_meta.cookies.get(idx).copied().unwrap_or_default();
+        debug!(
+            "Attaching uprobe to {}:{}, cookie {}, pid {}, retprobe {}",
+            binary, symbol, cookie, extra_meta.pid, retprobe
+        );
+        let link = program
+            .attach_uprobe_with_opts(
+                extra_meta.pid,
+                binary,
+                0,
+                UprobeOpts {
+                    retprobe,
+                    cookie,
+                    func_name: symbol.clone(),
+                    ..Default::default()
+                },
+            )
+            .with_context(|| anyhow!("Failed to attach uprobe to `{}:{}`", binary, symbol))?;
+        links.push(AttachLink::BpfLink(link));
+    }
+    Ok(links)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{glob_match, has_multi_target, parse_uprobe_multi_target};
+    use crate::tests::prog;
+
+    #[test]
+    fn test_multi_probe() {
+        assert!(glob_match("tcp_*", "tcp_sendmsg"));
+        assert!(glob_match("*_read*", "vfs_read_iter"));
+        assert!(glob_match("vfs_?ead", "vfs_read"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("tcp_*", "udp_sendmsg"));
+        assert!(!glob_match("vfs_?ead", "vfs_rread"));
+        assert!(!glob_match("a*b", "acbc"));
+
+        let prog = |attach: &str, others| prog("prog", attach, others);
+        let meta = prog("kprobe.multi", json!({"symbols": ["a", "b"]}));
+        assert!(has_multi_target(&meta.attach, &meta));
+        let meta = prog("uprobe", json!({"pattern": "SSL_*"}));
+        assert!(has_multi_target(&meta.attach, &meta));
+        let meta = prog("usdt", json!({"pattern": "a"}));
+        assert!(!has_multi_target(&meta.attach, &meta));
+        let meta = prog("kprobe.multi/tcp_*", json!({}));
+        assert!(!has_multi_target(&meta.attach, &meta));
+        let meta = prog("uprobe.multi//usr/lib/libc.so.6:malloc*", json!({}));
+        assert!(has_multi_target(&meta.attach, &meta));
+    }
+
+    #[test]
+    fn test_uprobe_multi_target() {
+        assert_eq!(
+            parse_uprobe_multi_target("/usr/lib/libc.so.6:malloc*"),
+            (Some("/usr/lib/libc.so.6"), Some("malloc*"))
+        );
+        assert_eq!(
+            parse_uprobe_multi_target("/bin/bash"),
+            (Some("/bin/bash"), None)
+        );
+        assert_eq!(parse_uprobe_multi_target(":SSL_*"), (None, Some("SSL_*")));
+    }
+}
@@ -0,0 +1,115 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+//! Netfilter programs were introduced in kernel 6.4, which is unknown to the libbpf in use. So their types are set manually after the object is opened, and links are created through the bpf syscall directly.
+
+use std::os::fd::{FromRawFd, OwnedFd};
+
+use anyhow::{anyhow, Context, Result};
+use libbpf_rs::{
+    libbpf_sys::{
+        bpf_attach_type, bpf_prog_type, bpf_program, bpf_program__set_expected_attach_type,
+        bpf_program__set_type,
+    },
+    Program,
+};
+use log::debug;
+use nix::libc;
+
+use crate::meta::{NetfilterFamily, NetfilterHook, NetfilterProgExtraMeta, ProgMeta};
+
+use super::AttachLink;
+
+const BPF_PROG_TYPE_NETFILTER: bpf_prog_type = 32;
+const BPF_NETFILTER: bpf_attach_type = 45;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_F_NETFILTER_IP_DEFRAG: u32 = 1;
+
+impl NetfilterFamily {
+    fn to_value(self) -> u32 {
+        match self {
+            NetfilterFamily::Ipv4 => 2,
+            NetfilterFamily::Ipv6 => 10,
+        }
+    }
+}
+
+impl NetfilterHook {
+    fn to_value(self) -> u32 {
+        match self {
+            NetfilterHook::Prerouting => 0,
+            NetfilterHook::LocalIn => 1,
+            NetfilterHook::Forward => 2,
+            NetfilterHook::LocalOut => 3,
+            NetfilterHook::Postrouting => 4,
+        }
+    }
+}
+
+/// The `link_create` part of `union bpf_attr`, with the netfilter fields
+#[repr(C)]
+#[derive(Default)]
+struct NetfilterLinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+    pf: u32,
+    hooknum: u32,
+    priority: i32,
+    netfilter_flags: u32,
+}
+
+/// Set the type of the program if it's a netfilter one
+///
+/// # Safety
+/// `prog` should point to a program in an opened but not loaded object
+pub(crate) unsafe fn prepare_netfilter_program(prog: *mut bpf_program, section: &str) {
+    if section == "netfilter" {
+        debug!("Setting the type of netfilter program in {}", section);
+        bpf_program__set_type(prog, BPF_PROG_TYPE_NETFILTER);
+        bpf_program__set_expected_attach_type(prog, BPF_NETFILTER);
+    }
+}
+
+pub(crate) fn attach_netfilter(program: &Program, meta: &ProgMeta) -> Result<AttachLink> {
+    let extra_meta = serde_json::from_value::<NetfilterProgExtraMeta>(meta.others.clone())
+        .with_context(|| anyhow!("Failed to deserialize netfilter extra meta"))?;
+    let attr = NetfilterLinkCreateAttr {
+        prog_fd: program.fd() as u32,
+        attach_type: BPF_NETFILTER,
+        pf: extra_meta.family.to_value(),
+        hooknum: extra_meta.hook.to_value(),
+        priority: extra_meta.priority,
+        netfilter_flags: if extra_meta.defrag {
+            BPF_F_NETFILTER_IP_DEFRAG
+        } else {
+            0
+        },
+        ..Default::default()
+    };
+    // SAFETY: attr is valid during the call, and the kernel accepts a prefix of `union bpf_attr`
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_LINK_CREATE,
+            &attr as *const NetfilterLinkCreateAttr,
+            std::mem::size_of::<NetfilterLinkCreateAttr>(),
+        )
+    };
+    if fd < 0 {
+        return Err(anyhow!(std::io::Error::last_os_error())).with_context(|| {
+            anyhow!(
+                "Failed to create netfilter link on {:?} {:?}, priority {}",
+                extra_meta.family,
+                extra_meta.hook,
+                extra_meta.priority
+            )
+        });
+    }
+    // SAFETY: The fd was just created by the kernel
+    Ok(AttachLink::Fd(unsafe { OwnedFd::from_raw_fd(fd as _) }))
+}
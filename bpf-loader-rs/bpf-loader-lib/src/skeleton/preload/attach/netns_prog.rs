@@ -0,0 +1,29 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::os::fd::AsRawFd;
+
+use anyhow::{anyhow, Context, Result};
+use libbpf_rs::Program;
+
+use crate::meta::{NetnsProgExtraMeta, ProgMeta};
+
+use super::{setns::open_netns, AttachLink};
+
+/// Attach `sk_lookup` or `flow_dissector` programs to the network namespace
+pub(crate) fn attach_netns(program: &mut Program, meta: &ProgMeta) -> Result<AttachLink> {
+    let extra_meta = serde_json::from_value::<NetnsProgExtraMeta>(meta.others.clone())
+        .with_context(|| anyhow!("Failed to deserialize netns extra meta"))?;
+    // The link holds the namespace, so the fd could be closed once attached
+    let netns = open_netns(&extra_meta.netns)?;
+    let link = program.attach_netns(netns.as_raw_fd()).with_context(|| {
+        anyhow!(
+            "Failed to attach to network namespace `{}`",
+            extra_meta.netns
+        )
+    })?;
+    Ok(AttachLink::BpfLink(link))
+}
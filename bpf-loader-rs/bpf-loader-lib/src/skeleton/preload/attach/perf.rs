@@ -4,71 +4,249 @@
 //! All rights reserved.
 //!
 
+use std::{
+    fs::File,
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd},
+};
+
 use anyhow::{anyhow, bail, Context, Result};
 use libbpf_rs::Program;
-use log::debug;
+use log::{debug, warn};
 use perf_event_open_sys::{
     bindings::{
-        perf_event_attr, perf_event_attr__bindgen_ty_1, PERF_COUNT_HW_CPU_CYCLES,
-        PERF_FLAG_FD_CLOEXEC, PERF_TYPE_HARDWARE,
+        perf_event_attr, perf_event_attr__bindgen_ty_1, PERF_COUNT_SW_CPU_CLOCK,
+        PERF_FLAG_FD_CLOEXEC, PERF_FLAG_PID_CGROUP, PERF_TYPE_HARDWARE, PERF_TYPE_HW_CACHE,
+        PERF_TYPE_RAW, PERF_TYPE_SOFTWARE, PERF_TYPE_TRACEPOINT,
     },
     perf_event_open,
 };
 
-use crate::meta::ProgMeta;
+use crate::meta::{PerfEventProgExtraMeta, PerfEventType, ProgMeta};
 
 use super::AttachLink;
 
-fn init_perf_monitor(freq: u64) -> Result<Vec<i32>> {
-    let nprocs =
-        libbpf_rs::num_possible_cpus().with_context(|| anyhow!("Failed to get processor count"))?;
+const ENOENT: i32 = 2;
+const ENODEV: i32 = 19;
+const EOPNOTSUPP: i32 = 95;
+
+impl PerfEventType {
+    fn to_value(self) -> u32 {
+        match self {
+            PerfEventType::Hardware => PERF_TYPE_HARDWARE,
+            PerfEventType::Software => PERF_TYPE_SOFTWARE,
+            PerfEventType::Tracepoint => PERF_TYPE_TRACEPOINT,
+            PerfEventType::HwCache => PERF_TYPE_HW_CACHE,
+            PerfEventType::Raw => PERF_TYPE_RAW,
+        }
+    }
+}
+
+/// Parse a CPU list like `0-3,8`
+fn parse_cpu_list(s: &str) -> Result<Vec<i32>> {
+    let mut result = vec![];
+    for part in s.trim().split(',').filter(|v| !v.is_empty()) {
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<i32>()
+                .with_context(|| anyhow!("Invalid CPU `{}` in `{}`", v, s))
+        };
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    bail!("Invalid CPU range `{}`", part);
+                }
+                result.extend(start..=end);
+            }
+            None => result.push(parse(part)?),
+        }
+    }
+    if result.is_empty() {
+        bail!("No CPU provided in `{}`", s);
+    }
+    Ok(result)
+}
+
+/// Where the perf events will be opened
+struct PerfEventTarget {
+    pid: i32,
+    /// Holds the cgroup directory open while the events are being opened
+    cgroup: Option<File>,
+    cpus: Vec<i32>,
+    /// Whether CPUs were explicitly provided. If not, offline CPUs are skipped
+    explicit_cpus: bool,
+}
+
+impl PerfEventTarget {
+    fn new(meta: &PerfEventProgExtraMeta) -> Result<Self> {
+        if meta.pid.is_some() && meta.cgroup.is_some() {
+            bail!("`pid` and `cgroup` could not be provided at the same time");
+        }
+        let cgroup = meta
+            .cgroup
+            .as_ref()
+            .map(|path| {
+                File::open(path).with_context(|| anyhow!("Failed to open cgroup `{}`", path))
+            })
+            .transpose()?;
+        let (cpus, explicit_cpus) = match (&meta.cpus, meta.pid) {
+            (Some(cpus), _) => (parse_cpu_list(cpus)?, true),
+            // A process could be followed on any CPU
+            (None, Some(_)) => (vec![-1], true),
+            (None, None) => {
+                let nprocs = libbpf_rs::num_possible_cpus()
+                    .with_context(|| anyhow!("Failed to get processor count"))?;
+                ((0..nprocs as i32).collect(), false)
+            }
+        };
+        if cgroup.is_some() && cpus.contains(&-1) {
+            bail!("Cgroup events should be opened on specified CPUs");
+        }
+        Ok(Self {
+            pid: cgroup
+                .as_ref()
+                .map(|v| v.as_raw_fd())
+                .unwrap_or(meta.pid.unwrap_or(-1)),
+            cgroup,
+            cpus,
+            explicit_cpus,
+        })
+    }
+    /// Open the event on each CPU. Fds are closed if any of them failed
+    fn open(&self, attr: &mut perf_event_attr) -> Result<Vec<OwnedFd>> {
+        let mut flags = PERF_FLAG_FD_CLOEXEC;
+        if self.cgroup.is_some() {
+            flags |= PERF_FLAG_PID_CGROUP;
+        }
+        let mut pefds = vec![];
+        let mut skipped = None;
+        for cpu in self.cpus.iter() {
+            // SAFETY: attr is valid during the call
+            let pefd = unsafe { perf_event_open(attr, self.pid, *cpu, -1, flags as u64) };
+            if pefd < 0 {
+                let err = std::io::Error::last_os_error();
+                if !self.explicit_cpus && err.raw_os_error() == Some(ENODEV) {
+                    debug!("Skip offline cpu {}", cpu);
+                    skipped = Some((*cpu, err));
+                    continue;
+                }
+                return Err(anyhow!(err)).with_context(|| {
+                    anyhow!(
+                        "Failed to call `perf_event_open` on cpu {}, pid {}",
+                        cpu,
+                        self.pid
+                    )
+                });
+            }
+            // SAFETY: The fd was just created by us
+            pefds.push(unsafe { OwnedFd::from_raw_fd(pefd) });
+        }
+        // ENODEV on every CPU means the event is unavailable, such as hardware events without a PMU
+        if let Some((cpu, err)) = skipped.filter(|_| pefds.is_empty()) {
+            return Err(anyhow!(err)).with_context(|| {
+                anyhow!(
+                    "Failed to call `perf_event_open` on any cpu, the last one is cpu {}, pid {}",
+                    cpu,
+                    self.pid
+                )
+            });
+        }
+        Ok(pefds)
+    }
+}
 
+fn init_perf_monitor(meta: &PerfEventProgExtraMeta) -> Result<Vec<OwnedFd>> {
     let mut attrs = perf_event_attr {
         size: std::mem::size_of::<perf_event_attr>() as u32,
-        type_: PERF_TYPE_HARDWARE,
-        config: PERF_COUNT_HW_CPU_CYCLES as u64,
+        type_: meta.event_type.to_value(),
+        config: meta.config,
         ..Default::default()
     };
-
-    attrs.set_freq(1);
     // This fiels stands for
     //  union {
     //      __u64		sample_period;
     //      __u64		sample_freq;
     // };
-    attrs.__bindgen_anon_1 = perf_event_attr__bindgen_ty_1 { sample_freq: freq };
-
-    let mut pefds = vec![];
-    for cpu in 0..nprocs {
-        // SAFETY: attrs is valid during the call
-        let pefd =
-            unsafe { perf_event_open(&mut attrs, -1, cpu as i32, -1, PERF_FLAG_FD_CLOEXEC as u64) };
-        if pefd < 0 {
-            bail!(
-                "Failed to call `perf_event_open`, pefd={}, errno={}",
-                pefd,
-                errno::errno()
+    match (meta.sample_freq, meta.sample_period) {
+        (Some(_), Some(_)) => bail!("`sample_freq` and `sample_period` conflict"),
+        (Some(0), _) | (_, Some(0)) => bail!("Sample frequency or period should not be zero"),
+        (None, Some(period)) => {
+            attrs.__bindgen_anon_1 = perf_event_attr__bindgen_ty_1 {
+                sample_period: period,
+            };
+        }
+        (freq, None) => {
+            attrs.set_freq(1);
+            attrs.__bindgen_anon_1 = perf_event_attr__bindgen_ty_1 {
+                sample_freq: freq.unwrap_or(1),
+            };
+        }
+    }
+    let target = PerfEventTarget::new(meta)?;
+    match target.open(&mut attrs) {
+        Err(e)
+            if meta.fallback_to_cpu_clock
+                && matches!(meta.event_type, PerfEventType::Hardware)
+                && matches!(
+                    e.downcast_ref::<std::io::Error>()
+                        .and_then(|v| v.raw_os_error()),
+                    Some(ENOENT | ENODEV | EOPNOTSUPP)
+                ) =>
+        {
+            warn!(
+                "Hardware perf event is not available ({:#}), falling back to cpu-clock",
+                e
             );
+            attrs.type_ = PERF_TYPE_SOFTWARE;
+            attrs.config = PERF_COUNT_SW_CPU_CLOCK as u64;
+            target.open(&mut attrs)
         }
-        pefds.push(pefd);
+        v => v,
     }
-    Ok(pefds)
 }
 
-pub(crate) fn attach_perf_event(
-    program: &mut Program,
-    _meta: &ProgMeta,
-) -> Result<Vec<AttachLink>> {
+pub(crate) fn attach_perf_event(program: &mut Program, meta: &ProgMeta) -> Result<Vec<AttachLink>> {
     debug!("Attaching perf event: {:?}", program);
+    let extra_meta = serde_json::from_value::<PerfEventProgExtraMeta>(meta.others.clone())
+        .with_context(|| anyhow!("Failed to deserialize perf event extra meta"))?;
 
-    let pefds = init_perf_monitor(1).with_context(|| anyhow!("Failed to init perf monitor"))?;
+    let pefds =
+        init_perf_monitor(&extra_meta).with_context(|| anyhow!("Failed to init perf monitor"))?;
     debug!("Loaded pefds: {:?}", pefds);
     let mut links = vec![];
-    for pefd in pefds.iter() {
-        links.push(AttachLink::PerfEventAttachWithFd(
-            program.attach_perf_event(*pefd).unwrap(),
-            *pefd,
-        ))
+    // Links attached so far will be dropped along with their fds if any attaching fails, and the left fds are closed when `pefds` is dropped
+    for pefd in pefds.into_iter() {
+        let link = program
+            .attach_perf_event(pefd.as_raw_fd())
+            .with_context(|| anyhow!("Failed to attach to pefd {}", pefd.as_raw_fd()))?;
+        links.push(AttachLink::PerfEventAttachWithFd(link, pefd.into_raw_fd()))
     }
     Ok(links)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::parse_cpu_list;
+    use crate::meta::{PerfEventProgExtraMeta, PerfEventType};
+
+    #[test]
+    fn test_perf_event_meta() {
+        assert_eq!(parse_cpu_list("0-3,8").unwrap(), vec![0, 1, 2, 3, 8]);
+        assert_eq!(parse_cpu_list("5").unwrap(), vec![5]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("a").is_err());
+
+        let meta: PerfEventProgExtraMeta = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(meta, PerfEventProgExtraMeta::default());
+        assert!(meta.fallback_to_cpu_clock);
+        let meta: PerfEventProgExtraMeta = serde_json::from_value(
+            json!({"event_type": "software", "sample_freq": 99, "cpus": "0"}),
+        )
+        .unwrap();
+        assert_eq!(meta.event_type, PerfEventType::Software);
+        assert_eq!(meta.sample_freq, Some(99));
+    }
+}
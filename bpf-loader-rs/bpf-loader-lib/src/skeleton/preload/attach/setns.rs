@@ -0,0 +1,82 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::{fs::File, os::fd::AsRawFd};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error};
+use nix::sched::{setns, CloneFlags};
+
+use super::AttachLink;
+
+/// Resolve the network namespace, which is a pid or a path, into a path
+pub(crate) fn resolve_netns_path(netns: &str) -> String {
+    match netns.parse::<i32>() {
+        Ok(pid) => format!("/proc/{}/ns/net", pid),
+        Err(_) => netns.to_string(),
+    }
+}
+
+/// Open the network namespace, which is a pid or a path
+pub(crate) fn open_netns(netns: &str) -> Result<File> {
+    let path = resolve_netns_path(netns);
+    File::open(&path).with_context(|| anyhow!("Failed to open network namespace `{}`", path))
+}
+
+/// Moves the current thread into a network namespace, and back to the original one when dropped
+pub(crate) struct NetnsGuard {
+    origin: File,
+}
+
+impl NetnsGuard {
+    pub(crate) fn enter(netns: &File) -> Result<Self> {
+        let origin = File::open("/proc/thread-self/ns/net")
+            .with_context(|| anyhow!("Failed to open the current network namespace"))?;
+        setns(netns.as_raw_fd(), CloneFlags::CLONE_NEWNET)
+            .with_context(|| anyhow!("Failed to enter the network namespace"))?;
+        Ok(Self { origin })
+    }
+}
+
+impl Drop for NetnsGuard {
+    fn drop(&mut self) {
+        if let Err(e) = setns(self.origin.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+            error!("Failed to return to the original network namespace: {}", e);
+        }
+    }
+}
+
+/// Run `attach` inside the network namespace if provided. The links are detached in the same namespace
+pub(crate) fn attach_in_netns(
+    netns: Option<&str>,
+    attach: impl FnOnce() -> Result<Vec<AttachLink>>,
+) -> Result<Vec<AttachLink>> {
+    let Some(netns) = netns else {
+        return attach();
+    };
+    let netns_file = open_netns(netns)?;
+    debug!("Attaching in network namespace {}", netns);
+    let links = {
+        let _guard = NetnsGuard::enter(&netns_file)?;
+        // Links attached before a failure are dropped inside the namespace
+        attach().with_context(|| anyhow!("Failed to attach in network namespace `{}`", netns))?
+    };
+    Ok(vec![AttachLink::InNetns(links, netns_file)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_netns_path;
+
+    #[test]
+    fn test_resolve_netns_path() {
+        assert_eq!(resolve_netns_path("1234"), "/proc/1234/ns/net");
+        assert_eq!(
+            resolve_netns_path("/var/run/netns/test"),
+            "/var/run/netns/test"
+        );
+    }
+}
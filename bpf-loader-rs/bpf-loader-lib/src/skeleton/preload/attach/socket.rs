@@ -0,0 +1,87 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use anyhow::{anyhow, bail, Context, Result};
+use libbpf_rs::Program;
+use log::debug;
+use nix::libc;
+
+use crate::meta::{ProgMeta, SocketFilterProgExtraMeta};
+
+use super::{iface::resolve_interfaces, AttachLink};
+
+/// Check the return value of a libc call
+fn check(ret: libc::c_int, what: &str) -> Result<libc::c_int> {
+    if ret < 0 {
+        return Err(anyhow!(std::io::Error::last_os_error()))
+            .with_context(|| anyhow!("Failed to {}", what));
+    }
+    Ok(ret)
+}
+
+/// Open a raw packet socket and attach the program to it. The program is detached once the socket is closed
+pub(crate) fn attach_socket_filter(program: &Program, meta: &ProgMeta) -> Result<AttachLink> {
+    let extra_meta = serde_json::from_value::<SocketFilterProgExtraMeta>(meta.others.clone())
+        .with_context(|| anyhow!("Failed to deserialize socket filter extra meta"))?;
+    let protocol = extra_meta.protocol.to_be();
+    // SAFETY: FFI call
+    let fd = check(
+        unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                protocol as _,
+            )
+        },
+        "open the packet socket",
+    )?;
+    // SAFETY: The fd was just created by us
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    if let Some(iface) = &extra_meta.iface {
+        let ifindex = match resolve_interfaces(std::slice::from_ref(iface), 0)?[..] {
+            [ifindex] => ifindex,
+            _ => bail!("Only one interface could be provided, got `{}`", iface),
+        };
+        // SAFETY: it's a C-repr struct, and only contains scalars. So it's safe to fill it with zero
+        let mut addr = unsafe { std::mem::zeroed::<libc::sockaddr_ll>() };
+        addr.sll_family = libc::AF_PACKET as _;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = ifindex;
+        // SAFETY: addr is valid during the call
+        check(
+            unsafe {
+                libc::bind(
+                    socket.as_raw_fd(),
+                    &addr as *const _ as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_ll>() as _,
+                )
+            },
+            "bind the packet socket to the interface",
+        )?;
+    }
+    let prog_fd = program.fd();
+    // SAFETY: prog_fd is valid during the call
+    check(
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_BPF,
+                &prog_fd as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&prog_fd) as _,
+            )
+        },
+        "attach the program to the socket",
+    )?;
+    debug!(
+        "Attached socket filter to socket {}, interface {:?}",
+        socket.as_raw_fd(),
+        extra_meta.iface
+    );
+    Ok(AttachLink::Fd(socket))
+}
@@ -4,26 +4,103 @@
 //! All rights reserved.
 //!
 
+use std::os::fd::{FromRawFd, OwnedFd};
+
 use anyhow::{anyhow, bail, Context, Result};
 use libbpf_rs::{
     libbpf_sys::{
-        bpf_tc_attach, bpf_tc_hook, bpf_tc_hook_create, bpf_tc_hook_destroy, bpf_tc_opts,
+        bpf_attach_type, bpf_link_create, bpf_tc_attach, bpf_tc_hook, bpf_tc_hook_create,
+        bpf_tc_hook_destroy, bpf_tc_opts, bpf_tc_query, BPF_TC_F_REPLACE,
     },
     Program,
 };
+use log::{debug, warn};
+
+use crate::meta::{ProgMeta, TCAttachPoint, TCMode, TCProgExtraMeta};
 
-use crate::meta::{ProgMeta, TCProgExtraMeta};
+use super::{iface::resolve_interfaces, setns::attach_in_netns, AttachLink};
 
-use super::AttachLink;
+const EEXIST: i32 = 17;
+// Attach types of tcx, which are not in the bindings of the libbpf in use
+const BPF_TCX_INGRESS: bpf_attach_type = 46;
+const BPF_TCX_EGRESS: bpf_attach_type = 47;
 
-pub(crate) fn attach_tc(program: &Program, meta: &ProgMeta) -> Result<AttachLink> {
+/// Attach the program to each of the interfaces. Attached ones are detached when the returned links are dropped, or if any of them failed
+pub(crate) fn attach_tc(program: &Program, meta: &ProgMeta) -> Result<Vec<AttachLink>> {
     let tc_extra_meta = serde_json::from_value::<TCProgExtraMeta>(meta.others.clone())
         .with_context(|| anyhow!("Failed to deserialize tc extra meta"))?;
+    attach_in_netns(tc_extra_meta.netns.as_deref(), || {
+        let mut links = vec![];
+        for ifindex in
+            resolve_interfaces(&tc_extra_meta.tchook.iface, tc_extra_meta.tchook.ifindex)?
+        {
+            links.push(
+                attach_tc_on(program, &tc_extra_meta, ifindex)
+                    .with_context(|| anyhow!("Failed to attach to interface {}", ifindex))?,
+            );
+        }
+        Ok(links)
+    })
+}
+
+fn attach_tc_on(
+    program: &Program,
+    tc_extra_meta: &TCProgExtraMeta,
+    ifindex: i32,
+) -> Result<AttachLink> {
+    let attach_point = &tc_extra_meta.tchook.attach_point;
+    match tc_extra_meta.mode {
+        TCMode::Tcx => attach_tcx(program, attach_point, ifindex),
+        TCMode::Netlink => attach_netlink(program, tc_extra_meta, ifindex),
+        // tcx only has ingress and egress hooks
+        TCMode::Auto if matches!(attach_point, TCAttachPoint::Custom) => {
+            attach_netlink(program, tc_extra_meta, ifindex)
+        }
+        TCMode::Auto => attach_tcx(program, attach_point, ifindex).or_else(|e| {
+            debug!("Falling back to netlink, since tcx is unavailable: {:#}", e);
+            attach_netlink(program, tc_extra_meta, ifindex)
+        }),
+    }
+}
+
+fn attach_tcx(program: &Program, attach_point: &TCAttachPoint, ifindex: i32) -> Result<AttachLink> {
+    let attach_type = match attach_point {
+        TCAttachPoint::Ingress => BPF_TCX_INGRESS,
+        TCAttachPoint::Egress => BPF_TCX_EGRESS,
+        TCAttachPoint::Custom => bail!("tcx doesn't support custom attach points"),
+    };
+    // SAFETY: FFI call. Null opts are accepted
+    let fd = unsafe { bpf_link_create(program.fd(), ifindex, attach_type, std::ptr::null()) };
+    if fd < 0 {
+        return Err(anyhow!(std::io::Error::from_raw_os_error(-fd)))
+            .with_context(|| anyhow!("Failed to create tcx link"));
+    }
+    // SAFETY: The fd was just created by libbpf
+    Ok(AttachLink::Fd(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// Find the id of the program attached with the handle and priority
+fn query_tc_prog_id(tc_hook: &bpf_tc_hook, handle: u32, priority: u32) -> Option<u32> {
+    // SAFETY: it's a C-repr struct, and only contains scalars. So it's safe to fill it with zero
+    let mut tc_opts = unsafe { std::mem::zeroed::<bpf_tc_opts>() };
+    tc_opts.sz = std::mem::size_of::<bpf_tc_opts>() as _;
+    tc_opts.handle = handle;
+    tc_opts.priority = priority;
+    // SAFETY: pointers are valid
+    let err = unsafe { bpf_tc_query(tc_hook, &mut tc_opts) };
+    (err == 0).then_some(tc_opts.prog_id)
+}
+
+fn attach_netlink(
+    program: &Program,
+    tc_extra_meta: &TCProgExtraMeta,
+    ifindex: i32,
+) -> Result<AttachLink> {
     // SAFETY: it's a C-repr struct, and only contains scalars. So it's safe to fill it with zero
     let mut tc_hook = Box::new(unsafe { std::mem::zeroed::<bpf_tc_hook>() });
     tc_hook.sz = std::mem::size_of::<bpf_tc_hook>() as _;
     tc_hook.attach_point = tc_extra_meta.tchook.attach_point.to_value();
-    tc_hook.ifindex = tc_extra_meta.tchook.ifindex;
+    tc_hook.ifindex = ifindex;
     // SAFETY: tc_hook is valid during the call
     let err = unsafe { bpf_tc_hook_create(&mut *tc_hook) };
     /* The hook (i.e. qdisc) may already exists because:
@@ -32,23 +109,51 @@ pub(crate) fn attach_tc(program: &Program, meta: &ProgMeta) -> Result<AttachLink
      *      bpf_tc_hook_destroy does NOT really remove the qdisc,
      *      there may be an egress filter on the qdisc
      */
-    if err != 0 && err != -17
-    /*EEXIST = 17*/
-    {
+    if err != 0 && err != -EEXIST {
         bail!("Failed to create tc hook: {}", err);
     }
+    // Filters of others are on the hook if it already exists, so it should be left as is
+    let hook_created = err == 0;
+    let (handle, priority) = (tc_extra_meta.tcopts.handle, tc_extra_meta.tcopts.priority);
     // SAFETY: it's a C-repr struct, and only contains scalars. So it's safe to fill it with zero
-    let mut tc_opts = Box::new(unsafe { std::mem::zeroed::<bpf_tc_opts>() });
+    let mut tc_opts = unsafe { std::mem::zeroed::<bpf_tc_opts>() };
     tc_opts.sz = std::mem::size_of::<bpf_tc_opts>() as _;
-    tc_opts.handle = tc_extra_meta.tcopts.handle;
-    tc_opts.priority = tc_extra_meta.tcopts.priority;
+    tc_opts.handle = handle;
+    tc_opts.priority = priority;
     tc_opts.prog_fd = program.fd();
+    if tc_extra_meta.tcopts.replace {
+        if let Some(prog_id) = query_tc_prog_id(&tc_hook, handle, priority) {
+            warn!(
+                "Replacing tc program {} on interface {}, handle {}, priority {}",
+                prog_id, ifindex, handle, priority
+            );
+        }
+        tc_opts.flags = BPF_TC_F_REPLACE;
+    }
     // SAFETY: pointers are valid
-    let err = unsafe { bpf_tc_attach(&*tc_hook, &mut *tc_opts) };
-    if err != 0 && err != -17 {
-        // SAFETY: pointer is valid
-        unsafe { bpf_tc_hook_destroy(&mut *tc_hook) };
+    let err = unsafe { bpf_tc_attach(&*tc_hook, &mut tc_opts) };
+    if err != 0 {
+        if hook_created {
+            // SAFETY: pointer is valid
+            unsafe { bpf_tc_hook_destroy(&mut *tc_hook) };
+        }
+        if err == -EEXIST {
+            bail!(
+                "tc program {} is already attached with handle {} and priority {}. Set `replace` in `tcopts` to replace it",
+                query_tc_prog_id(&tc_hook, handle, priority)
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "(unknown)".into()),
+                handle,
+                priority
+            );
+        }
         bail!("Failed to attach tc: {}", err);
     }
-    Ok(AttachLink::TCAttach(tc_hook))
+    // Only the filter itself will be detached
+    // SAFETY: it's a C-repr struct, and only contains scalars. So it's safe to fill it with zero
+    let mut detach_opts = Box::new(unsafe { std::mem::zeroed::<bpf_tc_opts>() });
+    detach_opts.sz = std::mem::size_of::<bpf_tc_opts>() as _;
+    detach_opts.handle = handle;
+    detach_opts.priority = priority;
+    Ok(AttachLink::TCAttach(tc_hook, detach_opts))
 }
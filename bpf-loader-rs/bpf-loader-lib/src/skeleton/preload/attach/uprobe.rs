@@ -0,0 +1,167 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use anyhow::{anyhow, bail, Context, Result};
+use libbpf_rs::{Program, UprobeOpts, UsdtOpts};
+use log::debug;
+
+use crate::meta::{
+    section::{parse_uprobe_section, UprobeKind},
+    ProgMeta, UprobeProgExtraMeta,
+};
+
+use super::AttachLink;
+
+/// Whether the program is a uprobe or USDT program, and its target was provided in the meta (or through the command line)
+/// Such programs are attached manually, even if the section name could be auto-attached
+pub(crate) fn has_uprobe_target(section: &str, meta: &ProgMeta) -> bool {
+    parse_uprobe_section(section).is_some()
+        && serde_json::from_value::<UprobeProgExtraMeta>(meta.others.clone())
+            .map(|v| v != UprobeProgExtraMeta::default())
+            .unwrap_or(false)
+}
+
+fn parse_offset(s: &str) -> Result<usize> {
+    let result = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse::<usize>(),
+    };
+    result.with_context(|| anyhow!("Invalid offset `{}`", s))
+}
+
+/// Parse the target of uprobes, `<binary>:<symbol>[+<offset>]` or `<binary>:<offset>`
+pub(crate) fn parse_uprobe_target(target: &str) -> Result<(&str, Option<&str>, usize)> {
+    let Some((binary, func)) = target.rsplit_once(':') else {
+        return Ok((target, None, 0));
+    };
+    if func.is_empty() {
+        return Ok((binary, None, 0));
+    }
+    if func.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok((binary, None, parse_offset(func)?));
+    }
+    match func.split_once('+') {
+        Some((symbol, offset)) => Ok((binary, Some(symbol), parse_offset(offset)?)),
+        None => Ok((binary, Some(func), 0)),
+    }
+}
+
+/// Parse the USDT probe, `<provider>:<name>`
+fn parse_usdt(probe: &str) -> Result<(&str, &str)> {
+    match probe.split_once(':') {
+        Some((provider, name)) if !provider.is_empty() && !name.is_empty() => Ok((provider, name)),
+        _ => bail!("Invalid USDT probe `{}`, expected `provider:name`", probe),
+    }
+}
+
+pub(crate) fn attach_uprobe(program: &mut Program, meta: &ProgMeta) -> Result<AttachLink> {
+    let extra_meta = serde_json::from_value::<UprobeProgExtraMeta>(meta.others.clone())
+        .with_context(|| anyhow!("Failed to deserialize uprobe extra meta"))?;
+    let section = program.section().to_string();
+    let Some((kind, target)) = parse_uprobe_section(&section) else {
+        bail!("`{}` is not a uprobe or USDT section", section);
+    };
+    let link = match kind {
+        UprobeKind::Uprobe { retprobe } => {
+            let (sec_binary, sec_symbol, sec_offset) = match target {
+                Some(target) => parse_uprobe_target(target)?,
+                None => ("", None, 0),
+            };
+            let binary = extra_meta
+                .binary
+                .as_deref()
+                .or(Some(sec_binary).filter(|v| !v.is_empty()))
+                .ok_or_else(|| anyhow!("Binary of the uprobe is not provided"))?;
+            let (symbol, offset) = if extra_meta.symbol.is_some() || extra_meta.offset != 0 {
+                (extra_meta.symbol.as_deref(), extra_meta.offset)
+            } else {
+                (sec_symbol, sec_offset)
+            };
+            if symbol.is_none() && offset == 0 {
+                bail!("Neither symbol nor offset of the uprobe is provided");
+            }
+            debug!(
+                "Attaching uprobe to {}:{:?}+{:#x}, pid {}, retprobe {}",
+                binary, symbol, offset, extra_meta.pid, retprobe
+            );
+            program
+                .attach_uprobe_with_opts(
+                    extra_meta.pid,
+                    binary,
+                    offset,
+                    UprobeOpts {
+                        retprobe,
+                        cookie: extra_meta.cookie,
+                        func_name: symbol.unwrap_or_default().to_string(),
+                        ..Default::default()
+                    },
+                )
+                .with_context(|| anyhow!("Failed to attach uprobe to `{}`", binary))?
+        }
+        UprobeKind::Usdt => {
+            // The target is `<binary>:<provider>:<name>`
+            let mut parts = target.unwrap_or_default().rsplitn(3, ':');
+            let (sec_name, sec_provider, sec_binary) = (parts.next(), parts.next(), parts.next());
+            let binary = extra_meta
+                .binary
+                .as_deref()
+                .or(sec_binary)
+                .ok_or_else(|| anyhow!("Binary of the USDT probe is not provided"))?;
+            let (provider, name) = match (&extra_meta.usdt, sec_provider, sec_name) {
+                (Some(probe), _, _) => parse_usdt(probe)?,
+                (None, Some(provider), Some(name)) => (provider, name),
+                _ => bail!("USDT probe is not provided"),
+            };
+            debug!(
+                "Attaching USDT {}:{} in {}, pid {}",
+                provider, name, binary, extra_meta.pid
+            );
+            program
+                .attach_usdt_with_opts(
+                    extra_meta.pid,
+                    binary,
+                    provider,
+                    name,
+                    UsdtOpts {
+                        cookie: extra_meta.cookie,
+                        ..Default::default()
+                    },
+                )
+                .with_context(|| {
+                    anyhow!(
+                        "Failed to attach USDT `{}:{}` in `{}`",
+                        provider,
+                        name,
+                        binary
+                    )
+                })?
+        }
+    };
+    Ok(AttachLink::BpfLink(link))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_uprobe_target, parse_usdt};
+
+    #[test]
+    fn test_parse_uprobe_target() {
+        assert_eq!(
+            parse_uprobe_target("/bin/bash:readline").unwrap(),
+            ("/bin/bash", Some("readline"), 0)
+        );
+        assert_eq!(
+            parse_uprobe_target("/lib/libc.so.6:malloc+0x10").unwrap(),
+            ("/lib/libc.so.6", Some("malloc"), 16)
+        );
+        assert_eq!(
+            parse_uprobe_target("/bin/app:0x1234").unwrap(),
+            ("/bin/app", None, 0x1234)
+        );
+        assert_eq!(parse_usdt("libc:setjmp").unwrap(), ("libc", "setjmp"));
+        assert!(parse_usdt("setjmp").is_err());
+    }
+}
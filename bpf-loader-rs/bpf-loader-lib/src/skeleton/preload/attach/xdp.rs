@@ -3,33 +3,148 @@
 //! Copyright (c) 2023, eunomia-bpf
 //! All rights reserved.
 //!
+use std::os::fd::{FromRawFd, OwnedFd};
+
 use anyhow::{anyhow, bail, Context, Result};
 use libbpf_rs::{
-    libbpf_sys::{bpf_xdp_attach, bpf_xdp_attach_opts},
+    libbpf_sys::{
+        bpf_prog_get_fd_by_id, bpf_xdp_attach, bpf_xdp_attach_opts, bpf_xdp_query_id,
+        XDP_FLAGS_DRV_MODE, XDP_FLAGS_HW_MODE, XDP_FLAGS_MODES, XDP_FLAGS_REPLACE,
+        XDP_FLAGS_SKB_MODE, XDP_FLAGS_UPDATE_IF_NOEXIST,
+    },
     Program,
 };
+use log::warn;
+
+use crate::meta::{ProgMeta, XDPMode, XDPProgExtraMeta};
+
+use super::{iface::resolve_interfaces, setns::attach_in_netns, AttachLink};
 
-use crate::meta::{ProgMeta, XDPProgExtraMeta};
+impl XDPMode {
+    fn to_flags(self) -> u32 {
+        match self {
+            XDPMode::Auto | XDPMode::Replace => 0,
+            XDPMode::Skb => XDP_FLAGS_SKB_MODE,
+            XDPMode::Drv => XDP_FLAGS_DRV_MODE,
+            XDPMode::Hw => XDP_FLAGS_HW_MODE,
+        }
+    }
+}
 
-use super::AttachLink;
-pub(crate) fn attach_xdp(program: &Program, meta: &ProgMeta) -> Result<AttachLink> {
+/// Attach the program to each of the interfaces. Attached ones are detached when the returned links are dropped, or if any of them failed
+pub(crate) fn attach_xdp(program: &Program, meta: &ProgMeta) -> Result<Vec<AttachLink>> {
     let xdp_extra_meta = serde_json::from_value::<XDPProgExtraMeta>(meta.others.clone())
         .with_context(|| anyhow!("Failed to deserialize xdp extra meta"))?;
+    let modes = (xdp_extra_meta.flags | xdp_extra_meta.mode.to_flags()) & XDP_FLAGS_MODES;
+    if modes.count_ones() > 1 {
+        bail!("Only one of the skb, drv and hw modes could be used");
+    }
+    attach_in_netns(xdp_extra_meta.netns.as_deref(), || {
+        let mut links = vec![];
+        for ifindex in resolve_interfaces(&xdp_extra_meta.iface, xdp_extra_meta.ifindex)? {
+            links.push(
+                attach_xdp_on(program, &xdp_extra_meta, ifindex)
+                    .with_context(|| anyhow!("Failed to attach to interface {}", ifindex))?,
+            );
+        }
+        Ok(links)
+    })
+}
 
-    let ifindex = xdp_extra_meta.ifindex;
-    let flags = xdp_extra_meta.flags;
+fn attach_xdp_on(
+    program: &Program,
+    xdp_extra_meta: &XDPProgExtraMeta,
+    ifindex: i32,
+) -> Result<AttachLink> {
+    let mut flags = xdp_extra_meta.flags | xdp_extra_meta.mode.to_flags();
     let prog_fd = program.fd();
 
     // SAFETY: it's a C-repr struct, and only contains scalars. So it's safe to fill it with zero
-    let mut xdp_attach_opts = Box::new(unsafe { std::mem::zeroed::<bpf_xdp_attach_opts>() });
+    let mut xdp_attach_opts = unsafe { std::mem::zeroed::<bpf_xdp_attach_opts>() };
     xdp_attach_opts.sz = std::mem::size_of::<bpf_xdp_attach_opts>() as _;
     xdp_attach_opts.old_prog_fd = xdp_extra_meta.xdpopts.old_prog_fd;
 
+    let mut old_prog_id = 0;
+    // SAFETY: the pointer is valid during the call
+    let err =
+        unsafe { bpf_xdp_query_id(ifindex, (flags & XDP_FLAGS_MODES) as _, &mut old_prog_id) };
+    if err < 0 {
+        bail!("Failed to query the attached xdp program: {}", err);
+    }
+    // Holds the fd of the replaced program during the attaching
+    let mut _old_prog_fd = None;
+    let replace = matches!(xdp_extra_meta.mode, XDPMode::Replace) || flags & XDP_FLAGS_REPLACE != 0;
+    if old_prog_id != 0 && !replace {
+        bail!(
+            "xdp program {} is already attached to interface {}. Use the `replace` mode to replace it",
+            old_prog_id,
+            ifindex
+        );
+    }
+    if old_prog_id != 0 {
+        warn!(
+            "Replacing xdp program {} on interface {}",
+            old_prog_id, ifindex
+        );
+        if xdp_attach_opts.old_prog_fd == 0 {
+            // SAFETY: FFI call
+            let fd = unsafe { bpf_prog_get_fd_by_id(old_prog_id) };
+            if fd < 0 {
+                bail!("Failed to get fd of xdp program {}: {}", old_prog_id, fd);
+            }
+            xdp_attach_opts.old_prog_fd = fd;
+            // SAFETY: The fd was just created by libbpf
+            _old_prog_fd = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+        flags |= XDP_FLAGS_REPLACE;
+    } else {
+        // Nothing to replace, and don't overwrite one attached meanwhile
+        flags &= !XDP_FLAGS_REPLACE;
+        xdp_attach_opts.old_prog_fd = 0;
+        flags |= XDP_FLAGS_UPDATE_IF_NOEXIST;
+    }
+
     // SAFETY: xdp_attach_opts is valid during the call
-    let err = unsafe { bpf_xdp_attach(ifindex, prog_fd, flags, &*xdp_attach_opts) };
+    let err = unsafe { bpf_xdp_attach(ifindex, prog_fd, flags, &xdp_attach_opts) };
     if err < 0 {
         bail!("Failed to attach xdp: {}", err);
     }
 
-    Ok(AttachLink::XDPAttach(ifindex, flags, xdp_attach_opts))
+    // Only detach the program if it's still ours
+    // SAFETY: it's a C-repr struct, and only contains scalars. So it's safe to fill it with zero
+    let mut detach_opts = Box::new(unsafe { std::mem::zeroed::<bpf_xdp_attach_opts>() });
+    detach_opts.sz = std::mem::size_of::<bpf_xdp_attach_opts>() as _;
+    detach_opts.old_prog_fd = prog_fd;
+    Ok(AttachLink::XDPAttach(
+        ifindex,
+        (flags & XDP_FLAGS_MODES) | XDP_FLAGS_REPLACE,
+        detach_opts,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::meta::{TCMode, TCProgExtraMeta, XDPMode, XDPProgExtraMeta};
+
+    #[test]
+    fn test_attach_modes() {
+        let meta: XDPProgExtraMeta =
+            serde_json::from_value(json!({"mode": "skb", "iface": "eth0"})).unwrap();
+        assert_eq!(meta.mode, XDPMode::Skb);
+        assert_eq!(meta.mode.to_flags(), 2);
+        let meta: XDPProgExtraMeta = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(meta.mode, XDPMode::Auto);
+        assert!(serde_json::from_value::<XDPProgExtraMeta>(json!({"mode": "native"})).is_err());
+
+        let meta: TCProgExtraMeta = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(meta.mode, TCMode::Auto);
+        assert!(!meta.tcopts.replace);
+        let meta: TCProgExtraMeta =
+            serde_json::from_value(json!({"mode": "netlink", "tcopts": {"replace": true}}))
+                .unwrap();
+        assert_eq!(meta.mode, TCMode::Netlink);
+        assert!(meta.tcopts.replace);
+    }
 }
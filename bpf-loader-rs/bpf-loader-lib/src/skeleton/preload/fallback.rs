@@ -0,0 +1,313 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+use libbpf_rs::libbpf_sys::{
+    bpf_attach_type, bpf_insn, bpf_prog_load, bpf_prog_load_opts, bpf_prog_type,
+    bpf_raw_tracepoint_open, libbpf_find_vmlinux_btf_id, BPF_ALU64, BPF_EXIT, BPF_JMP, BPF_K,
+    BPF_MODIFY_RETURN, BPF_MOV, BPF_PROG_TYPE_RAW_TRACEPOINT, BPF_PROG_TYPE_TRACING,
+    BPF_TRACE_FENTRY, BPF_TRACE_FEXIT, BPF_TRACE_RAW_TP,
+};
+use log::info;
+
+use crate::meta::ProgMeta;
+
+const TRACEFS_EVENTS_DIRS: [&str; 2] = [
+    "/sys/kernel/tracing/events",
+    "/sys/kernel/debug/tracing/events",
+];
+
+/// Attach points which could be probed
+#[derive(Debug, PartialEq, Eq)]
+enum ProbeTarget<'a> {
+    /// fentry, fexit, fmod_ret and tp_btf, which need BTF and BPF trampolines
+    Trampoline(bpf_attach_type, &'a str),
+    RawTracepoint(&'a str),
+    Tracepoint(&'a str),
+    Kprobe(&'a str),
+}
+
+fn parse_probe_target(section: &str) -> Option<ProbeTarget<'_>> {
+    let (prefix, target) = section.split_once('/')?;
+    Some(match prefix {
+        "fentry" => ProbeTarget::Trampoline(BPF_TRACE_FENTRY, target),
+        "fexit" => ProbeTarget::Trampoline(BPF_TRACE_FEXIT, target),
+        "fmod_ret" => ProbeTarget::Trampoline(BPF_MODIFY_RETURN, target),
+        "tp_btf" => ProbeTarget::Trampoline(BPF_TRACE_RAW_TP, target),
+        "raw_tp" | "raw_tracepoint" => ProbeTarget::RawTracepoint(target),
+        "tp" | "tracepoint" => ProbeTarget::Tracepoint(target),
+        // Offsets such as `do_unlinkat+0x10` are ignored
+        "kprobe" | "kretprobe" => ProbeTarget::Kprobe(target.split('+').next().unwrap_or(target)),
+        _ => return None,
+    })
+}
+
+/// Load a program which only returns 0
+fn load_probe_prog(
+    prog_type: bpf_prog_type,
+    expected_attach_type: bpf_attach_type,
+    attach_btf_id: u32,
+) -> Result<OwnedFd> {
+    let mut insns = [bpf_insn::default(); 2];
+    // r0 = 0
+    insns[0].code = (BPF_ALU64 | BPF_MOV | BPF_K) as u8;
+    // exit
+    insns[1].code = (BPF_JMP | BPF_EXIT) as u8;
+    let mut opts = bpf_prog_load_opts {
+        sz: std::mem::size_of::<bpf_prog_load_opts>() as _,
+        expected_attach_type,
+        attach_btf_id,
+        ..Default::default()
+    };
+    // SAFETY: pointers are valid during the call
+    let fd = unsafe {
+        bpf_prog_load(
+            prog_type,
+            std::ptr::null(),
+            c"GPL".as_ptr(),
+            insns.as_ptr(),
+            insns.len() as _,
+            &mut opts,
+        )
+    };
+    if fd < 0 {
+        bail!(
+            "Failed to load the probing program: {}",
+            std::io::Error::from_raw_os_error(-fd)
+        );
+    }
+    // SAFETY: The fd was just created by libbpf
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Attach the probing program, and detach it immediately
+fn attach_probe_prog(name: Option<&str>, prog: &OwnedFd) -> Result<()> {
+    let name = name.map(CString::new).transpose()?;
+    // SAFETY: The name lives during the call
+    let fd = unsafe {
+        bpf_raw_tracepoint_open(
+            name.as_ref()
+                .map(|v| v.as_ptr())
+                .unwrap_or(std::ptr::null()),
+            prog.as_raw_fd(),
+        )
+    };
+    if fd < 0 {
+        bail!(
+            "Failed to attach the probing program: {}",
+            std::io::Error::from_raw_os_error(-fd)
+        );
+    }
+    // SAFETY: The fd was just created by libbpf
+    drop(unsafe { OwnedFd::from_raw_fd(fd) });
+    Ok(())
+}
+
+/// Check whether the kernel supports the attach point of the section. Sections which couldn't be probed are regarded as supported
+pub(crate) fn probe_attach_support(section: &str) -> Result<()> {
+    match parse_probe_target(section) {
+        Some(ProbeTarget::Trampoline(attach_type, name)) => {
+            let c_name = CString::new(name)?;
+            // SAFETY: The name lives during the call
+            let btf_id = unsafe { libbpf_find_vmlinux_btf_id(c_name.as_ptr(), attach_type) };
+            if btf_id < 0 {
+                bail!("`{}` not found in the vmlinux BTF", name);
+            }
+            let prog = load_probe_prog(BPF_PROG_TYPE_TRACING, attach_type, btf_id as u32)?;
+            // Architectures without BPF trampolines fail here
+            attach_probe_prog(None, &prog)
+        }
+        Some(ProbeTarget::RawTracepoint(name)) => {
+            let prog = load_probe_prog(BPF_PROG_TYPE_RAW_TRACEPOINT, 0, 0)?;
+            attach_probe_prog(Some(name), &prog)
+        }
+        Some(ProbeTarget::Tracepoint(name)) => {
+            if TRACEFS_EVENTS_DIRS
+                .iter()
+                .any(|v| Path::new(v).join(name).exists())
+            {
+                Ok(())
+            } else {
+                bail!("Tracepoint `{}` not found in tracefs", name)
+            }
+        }
+        Some(ProbeTarget::Kprobe(name)) => {
+            // Symbols could not be checked if kallsyms is unavailable
+            let Ok(kallsyms) = std::fs::read_to_string("/proc/kallsyms") else {
+                return Ok(());
+            };
+            if kallsyms
+                .lines()
+                .any(|v| v.split_whitespace().nth(2) == Some(name))
+            {
+                Ok(())
+            } else {
+                bail!("Kernel function `{}` not found", name)
+            }
+        }
+        None => Ok(()),
+    }
+}
+
+/// Enable the first program supported by the kernel in each fallback group, and disable the others in the group. A group is a program along with its `fallbacks`, in the order of preference
+pub(crate) fn resolve_fallback_groups(
+    progs: &mut [ProgMeta],
+    probe: impl Fn(&str) -> Result<()>,
+) -> Result<()> {
+    let groups = progs
+        .iter()
+        .filter(|v| !v.fallbacks.is_empty())
+        .map(|v| {
+            std::iter::once(v.name.clone())
+                .chain(v.fallbacks.iter().cloned())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    for name in groups.iter().flatten() {
+        if !progs.iter().any(|v| &v.name == name) {
+            bail!("Fallback program `{}` not found", name);
+        }
+        if !seen.insert(name) {
+            bail!("Program `{}` is in more than one fallback group", name);
+        }
+    }
+    for group in groups.iter() {
+        let mut chosen = None;
+        let mut has_candidate = false;
+        for name in group.iter() {
+            let prog = progs.iter().find(|v| &v.name == name).unwrap();
+            // Programs disabled by users are never chosen
+            if !prog.autoload {
+                continue;
+            }
+            has_candidate = true;
+            match probe(&prog.attach) {
+                Ok(()) => {
+                    chosen = Some(name);
+                    break;
+                }
+                Err(e) => info!(
+                    "Program `{}` ({}) is not supported by the kernel: {:#}",
+                    name, prog.attach, e
+                ),
+            }
+        }
+        let chosen = match chosen {
+            Some(v) => v,
+            None if has_candidate => {
+                return Err(anyhow!(
+                    "None of the programs in fallback group {:?} is supported by the kernel",
+                    group
+                ))
+            }
+            None => continue,
+        };
+        info!("Using program `{}` in fallback group {:?}", chosen, group);
+        for prog in progs.iter_mut() {
+            if group.contains(&prog.name) && &prog.name != chosen {
+                prog.autoload = false;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+    use serde_json::json;
+
+    use super::{parse_probe_target, resolve_fallback_groups, ProbeTarget};
+    use crate::meta::ProgMeta;
+    use libbpf_rs::libbpf_sys::{BPF_TRACE_FENTRY, BPF_TRACE_RAW_TP};
+
+    #[test]
+    fn test_resolve_fallback_groups() {
+        assert_eq!(
+            parse_probe_target("fentry/do_unlinkat"),
+            Some(ProbeTarget::Trampoline(BPF_TRACE_FENTRY, "do_unlinkat"))
+        );
+        assert_eq!(
+            parse_probe_target("tp_btf/sched_switch"),
+            Some(ProbeTarget::Trampoline(BPF_TRACE_RAW_TP, "sched_switch"))
+        );
+        assert_eq!(
+            parse_probe_target("tracepoint/sched/sched_switch"),
+            Some(ProbeTarget::Tracepoint("sched/sched_switch"))
+        );
+        assert_eq!(
+            parse_probe_target("kretprobe/do_unlinkat+0x10"),
+            Some(ProbeTarget::Kprobe("do_unlinkat"))
+        );
+        assert_eq!(parse_probe_target("xdp"), None);
+
+        let prog = |name: &str, attach: &str, fallbacks: &[&str]| ProgMeta {
+            fallbacks: fallbacks.iter().map(|v| v.to_string()).collect(),
+            ..crate::tests::prog(name, attach, json!({}))
+        };
+        let progs = vec![
+            prog("unlink_fentry", "fentry/do_unlinkat", &["unlink_kprobe"]),
+            prog("unlink_kprobe", "kprobe/do_unlinkat", &[]),
+            prog(
+                "switch_btf",
+                "tp_btf/sched_switch",
+                &["switch_raw", "switch_tp"],
+            ),
+            prog("switch_raw", "raw_tp/sched_switch", &[]),
+            prog("switch_tp", "tp/sched/sched_switch", &[]),
+            prog("exec", "tp/sched/sched_process_exec", &[]),
+        ];
+        // An old kernel without BPF trampolines
+        let probe = |section: &str| {
+            if section.starts_with("fentry") || section.starts_with("tp_btf") {
+                bail!("Not supported");
+            }
+            Ok(())
+        };
+        let autoload = |progs: &[ProgMeta]| progs.iter().map(|v| v.autoload).collect::<Vec<_>>();
+
+        let mut resolved = progs.clone();
+        resolve_fallback_groups(&mut resolved, probe).unwrap();
+        assert_eq!(
+            autoload(&resolved),
+            vec![false, true, false, true, false, true]
+        );
+
+        let mut resolved = progs.clone();
+        resolve_fallback_groups(&mut resolved, |_| Ok(())).unwrap();
+        assert_eq!(
+            autoload(&resolved),
+            vec![true, false, true, false, false, true]
+        );
+
+        // Disabled programs are skipped
+        let mut resolved = progs.clone();
+        resolved[3].autoload = false;
+        resolve_fallback_groups(&mut resolved, probe).unwrap();
+        assert_eq!(
+            autoload(&resolved),
+            vec![false, true, false, false, true, true]
+        );
+
+        let mut resolved = progs.clone();
+        assert!(resolve_fallback_groups(&mut resolved, |_| bail!("Not supported")).is_err());
+
+        let mut resolved = progs.clone();
+        resolved[1].fallbacks = vec!["switch_tp".into()];
+        assert!(resolve_fallback_groups(&mut resolved, probe).is_err());
+        let mut resolved = progs;
+        resolved[0].fallbacks = vec!["no_such_prog".into()];
+        assert!(resolve_fallback_groups(&mut resolved, probe).is_err());
+    }
+}
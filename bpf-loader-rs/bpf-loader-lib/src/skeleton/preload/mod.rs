@@ -9,9 +9,19 @@ use std::{collections::HashMap, sync::Arc};
 use crate::{
     btf_container::BtfContainer,
     elf_container::ElfContainer,
-    meta::{EunomiaObjectMeta, RunnerConfig},
+    meta::{
+        section::{
+            is_cgroup_section, is_netns_section, parse_kprobe_multi_section, parse_uprobe_section,
+        },
+        EunomiaObjectMeta, RunnerConfig,
+    },
     skeleton::preload::{
-        attach::{attach_perf_event, attach_tc, attach_xdp, AttachLink},
+        attach::{
+            attach_cgroup, attach_kprobe_multi, attach_netfilter, attach_netns, attach_perf_event,
+            attach_socket_filter, attach_tc, attach_uprobe, attach_uprobe_multi, attach_xdp,
+            has_multi_target, has_uprobe_target, AttachLink,
+        },
+        fallback::{probe_attach_support, resolve_fallback_groups},
         section_loader::load_section_data_with_skel_value,
     },
 };
@@ -20,8 +30,9 @@ use libbpf_rs::OpenObject;
 use log::debug;
 use object::{Object, ObjectSection};
 
-use super::{handle::PollingHandle, BpfSkeleton};
+use super::{handle::PollingHandle, stats::StatsHandle, BpfSkeleton};
 pub(crate) mod attach;
+pub(crate) mod fallback;
 pub(crate) mod section_loader;
 /// Represents an initialized bpf skeleton. It's waiting for the loading and attaching of bpf programs
 pub struct PreLoadBpfSkeleton {
@@ -119,18 +130,44 @@ impl PreLoadBpfSkeleton {
             map.set_initial_value(&buffer[..])
                 .map_err(|e| anyhow!("Failed to set initial value of map `{}`: {}", map_name, e))?;
         }
+        // Choose the programs supported by the kernel in fallback groups
+        resolve_fallback_groups(&mut self.meta.bpf_skel.progs, probe_attach_support)
+            .with_context(|| anyhow!("Failed to resolve fallback programs"))?;
+        // Disabled programs are left in the object, but won't be loaded
+        for prog_meta in self.meta.bpf_skel.progs.iter().filter(|v| !v.autoload) {
+            debug!("Disabling the loading of program `{}`", prog_meta.name);
+            self.bpf_object
+                .prog_mut(&prog_meta.name)
+                .ok_or_else(|| anyhow!("Program named `{}` not found in libbpf", prog_meta.name))?
+                .set_autoload(false)
+                .with_context(|| anyhow!("Failed to disable program `{}`", prog_meta.name))?;
+        }
 
         let mut bpf_object = self
             .bpf_object
             .load()
             .with_context(|| anyhow!("Failed to load bpf object"))?;
+        let object_ptr = bpf_object.as_libbpf_bpf_object_ptr();
         // Next steps are attaching...
         let mut not_attached = vec![];
         let mut links = vec![];
-        for prog_meta in self.meta.bpf_skel.progs.iter() {
+        for prog_meta in self
+            .meta
+            .bpf_skel
+            .progs
+            .iter()
+            .filter(|v| v.autoload && v.autoattach)
+        {
             let bpf_prog = bpf_object
                 .prog_mut(&prog_meta.name)
                 .ok_or_else(|| anyhow!("Program named `{}` not found in libbpf", prog_meta.name))?;
+            // Targets in the meta take precedence over the ones in the section name
+            if has_uprobe_target(bpf_prog.section(), prog_meta)
+                || has_multi_target(bpf_prog.section(), prog_meta)
+            {
+                not_attached.push(prog_meta);
+                continue;
+            }
             match bpf_prog.attach() {
                 Ok(link) => links.push(AttachLink::BpfLink(link)),
                 // EOPNOTSUPP 95 Operation not supported
@@ -147,12 +184,18 @@ impl PreLoadBpfSkeleton {
                 .prog_mut(&prog_meta.name)
                 .ok_or_else(|| anyhow!("Program named `{}` not found", prog_meta.name))?;
             match bpf_prog.section() {
-                "tc" => links.push(attach_tc(bpf_prog, prog_meta).with_context(|| {
-                    anyhow!("Failed to attach tc program `{}`", prog_meta.name)
-                })?),
-                "xdp" => links.push(attach_xdp(bpf_prog, prog_meta).with_context(|| {
-                    anyhow!("Failed to attach xdp program `{}`", prog_meta.name)
-                })?),
+                "tc" => {
+                    let mut tc_links = attach_tc(bpf_prog, prog_meta).with_context(|| {
+                        anyhow!("Failed to attach tc program `{}`", prog_meta.name)
+                    })?;
+                    links.append(&mut tc_links);
+                }
+                "xdp" => {
+                    let mut xdp_links = attach_xdp(bpf_prog, prog_meta).with_context(|| {
+                        anyhow!("Failed to attach xdp program `{}`", prog_meta.name)
+                    })?;
+                    links.append(&mut xdp_links);
+                }
                 "perf_event" => {
                     let mut perf_links =
                         attach_perf_event(bpf_prog, prog_meta).with_context(|| {
@@ -160,11 +203,52 @@ impl PreLoadBpfSkeleton {
                         })?;
                     links.append(&mut perf_links);
                 }
+                "socket" => {
+                    links.push(attach_socket_filter(bpf_prog, prog_meta).with_context(|| {
+                        anyhow!(
+                            "Failed to attach socket filter program `{}`",
+                            prog_meta.name
+                        )
+                    })?)
+                }
+                "netfilter" => {
+                    links.push(attach_netfilter(bpf_prog, prog_meta).with_context(|| {
+                        anyhow!("Failed to attach netfilter program `{}`", prog_meta.name)
+                    })?)
+                }
+                s if is_netns_section(s) => {
+                    links.push(attach_netns(bpf_prog, prog_meta).with_context(|| {
+                        anyhow!("Failed to attach netns program `{}`", prog_meta.name)
+                    })?)
+                }
+                s if is_cgroup_section(s) => {
+                    links.push(attach_cgroup(bpf_prog, prog_meta).with_context(|| {
+                        anyhow!("Failed to attach cgroup program `{}`", prog_meta.name)
+                    })?)
+                }
+                s if parse_kprobe_multi_section(s).is_some() => links.push(
+                    attach_kprobe_multi(object_ptr, bpf_prog, prog_meta).with_context(|| {
+                        anyhow!("Failed to attach kprobe.multi program `{}`", prog_meta.name)
+                    })?,
+                ),
+                s if has_multi_target(s, prog_meta) => {
+                    let mut uprobe_links =
+                        attach_uprobe_multi(bpf_prog, prog_meta).with_context(|| {
+                            anyhow!("Failed to attach uprobe program `{}`", prog_meta.name)
+                        })?;
+                    links.append(&mut uprobe_links);
+                }
+                s if parse_uprobe_section(s).is_some() => {
+                    links.push(attach_uprobe(bpf_prog, prog_meta).with_context(|| {
+                        anyhow!("Failed to attach uprobe program `{}`", prog_meta.name)
+                    })?)
+                }
                 s => bail!("Unsupported attach type: {}", s),
             }
         }
         Ok(BpfSkeleton {
-            handle: PollingHandle::new(),
+            handle: PollingHandle::new()?,
+            stats: StatsHandle::default(),
             meta: self.meta,
             config_data: self.config_data,
             btf: Arc::new(self.btf),
@@ -201,4 +285,38 @@ mod tests {
         }
         assert_eq!(loaded.links.len(), skel.meta.bpf_skel.progs.len());
     }
+
+    #[test]
+    fn test_load_and_attach_selected_programs() {
+        let mut skel: ComposedObject = serde_json::from_str(
+            &std::fs::read_to_string(get_assets_dir().join("bootstrap.json")).unwrap(),
+        )
+        .unwrap();
+        skel.meta.bpf_skel.progs[0].autoload = false;
+        let loaded = BpfSkeletonBuilder::from_json_package(&skel, None)
+            .build()
+            .unwrap()
+            .load_and_attach()
+            .unwrap();
+        let disabled = loaded.prog.prog(&skel.meta.bpf_skel.progs[0].name).unwrap();
+        assert!(!disabled.autoload());
+        assert_eq!(loaded.links.len(), 1);
+
+        skel.meta.bpf_skel.progs[0].autoload = true;
+        skel.meta.bpf_skel.progs[0].autoattach = false;
+        let loaded = BpfSkeletonBuilder::from_json_package(&skel, None)
+            .build()
+            .unwrap()
+            .load_and_attach()
+            .unwrap();
+        assert!(
+            loaded
+                .prog
+                .prog(&skel.meta.bpf_skel.progs[0].name)
+                .unwrap()
+                .fd()
+                >= 0
+        );
+        assert_eq!(loaded.links.len(), 1);
+    }
 }
@@ -0,0 +1,154 @@
+//!  SPDX-License-Identifier: MIT
+//!
+//! Copyright (c) 2023, eunomia-bpf
+//! All rights reserved.
+//!
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::Serialize;
+
+/// The counters of a single export map, updated by the poller
+#[derive(Debug, Default)]
+pub(crate) struct MapStatsCounter {
+    events: AtomicU64,
+    bytes: AtomicU64,
+    decode_failures: AtomicU64,
+    ringbuf_errors: AtomicU64,
+    /// Lost samples are reported by the perf buffer, grouped by cpu. They are rare, so a mutex is enough
+    lost_samples: Mutex<BTreeMap<i32, u64>>,
+}
+
+impl MapStatsCounter {
+    /// Returns the sequence number of this event in the map
+    #[inline]
+    pub(crate) fn record_event(&self, size: usize) -> u64 {
+        self.bytes.fetch_add(size as u64, Ordering::Relaxed);
+        self.events.fetch_add(1, Ordering::Relaxed)
+    }
+    #[inline]
+    pub(crate) fn record_decode_failure(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+    #[inline]
+    pub(crate) fn record_ringbuf_error(&self) {
+        self.ringbuf_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_lost(&self, cpu: i32, count: u64) {
+        *self
+            .lost_samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(cpu)
+            .or_default() += count;
+    }
+    fn snapshot(&self, map_name: &str) -> MapStats {
+        MapStats {
+            map_name: map_name.to_string(),
+            events: self.events.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            decode_failures: self.decode_failures.load(Ordering::Relaxed),
+            ringbuf_errors: self.ringbuf_errors.load(Ordering::Relaxed),
+            lost_samples: self
+                .lost_samples
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        }
+    }
+}
+
+/// Delivery statistics of an export map
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MapStats {
+    /// Name of the map
+    pub map_name: String,
+    /// Events received from the map. For sample maps, each key-value pair counts as one event
+    pub events: u64,
+    /// Total size of the received events, in bytes
+    pub bytes: u64,
+    /// Events that were received but failed to be decoded or handled
+    pub decode_failures: u64,
+    /// Times that a ringbuf callback reported an error, which stops the current consumption
+    pub ringbuf_errors: u64,
+    /// Samples lost by the perf buffer, keyed by cpu
+    pub lost_samples: BTreeMap<i32, u64>,
+}
+
+impl MapStats {
+    /// Total samples lost on all cpus
+    pub fn total_lost_samples(&self) -> u64 {
+        self.lost_samples.values().sum()
+    }
+}
+
+type MapStatsList = Vec<(String, Arc<MapStatsCounter>)>;
+
+/// Holds the statistics of the export maps being polled
+///
+/// It's cheap to clone, and all clones refer to the same statistics. So you can send it to another thread and read the statistics while polling
+#[derive(Debug, Clone, Default)]
+pub struct StatsHandle {
+    maps: Arc<Mutex<MapStatsList>>,
+}
+
+impl StatsHandle {
+    /// Create counters for a map. The counters will be kept until `clear` is called
+    pub(crate) fn register(&self, map_name: &str) -> Arc<MapStatsCounter> {
+        let counter = Arc::new(MapStatsCounter::default());
+        self.maps
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((map_name.to_string(), counter.clone()));
+        counter
+    }
+    /// Remove counters of all maps
+    pub(crate) fn clear(&self) {
+        self.maps.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+    /// Get the current statistics of each export map
+    pub fn snapshot(&self) -> Vec<MapStats> {
+        self.maps
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(name, counter)| counter.snapshot(name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatsHandle;
+
+    #[test]
+    fn test_stats_snapshot() {
+        let stats = StatsHandle::default();
+        let rb = stats.register("rb");
+        let perf = stats.register("events");
+        rb.record_event(16);
+        rb.record_event(8);
+        rb.record_decode_failure();
+        rb.record_ringbuf_error();
+        perf.record_lost(0, 3);
+        perf.record_lost(2, 1);
+        perf.record_lost(0, 2);
+        let snapshot = stats.clone().snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].map_name, "rb");
+        assert_eq!(snapshot[0].events, 2);
+        assert_eq!(snapshot[0].bytes, 24);
+        assert_eq!(snapshot[0].decode_failures, 1);
+        assert_eq!(snapshot[0].ringbuf_errors, 1);
+        assert_eq!(snapshot[1].lost_samples.get(&0), Some(&5));
+        assert_eq!(snapshot[1].total_lost_samples(), 6);
+        stats.clear();
+        assert!(stats.snapshot().is_empty());
+    }
+}
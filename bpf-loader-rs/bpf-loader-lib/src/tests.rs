@@ -7,10 +7,25 @@
 use std::path::PathBuf;
 
 use serde::Deserialize;
+use serde_json::Value;
+
+use crate::meta::ProgMeta;
 
 pub(crate) fn get_assets_dir() -> PathBuf {
     PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets")
 }
+/// A program with the given name, section and extra fields. Other fields are the defaults
+pub(crate) fn prog(name: &str, attach: &str, others: Value) -> ProgMeta {
+    ProgMeta {
+        name: name.into(),
+        attach: attach.into(),
+        link: true,
+        autoload: true,
+        autoattach: true,
+        fallbacks: vec![],
+        others,
+    }
+}
 #[allow(unused)]
 pub(crate) fn start_logger() {
     flexi_logger::Logger::try_with_str("debug")
@@ -12,6 +12,9 @@ enum export_format_type {
     EXPORT_PLAIN_TEXT,
     EXPORT_JSON,
     EXPORT_RAW_EVENT,
+    EXPORT_CSV,
+    EXPORT_LOGFMT,
+    EXPORT_NDJSON,
 };
 struct eunomia_bpf* open_eunomia_skel_from_json_package_with_args(
     const char* json_data,
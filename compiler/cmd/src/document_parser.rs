@@ -150,6 +150,23 @@ fn resolve_map_entities(entities: &Vec<Entity>, map: &mut Value) {
     }
 }
 
+/// Plain text format commands of a map, such as `@template {time} {comm}[{pid}]`
+const MAP_PLAIN_TEXT_COMMANDS: [&str; 3] = ["template", "header", "print_header"];
+
+/// move the plain text format commands of a map into the field `plain_text`
+fn resolve_map_plain_text_format(map: &mut Value) {
+    for command in MAP_PLAIN_TEXT_COMMANDS {
+        if let Some(value) = map.as_object_mut().and_then(|m| m.remove(command)) {
+            map["plain_text"][command] = match (command, value) {
+                // Templates and headers are always strings, even if they look like json
+                ("template" | "header", Value::String(s)) => json!(s),
+                ("template" | "header", v) => json!(v.to_string()),
+                (_, v) => v,
+            };
+        }
+    }
+}
+
 /// resolve bpf progs comments
 fn resolve_progs_entities(entities: &Vec<Entity>, progs: &mut Value) {
     for e in entities {
@@ -210,6 +227,7 @@ fn resolve_bpf_skel_entities(entities: &Vec<Entity>, bpf_skel_json: Value) -> Re
         // resolve comments for section maps
         for map_sec in map_secs {
             resolve_map_entities(entities, map_sec);
+            resolve_map_plain_text_format(map_sec);
         }
     }
     if let Some(progs_secs) = new_skel_json["progs"].as_array_mut() {
@@ -276,7 +294,7 @@ mod test {
 
     use crate::config::{init_eunomia_workspace, CompileArgs, Options};
 
-    use super::parse_source_documents;
+    use super::{parse_source_documents, resolve_map_plain_text_format};
 
     const TEMP_EUNOMIA_DIR: &str = "/tmp/eunomia";
 
@@ -448,6 +466,48 @@ mod test {
         assert_eq!(exec_start, &test_case_res);
     }
 
+    #[test]
+    fn test_parse_map_plain_text_format() {
+        let test_case_res = json!({
+            "ident": "rb",
+            "name": "rb",
+            "plain_text": {
+                "template": "{time} {comm:16} {pid:>7} exited after {duration_ns} ns",
+                "header": "TIME     COMM                 PID"
+            }
+        });
+        let args = create_args();
+        let skel = parse_source_documents(
+            &args,
+            args.compile_opts.source_path.as_str(),
+            json!({"maps": [
+                {
+                    "ident": "rb",
+                    "name": "rb",
+                }
+            ], "progs":[], "data_sections":[]}),
+        );
+        let skel = match skel {
+            Ok(skel) => skel,
+            Err(e) => {
+                if e.to_string()
+                    != "Failed to create Clang instance: an instance of `Clang` already exists"
+                {
+                    panic!("failed to parse source documents: {}", e);
+                }
+                return;
+            }
+        };
+        assert_eq!(&skel["maps"][0], &test_case_res);
+
+        let mut map = json!({"name": "rb", "print_header": false, "template": 1});
+        resolve_map_plain_text_format(&mut map);
+        assert_eq!(
+            map,
+            json!({"name": "rb", "plain_text": {"print_header": false, "template": "1"}})
+        );
+    }
+
     #[test]
     fn test_parse_empty() {
         let args = create_args();
@@ -25,6 +25,8 @@ struct
 	__type(value, u64);
 } exec_start SEC(".maps");
 
+/// @template {time} {comm:16} {pid:>7} exited after {duration_ns} ns
+/// @header TIME     COMM                 PID
 struct
 {
 	__uint(type, BPF_MAP_TYPE_RINGBUF);
@@ -5,7 +5,7 @@
 //!
 use anyhow::bail;
 use ecli_lib::{
-    config::ProgramType,
+    config::{ExportFormat, ProgramType},
     error::{Error, Result},
     runner::helper::try_load_program_buf_and_guess_type,
 };
@@ -46,6 +46,9 @@ pub fn prog_type_value_parser(s: &str) -> std::result::Result<ProgramType, Strin
         }
     })
 }
+pub fn export_format_value_parser(s: &str) -> std::result::Result<ExportFormat, String> {
+    s.parse().map_err(|e: Error| e.to_string())
+}
 #[allow(unused)]
 pub async fn read_stdio_input() -> Result<Vec<u8>> {
     let mut result = vec![];
@@ -136,6 +136,25 @@ pub enum Action {
             help = "Let the ebpf program prints the logs in json format. Only works for JSON program"
         )]
         json: bool,
+        #[arg(
+            long,
+            conflicts_with = "json",
+            help = "Output format of the ebpf program logs, one of `plain`, `json`, `csv`, `logfmt` and `ndjson`. Only works for JSON program",
+            value_parser = helper::export_format_value_parser
+        )]
+        format: Option<ecli_lib::config::ExportFormat>,
+        #[arg(
+            long,
+            short,
+            help = "Only export events matching the expression, such as `comm == \"nginx\" && latency_ns > 1ms`. Only works for JSON program"
+        )]
+        filter: Option<String>,
+        #[arg(
+            long,
+            value_name = "ADDRESS",
+            help = "Serve sample maps and event counters as Prometheus metrics at http://<ADDRESS>/metrics, such as `127.0.0.1:9100`. Sample maps will not be printed. Only works for JSON program"
+        )]
+        metrics: Option<String>,
         #[clap(long, short, help = "Manually specity the program type", value_parser = helper::prog_type_value_parser)]
         prog_type: Option<ecli_lib::config::ProgramType>,
         #[arg(help = "Command line to run. The executable could either be a local path or URL or `-` (read from stdin). The following arguments will be passed to the program", action = clap::ArgAction::Append, allow_hyphen_values = true, required = true)]
@@ -199,9 +218,16 @@ async fn main() -> anyhow::Result<()> {
     #[cfg(feature = "native")]
     {
         if let Some((prog, extra_args)) = args.command_line.split_first() {
-            native_client::run_native(false, prog.to_string(), extra_args, None)
-                .await
-                .with_context(|| anyhow!("Failed to run native eBPF program"))?;
+            native_client::run_native(
+                ecli_lib::config::ExportFormat::PlainText,
+                None,
+                None,
+                prog.to_string(),
+                extra_args,
+                None,
+            )
+            .await
+            .with_context(|| anyhow!("Failed to run native eBPF program"))?;
             return Ok(());
         }
     }
@@ -210,11 +236,19 @@ async fn main() -> anyhow::Result<()> {
         #[cfg(feature = "native")]
         Some(Action::Run {
             json,
+            format,
+            filter,
+            metrics,
             command_line,
             prog_type,
         }) => {
             let (prog, args) = command_line.split_first().unwrap();
-            native_client::run_native(json, prog.to_string(), args, prog_type)
+            let format = if json {
+                ecli_lib::config::ExportFormat::Json
+            } else {
+                format.unwrap_or_default()
+            };
+            native_client::run_native(format, filter, metrics, prog.to_string(), args, prog_type)
                 .await
                 .with_context(|| anyhow!("Failed to run native eBPF program"))
         }
@@ -8,7 +8,7 @@ use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use ecli_lib::{
-    config::ProgramType,
+    config::{ExportFormat, ProgramType},
     runner::{
         client::{native::EcliNativeClient, AbstractClient},
         LogType,
@@ -20,7 +20,9 @@ use crate::helper::load_prog_buf_and_guess_type;
 pub static TERMINATED: AtomicBool = AtomicBool::new(false);
 
 pub(crate) async fn run_native(
-    export_json: bool,
+    export_format: ExportFormat,
+    event_filter: Option<String>,
+    metrics_address: Option<String>,
     prog: String,
     args: &[String],
     user_prog_type: Option<ProgramType>,
@@ -33,7 +35,9 @@ pub(crate) async fn run_native(
             Some("NativeProgram".to_string()),
             &buf,
             prog_type,
-            export_json,
+            export_format,
+            event_filter,
+            metrics_address,
             args,
             None,
         )
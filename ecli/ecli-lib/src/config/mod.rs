@@ -35,6 +35,39 @@ impl TryFrom<&str> for ProgramType {
     }
 }
 
+/// Output format of the events exported by JSON programs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    /// Human-readable texts
+    #[default]
+    PlainText,
+    /// A json object per event
+    Json,
+    /// Comma-separated values, with a header of field names
+    Csv,
+    /// `key=value` pairs separated by spaces
+    Logfmt,
+    /// A json object per event, wrapped with the timestamp, map name and type name
+    Ndjson,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" | "plaintext" => Ok(ExportFormat::PlainText),
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            "logfmt" => Ok(ExportFormat::Logfmt),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            s => Err(Error::InvalidParam(format!(
+                "Unknown export format: {s}. Valids are `plain`, `json`, `csv`, `logfmt` and `ndjson`"
+            ))),
+        }
+    }
+}
+
 impl FromStr for ProgramType {
     type Err = Error;
 
@@ -8,7 +8,10 @@
 #[cfg(feature = "native-client")]
 pub mod native;
 
-use crate::{config::ProgramType, error::Result};
+use crate::{
+    config::{ExportFormat, ProgramType},
+    error::Result,
+};
 
 use super::{LogEntry, ProgramHandle};
 
@@ -33,7 +36,9 @@ pub trait AbstractClient {
         name: Option<String>,
         prog_buf: &[u8],
         prog_type: ProgramType,
-        export_json: bool,
+        export_format: ExportFormat,
+        event_filter: Option<String>,
+        metrics_address: Option<String>,
         args: &[String],
         btf_archive_path: Option<String>,
     ) -> Result<ProgramHandle>;
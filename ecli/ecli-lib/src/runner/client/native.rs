@@ -6,7 +6,7 @@
 use std::sync::RwLock;
 
 use crate::{
-    config::ProgramType,
+    config::{ExportFormat, ProgramType},
     error::{Error, Result},
     runner::{
         task_manager::NativeTaskManager, LogEntry, ProgramHandle, DEFAULT_MAXIMUM_LOG_ENTRIES,
@@ -36,7 +36,9 @@ impl AbstractClient for EcliNativeClient {
         name: Option<String>,
         prog_buf: &[u8],
         prog_type: ProgramType,
-        export_json: bool,
+        export_format: ExportFormat,
+        event_filter: Option<String>,
+        metrics_address: Option<String>,
         args: &[String],
         btf_archive_path: Option<String>,
     ) -> Result<ProgramHandle> {
@@ -46,7 +48,9 @@ impl AbstractClient for EcliNativeClient {
             name.unwrap_or_else(|| "NativeProgram".to_string()),
             &buf,
             prog_type,
-            export_json,
+            export_format,
+            event_filter,
+            metrics_address,
             args,
             btf_archive_path,
         )? as ProgramHandle;
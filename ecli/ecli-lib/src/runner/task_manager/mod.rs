@@ -16,7 +16,11 @@ use std::{
 
 use bpf_compatible_rs::{tempfile::TempDir, unpack_tar};
 use bpf_loader_lib::{
-    export_event::{EventHandler, ExportFormatType, ReceivedEventData},
+    export_event::{
+        filter::EventFilter,
+        metrics::{MetricsRegistry, MetricsServer},
+        EventHandler, ExportFormatType, ReceivedEventData,
+    },
     meta::ComposedObject,
     skeleton::{builder::BpfSkeletonBuilder, handle::PollingHandle},
 };
@@ -25,7 +29,7 @@ use wasm_bpf_rs::{
 };
 
 use crate::{
-    config::ProgramType,
+    config::{ExportFormat, ProgramType},
     error::{Error, Result},
 };
 
@@ -170,12 +174,17 @@ impl NativeTaskManager {
         Ok(())
     }
     /// Start a task
+    /// For JSON programs, only events matching `event_filter` will be exported, if provided. It overrides the filter in the package
+    /// For JSON programs, sample maps and event counters will be served as Prometheus metrics at `http://<metrics_address>/metrics`, if provided
+    #[allow(clippy::too_many_arguments)]
     pub fn start_task(
         &mut self,
         name: impl Into<String>,
         prog_buf: &[u8],
         prog_type: ProgramType,
-        export_json: bool,
+        export_format: ExportFormat,
+        event_filter: Option<String>,
+        metrics_address: Option<String>,
         args: &[String],
         btf_archive_path: Option<String>,
     ) -> Result<usize> {
@@ -229,6 +238,12 @@ impl NativeTaskManager {
                         bpf_loader_lib::meta::arg_parser::UnpresentVariableAction::FillWithZero,
                     )
                     .map_err(|e| Error::Bpf(format!("Failed to parse arguments: {}", e)))?;
+                // A filter shipped in the package is kept unless overridden
+                if let Some(filter) = event_filter {
+                    EventFilter::parse(&filter)
+                        .map_err(|e| Error::InvalidParam(format!("Invalid filter: {:?}", e)))?;
+                    package.meta.event_filter = Some(filter);
+                }
                 let btf_path = btf.extract_archive_path().map(|e| e.to_string());
                 let join_handle = std::thread::spawn(move || {
                     let skel = BpfSkeletonBuilder::from_json_package(&package, btf_path.as_deref())
@@ -236,19 +251,39 @@ impl NativeTaskManager {
                         .map_err(|e| Error::Bpf(format!("Failed to build skeleton: {:?}", e)))?
                         .load_and_attach()
                         .map_err(|e| Error::Bpf(format!("Failed to load and attach: {:?}", e)))?;
+                    let metrics = match metrics_address {
+                        Some(address) => {
+                            let registry =
+                                MetricsRegistry::new().with_stats(skel.create_stats_handle());
+                            let server = MetricsServer::serve(address.as_str(), registry.clone())
+                                .map_err(|e| {
+                                Error::Bpf(format!("Failed to serve metrics: {:?}", e))
+                            })?;
+                            Some((registry, server))
+                        }
+                        None => None,
+                    };
                     tx.send(skel.create_poll_handle()).unwrap();
-                    skel.wait_and_poll_to_handler(
-                        if export_json {
-                            ExportFormatType::Json
-                        } else {
-                            ExportFormatType::PlainText
-                        },
-                        Some(Arc::new(MyEventHandler {
-                            log_buffer: log_buffer_inner,
-                            log_cursor: log_cursor_inner,
-                        })),
-                        None,
-                    )
+                    let export_format = match export_format {
+                        ExportFormat::PlainText => ExportFormatType::PlainText,
+                        ExportFormat::Json => ExportFormatType::Json,
+                        ExportFormat::Csv => ExportFormatType::Csv,
+                        ExportFormat::Logfmt => ExportFormatType::Logfmt,
+                        ExportFormat::Ndjson => ExportFormatType::Ndjson,
+                    };
+                    let handler: Arc<dyn EventHandler> = Arc::new(MyEventHandler {
+                        log_buffer: log_buffer_inner,
+                        log_cursor: log_cursor_inner,
+                    });
+                    match &metrics {
+                        Some((registry, _)) => skel.wait_and_poll_to_handler_with_metrics(
+                            export_format,
+                            Some(handler),
+                            None,
+                            registry,
+                        ),
+                        None => skel.wait_and_poll_to_handler(export_format, Some(handler), None),
+                    }
                     .unwrap();
                     Result::Ok(())
                 });
@@ -438,7 +473,9 @@ impl EventHandler for MyEventHandler {
     ) {
         let mut guard = self.log_buffer.write().unwrap();
         match data {
-            ReceivedEventData::JsonText(j) | ReceivedEventData::PlainText(j) => {
+            ReceivedEventData::JsonText(j)
+            | ReceivedEventData::NdjsonText(j)
+            | ReceivedEventData::PlainText(j) => {
                 guard.push((
                     self.log_cursor
                         .fetch_add(1, std::sync::atomic::Ordering::Relaxed),